@@ -0,0 +1,32 @@
+//! Build script: records the git commit and rustc version for `pmacs-vpn version`
+
+use std::process::Command;
+
+fn main() {
+    let git_sha = command_output("git", &["rev-parse", "--short=12", "HEAD"]);
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]);
+
+    println!("cargo:rustc-env=PMACS_VPN_GIT_SHA={}", git_sha.as_deref().unwrap_or("unknown"));
+    println!(
+        "cargo:rustc-env=PMACS_VPN_RUSTC_VERSION={}",
+        rustc_version.as_deref().unwrap_or("unknown")
+    );
+
+    // Pick up new commits; absent outside a git checkout (e.g. a crate tarball)
+    if std::path::Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs/heads");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+/// Trimmed stdout of a command, if it ran successfully
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
@@ -0,0 +1,70 @@
+//! Build information for `pmacs-vpn version`
+//!
+//! Bug reports depend on which build someone is running, so this collects
+//! the crate version, the git commit and rustc version recorded by
+//! `build.rs`, and what the build can do.
+
+use serde::Serialize;
+use std::fmt;
+
+/// Version and capabilities of this build
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Short commit hash, or "unknown" when built outside a git checkout
+    pub git_commit: &'static str,
+    pub rustc: &'static str,
+    pub target_os: &'static str,
+    /// Login methods the client can complete
+    pub auth_methods: Vec<&'static str>,
+    /// MFA methods usable with password login
+    pub mfa_methods: Vec<&'static str>,
+    /// Data channels the tunnel can carry traffic over
+    pub transports: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// Information about the running binary
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("PMACS_VPN_GIT_SHA"),
+            rustc: env!("PMACS_VPN_RUSTC_VERSION"),
+            target_os: std::env::consts::OS,
+            // SAML gateways are detected at prelogin but can't be completed
+            auth_methods: vec!["password"],
+            mfa_methods: vec!["duo-push", "duo-sms", "duo-call", "duo-passcode"],
+            transports: vec!["ssl", "udp"],
+        }
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pmacs-vpn {}", self.version)?;
+        writeln!(f, "  Commit: {}", self.git_commit)?;
+        writeln!(f, "  Compiler: {}", self.rustc)?;
+        writeln!(f, "  Platform: {}", self.target_os)?;
+        writeln!(f, "  Auth methods: {}", self.auth_methods.join(", "))?;
+        writeln!(f, "  MFA methods: {}", self.mfa_methods.join(", "))?;
+        write!(f, "  Transports: {}", self.transports.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info_json() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_commit.is_empty());
+
+        let json: serde_json::Value = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["transports"], serde_json::json!(["ssl", "udp"]));
+        assert!(json["auth_methods"].as_array().unwrap().contains(&"password".into()));
+        assert!(info.to_string().starts_with("pmacs-vpn "));
+    }
+}
@@ -1,7 +1,10 @@
 //! Configuration handling for PMACS VPN
 
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use crate::gp::auth::ClientIdentity;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -12,6 +15,24 @@ pub enum ConfigError {
     ParseError(#[from] toml::de::Error),
     #[error("Failed to serialize config: {0}")]
     SerializeError(#[from] toml::ser::Error),
+    #[error("Invalid host entry '{0}': {1}")]
+    InvalidHost(String, String),
+    #[error("Invalid gateway '{0}': {1}")]
+    InvalidGateway(String, String),
+    #[error("Invalid route_table {0}: tables 0 and 253-255 are reserved")]
+    InvalidRouteTable(u32),
+    #[error("Failed to read hosts_file {0}: {1}")]
+    HostsFileError(String, std::io::Error),
+    #[error("Invalid [client] {0}: must be non-empty with no control characters")]
+    InvalidClient(&'static str),
+    #[error("Invalid [client.headers] entry {0:?}: needs a valid header name other than Host/User-Agent and a value with no control characters")]
+    InvalidClientHeader(String),
+    #[error("Failed to read included config {0}: {1}")]
+    IncludeError(String, std::io::Error),
+    #[error("Config {0} includes itself")]
+    IncludeCycle(String),
+    #[error("skip_if_onnet needs onnet_probe: an internal host (or host:port) that only answers on the PMACS network")]
+    MissingOnnetProbe,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
@@ -35,6 +56,21 @@ impl DuoMethod {
         }
     }
 
+    /// Auth string for the `n`th enrolled DUO device (`--duo-device`)
+    ///
+    /// DUO numbers devices from 1: device 2 gets "push2", "sms2" or "phone2".
+    /// Whether the gateway passes the suffix through to DUO depends on its
+    /// configuration; device 1 (or no device) is the same as `as_auth_str`.
+    pub fn auth_str_for_device(&self, device: Option<u8>) -> Option<String> {
+        match (self, device) {
+            (_, None | Some(1)) => self.as_auth_str().map(str::to_string),
+            (DuoMethod::Push, Some(n)) => Some(format!("push{}", n)),
+            (DuoMethod::Sms, Some(n)) => Some(format!("sms{}", n)),
+            (DuoMethod::Call, Some(n)) => Some(format!("phone{}", n)),
+            (DuoMethod::Passcode, Some(_)) => None,
+        }
+    }
+
     /// Get user-friendly description for prompts
     pub fn description(&self) -> &'static str {
         match self {
@@ -46,6 +82,28 @@ impl DuoMethod {
     }
 }
 
+/// Data channel used for tunnel traffic
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Packets inside the TLS connection (always available)
+    #[default]
+    Ssl,
+    /// ESP over UDP when the gateway offers it, falling back to SSL
+    Udp,
+}
+
+/// Whether the SSL tunnel sends keepalives when idle
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Keepalive {
+    /// Send a keepalive after each idle interval
+    #[default]
+    On,
+    /// Never send one; the gateway alone decides when an idle tunnel ends
+    Off,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Preferences {
     /// Save password to OS keychain
@@ -80,6 +138,87 @@ pub struct Preferences {
     /// Lower values detect dead tunnels faster but may cause false positives
     #[serde(default = "default_inbound_timeout")]
     pub inbound_timeout_secs: u32,
+
+    /// Block traffic to routed hosts if the tunnel drops unexpectedly
+    /// (blackhole routes stay in place until reconnect or disconnect)
+    #[serde(default)]
+    pub kill_switch: bool,
+
+    /// Data channel: "ssl" (default) or "udp" (ESP, falls back to SSL)
+    #[serde(default)]
+    pub transport: Transport,
+
+    /// SSL keepalives: "on" (default) or "off" (for debugging disconnects)
+    #[serde(default)]
+    pub keepalive: Keepalive,
+
+    /// Vary each keepalive interval at random by up to this many percent
+    /// either way (default: 10, max: 50, 0 = exact intervals)
+    #[serde(default = "default_keepalive_jitter")]
+    pub keepalive_jitter_percent: u32,
+
+    /// Retries when the gateway answers login/getconfig with HTTP 5xx
+    #[serde(default = "default_gateway_retries")]
+    pub gateway_retries: u32,
+
+    /// Also route the networks the gateway's split tunnel includes
+    #[serde(default = "default_true")]
+    pub gateway_routes: bool,
+
+    /// Refuse to connect when there is nothing to route (no hosts and no
+    /// gateway split-tunnel networks)
+    #[serde(default)]
+    pub require_hosts: bool,
+
+    /// Give the tunnel the IPv6 address the gateway assigns and route IPv6
+    /// hosts through it (Windows only for now)
+    #[serde(default)]
+    pub ipv6: bool,
+
+    /// Flush the system DNS cache after disconnecting
+    #[serde(default = "default_true")]
+    pub flush_dns_on_disconnect: bool,
+
+    /// Linux only: add routes to this routing table (plus policy rules)
+    /// instead of the main table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route_table: Option<u32>,
+
+    /// Write routed hostnames to the hosts file (off: rely on the system resolver)
+    #[serde(default = "default_true")]
+    pub manage_hosts: bool,
+
+    /// Send all IPv4 traffic through the tunnel, not just the configured hosts
+    #[serde(default)]
+    pub full_tunnel: bool,
+
+    /// Hosts file to manage instead of the system one (e.g. in a container
+    /// or test rig); relative to the config file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hosts_file_path: Option<PathBuf>,
+
+    /// When a host resolves to several addresses, route a private one
+    /// rather than a public one listed first (split-horizon DNS)
+    #[serde(default = "default_true")]
+    pub prefer_private: bool,
+
+    /// Linux only: also point the system resolver (systemd-resolved,
+    /// resolvconf or /etc/resolv.conf) at the VPN DNS for its domains
+    #[serde(default)]
+    pub manage_resolver: bool,
+
+    /// Don't connect when `onnet_probe` answers without the VPN (already on
+    /// the PMACS network)
+    #[serde(default)]
+    pub skip_if_onnet: bool,
+
+    /// Internal host (or host:port, default port 443) probed for `skip_if_onnet`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub onnet_probe: Option<String>,
+
+    /// How long the `skip_if_onnet` probe waits for an answer
+    #[serde(default = "default_onnet_probe_timeout")]
+    pub onnet_probe_timeout_secs: u32,
 }
 
 fn default_true() -> bool {
@@ -94,6 +233,18 @@ fn default_reconnect_delay() -> u32 {
     5
 }
 
+fn default_gateway_retries() -> u32 {
+    crate::gp::auth::DEFAULT_GATEWAY_RETRIES
+}
+
+fn default_keepalive_jitter() -> u32 {
+    crate::gp::tunnel::DEFAULT_KEEPALIVE_JITTER_PERCENT
+}
+
+fn default_onnet_probe_timeout() -> u32 {
+    2
+}
+
 fn default_inbound_timeout() -> u32 {
     45  // Faster dead tunnel detection (was 90s)
 }
@@ -109,16 +260,353 @@ impl Default for Preferences {
             max_reconnect_attempts: 3,
             reconnect_delay_secs: 5,
             inbound_timeout_secs: 45,
+            kill_switch: false,
+            transport: Transport::Ssl,
+            keepalive: Keepalive::On,
+            keepalive_jitter_percent: default_keepalive_jitter(),
+            gateway_retries: default_gateway_retries(),
+            gateway_routes: true,
+            require_hosts: false,
+            ipv6: false,
+            flush_dns_on_disconnect: true,
+            route_table: None,
+            manage_hosts: true,
+            full_tunnel: false,
+            hosts_file_path: None,
+            prefer_private: true,
+            manage_resolver: false,
+            skip_if_onnet: false,
+            onnet_probe: None,
+            onnet_probe_timeout_secs: default_onnet_probe_timeout(),
         }
     }
 }
 
+/// A host to route through the VPN
+///
+/// In config this is either a plain string (`"prometheus.pmacs.upenn.edu"`)
+/// or a table with a DNS server override:
+/// `{ name = "prometheus.pmacs.upenn.edu", dns = "10.0.0.2" }`.
+///
+/// Either form may name a service port (`"prometheus.pmacs.upenn.edu:22"`,
+/// or `port = 22` in the table) for `status --check` to probe. Routing
+/// always covers the whole host, whatever the port.
+///
+/// The table form may also carry a `comment` saying why the host is routed;
+/// it is written after the host's lines in the hosts file and shown by
+/// `status`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostEntry {
+    /// Hostname, optionally with a `/prefix` route annotation
+    pub name: String,
+    /// DNS server to try first for this host (before the tunnel's DNS)
+    pub dns: Option<IpAddr>,
+    /// Service port to probe when checking reachability
+    pub port: Option<u16>,
+    /// Why the host is routed (table form only)
+    pub comment: Option<String>,
+}
+
+impl HostEntry {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            dns: None,
+            port: None,
+            comment: None,
+        }
+    }
+
+    /// Hostname (with any `/prefix`), what the rest of the code routes by
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether `other` routes the same way (only the port or comment differ)
+    pub fn same_route(&self, other: &HostEntry) -> bool {
+        self.name == other.name && self.dns == other.dns
+    }
+}
+
+impl From<&str> for HostEntry {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+impl From<String> for HostEntry {
+    fn from(name: String) -> Self {
+        Self::new(name)
+    }
+}
+
+impl PartialEq<&str> for HostEntry {
+    fn eq(&self, other: &&str) -> bool {
+        self.dns.is_none() && self.port.is_none() && self.comment.is_none() && self.name == *other
+    }
+}
+
+/// Table form of a host entry
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostEntryTable {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dns: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+impl Serialize for HostEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Keep the plain-string form unless there's something extra to say
+        match (self.dns, self.port, &self.comment) {
+            (None, None, None) => serializer.serialize_str(&self.name),
+            (None, Some(port), None) => {
+                serializer.serialize_str(&format!("{}:{}", self.name, port))
+            }
+            (dns, port, comment) => HostEntryTable {
+                name: self.name.clone(),
+                dns,
+                port,
+                comment: comment.clone(),
+            }
+            .serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HostEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HostEntryVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for HostEntryVisitor {
+            type Value = HostEntry;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a hostname or a table like { name = \"...\", dns = \"10.0.0.2\" }")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<HostEntry, E> {
+                let (name, port) = split_host_port(name);
+                Ok(HostEntry {
+                    port,
+                    ..HostEntry::new(name)
+                })
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<HostEntry, A::Error> {
+                let table =
+                    HostEntryTable::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                let (name, port) = split_host_port(&table.name);
+                Ok(HostEntry {
+                    name,
+                    dns: table.dns,
+                    port: table.port.or(port),
+                    comment: clean_comment(table.comment),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(HostEntryVisitor)
+    }
+}
+
+/// A host comment fit for one hosts file line (None if blank)
+fn clean_comment(comment: Option<String>) -> Option<String> {
+    let comment = comment?.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!comment.is_empty()).then_some(comment)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub vpn: VpnConfig,
-    pub hosts: Vec<String>,
+    /// Hosts routed before `hosts` (e.g. a jump host other hosts depend on)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub priority_hosts: Vec<HostEntry>,
+    pub hosts: Vec<HostEntry>,
+    /// File with more hosts, one per line (relative to the config file)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hosts_file: Option<PathBuf>,
+    /// Hosts read from `hosts_file` by `load` (never written back)
+    #[serde(skip)]
+    pub file_hosts: Vec<HostEntry>,
+    /// Domains appended to names that don't resolve as given (e.g. "pmacs.upenn.edu")
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search_domains: Vec<String>,
+    /// DNS servers to resolve hosts with instead of the gateway's (routed through the tunnel)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<IpAddr>,
     #[serde(default)]
     pub preferences: Preferences,
+    /// Client OS/version reported to the gateway (`[client]`, defaults if omitted)
+    #[serde(default, skip_serializing_if = "ClientIdentity::is_default")]
+    pub client: ClientIdentity,
+    /// Config files merged in before this one, relative to it (see `load`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+    /// This file's `hosts` replace the included files' instead of adding to them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hosts_replace: bool,
+    /// Which file each setting came from (filled in by `load`)
+    #[serde(skip)]
+    pub sources: ConfigSources,
+}
+
+/// Lists a file adds to those of the files it includes; every other value
+/// replaces the included one
+const APPENDED_LISTS: [&str; 4] = ["hosts", "priority_hosts", "search_domains", "dns_servers"];
+
+/// Where a loaded config's settings came from
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfigSources {
+    /// Every file read, included ones first
+    pub files: Vec<PathBuf>,
+    /// Files that set each setting (dotted key, e.g. "vpn.gateway"); several
+    /// for lists that were added to
+    pub keys: BTreeMap<String, Vec<PathBuf>>,
+    /// The included files merged, without the loaded file's own settings
+    #[serde(skip)]
+    base: Option<toml::Table>,
+}
+
+/// One file of an `include` chain, with only its own settings
+struct ConfigLayer {
+    path: PathBuf,
+    table: toml::Table,
+    hosts_replace: bool,
+}
+
+/// Read `path` and the files it includes into `layers`, included ones first;
+/// returns its own `include` and `hosts_replace`
+///
+/// `chain` holds the files currently being read, to catch include cycles.
+fn collect_layers(
+    path: &Path,
+    chain: &mut Vec<PathBuf>,
+    layers: &mut Vec<ConfigLayer>,
+) -> Result<(Vec<PathBuf>, bool), ConfigError> {
+    let read = std::fs::read_to_string(path).and_then(|content| Ok((content, std::fs::canonicalize(path)?)));
+    let (content, canonical) = match read {
+        Ok(read) => read,
+        Err(e) if chain.is_empty() => return Err(e.into()),
+        Err(e) => return Err(ConfigError::IncludeError(path.display().to_string(), e)),
+    };
+    if chain.contains(&canonical) {
+        return Err(ConfigError::IncludeCycle(path.display().to_string()));
+    }
+    let mut table: toml::Table = toml::from_str(&content)?;
+    let include: Vec<PathBuf> = match table.remove("include") {
+        Some(value) => value.try_into()?,
+        None => vec![],
+    };
+    let hosts_replace: bool = match table.remove("hosts_replace") {
+        Some(value) => value.try_into()?,
+        None => false,
+    };
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    // The loaded file's own paths are resolved (or kept relative) by `load`
+    if !chain.is_empty() {
+        absolutize_paths(&mut table, dir);
+    }
+    chain.push(canonical);
+    for file in &include {
+        collect_layers(&dir.join(file), chain, layers)?;
+    }
+    chain.pop();
+    layers.push(ConfigLayer {
+        path: path.to_path_buf(),
+        table,
+        hosts_replace,
+    });
+    Ok((include, hosts_replace))
+}
+
+/// Make an included file's paths absolute, so they stay relative to it
+fn absolutize_paths(table: &mut toml::Table, dir: &Path) {
+    let absolute = |value: &mut toml::Value| {
+        if let toml::Value::String(path) = value {
+            let joined = dir.join(&*path);
+            *path = std::path::absolute(&joined).unwrap_or(joined).display().to_string();
+        }
+    };
+    if let Some(value) = table.get_mut("hosts_file") {
+        absolute(value);
+    }
+    if let Some(toml::Value::Table(preferences)) = table.get_mut("preferences")
+        && let Some(value) = preferences.get_mut("hosts_file_path")
+    {
+        absolute(value);
+    }
+}
+
+/// Merge `layer` (from `file`) over `merged`, noting in `keys` what it set
+///
+/// Tables merge key by key. `APPENDED_LISTS` are added to unless
+/// `hosts_replace` (which covers `hosts` only); anything else replaces.
+fn merge_layer(
+    merged: &mut toml::Table,
+    layer: toml::Table,
+    prefix: &str,
+    hosts_replace: bool,
+    file: &Path,
+    keys: &mut BTreeMap<String, Vec<PathBuf>>,
+) {
+    for (key, value) in layer {
+        let dotted = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        let appended = prefix.is_empty() && APPENDED_LISTS.contains(&key.as_str()) && !(hosts_replace && key == "hosts");
+        match (merged.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(table)) => {
+                merge_layer(base, table, &dotted, hosts_replace, file, keys);
+            }
+            (None, toml::Value::Table(table)) => {
+                let mut base = toml::Table::new();
+                merge_layer(&mut base, table, &dotted, hosts_replace, file, keys);
+                merged.insert(key, toml::Value::Table(base));
+            }
+            (Some(toml::Value::Array(base)), toml::Value::Array(items)) if appended => {
+                base.extend(items);
+                keys.entry(dotted).or_default().push(file.to_path_buf());
+            }
+            (_, value) => {
+                merged.insert(key, value);
+                keys.insert(dotted, vec![file.to_path_buf()]);
+            }
+        }
+    }
+}
+
+/// What a file including `base` needs to say for `load` to give `full`
+///
+/// Values the same as in `base` are left out, and appended lists keep only
+/// the entries `base` doesn't have.
+fn overlay_diff(base: &toml::Table, full: toml::Table, prefix: &str, hosts_replace: bool) -> toml::Table {
+    let mut overlay = toml::Table::new();
+    for (key, value) in full {
+        let appended = prefix.is_empty() && APPENDED_LISTS.contains(&key.as_str()) && !(hosts_replace && key == "hosts");
+        match (base.get(&key), value) {
+            (Some(base_value), value) if *base_value == value => {}
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(table)) => {
+                let table = overlay_diff(base_table, table, &key, hosts_replace);
+                if !table.is_empty() {
+                    overlay.insert(key, toml::Value::Table(table));
+                }
+            }
+            (Some(toml::Value::Array(base_items)), toml::Value::Array(items)) if appended => {
+                let added: Vec<toml::Value> = items.into_iter().filter(|item| !base_items.contains(item)).collect();
+                if !added.is_empty() {
+                    overlay.insert(key, toml::Value::Array(added));
+                }
+            }
+            (_, value) => {
+                overlay.insert(key, value);
+            }
+        }
+    }
+    overlay
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -128,6 +616,12 @@ pub struct VpnConfig {
     /// Username for VPN authentication (optional, will prompt if not set)
     #[serde(default)]
     pub username: Option<String>,
+    /// Profile name for this connection (labels its /etc/hosts section)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Port for the SSL tunnel when it isn't 443 (login stays on the gateway's HTTPS port)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
 }
 
 impl Default for Config {
@@ -137,32 +631,680 @@ impl Default for Config {
                 gateway: "psomvpn.uphs.upenn.edu".to_string(),
                 protocol: "gp".to_string(),
                 username: None,
+                profile: None,
+                port: None,
             },
-            hosts: vec!["prometheus.pmacs.upenn.edu".to_string()],
+            priority_hosts: vec![],
+            hosts: vec!["prometheus.pmacs.upenn.edu".into()],
+            hosts_file: None,
+            file_hosts: vec![],
+            search_domains: vec![],
+            dns_servers: vec![],
             preferences: Preferences::default(),
+            client: ClientIdentity::default(),
+            include: vec![],
+            hosts_replace: false,
+            sources: ConfigSources::default(),
         }
     }
 }
 
 impl Config {
-    pub fn load(path: &PathBuf) -> Result<Self, ConfigError> {
-        let content = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
+    /// Read the config at `path`, merged over the files it includes
+    ///
+    /// `include = ["base.toml"]` (relative to the including file) reads
+    /// those files first, in order, each with its own includes. The
+    /// including file's settings then win, except that `hosts`,
+    /// `priority_hosts`, `search_domains` and `dns_servers` are added to the
+    /// included lists; `hosts_replace = true` makes its `hosts` replace them
+    /// instead. `sources` records which file set what.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut layers = Vec::new();
+        let (include, hosts_replace) = collect_layers(path, &mut vec![], &mut layers)?;
+        let mut sources = ConfigSources::default();
+        let mut merged = toml::Table::new();
+        let own = layers.len() - 1;
+        for (i, layer) in layers.into_iter().enumerate() {
+            if i == own && i > 0 {
+                sources.base = Some(merged.clone());
+            }
+            merge_layer(&mut merged, layer.table, "", layer.hosts_replace, &layer.path, &mut sources.keys);
+            sources.files.push(layer.path);
+        }
+        let mut config: Config = toml::Value::Table(merged).try_into()?;
+        config.include = include;
+        config.hosts_replace = hosts_replace;
+        config.sources = sources;
+        config.priority_hosts = normalize_hosts(&config.priority_hosts);
+        config.hosts = normalize_hosts(&config.hosts);
+        // Relative paths are relative to the config file, not wherever we
+        // were started from
+        let config_dir = path.parent().unwrap_or(Path::new("."));
+        if let Some(file) = &config.preferences.hosts_file_path {
+            // Kept absolute: disconnect may run from another directory
+            let file = config_dir.join(file);
+            config.preferences.hosts_file_path = Some(std::path::absolute(&file).unwrap_or(file));
+        }
+        if let Some(file) = &config.hosts_file {
+            let file = config_dir.join(file);
+            let content = std::fs::read_to_string(&file)
+                .map_err(|e| ConfigError::HostsFileError(file.display().to_string(), e))?;
+            config.file_hosts = normalize_hosts(&parse_hosts_file(&content));
+        }
+        for host in config
+            .priority_hosts
+            .iter()
+            .chain(&config.hosts)
+            .chain(&config.file_hosts)
+        {
+            parse_host_entry(&host.name)?;
+        }
+        if let Some(table) = config.preferences.route_table
+            && matches!(table, 0 | 253..=255)
+        {
+            return Err(ConfigError::InvalidRouteTable(table));
+        }
+        if let Some(field) = config.client.invalid_field() {
+            return Err(ConfigError::InvalidClient(field));
+        }
+        if let Some(name) = config.client.invalid_header() {
+            return Err(ConfigError::InvalidClientHeader(name.to_string()));
+        }
+        if config.preferences.skip_if_onnet && config.preferences.onnet_probe.is_none() {
+            return Err(ConfigError::MissingOnnetProbe);
+        }
+        split_gateway_port(&config.vpn.gateway)?;
+        if config.vpn.port == Some(0) {
+            return Err(ConfigError::InvalidGateway(
+                config.vpn.gateway.clone(),
+                "port must be between 1 and 65535".to_string(),
+            ));
+        }
         Ok(config)
     }
 
+    /// Hosts in the order they are routed at connect time
+    ///
+    /// `priority_hosts` come first, in the order given, followed by `hosts`
+    /// and then the hosts from `hosts_file`. A host listed more than once
+    /// keeps its first position. DNS server routes are added before any of
+    /// these by the connect flow.
+    pub fn ordered_hosts(&self) -> Vec<HostEntry> {
+        let combined: Vec<HostEntry> = self
+            .priority_hosts
+            .iter()
+            .chain(&self.hosts)
+            .chain(&self.file_hosts)
+            .cloned()
+            .collect();
+        normalize_hosts(&combined)
+    }
+
+    /// The configuration as connect uses it, for `config --show`
+    ///
+    /// Hosts from `hosts_file` are merged into `hosts` (after the inline
+    /// ones, without repeats) and the file reference is dropped, so the
+    /// result lists everything that will be routed. Defaults are already
+    /// filled in by `load`.
+    pub fn effective(&self) -> Config {
+        let mut config = self.clone();
+        let listed: Vec<HostEntry> = self.priority_hosts.iter().chain(&self.hosts).cloned().collect();
+        let priority = normalize_hosts(&self.priority_hosts).len();
+        config.hosts = normalize_hosts(&[listed, self.file_hosts.clone()].concat())
+            .split_off(priority);
+        config.hosts_file = None;
+        config.file_hosts = vec![];
+        config
+    }
+
+    /// Write the config to `path`
+    ///
+    /// A config loaded with `include` writes only what differs from the
+    /// included files, so they keep applying.
     pub fn save(&self, path: &PathBuf) -> Result<(), ConfigError> {
-        let content = toml::to_string_pretty(self)?;
+        let content = match (&self.sources.base, toml::Value::try_from(self)?) {
+            (Some(base), toml::Value::Table(full)) => {
+                toml::to_string_pretty(&overlay_diff(base, full, "", self.hosts_replace))?
+            }
+            _ => toml::to_string_pretty(self)?,
+        };
         std::fs::write(path, content)?;
         Ok(())
     }
 }
 
+/// Normalize a gateway given on the command line
+///
+/// Accepts a bare host (`psomvpn.uphs.upenn.edu`), optionally with a port,
+/// and tolerates a pasted `https://` prefix or trailing slash.
+pub fn parse_gateway_host(value: &str) -> Result<String, ConfigError> {
+    let invalid = |reason: &str| ConfigError::InvalidGateway(value.to_string(), reason.to_string());
+
+    let host = value.trim();
+    let host = host.strip_prefix("https://").unwrap_or(host);
+    let host = host.trim_end_matches('/');
+    if host.is_empty() {
+        return Err(invalid("empty host"));
+    }
+    if host.contains('/') || host.contains(char::is_whitespace) {
+        return Err(invalid("expected a host name, not a URL path"));
+    }
+    split_gateway_port(host)?;
+    Ok(host.to_ascii_lowercase())
+}
+
+/// Split a gateway into its host and the tunnel port after it, if any
+///
+/// `vpn.example.com:4443` gives ("vpn.example.com", Some(4443)). The port
+/// is only the tunnel's: login and getconfig go to the bare host, which
+/// may well be on 443. An IPv6 address is written in brackets when it has
+/// a port (`[2001:db8::1]:4443`) and may be when it hasn't; the host comes
+/// back without them.
+pub fn split_gateway_port(gateway: &str) -> Result<(&str, Option<u16>), ConfigError> {
+    let invalid = |reason: &str| ConfigError::InvalidGateway(gateway.to_string(), reason.to_string());
+    let (host, port) = if let Some(rest) = gateway.strip_prefix('[') {
+        let Some((host, after)) = rest.split_once(']') else {
+            return Err(invalid("missing ] after IPv6 address"));
+        };
+        if host.parse::<std::net::Ipv6Addr>().is_err() {
+            return Err(invalid("expected an IPv6 address in brackets"));
+        }
+        match after {
+            "" => return Ok((host, None)),
+            _ => match after.strip_prefix(':') {
+                Some(port) => (host, port),
+                None => return Err(invalid("expected :port after the IPv6 address")),
+            },
+        }
+    } else if gateway.parse::<std::net::Ipv6Addr>().is_ok() {
+        return Ok((gateway, None));
+    } else {
+        let Some((host, port)) = gateway.rsplit_once(':') else {
+            return Ok((gateway, None));
+        };
+        if host.contains(':') {
+            return Err(invalid("put an IPv6 address in brackets to give a port"));
+        }
+        (host, port)
+    };
+    if host.is_empty() {
+        return Err(invalid("empty host"));
+    }
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok((host, Some(port))),
+        _ => Err(invalid("port must be a number between 1 and 65535")),
+    }
+}
+
+/// Split a host entry into hostname and optional route prefix
+///
+/// `prometheus.pmacs.upenn.edu` routes just that host; `prometheus.pmacs.upenn.edu/24`
+/// resolves the host and routes the /24 network containing it. Prefixes must
+/// be between 8 and 32. A `:port` has already been split off by
+/// [`split_host_port`], so one still here wasn't a valid port.
+pub fn parse_host_entry(entry: &str) -> Result<(&str, Option<u8>), ConfigError> {
+    use crate::vpn::routing::{MAX_ROUTE_PREFIX, MIN_ROUTE_PREFIX};
+
+    let invalid = |reason: &str| ConfigError::InvalidHost(entry.to_string(), reason.to_string());
+
+    if entry.contains(':') {
+        return Err(invalid("port must be a number between 1 and 65535"));
+    }
+
+    let Some((host, prefix)) = entry.split_once('/') else {
+        validate_hostname(entry).map_err(invalid)?;
+        return Ok((entry, None));
+    };
+    if host.is_empty() {
+        return Err(invalid("missing hostname"));
+    }
+    validate_hostname(host).map_err(invalid)?;
+    let prefix: u8 = prefix.parse().map_err(|_| invalid("prefix is not a number"))?;
+    if !(MIN_ROUTE_PREFIX..=MAX_ROUTE_PREFIX).contains(&prefix) {
+        return Err(invalid("prefix must be between 8 and 32"));
+    }
+    Ok((host, Some(prefix)))
+}
+
+/// A gateway host as written in a URL or `Host` header: IPv6 in brackets
+pub fn url_host(host: &str) -> std::borrow::Cow<'_, str> {
+    if host.parse::<std::net::Ipv6Addr>().is_ok() {
+        format!("[{}]", host).into()
+    } else {
+        host.into()
+    }
+}
+
+/// Split a `:port` off a host entry, keeping any `/prefix`
+///
+/// `a.example.com:22` gives ("a.example.com", Some(22)) and
+/// `a.example.com:22/24` gives ("a.example.com/24", Some(22)). Anything that
+/// isn't a port from 1 to 65535 is left in place for `parse_host_entry` to reject.
+pub fn split_host_port(entry: &str) -> (String, Option<u16>) {
+    let (host, prefix) = match entry.find('/') {
+        Some(slash) => entry.split_at(slash),
+        None => (entry, ""),
+    };
+    match host.rsplit_once(':') {
+        Some((name, port)) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => {
+                (format!("{}{}", name, prefix), Some(port))
+            }
+            _ => (entry.to_string(), None),
+        },
+        None => (entry.to_string(), None),
+    }
+}
+
+/// Longest DNS name and label, per RFC 1035
+const MAX_HOSTNAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Check a hostname is plain DNS syntax before it reaches the resolver,
+/// the route commands or /etc/hosts
+///
+/// Letters, digits, `-` and `_` in dot-separated labels; a label can't start
+/// with `-`, so nothing can be mistaken for a command-line option.
+fn validate_hostname(host: &str) -> Result<(), &'static str> {
+    if host.len() > MAX_HOSTNAME_LEN {
+        return Err("hostname is longer than 253 characters");
+    }
+    for label in host.split('.') {
+        if label.is_empty() {
+            return Err("hostname has an empty label");
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err("hostname label is longer than 63 characters");
+        }
+        if label.starts_with('-') {
+            return Err("hostname label starts with '-'");
+        }
+        if !label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err("hostname may only contain letters, digits, '-', '_' and '.'");
+        }
+    }
+    Ok(())
+}
+
+/// Parse a `hosts_file`: one host per line, `#` starts a comment
+pub fn parse_hosts_file(content: &str) -> Vec<HostEntry> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(HostEntry::new)
+        .collect()
+}
+
+/// Canonicalize a host list: trim, lowercase, drop empties and duplicates
+///
+/// Keeps the first occurrence of each host (and its DNS override) so routing
+/// output stays in config order.
+pub fn normalize_hosts(hosts: &[HostEntry]) -> Vec<HostEntry> {
+    let mut seen = std::collections::HashSet::new();
+    hosts
+        .iter()
+        .map(|h| {
+            // Entries from `hosts_file` still carry their `:port`
+            let (name, port) = split_host_port(h.name.trim());
+            HostEntry {
+                name: name.trim_end_matches('.').to_ascii_lowercase(),
+                dns: h.dns,
+                port: h.port.or(port),
+                comment: h.comment.clone(),
+            }
+        })
+        .filter(|h| !h.name.is_empty())
+        .filter(|h| seen.insert(h.name.clone()))
+        .collect()
+}
+
+/// How a reloaded host list differs from the one being routed
+#[derive(Debug, Default, PartialEq)]
+pub struct HostChanges {
+    /// Hosts to unroute: gone, or now routed differently
+    pub removed: Vec<HostEntry>,
+    /// Hosts to route: new, routed differently, or not routed last time
+    pub added: Vec<HostEntry>,
+    /// Routed hosts whose port or comment changed; their routes stay
+    pub updated: Vec<HostEntry>,
+}
+
+impl HostChanges {
+    /// Compare `current` (what's routed) with `hosts` (the reloaded config)
+    ///
+    /// `is_routed` says whether a current host's route went in; those that
+    /// failed are tried again. Moving a host between `priority_hosts` and
+    /// `hosts` changes nothing.
+    pub fn between(
+        current: &[HostEntry],
+        hosts: &[HostEntry],
+        is_routed: impl Fn(&HostEntry) -> bool,
+    ) -> Self {
+        let mut changes = Self::default();
+        for entry in current {
+            if !hosts.iter().any(|h| h.same_route(entry)) {
+                changes.removed.push(entry.clone());
+            }
+        }
+        for entry in hosts {
+            match current.iter().find(|h| h.same_route(entry)) {
+                Some(old) if is_routed(old) => {
+                    if old != entry {
+                        changes.updated.push(entry.clone());
+                    }
+                }
+                _ => changes.added.push(entry.clone()),
+            }
+        }
+        changes
+    }
+
+    /// Whether the reload has nothing to do
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty() && self.updated.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use tempfile::TempDir;
 
+    fn commented(name: &str, comment: &str) -> HostEntry {
+        HostEntry {
+            comment: Some(comment.to_string()),
+            ..HostEntry::new(name)
+        }
+    }
+
+    #[test]
+    fn test_host_changes_host_moved_between_lists() {
+        // Was in priority_hosts, now in hosts: same routes, different order
+        let current = vec![HostEntry::new("b.example.com"), HostEntry::new("a.example.com")];
+        let hosts = vec![HostEntry::new("a.example.com"), HostEntry::new("b.example.com")];
+        let changes = HostChanges::between(&current, &hosts, |_| true);
+        assert!(changes.is_empty(), "{:?}", changes);
+    }
+
+    #[test]
+    fn test_host_changes_retries_failed_host() {
+        let current = vec![HostEntry::new("a.example.com"), HostEntry::new("down.example.com")];
+        let changes =
+            HostChanges::between(&current, &current, |h| h.name() != "down.example.com");
+        assert_eq!(changes.added, vec![HostEntry::new("down.example.com")]);
+        assert!(changes.removed.is_empty());
+        assert!(changes.updated.is_empty());
+    }
+
+    #[test]
+    fn test_host_changes_comment_only_reroutes_nothing() {
+        let current = vec![commented("a.example.com", "old"), HostEntry::new("b.example.com")];
+        let hosts = vec![commented("a.example.com", "new"), HostEntry::new("b.example.com")];
+        let changes = HostChanges::between(&current, &hosts, |_| true);
+        assert!(changes.added.is_empty());
+        assert!(changes.removed.is_empty());
+        assert_eq!(changes.updated, vec![commented("a.example.com", "new")]);
+    }
+
+    #[test]
+    fn test_host_changes_added_removed_and_rerouted() {
+        let mut moved_dns = HostEntry::new("c.example.com");
+        moved_dns.dns = Some("10.0.0.53".parse().unwrap());
+        let current = vec![HostEntry::new("a.example.com"), HostEntry::new("c.example.com")];
+        let hosts = vec![HostEntry::new("b.example.com"), moved_dns.clone()];
+        let changes = HostChanges::between(&current, &hosts, |_| true);
+        assert_eq!(
+            changes.removed,
+            vec![HostEntry::new("a.example.com"), HostEntry::new("c.example.com")]
+        );
+        assert_eq!(changes.added, vec![HostEntry::new("b.example.com"), moved_dns]);
+    }
+
+    #[test]
+    fn test_parse_host_entry() {
+        assert_eq!(parse_host_entry("a.example.com").unwrap(), ("a.example.com", None));
+        assert_eq!(parse_host_entry("a.example.com/24").unwrap(), ("a.example.com", Some(24)));
+        assert_eq!(parse_host_entry("a.example.com/8").unwrap(), ("a.example.com", Some(8)));
+        assert_eq!(parse_host_entry("a.example.com/32").unwrap(), ("a.example.com", Some(32)));
+
+        assert!(parse_host_entry("a.example.com/7").is_err());
+        assert!(parse_host_entry("a.example.com/33").is_err());
+        assert!(parse_host_entry("a.example.com/x").is_err());
+        assert!(parse_host_entry("/24").is_err());
+    }
+
+    #[test]
+    fn test_parse_host_entry_rejects_unsafe_names() {
+        assert!(parse_host_entry("prometheus").is_ok());
+        assert!(parse_host_entry("10.30.1.5").is_ok());
+        assert!(parse_host_entry("_srv.example.com").is_ok());
+
+        assert!(parse_host_entry("-net.example.com").is_err());
+        assert!(parse_host_entry("a.example.com\n10.0.0.1 evil").is_err());
+        assert!(parse_host_entry("a b.example.com/24").is_err());
+        assert!(parse_host_entry("a..example.com").is_err());
+        assert!(parse_host_entry("a;rm.example.com").is_err());
+        assert!(parse_host_entry(&format!("{}.com", "a".repeat(64))).is_err());
+        assert!(parse_host_entry(&"a.".repeat(127)).is_err());
+    }
+
+    #[test]
+    fn test_parse_gateway_host() {
+        assert_eq!(parse_gateway_host("vpn2.example.com").unwrap(), "vpn2.example.com");
+        assert_eq!(parse_gateway_host(" https://VPN2.example.com/ ").unwrap(), "vpn2.example.com");
+        assert_eq!(parse_gateway_host("vpn2.example.com:8443").unwrap(), "vpn2.example.com:8443");
+
+        assert!(parse_gateway_host("").is_err());
+        assert!(parse_gateway_host("https://").is_err());
+        assert!(parse_gateway_host("vpn2.example.com/ssl-vpn").is_err());
+        assert!(parse_gateway_host("http://vpn2.example.com").is_err());
+        assert!(parse_gateway_host("vpn2.example.com:0").is_err());
+        assert!(parse_gateway_host("vpn2.example.com:https").is_err());
+        assert!(parse_gateway_host("vpn2.example.com:70000").is_err());
+    }
+
+    #[test]
+    fn test_split_gateway_port() {
+        assert_eq!(split_gateway_port("vpn.example.com").unwrap(), ("vpn.example.com", None));
+        assert_eq!(split_gateway_port("vpn.example.com:4443").unwrap(), ("vpn.example.com", Some(4443)));
+        assert_eq!(split_gateway_port("10.0.0.1:443").unwrap(), ("10.0.0.1", Some(443)));
+
+        assert!(split_gateway_port("vpn.example.com:").is_err());
+        assert!(split_gateway_port(":4443").is_err());
+        assert!(split_gateway_port("vpn.example.com:0").is_err());
+        assert!(split_gateway_port("vpn.example.com:4443x").is_err());
+    }
+
+    #[test]
+    fn test_split_gateway_port_ipv6() {
+        assert_eq!(split_gateway_port("[2001:db8::1]").unwrap(), ("2001:db8::1", None));
+        assert_eq!(split_gateway_port("[2001:db8::1]:4443").unwrap(), ("2001:db8::1", Some(4443)));
+        // Without a port the brackets are optional
+        assert_eq!(split_gateway_port("2001:db8::1").unwrap(), ("2001:db8::1", None));
+        assert_eq!(parse_gateway_host("https://[2001:DB8::1]:4443/").unwrap(), "[2001:db8::1]:4443");
+
+        assert!(split_gateway_port("[2001:db8::1").is_err());
+        assert!(split_gateway_port("[2001:db8::1]4443").is_err());
+        assert!(split_gateway_port("[2001:db8::1]:").is_err());
+        assert!(split_gateway_port("[vpn.example.com]:4443").is_err());
+        assert!(split_gateway_port("2001:db8::1:4443x").is_err());
+
+        assert_eq!(url_host("vpn.example.com"), "vpn.example.com");
+        assert_eq!(url_host("10.0.0.1"), "10.0.0.1");
+        assert_eq!(url_host("2001:db8::1"), "[2001:db8::1]");
+    }
+
+    #[test]
+    fn test_load_rejects_bad_prefix() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("bad-prefix.toml");
+
+        let config = Config {
+            hosts: vec!["prometheus.pmacs.upenn.edu/4".into()],
+            ..Config::default()
+        };
+        config.save(&config_path).unwrap();
+
+        assert!(matches!(
+            Config::load(&config_path),
+            Err(ConfigError::InvalidHost(..))
+        ));
+    }
+
+    #[test]
+    fn test_ordered_hosts_priority_first() {
+        let config = Config {
+            priority_hosts: vec!["jump.example.com".into(), "DB.example.com.".into()],
+            hosts: vec![
+                "app.example.com".into(),
+                "db.example.com".into(),
+                "web.example.com".into(),
+            ],
+            ..Config::default()
+        };
+
+        let names: Vec<String> = config.ordered_hosts().into_iter().map(|h| h.name).collect();
+        assert_eq!(
+            names,
+            vec!["jump.example.com", "db.example.com", "app.example.com", "web.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_priority_hosts_roundtrip_and_validation() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("priority.toml");
+
+        std::fs::write(
+            &config_path,
+            "priority_hosts = [\"Jump.example.com\"]\nhosts = [\"a.example.com\"]\n\n[vpn]\ngateway = \"gw\"\nprotocol = \"gp\"\n",
+        )
+        .unwrap();
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(loaded.priority_hosts, vec![HostEntry::new("jump.example.com")]);
+
+        let config = Config {
+            priority_hosts: vec!["jump.example.com/40".into()],
+            ..Config::default()
+        };
+        config.save(&config_path).unwrap();
+        assert!(matches!(
+            Config::load(&config_path),
+            Err(ConfigError::InvalidHost(..))
+        ));
+    }
+
+    #[test]
+    fn test_parse_hosts_file() {
+        let content = "# compute nodes\nnode01.example.com\n\n  node02.example.com  # GPU\nnode03.example.com/24\n";
+        let hosts = parse_hosts_file(content);
+        assert_eq!(
+            hosts,
+            vec!["node01.example.com", "node02.example.com", "node03.example.com/24"]
+        );
+    }
+
+    #[test]
+    fn test_hosts_file_merged_relative_to_config() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("lab.toml");
+        std::fs::write(
+            temp_dir.path().join("nodes.txt"),
+            "node01.example.com\nA.example.com\n# retired: node00\n",
+        )
+        .unwrap();
+
+        let config = Config {
+            hosts: vec!["a.example.com".into()],
+            hosts_file: Some(PathBuf::from("nodes.txt")),
+            ..Config::default()
+        };
+        config.save(&config_path).unwrap();
+
+        let loaded = Config::load(&config_path).unwrap();
+        let names: Vec<String> = loaded.ordered_hosts().into_iter().map(|h| h.name).collect();
+        assert_eq!(names, vec!["a.example.com", "node01.example.com"]);
+
+        // Saving keeps the file reference rather than inlining its hosts
+        loaded.save(&config_path).unwrap();
+        let saved = std::fs::read_to_string(&config_path).unwrap();
+        assert!(saved.contains("hosts_file = \"nodes.txt\""));
+        assert!(!saved.contains("node01"));
+    }
+
+    #[test]
+    fn test_effective_merges_hosts_file() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("nodes.txt"), "node1.example.com\nb.example.com\n").unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            r#"priority_hosts = ["jump.example.com"]
+hosts = ["b.example.com", "jump.example.com"]
+hosts_file = "nodes.txt"
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+"#,
+        )
+        .unwrap();
+
+        let loaded = Config::load(&config_path).unwrap();
+        let effective = loaded.effective();
+        assert_eq!(effective.priority_hosts, loaded.priority_hosts);
+        assert_eq!(effective.hosts, ["b.example.com", "node1.example.com"]);
+        assert_eq!(effective.hosts_file, None);
+        assert_eq!(
+            effective.ordered_hosts().len(),
+            loaded.ordered_hosts().len()
+        );
+
+        let shown = toml::to_string_pretty(&effective).unwrap();
+        assert!(shown.contains("node1.example.com"));
+        assert!(shown.contains("[preferences]"));
+    }
+
+    #[test]
+    fn test_hosts_file_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("lab.toml");
+        let config = Config {
+            hosts_file: Some(PathBuf::from("missing.txt")),
+            ..Config::default()
+        };
+        config.save(&config_path).unwrap();
+        assert!(matches!(
+            Config::load(&config_path),
+            Err(ConfigError::HostsFileError(..))
+        ));
+
+        std::fs::write(temp_dir.path().join("missing.txt"), "bad host.example.com\n").unwrap();
+        assert!(matches!(
+            Config::load(&config_path),
+            Err(ConfigError::InvalidHost(..))
+        ));
+    }
+
+    #[test]
+    fn test_route_table_validation() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("table.toml");
+
+        let mut config = Config::default();
+        config.preferences.route_table = Some(100);
+        config.save(&config_path).unwrap();
+        assert_eq!(Config::load(&config_path).unwrap().preferences.route_table, Some(100));
+
+        config.preferences.route_table = Some(254);
+        config.save(&config_path).unwrap();
+        assert!(matches!(
+            Config::load(&config_path),
+            Err(ConfigError::InvalidRouteTable(254))
+        ));
+    }
+
     #[test]
     fn test_default_config() {
         let config = Config::default();
@@ -196,19 +1338,31 @@ mod tests {
                 gateway: "custom.vpn.example.com".to_string(),
                 protocol: "anyconnect".to_string(),
                 username: Some("testuser".to_string()),
+                profile: Some("lab".to_string()),
+                port: Some(4443),
             },
-            hosts: vec![
-                "host1.example.com".to_string(),
-                "host2.example.com".to_string(),
-            ],
+            priority_hosts: vec![],
+            hosts: vec!["host1.example.com".into(), "host2.example.com".into()],
+            hosts_file: None,
+            file_hosts: vec![],
+            search_domains: vec!["example.com".to_string()],
+            dns_servers: vec!["10.1.2.3".parse().unwrap()],
             preferences: Preferences::default(),
+            client: ClientIdentity::default(),
+            include: vec![],
+            hosts_replace: false,
+            sources: ConfigSources::default(),
         };
         config.save(&config_path).unwrap();
 
         let loaded = Config::load(&config_path).unwrap();
         assert_eq!(loaded.vpn.gateway, "custom.vpn.example.com");
         assert_eq!(loaded.vpn.protocol, "anyconnect");
+        assert_eq!(loaded.vpn.profile.as_deref(), Some("lab"));
+        assert_eq!(loaded.vpn.port, Some(4443));
         assert_eq!(loaded.hosts.len(), 2);
+        assert_eq!(loaded.search_domains, vec!["example.com"]);
+        assert_eq!(loaded.dns_servers, ["10.1.2.3".parse::<IpAddr>().unwrap()]);
     }
 
     #[test]
@@ -242,6 +1396,9 @@ mod tests {
         assert!(content.contains("gateway"));
         assert!(content.contains("protocol"));
         assert!(content.contains("hosts"));
+        // Optional and empty by default
+        assert!(!content.contains("search_domains"));
+        assert!(!content.contains("dns_servers"));
     }
 
     #[test]
@@ -255,6 +1412,9 @@ mod tests {
         assert_eq!(prefs.max_reconnect_attempts, 3);
         assert_eq!(prefs.reconnect_delay_secs, 5);
         assert_eq!(prefs.inbound_timeout_secs, 45);
+        assert!(!prefs.require_hosts);
+        assert!(!prefs.ipv6);
+        assert!(prefs.flush_dns_on_disconnect);
     }
 
     #[test]
@@ -268,9 +1428,29 @@ mod tests {
             max_reconnect_attempts: 3,
             reconnect_delay_secs: 5,
             inbound_timeout_secs: 45,
+            kill_switch: true,
+            transport: Transport::Udp,
+            keepalive: Keepalive::On,
+            keepalive_jitter_percent: default_keepalive_jitter(),
+            gateway_retries: 2,
+            gateway_routes: true,
+            require_hosts: false,
+            ipv6: false,
+            flush_dns_on_disconnect: true,
+            route_table: None,
+            manage_hosts: true,
+            full_tunnel: false,
+            hosts_file_path: None,
+            prefer_private: true,
+            manage_resolver: false,
+            skip_if_onnet: false,
+            onnet_probe: None,
+            onnet_probe_timeout_secs: default_onnet_probe_timeout(),
         };
 
         let toml_str = toml::to_string(&prefs).unwrap();
+        assert!(toml_str.contains("kill_switch = true"));
+        assert!(toml_str.contains("transport = \"udp\""));
         assert!(toml_str.contains("save_password = false"));
         assert!(toml_str.contains("duo_method = \"sms\""));
         assert!(toml_str.contains("start_at_login = true"));
@@ -296,6 +1476,8 @@ mod tests {
         assert_eq!(prefs.max_reconnect_attempts, 3);
         assert_eq!(prefs.reconnect_delay_secs, 5);
         assert_eq!(prefs.inbound_timeout_secs, 45);
+        assert!(!prefs.kill_switch);
+        assert_eq!(prefs.transport, Transport::Ssl);
     }
 
     #[test]
@@ -318,6 +1500,16 @@ mod tests {
         assert_eq!(DuoMethod::default(), DuoMethod::Push);
     }
 
+    #[test]
+    fn test_duo_device_auth_str() {
+        assert_eq!(DuoMethod::Push.auth_str_for_device(None).as_deref(), Some("push"));
+        assert_eq!(DuoMethod::Push.auth_str_for_device(Some(1)).as_deref(), Some("push"));
+        assert_eq!(DuoMethod::Push.auth_str_for_device(Some(2)).as_deref(), Some("push2"));
+        assert_eq!(DuoMethod::Sms.auth_str_for_device(Some(1)).as_deref(), Some("sms1"));
+        assert_eq!(DuoMethod::Call.auth_str_for_device(Some(3)).as_deref(), Some("phone3"));
+        assert_eq!(DuoMethod::Passcode.auth_str_for_device(Some(2)), None);
+    }
+
     #[test]
     fn test_duo_method_in_preferences_serialization() {
         // Test serialization in context of a struct
@@ -330,6 +1522,24 @@ mod tests {
             max_reconnect_attempts: 3,
             reconnect_delay_secs: 5,
             inbound_timeout_secs: 45,
+            kill_switch: false,
+            transport: Transport::Ssl,
+            keepalive: Keepalive::On,
+            keepalive_jitter_percent: default_keepalive_jitter(),
+            gateway_retries: 2,
+            gateway_routes: true,
+            require_hosts: false,
+            ipv6: false,
+            flush_dns_on_disconnect: true,
+            route_table: None,
+            manage_hosts: true,
+            full_tunnel: false,
+            hosts_file_path: None,
+            prefer_private: true,
+            manage_resolver: false,
+            skip_if_onnet: false,
+            onnet_probe: None,
+            onnet_probe_timeout_secs: default_onnet_probe_timeout(),
         };
 
         let toml_str = toml::to_string(&prefs).unwrap();
@@ -385,6 +1595,102 @@ protocol = "gp"
         assert_eq!(loaded.preferences.inbound_timeout_secs, 45);
     }
 
+    #[test]
+    fn test_include_merges_over_base() {
+        let temp_dir = TempDir::new().unwrap();
+        let shared = temp_dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::write(
+            shared.join("base.toml"),
+            r#"hosts = ["prometheus.pmacs.upenn.edu"]
+hosts_file = "lab-hosts.txt"
+search_domains = ["pmacs.upenn.edu"]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+
+[preferences]
+kill_switch = false
+transport = "udp"
+"#,
+        )
+        .unwrap();
+        std::fs::write(shared.join("lab-hosts.txt"), "db.pmacs.upenn.edu\n").unwrap();
+        let config_path = temp_dir.path().join("pmacs-vpn.toml");
+        let personal = r#"include = ["shared/base.toml"]
+hosts = ["scratch.pmacs.upenn.edu"]
+
+[vpn]
+username = "alice"
+
+[preferences]
+transport = "ssl"
+"#;
+        std::fs::write(&config_path, personal).unwrap();
+
+        let config = Config::load(&config_path).unwrap();
+        // Scalars from the including file win; the rest come from the base
+        assert_eq!(config.vpn.gateway, "psomvpn.uphs.upenn.edu");
+        assert_eq!(config.vpn.username.as_deref(), Some("alice"));
+        assert_eq!(config.preferences.transport, Transport::Ssl);
+        assert!(!config.preferences.kill_switch);
+        // Lists are added to; the base's hosts_file is relative to the base
+        let names: Vec<&str> = config.hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, ["prometheus.pmacs.upenn.edu", "scratch.pmacs.upenn.edu"]);
+        assert_eq!(config.file_hosts[0].name, "db.pmacs.upenn.edu");
+        assert_eq!(config.sources.files.len(), 2);
+        assert_eq!(config.sources.keys["vpn.gateway"], [shared.join("base.toml")]);
+        assert_eq!(config.sources.keys["preferences.transport"][0], config_path);
+        assert_eq!(config.sources.keys["hosts"].len(), 2);
+
+        // Saving writes back only what the including file adds
+        let mut config = config;
+        config.preferences.duo_method = DuoMethod::Sms;
+        config.save(&config_path).unwrap();
+        let saved = std::fs::read_to_string(&config_path).unwrap();
+        assert!(saved.contains("include = [\"shared/base.toml\"]"));
+        assert!(!saved.contains("psomvpn") && !saved.contains("prometheus") && !saved.contains("kill_switch"));
+        let reloaded = Config::load(&config_path).unwrap();
+        assert_eq!(reloaded.hosts.len(), 2);
+        assert_eq!(reloaded.preferences.duo_method, DuoMethod::Sms);
+
+        // hosts_replace drops the base's hosts
+        std::fs::write(&config_path, format!("hosts_replace = true\n{}", personal)).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        assert_eq!(config.hosts.len(), 1);
+        assert_eq!(config.search_domains, ["pmacs.upenn.edu"]);
+
+        // A file including itself (through another) is an error
+        std::fs::write(shared.join("base.toml"), "include = [\"../pmacs-vpn.toml\"]\n").unwrap();
+        assert!(matches!(Config::load(&config_path), Err(ConfigError::IncludeCycle(_))));
+        std::fs::remove_file(shared.join("base.toml")).unwrap();
+        assert!(matches!(Config::load(&config_path), Err(ConfigError::IncludeError(..))));
+    }
+
+    #[test]
+    fn test_skip_if_onnet_needs_probe() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("onnet.toml");
+        let content = r#"hosts = ["prometheus.pmacs.upenn.edu"]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+
+[preferences]
+skip_if_onnet = true
+"#;
+        std::fs::write(&config_path, content).unwrap();
+        assert!(matches!(Config::load(&config_path), Err(ConfigError::MissingOnnetProbe)));
+
+        let content = format!("{}onnet_probe = \"prometheus.pmacs.upenn.edu:22\"\n", content);
+        std::fs::write(&config_path, content).unwrap();
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(loaded.preferences.onnet_probe.as_deref(), Some("prometheus.pmacs.upenn.edu:22"));
+        assert_eq!(loaded.preferences.onnet_probe_timeout_secs, 2);
+    }
+
     #[test]
     fn test_partial_preferences_uses_defaults() {
         let temp_dir = TempDir::new().unwrap();
@@ -415,4 +1721,273 @@ duo_method = "sms"
         assert!(loaded.preferences.auto_reconnect);
         assert_eq!(loaded.preferences.inbound_timeout_secs, 45);
     }
+
+    #[test]
+    fn test_hosts_file_path_is_relative_to_config() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("pmacs-vpn.toml");
+        std::fs::write(
+            &config_path,
+            r#"hosts = ["prometheus.pmacs.upenn.edu"]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+
+[preferences]
+hosts_file_path = "rig/hosts"
+"#,
+        )
+        .unwrap();
+
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(
+            loaded.preferences.hosts_file_path,
+            Some(temp_dir.path().join("rig/hosts"))
+        );
+        assert_eq!(Preferences::default().hosts_file_path, None);
+    }
+
+    #[test]
+    fn test_client_section_overrides_and_defaults() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("client.toml");
+
+        let content = r#"hosts = ["prometheus.pmacs.upenn.edu"]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+
+[client]
+os = "Mac"
+app_version = "6.2.1-89"
+"#;
+        std::fs::write(&config_path, content).unwrap();
+
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(loaded.client.os, "Mac");
+        assert_eq!(loaded.client.app_version, "6.2.1-89");
+        assert_eq!(loaded.client.client_version, "4100");
+        assert_eq!(loaded.client.user_agent, "PAN GlobalProtect");
+
+        // An untouched section isn't written out
+        let saved = toml::to_string_pretty(&Config::default()).unwrap();
+        assert!(!saved.contains("[client]"));
+
+        let bad = content.replace("os = \"Mac\"", "user_agent = \"PAN\\r\\nX-Injected: 1\"");
+        std::fs::write(&config_path, bad).unwrap();
+        assert!(matches!(
+            Config::load(&config_path),
+            Err(ConfigError::InvalidClient("user_agent"))
+        ));
+    }
+
+    #[test]
+    fn test_client_headers() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("headers.toml");
+
+        let content = r#"hosts = ["prometheus.pmacs.upenn.edu"]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+
+[client]
+user_agent = "PAN GlobalProtect/6.2.1"
+
+[client.headers]
+X-GlobalProtect = "6.2.1-89"
+"#;
+        std::fs::write(&config_path, content).unwrap();
+
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(loaded.client.user_agent, "PAN GlobalProtect/6.2.1");
+        assert_eq!(loaded.client.headers["X-GlobalProtect"], "6.2.1-89");
+        let map = loaded.client.header_map();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map["x-globalprotect"], "6.2.1-89");
+
+        let saved = toml::to_string_pretty(&loaded).unwrap();
+        assert!(saved.contains("[client.headers]"));
+
+        for bad in ["\"Bad Name\" = \"1\"", "Host = \"evil.example\"", "user-agent = \"x\"", "X-Ok = \"a\\nb\""] {
+            let content = content.replace("X-GlobalProtect = \"6.2.1-89\"", bad);
+            std::fs::write(&config_path, content).unwrap();
+            assert!(
+                matches!(Config::load(&config_path), Err(ConfigError::InvalidClientHeader(_))),
+                "{bad} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize_hosts_dedupes_case_insensitively() {
+        let hosts: Vec<HostEntry> = vec![
+            "Prometheus.PMACS.upenn.edu".into(),
+            "  prometheus.pmacs.upenn.edu ".into(),
+            "consign.pmacs.upenn.edu".into(),
+            "".into(),
+            "prometheus.pmacs.upenn.edu.".into(),
+        ];
+
+        let normalized = normalize_hosts(&hosts);
+        assert_eq!(
+            normalized,
+            vec!["prometheus.pmacs.upenn.edu", "consign.pmacs.upenn.edu"]
+        );
+    }
+
+    #[test]
+    fn test_load_normalizes_hosts() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("dupes.toml");
+
+        let content = r#"hosts = ["B.example.com", "a.example.com", "b.example.com"]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+"#;
+        std::fs::write(&config_path, content).unwrap();
+
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(loaded.hosts, vec!["b.example.com", "a.example.com"]);
+    }
+
+    #[test]
+    fn test_hosts_accept_string_and_table_forms() {
+        let content = r#"hosts = [
+    "prometheus.pmacs.upenn.edu",
+    { name = "Consign.pmacs.upenn.edu", dns = "10.0.0.2" },
+]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+"#;
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("mixed.toml");
+        std::fs::write(&config_path, content).unwrap();
+
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(loaded.hosts.len(), 2);
+        assert_eq!(loaded.hosts[0], "prometheus.pmacs.upenn.edu");
+        assert_eq!(loaded.hosts[1].name, "consign.pmacs.upenn.edu");
+        assert_eq!(loaded.hosts[1].dns, Some("10.0.0.2".parse().unwrap()));
+
+        // Round-trips, keeping the plain form for hosts without an override
+        loaded.save(&config_path).unwrap();
+        let saved = std::fs::read_to_string(&config_path).unwrap();
+        assert!(saved.contains("\"prometheus.pmacs.upenn.edu\""));
+        let reloaded = Config::load(&config_path).unwrap();
+        assert_eq!(reloaded.hosts, loaded.hosts);
+    }
+
+    #[test]
+    fn test_host_ports() {
+        assert_eq!(split_host_port("a.example.com:22"), ("a.example.com".to_string(), Some(22)));
+        assert_eq!(split_host_port("a.example.com:22/24"), ("a.example.com/24".to_string(), Some(22)));
+        assert_eq!(split_host_port("a.example.com"), ("a.example.com".to_string(), None));
+        assert_eq!(split_host_port("a.example.com:0"), ("a.example.com:0".to_string(), None));
+
+        let content = r#"hosts = [
+    "Prometheus.pmacs.upenn.edu:22",
+    { name = "consign.pmacs.upenn.edu", dns = "10.0.0.2", port = 443 },
+]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+"#;
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("ports.toml");
+        std::fs::write(&config_path, content).unwrap();
+
+        // The routing key stays the bare host
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(loaded.hosts[0].name, "prometheus.pmacs.upenn.edu");
+        assert_eq!(loaded.hosts[0].port, Some(22));
+        assert_eq!(loaded.hosts[1].port, Some(443));
+
+        loaded.save(&config_path).unwrap();
+        let saved = std::fs::read_to_string(&config_path).unwrap();
+        assert!(saved.contains("\"prometheus.pmacs.upenn.edu:22\""));
+        assert_eq!(Config::load(&config_path).unwrap().hosts, loaded.hosts);
+
+        std::fs::write(&config_path, content.replace(":22", ":ssh")).unwrap();
+        assert!(matches!(Config::load(&config_path), Err(ConfigError::InvalidHost(..))));
+    }
+
+    #[test]
+    fn test_host_comments() {
+        let content = r#"hosts = [
+    "prometheus.pmacs.upenn.edu",
+    { name = "grafana.pmacs.upenn.edu", comment = "monitoring\n dashboards" },
+    { name = "consign.pmacs.upenn.edu", comment = "  " },
+]
+
+[vpn]
+gateway = "psomvpn.uphs.upenn.edu"
+protocol = "gp"
+"#;
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("comments.toml");
+        std::fs::write(&config_path, content).unwrap();
+
+        let loaded = Config::load(&config_path).unwrap();
+        assert_eq!(loaded.hosts[0].comment, None);
+        assert_eq!(loaded.hosts[1].comment.as_deref(), Some("monitoring dashboards"));
+        assert_eq!(loaded.hosts[2], "consign.pmacs.upenn.edu");
+
+        loaded.save(&config_path).unwrap();
+        let saved = std::fs::read_to_string(&config_path).unwrap();
+        assert!(saved.contains("comment = \"monitoring dashboards\""));
+        assert!(saved.contains("\"consign.pmacs.upenn.edu\""));
+        assert_eq!(Config::load(&config_path).unwrap().hosts, loaded.hosts);
+    }
+
+    #[test]
+    fn test_host_table_rejects_bad_dns_and_unknown_keys() {
+        let bad_dns = r#"hosts = [{ name = "a.example.com", dns = "not-an-ip" }]"#;
+        assert!(toml::from_str::<toml::Table>(bad_dns).is_ok());
+
+        #[derive(Deserialize)]
+        struct Hosts {
+            #[allow(dead_code)]
+            hosts: Vec<HostEntry>,
+        }
+        assert!(toml::from_str::<Hosts>(bad_dns).is_err());
+        assert!(toml::from_str::<Hosts>(r#"hosts = [{ name = "a", dnss = "10.0.0.2" }]"#).is_err());
+        assert!(toml::from_str::<Hosts>(r#"hosts = [{ dns = "10.0.0.2" }]"#).is_err());
+    }
+
+    #[test]
+    fn test_mixed_host_list_serde_roundtrip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Hosts {
+            hosts: Vec<HostEntry>,
+        }
+        let hosts = Hosts {
+            hosts: vec![
+                HostEntry::new("a.example.com"),
+                HostEntry {
+                    port: Some(22),
+                    ..HostEntry::new("b.example.com")
+                },
+                HostEntry {
+                    dns: Some("10.0.0.2".parse().unwrap()),
+                    comment: Some("cluster".to_string()),
+                    ..HostEntry::new("c.example.com/24")
+                },
+            ],
+        };
+        let serialized = toml::to_string(&hosts).unwrap();
+        assert!(serialized.contains("\"a.example.com\""));
+        assert!(serialized.contains("\"b.example.com:22\""));
+        assert!(serialized.contains("name = \"c.example.com/24\""));
+        assert_eq!(toml::from_str::<Hosts>(&serialized).unwrap(), hosts);
+        assert_eq!(hosts.hosts[2].name(), "c.example.com/24");
+    }
 }
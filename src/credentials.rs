@@ -7,8 +7,8 @@
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use keyring::Entry;
 use std::fs;
-use std::path::PathBuf;
-use tracing::{debug, info};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
 
 const SERVICE_NAME: &str = "pmacs-vpn";
 const CREDENTIALS_FILENAME: &str = ".credentials";
@@ -239,6 +239,55 @@ pub fn has_password(username: &str) -> bool {
     get_password(username).is_some()
 }
 
+/// Read the VPN password from a user-supplied file (`--password-file`)
+///
+/// The password is the file's first line, trimmed. On Unix the file should
+/// be mode 0600: other permissions get a warning, and a world-readable file
+/// is refused unless `allow_insecure` is set.
+pub fn read_password_file(path: &Path, allow_insecure: bool) -> Result<String, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!("Password file not found: {}", path.display()));
+        }
+        Err(e) => {
+            return Err(format!("Failed to read password file {}: {}", path.display(), e));
+        }
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)
+            .map_err(|e| format!("Failed to read password file {}: {}", path.display(), e))?
+            .permissions()
+            .mode()
+            & 0o777;
+        if mode & 0o004 != 0 && !allow_insecure {
+            return Err(format!(
+                "Password file {} is world-readable (mode {:o}); run `chmod 600` on it, or pass --insecure-perms",
+                path.display(),
+                mode
+            ));
+        }
+        if mode & 0o077 != 0 {
+            warn!(
+                "Password file {} has mode {:o}; it should be 600",
+                path.display(),
+                mode
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = allow_insecure;
+
+    let password = contents.lines().next().unwrap_or("").trim();
+    if password.is_empty() {
+        return Err(format!("Password file {} is empty", path.display()));
+    }
+    Ok(password.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -268,4 +317,46 @@ mod tests {
         // Verify deleted
         assert!(get_password(username).is_none());
     }
+
+    fn password_file(contents: &str, mode: u32) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        fs::write(&path, contents).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        (dir, path)
+    }
+
+    #[test]
+    fn test_read_password_file_first_line() {
+        let (_dir, path) = password_file("  s3cret \nignored\n", 0o600);
+        assert_eq!(read_password_file(&path, false).unwrap(), "s3cret");
+
+        let (_dir, path) = password_file("\n", 0o600);
+        assert!(read_password_file(&path, false).unwrap_err().contains("empty"));
+    }
+
+    #[test]
+    fn test_read_password_file_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = read_password_file(&dir.path().join("nope"), false).unwrap_err();
+        assert!(err.contains("not found"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_password_file_world_readable() {
+        let (_dir, path) = password_file("s3cret\n", 0o644);
+        assert!(read_password_file(&path, false).unwrap_err().contains("--insecure-perms"));
+        assert_eq!(read_password_file(&path, true).unwrap(), "s3cret");
+
+        // Group-readable only warns
+        let (_dir, path) = password_file("s3cret\n", 0o640);
+        assert_eq!(read_password_file(&path, false).unwrap(), "s3cret");
+    }
 }
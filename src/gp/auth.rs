@@ -5,12 +5,138 @@
 //! 2. login - Authenticate with credentials (supports DUO push)
 //! 3. getconfig - Get tunnel configuration
 
-use reqwest::Client;
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::config::url_host;
+use crate::gp::debug_bundle;
+use crate::gp::esp::{self, EspCipher, EspConfig};
+use crate::gp::events::{self, ConnectEvent};
+use ipnet::IpNet;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING};
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
 use std::net::IpAddr;
+use std::time::Duration;
 use thiserror::Error;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// Default retries for a login/getconfig request the gateway answered with 5xx
+pub const DEFAULT_GATEWAY_RETRIES: u32 = 2;
+
+/// How long to wait for a DUO push to be approved before asking for a
+/// passcode instead (DUO itself gives up on a push after 60s)
+pub const PUSH_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// Delay before the first retry; doubles on each further attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound on the delay between retries
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(8);
+
+/// How this client identifies itself to the gateway
+///
+/// Sent with prelogin, login and getconfig, and as the User-Agent of every
+/// request including the tunnel's. The defaults match the Windows client the
+/// protocol was worked out against; some gateways treat other client types
+/// or versions differently, so all of it can be overridden under `[client]`.
+/// Gateways that also check other headers get them from `[client.headers]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientIdentity {
+    /// `clientos` (e.g. "Windows", "Mac", "Linux")
+    pub os: String,
+    /// `os-version` (e.g. "Microsoft Windows 10 Pro")
+    pub os_version: String,
+    /// `app-version` sent with getconfig
+    pub app_version: String,
+    /// `clientVer` sent with prelogin and login
+    pub client_version: String,
+    /// HTTP User-Agent header
+    pub user_agent: String,
+    /// Extra headers sent on every request, including the tunnel's
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for ClientIdentity {
+    fn default() -> Self {
+        Self {
+            os: "Windows".to_string(),
+            os_version: "Microsoft Windows 10 Pro".to_string(),
+            app_version: "4.1.0-10".to_string(),
+            client_version: "4100".to_string(),
+            user_agent: "PAN GlobalProtect".to_string(),
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl ClientIdentity {
+    /// Whether every field still has its default value
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Name of the first field that can't go in an HTTP request, if any
+    ///
+    /// Values end up in form fields and raw request headers, so control
+    /// characters (a stray newline) are rejected rather than sent.
+    pub fn invalid_field(&self) -> Option<&'static str> {
+        [
+            ("os", &self.os),
+            ("os_version", &self.os_version),
+            ("app_version", &self.app_version),
+            ("client_version", &self.client_version),
+            ("user_agent", &self.user_agent),
+        ]
+        .into_iter()
+        .find(|(_, value)| value.trim().is_empty() || value.chars().any(char::is_control))
+        .map(|(name, _)| name)
+    }
+
+    /// Name of the first entry in `headers` that can't be sent, if any
+    ///
+    /// Names must be valid header names and values free of control
+    /// characters. `Host` is set per request and `User-Agent` comes from
+    /// `user_agent`, so neither may be given here.
+    pub fn invalid_header(&self) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, value)| {
+                HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || name.eq_ignore_ascii_case("host")
+                    || name.eq_ignore_ascii_case("user-agent")
+                    || HeaderValue::from_str(value).is_err()
+                    || value.chars().any(char::is_control)
+            })
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The extra headers as a map for an HTTP client
+    ///
+    /// Entries rejected by [`Self::invalid_header`] are skipped; a loaded
+    /// config never has any.
+    pub fn header_map(&self) -> HeaderMap {
+        self.headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    HeaderValue::from_str(value).ok()?,
+                ))
+            })
+            .collect()
+    }
+
+    /// HTTP client builder carrying this identity's User-Agent and headers
+    fn client_builder(&self) -> reqwest::ClientBuilder {
+        Client::builder()
+            .danger_accept_invalid_certs(false)
+            .user_agent(self.user_agent.as_str())
+            .default_headers(self.header_map())
+    }
+}
 
 /// Authentication errors
 #[derive(Error, Debug)]
@@ -29,6 +155,139 @@ pub enum AuthError {
 
     #[error("Invalid response format")]
     InvalidResponse,
+
+    #[error("Gateway temporarily unavailable (HTTP {0})")]
+    GatewayUnavailable(u16),
+
+    #[error("Couldn't decode the gateway's response: {0}")]
+    DecodeError(String),
+
+    #[error("Gateway sent an HTML page instead of XML ({0}); a proxy or load balancer may be answering")]
+    HtmlResponse(String),
+}
+
+impl AuthError {
+    /// Whether retrying the same request might succeed
+    ///
+    /// Only 5xx responses (e.g. a 502/503 from the gateway's load balancer)
+    /// count; 4xx responses and rejected credentials are final.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::GatewayUnavailable(_))
+    }
+}
+
+/// Map an HTTP status to an error if it's a transient gateway failure
+fn check_status(status: StatusCode) -> Result<(), AuthError> {
+    if status.is_server_error() {
+        Err(AuthError::GatewayUnavailable(status.as_u16()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Read a response body as text, decompressing it if the gateway sent it
+/// gzip- or deflate-encoded
+async fn read_body(response: reqwest::Response) -> Result<String, AuthError> {
+    let encoding = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase());
+    let bytes = response.bytes().await?;
+    decode_body(&bytes, encoding.as_deref())
+}
+
+/// Decode a response body per its `Content-Encoding`
+///
+/// Some load balancers compress responses without being asked, and a few
+/// without saying so, so a gzip header is recognized even then. "deflate"
+/// is meant to be zlib-wrapped, but raw deflate is accepted too.
+fn decode_body(bytes: &[u8], encoding: Option<&str>) -> Result<String, AuthError> {
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+    let mut text = String::new();
+    let decoded = match encoding.unwrap_or("identity") {
+        "gzip" | "x-gzip" => flate2::read::GzDecoder::new(bytes).read_to_string(&mut text),
+        "deflate" => flate2::read::ZlibDecoder::new(bytes)
+            .read_to_string(&mut text)
+            .or_else(|_| {
+                text.clear();
+                flate2::read::DeflateDecoder::new(bytes).read_to_string(&mut text)
+            }),
+        "identity" | "" if bytes.starts_with(GZIP_MAGIC) => {
+            flate2::read::GzDecoder::new(bytes).read_to_string(&mut text)
+        }
+        "identity" | "" => return Ok(String::from_utf8_lossy(bytes).into_owned()),
+        other => return Err(AuthError::DecodeError(format!("unsupported encoding {}", other))),
+    };
+    decoded.map_err(|e| AuthError::DecodeError(e.to_string()))?;
+    Ok(text)
+}
+
+/// Deserialize an XML response, reporting an HTML page for what it is
+fn parse_xml<T: DeserializeOwned>(body: &str) -> Result<T, AuthError> {
+    quick_xml::de::from_str(body).map_err(|e| match html_summary(body) {
+        Some(summary) => AuthError::HtmlResponse(summary),
+        None => e.into(),
+    })
+}
+
+/// The title of an HTML page ("untitled" without one); None if `body`
+/// isn't HTML
+fn html_summary(body: &str) -> Option<String> {
+    let start = body.trim_start().get(..64).unwrap_or(body.trim_start()).to_ascii_lowercase();
+    if !start.starts_with("<!doctype html") && !start.starts_with("<html") {
+        return None;
+    }
+    let lower = body.to_ascii_lowercase();
+    let title = lower
+        .find("<title>")
+        .map(|open| open + "<title>".len())
+        .and_then(|from| lower[from..].find("</title>").map(|len| body[from..from + len].trim()))
+        .filter(|title| !title.is_empty());
+    Some(format!("\"{}\"", title.unwrap_or("untitled")))
+}
+
+/// Backoff before retry number `attempt` (0-based)
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RETRY_MAX_DELAY)
+}
+
+/// POST a form and return the response body
+///
+/// A 5xx response is retried up to `retries` times with exponential backoff;
+/// anything else (including 4xx) is returned to the caller to interpret.
+/// Only the failed request is repeated, so a hiccup after the MFA step
+/// doesn't cost the user another DUO push.
+async fn post_form<T: Serialize + ?Sized>(
+    client: &Client,
+    url: &str,
+    params: &T,
+    retries: u32,
+) -> Result<String, AuthError> {
+    let mut attempt = 0;
+    loop {
+        let response = client
+            .post(url)
+            .form(params)
+            .send()
+            .await?;
+        debug!("{} status: {}", url, response.status());
+
+        let err = match check_status(response.status()) {
+            Ok(()) => return read_body(response).await,
+            Err(e) => e,
+        };
+        if attempt >= retries {
+            return Err(err);
+        }
+        let delay = retry_delay(attempt);
+        attempt += 1;
+        warn!("{}; retrying in {}s ({}/{})", err, delay.as_secs(), attempt, retries);
+        tokio::time::sleep(delay).await;
+    }
 }
 
 /// Authentication method
@@ -55,6 +314,132 @@ pub struct LoginResponse {
     pub domain: String,
     pub portal: String,
     pub gateway_address: String,
+    /// Every gateway the login response named (`(gateway-address)` and
+    /// preferred-gateway hints), preferred first; empty for positional responses
+    pub gateways: Vec<String>,
+}
+
+/// How long `choose_gateway` waits for each alternative gateway to accept a connection
+pub const GATEWAY_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+impl LoginResponse {
+    /// A login made elsewhere, from its auth cookie (`connect --auth-cookie`)
+    ///
+    /// Takes the bare cookie or OpenConnect's GlobalProtect cookie string
+    /// (`authcookie=...&portal=...&user=...&domain=...`). The user and
+    /// portal come from the string when it has them, else from `username`
+    /// and the gateway itself.
+    pub fn from_cookie(gateway: &str, username: Option<&str>, cookie: &str) -> Result<Self, AuthError> {
+        let cookie = cookie.trim();
+        let fields: HashMap<String, String> = if cookie.contains("authcookie=") {
+            reqwest::Url::parse(&format!("https://{}/?{}", url_host(gateway), cookie))
+                .map_err(|_| AuthError::InvalidResponse)?
+                .query_pairs()
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        let field = |key: &str| fields.get(key).map(String::as_str).filter(|v| !v.is_empty());
+
+        let auth_cookie = if fields.is_empty() { Some(cookie) } else { field("authcookie") };
+        let auth_cookie = auth_cookie
+            .filter(|c| !c.is_empty())
+            .ok_or_else(|| AuthError::MissingField("authcookie".to_string()))?;
+        let username = field("user")
+            .or(username)
+            .ok_or_else(|| AuthError::MissingField("user".to_string()))?;
+        Ok(Self {
+            auth_cookie: auth_cookie.to_string(),
+            username: username.to_string(),
+            domain: field("domain").unwrap_or("").to_string(),
+            portal: field("portal").unwrap_or(gateway).to_string(),
+            gateway_address: gateway.to_string(),
+            gateways: Vec::new(),
+        })
+    }
+
+    /// Gateway hosts to try, in order, for a login made against `requested`
+    ///
+    /// IP literals are skipped: the gateway's certificate names its host, so
+    /// a tunnel to a bare address wouldn't verify. `requested` itself comes
+    /// last unless the response listed it earlier.
+    pub fn gateway_candidates<'a>(&'a self, requested: &'a str) -> Vec<&'a str> {
+        let mut candidates: Vec<&str> = Vec::new();
+        for gateway in &self.gateways {
+            let host = gateway.rsplit_once(':').map_or(gateway.as_str(), |(host, _)| host);
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            if host.parse::<IpAddr>().is_ok() || gateway.parse::<IpAddr>().is_ok() {
+                continue;
+            }
+            if !candidates.iter().any(|c| c.eq_ignore_ascii_case(gateway)) {
+                candidates.push(gateway);
+            }
+        }
+        if !candidates.iter().any(|c| c.eq_ignore_ascii_case(requested)) {
+            candidates.push(requested);
+        }
+        candidates
+    }
+}
+
+/// Pick the gateway to get the tunnel config from after logging in
+///
+/// Walks `login.gateway_candidates(requested)` in order and takes the first
+/// that accepts a TCP connection within `GATEWAY_PROBE_TIMEOUT`. `requested`
+/// answered the login, so reaching it ends the search without a probe, and
+/// it is the fallback when no alternative answers (or, through
+/// `with_gateway_fallback`, when the one picked fails getconfig).
+pub async fn choose_gateway(login: &LoginResponse, requested: &str) -> String {
+    for candidate in login.gateway_candidates(requested) {
+        if candidate.eq_ignore_ascii_case(requested) {
+            break;
+        }
+        if probe_gateway(candidate, GATEWAY_PROBE_TIMEOUT).await {
+            info!("Login listed gateway {}; using it", candidate);
+            return candidate.to_string();
+        }
+        debug!("Listed gateway {} didn't answer; trying the next", candidate);
+    }
+    requested.to_string()
+}
+
+/// Run `attempt` (e.g. getconfig) against `chosen`, and against `requested`
+/// if that fails
+///
+/// `chosen` is `choose_gateway`'s pick. A gateway the login listed can
+/// answer the probe and still fail, so the one the user logged in to is the
+/// fallback. Returns the gateway that succeeded along with its result.
+pub async fn with_gateway_fallback<T, F, Fut>(
+    chosen: &str,
+    requested: &str,
+    mut attempt: F,
+) -> Result<(String, T), AuthError>
+where
+    F: FnMut(String) -> Fut,
+    Fut: std::future::Future<Output = Result<T, AuthError>>,
+{
+    match attempt(chosen.to_string()).await {
+        Ok(value) => Ok((chosen.to_string(), value)),
+        Err(e) if !chosen.eq_ignore_ascii_case(requested) => {
+            warn!("Gateway {} failed ({}); falling back to {}", chosen, e, requested);
+            let value = attempt(requested.to_string()).await?;
+            Ok((requested.to_string(), value))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `gateway` (host, or host:port) accepts a TCP connection in time
+async fn probe_gateway(gateway: &str, timeout: Duration) -> bool {
+    let Ok((host, port)) = crate::config::split_gateway_port(gateway) else {
+        return false;
+    };
+    let address = (host, port.unwrap_or(443));
+    matches!(
+        tokio::time::timeout(timeout, tokio::net::TcpStream::connect(address)).await,
+        Ok(Ok(_))
+    )
 }
 
 /// Tunnel configuration from getconfig
@@ -64,7 +449,54 @@ pub struct TunnelConfig {
     pub internal_ip: IpAddr,
     pub internal_ip6: Option<std::net::Ipv6Addr>,
     pub dns_servers: Vec<IpAddr>,
-    pub timeout_seconds: u64,
+    /// Session timeout pushed by the gateway, if it sent one
+    pub timeout_seconds: Option<u64>,
+    /// Netmask pushed by the gateway (see `onlink_network`)
+    pub netmask: Option<String>,
+    /// Split-include networks pushed by the gateway (`access-routes`)
+    pub split_include: Vec<String>,
+    /// Parsed `access-routes`: networks the gateway wants tunneled
+    #[serde(default)]
+    pub include_routes: Vec<IpNet>,
+    /// Parsed `exclude-access-routes`: networks to keep off the tunnel
+    #[serde(default)]
+    pub exclude_routes: Vec<IpNet>,
+    /// DNS search suffixes pushed by the gateway (`dns-suffix`)
+    #[serde(default)]
+    pub dns_suffixes: Vec<String>,
+    /// Gateway that issued this config, when the first getconfig only
+    /// returned a gateway list and was followed up elsewhere
+    #[serde(default)]
+    pub gateway_host: Option<String>,
+    /// ESP parameters, if the gateway offered a supported UDP data channel
+    #[serde(skip)]
+    pub esp: Option<EspConfig>,
+}
+
+impl TunnelConfig {
+    /// Session length for display ("16 hours", "1h 30m"), if the gateway sent one
+    pub fn session_length(&self) -> Option<String> {
+        let secs = self.timeout_seconds.filter(|&secs| secs > 0)?;
+        let (hours, minutes) = (secs / 3600, secs % 3600 / 60);
+        Some(match (hours, minutes) {
+            (0, 0) => format!("{} seconds", secs),
+            (0, m) => format!("{} minutes", m),
+            (1, 0) => "1 hour".to_string(),
+            (h, 0) => format!("{} hours", h),
+            (h, m) => format!("{}h {}m", h, m),
+        })
+    }
+
+    /// Subnet around our tunnel address that the netmask puts on-link
+    ///
+    /// None for the usual point-to-point /32, or a netmask too short to route.
+    pub fn onlink_network(&self) -> Option<String> {
+        let prefix = crate::vpn::routing::netmask_prefix(self.netmask.as_deref()?)?;
+        if prefix >= 32 {
+            return None;
+        }
+        crate::vpn::routing::network_cidr(self.internal_ip, prefix).ok()
+    }
 }
 
 // XML deserialization structures for prelogin
@@ -107,21 +539,44 @@ struct PolicyXml {
     mtu: Option<String>,
     #[serde(rename = "dns", default)]
     dns: Option<Dns>,
+    #[serde(rename = "dns-suffix", default)]
+    dns_suffix: Option<DnsSuffix>,
     #[serde(rename = "timeout", default)]
     timeout: Option<String>,
+    #[serde(rename = "netmask", default)]
+    netmask: Option<String>,
+    #[serde(rename = "access-routes", default)]
+    access_routes: Option<AccessRoutes>,
+    #[serde(rename = "exclude-access-routes", default)]
+    exclude_access_routes: Option<AccessRoutes>,
+    #[serde(rename = "gw-address", default)]
+    gw_address: Option<String>,
+    #[serde(rename = "ipsec", default)]
+    ipsec: Option<IpsecXml>,
 }
 
 #[derive(Debug, Deserialize)]
-#[allow(dead_code)]
 struct Gateways {
     #[serde(rename = "external")]
     external: External,
 }
 
 #[derive(Debug, Deserialize)]
-#[allow(dead_code)]
 struct External {
-    list: String,
+    #[serde(default)]
+    list: Option<GatewayList>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GatewayList {
+    #[serde(rename = "entry", default)]
+    entries: Vec<GatewayEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GatewayEntry {
+    #[serde(rename = "@name")]
+    name: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -129,38 +584,70 @@ struct Dns {
     member: Vec<String>,
 }
 
+#[derive(Debug, Deserialize)]
+struct DnsSuffix {
+    #[serde(default)]
+    member: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessRoutes {
+    #[serde(default)]
+    member: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpsecXml {
+    #[serde(rename = "udp-port", default)]
+    udp_port: Option<String>,
+    #[serde(rename = "enc-algo", default)]
+    enc_algo: Option<String>,
+    #[serde(rename = "c2s-spi", default)]
+    c2s_spi: Option<String>,
+    #[serde(rename = "s2c-spi", default)]
+    s2c_spi: Option<String>,
+    #[serde(rename = "ekey-c2s", default)]
+    ekey_c2s: Option<KeyXml>,
+    #[serde(rename = "ekey-s2c", default)]
+    ekey_s2c: Option<KeyXml>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyXml {
+    val: String,
+}
+
 /// Step 1: Check what auth method is required
 ///
 /// # Arguments
 /// * `gateway` - Gateway hostname (e.g., "psomvpn.uphs.upenn.edu")
+/// * `identity` - Client OS/version to report
 ///
 /// # Returns
 /// Pre-login response with authentication method details
-pub async fn prelogin(gateway: &str) -> Result<PreloginResponse, AuthError> {
+pub async fn prelogin(gateway: &str, identity: &ClientIdentity) -> Result<PreloginResponse, AuthError> {
     info!("Sending prelogin request to {}", gateway);
 
-    let client = Client::builder()
-        .danger_accept_invalid_certs(false)
-        .build()?;
+    let client = identity.client_builder().build()?;
 
-    let url = format!("https://{}/ssl-vpn/prelogin.esp", gateway);
+    let url = format!("https://{}/ssl-vpn/prelogin.esp", url_host(gateway));
     let params = [
         ("tmp", "tmp"),
-        ("clientVer", "4100"),
-        ("clientos", "Windows"),
+        ("clientVer", identity.client_version.as_str()),
+        ("clientos", identity.os.as_str()),
     ];
 
     let response = client
         .post(&url)
-        .header("User-Agent", "PAN GlobalProtect")
         .form(&params)
         .send()
         .await?;
 
-    let body = response.text().await?;
+    let body = read_body(response).await?;
     debug!("Prelogin response received ({} bytes)", body.len());
+    debug_bundle::record_response("prelogin", &body);
 
-    let prelogin: PreloginXml = quick_xml::de::from_str(&body)?;
+    let prelogin: PreloginXml = parse_xml(&body)?;
 
     if prelogin.status != "Success" {
         return Err(AuthError::AuthFailed(format!(
@@ -192,38 +679,39 @@ struct ChallengeResponse {
 
 /// Parse HTML challenge response
 /// Format: var respStatus = "Challenge"; var respMsg = "..."; thisForm.inputStr.value = "...";
+///
+/// Gateways differ in spacing around `=`, so any is accepted.
 fn parse_challenge(body: &str) -> Option<ChallengeResponse> {
     // Check if this is a challenge response
-    if !body.contains("respStatus = \"Challenge\"") {
+    if script_value(body, "respStatus")? != "Challenge" {
         return None;
     }
 
-    // Extract inputStr value using regex-like parsing
-    let input_str = body
-        .find("inputStr.value = \"")
-        .and_then(|start| {
-            let rest = &body[start + 18..];
-            rest.find('"').map(|end| rest[..end].to_string())
-        })?;
-
-    // Extract message
-    let message = body
-        .find("respMsg = \"")
-        .and_then(|start| {
-            let rest = &body[start + 11..];
-            rest.find('"').map(|end| rest[..end].to_string())
-        })
+    let input_str = script_value(body, "inputStr.value")?.to_string();
+    let message = script_value(body, "respMsg")
+        .map(str::to_string)
         .unwrap_or_else(|| "Enter passcode".to_string());
 
     Some(ChallengeResponse { input_str, message })
 }
 
+/// The string assigned to `name` in a login response's script (`name = "value"`)
+fn script_value<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    body.match_indices(name).find_map(|(start, _)| {
+        let rest = body[start + name.len()..].trim_start().strip_prefix('=')?;
+        let rest = rest.trim_start().strip_prefix('"')?;
+        rest.find('"').map(|end| &rest[..end])
+    })
+}
+
 /// Parse JNLP login response
 /// Handles both labeled format: (auth-cookie), value, (portal), value, ...
 /// And positional format: empty, cookie, persistent-cookie, gateway, user, profile, vsys, domain, ...
 fn parse_jnlp_response(body: &str, username: &str, gateway: &str) -> Result<LoginResponse, AuthError> {
-    let jnlp: JnlpXml = quick_xml::de::from_str(body)
-        .map_err(|e| AuthError::AuthFailed(format!("Invalid login response: {}", e)))?;
+    let jnlp: JnlpXml = parse_xml(body).map_err(|e| match e {
+        AuthError::XmlError(e) => AuthError::AuthFailed(format!("Invalid login response: {}", e)),
+        other => other,
+    })?;
 
     let args = &jnlp.application_desc.argument;
 
@@ -240,6 +728,8 @@ fn parse_jnlp_response(body: &str, username: &str, gateway: &str) -> Result<Logi
         let mut portal = None;
         let mut domain = None;
         let mut gateway_address = None;
+        let mut gateways = Vec::new();
+        let mut preferred_gateways = Vec::new();
 
         let mut i = 0;
         while i < args.len() {
@@ -250,12 +740,21 @@ fn parse_jnlp_response(body: &str, username: &str, gateway: &str) -> Result<Logi
                     "(auth-cookie)" => auth_cookie = Some(value.clone()),
                     "(portal)" => portal = Some(value.clone()),
                     "(domain)" => domain = Some(value.clone()),
-                    "(gateway-address)" => gateway_address = Some(value.clone()),
+                    "(gateway-address)" => {
+                        if gateway_address.is_none() {
+                            gateway_address = Some(value.clone());
+                        }
+                        gateways.extend(split_gateway_list(value));
+                    }
+                    "(preferred-gateway)" | "(preferred-gateway-address)" => {
+                        preferred_gateways.extend(split_gateway_list(value));
+                    }
                     _ => {}
                 }
             }
             i += 2;
         }
+        preferred_gateways.extend(gateways);
 
         Ok(LoginResponse {
             auth_cookie: auth_cookie.ok_or_else(|| AuthError::MissingField("auth-cookie".to_string()))?,
@@ -263,6 +762,7 @@ fn parse_jnlp_response(body: &str, username: &str, gateway: &str) -> Result<Logi
             domain: domain.unwrap_or_default(),
             portal: portal.unwrap_or_else(|| gateway.to_string()),
             gateway_address: gateway_address.unwrap_or_else(|| gateway.to_string()),
+            gateways: preferred_gateways,
         })
     } else {
         // Positional format from PMACS-style servers:
@@ -306,10 +806,48 @@ fn parse_jnlp_response(body: &str, username: &str, gateway: &str) -> Result<Logi
             domain,
             portal: gateway.to_string(),
             gateway_address: gateway_name,
+            // [3] names the gateway's config, not a host to connect to
+            gateways: Vec::new(),
         })
     }
 }
 
+/// Gateways in one JNLP argument value ("gw1.example.edu,gw2.example.edu")
+fn split_gateway_list(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split([',', ';'])
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+}
+
+/// Asks the user for a passcode, given the gateway's challenge message
+///
+/// `None` means the user gave up.
+pub type PasscodePrompt<'a> = dyn Fn(&str) -> Option<String> + Send + Sync + 'a;
+
+/// What to answer the gateway's MFA challenge with
+#[derive(Clone, Copy)]
+pub enum MfaAnswer<'a> {
+    /// Send this: a passcode, or a DUO factor such as "push" or "sms1"
+    Passcode(&'a str),
+    /// Ask once the gateway challenges (only then is a passcode needed)
+    Prompt(&'a PasscodePrompt<'a>),
+    /// Send `factor` (e.g. "push"); if it's denied or not approved within
+    /// `timeout`, ask for a passcode and answer a fresh challenge with it
+    PushOrPrompt {
+        factor: &'a str,
+        timeout: Duration,
+        prompt: &'a PasscodePrompt<'a>,
+    },
+}
+
+impl Default for MfaAnswer<'_> {
+    fn default() -> Self {
+        MfaAnswer::Passcode("push")
+    }
+}
+
 /// Step 2: Authenticate with username/password
 ///
 /// For DUO MFA, use passcode="push" to trigger a push notification.
@@ -331,155 +869,207 @@ pub async fn login(
     password: &str,
     passcode: Option<&str>,
 ) -> Result<LoginResponse, AuthError> {
-    info!("Logging in as {} (passcode: {})", username, if passcode.is_some() { "provided" } else { "none" });
+    login_with_events(
+        gateway,
+        username,
+        password,
+        passcode.map_or_else(MfaAnswer::default, MfaAnswer::Passcode),
+        DEFAULT_GATEWAY_RETRIES,
+        &ClientIdentity::default(),
+        None,
+    )
+    .await
+}
+
+/// Step 2, reporting `ConnectEvent::AwaitingMfa` when the gateway challenges
+///
+/// Same as `login`; the event lets callers tell the user to approve the
+/// push (the challenge request blocks until they do). Each request is
+/// retried up to `retries` times if the gateway answers with HTTP 5xx, and
+/// reports the client OS/version from `identity`. The challenge is answered
+/// as `mfa` says, prompting only if the gateway asks.
+pub async fn login_with_events(
+    gateway: &str,
+    username: &str,
+    password: &str,
+    mfa: MfaAnswer<'_>,
+    retries: u32,
+    identity: &ClientIdentity,
+    events: Option<&tokio::sync::mpsc::Sender<ConnectEvent>>,
+) -> Result<LoginResponse, AuthError> {
+    let url = format!("https://{}/ssl-vpn/login.esp", url_host(gateway));
+    login_at(&url, gateway, username, password, mfa, retries, identity, events).await
+}
+
+/// The login flow against `url` (the gateway's `login.esp`)
+#[allow(clippy::too_many_arguments)]
+async fn login_at(
+    url: &str,
+    gateway: &str,
+    username: &str,
+    password: &str,
+    mfa: MfaAnswer<'_>,
+    retries: u32,
+    identity: &ClientIdentity,
+    events: Option<&tokio::sync::mpsc::Sender<ConnectEvent>>,
+) -> Result<LoginResponse, AuthError> {
+    let passcode_kind = match mfa {
+        MfaAnswer::Passcode(_) => "provided",
+        MfaAnswer::Prompt(_) => "on challenge",
+        MfaAnswer::PushOrPrompt { .. } => "provided, prompt if it fails",
+    };
+    info!("Logging in as {} (passcode: {})", username, passcode_kind);
 
-    let client = Client::builder()
-        .danger_accept_invalid_certs(false)
+    let client = identity
+        .client_builder()
         .cookie_store(true)  // Maintain session cookies for MFA flow
         .build()?;
 
-    let url = format!("https://{}/ssl-vpn/login.esp", gateway);
-
     let hostname = hostname::get()
         .ok()
         .and_then(|h| h.into_string().ok())
         .unwrap_or_else(|| "unknown".to_string());
+    let form = LoginForm {
+        username,
+        gateway,
+        hostname: &hostname,
+        identity,
+    };
 
     // First request: send credentials
-    // Required params per GP protocol doc: user, passwd, ok=Login, jnlpReady, direct, server, etc.
-    let params: HashMap<&str, String> = [
-        ("user", username.to_string()),
-        ("passwd", password.to_string()),
-        ("jnlpReady", "jnlpReady".to_string()),  // Required!
-        ("ok", "Login".to_string()),              // Required!
-        ("direct", "yes".to_string()),            // Required!
-        ("prot", "https:".to_string()),
-        ("server", gateway.to_string()),
-        ("computer", hostname.clone()),
-        ("os-version", "Microsoft Windows 10 Pro".to_string()),
-        ("clientos", "Windows".to_string()),
-        ("clientVer", "4100".to_string()),
-        ("ipv6-support", "yes".to_string()),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-
-    let response = client
-        .post(&url)
-        .header("User-Agent", "PAN GlobalProtect")
-        .form(&params)
-        .send()
-        .await?;
-
-    let body = response.text().await?;
+    let body = post_form(&client, url, &form.params(password, None), retries).await?;
     debug!("Login response received ({} bytes)", body.len());
+    debug_bundle::record_response("login", &body);
 
     // Check if this is a challenge response (MFA required)
-    if let Some(challenge) = parse_challenge(&body) {
-        info!("MFA challenge received: {}", challenge.message);
-
-        // Second request: send challenge token with passcode in passwd field
-        // For DUO push, the server will block until the user approves
-        let passcode = passcode.unwrap_or("push");
-        info!("Sending MFA response with passcode: {} (waiting for approval...)", passcode);
-
-        let challenge_params: HashMap<&str, String> = [
-            ("user", username.to_string()),
-            ("passwd", passcode.to_string()),  // Passcode goes in passwd field for MFA step
-            ("inputStr", challenge.input_str),
-            ("jnlpReady", "jnlpReady".to_string()),  // Required!
-            ("ok", "Login".to_string()),              // Required!
-            ("direct", "yes".to_string()),            // Required!
-            ("prot", "https:".to_string()),
-            ("server", gateway.to_string()),
-            ("computer", hostname.clone()),
-            ("os-version", "Microsoft Windows 10 Pro".to_string()),
-            ("clientos", "Windows".to_string()),
-            ("clientVer", "4100".to_string()),
-            ("ipv6-support", "yes".to_string()),
-        ]
-        .iter()
-        .cloned()
-        .collect();
-
-        let challenge_response = client
-            .post(&url)
-            .header("User-Agent", "PAN GlobalProtect")
-            .form(&challenge_params)
-            .send()
-            .await?;
-
-        debug!("MFA response status: {}", challenge_response.status());
-
-        let challenge_body = challenge_response.text().await?;
-        debug!("MFA response received ({} bytes)", challenge_body.len());
-
-        // Check for error response
-        if challenge_body.contains("respStatus = \"Error\"") {
-            // Extract error message
-            let msg = challenge_body
-                .find("respMsg = \"")
-                .and_then(|start| {
-                    let rest = &challenge_body[start + 11..];
-                    rest.find('"').map(|end| rest[..end].to_string())
-                })
-                .unwrap_or_else(|| "Unknown error".to_string());
-            return Err(AuthError::AuthFailed(format!("MFA failed: {}", msg)));
+    let Some(challenge) = parse_challenge(&body) else {
+        // No challenge - parse as JNLP directly
+        return parse_jnlp_response(&body, username, gateway);
+    };
+    info!("MFA challenge received: {}", challenge.message);
+    events::emit(events, ConnectEvent::AwaitingMfa).await;
+
+    // Second request: send challenge token with passcode in passwd field
+    // For DUO push, the server will block until the user approves
+    let challenge_body = match mfa {
+        MfaAnswer::Passcode(passcode) => {
+            info!("Sending MFA response with passcode: {} (waiting for approval...)", passcode);
+            answer_challenge(&client, url, &form, passcode, challenge.input_str, retries).await?
         }
-
-        // Check for another challenge (wrong passcode, etc.)
-        if let Some(retry_challenge) = parse_challenge(&challenge_body) {
-            return Err(AuthError::AuthFailed(format!(
-                "MFA failed: {}",
-                retry_challenge.message
-            )));
+        MfaAnswer::Prompt(prompt) => {
+            let passcode = prompt(&challenge.message)
+                .ok_or_else(|| AuthError::AuthFailed("MFA cancelled".to_string()))?;
+            info!("Sending MFA response with the entered passcode");
+            answer_challenge(&client, url, &form, &passcode, challenge.input_str, retries).await?
+        }
+        MfaAnswer::PushOrPrompt { factor, timeout, prompt } => {
+            info!("Sending MFA response with passcode: {} (waiting for approval...)", factor);
+            let answer = answer_challenge(&client, url, &form, factor, challenge.input_str, retries);
+            let pushed = tokio::time::timeout(timeout, answer).await.unwrap_or_else(|_| {
+                Err(AuthError::AuthFailed(format!("no answer within {}s", timeout.as_secs())))
+            });
+            match pushed {
+                Err(AuthError::AuthFailed(reason)) => {
+                    warn!("MFA with {} didn't go through ({}); asking for a passcode", factor, reason);
+                    // The failed answer used up the challenge: log in again for a new one
+                    let body = post_form(&client, url, &form.params(password, None), retries).await?;
+                    debug_bundle::record_response("login", &body);
+                    let challenge = parse_challenge(&body).ok_or_else(|| {
+                        AuthError::AuthFailed(format!("MFA failed: {}", reason))
+                    })?;
+                    let passcode = prompt(&challenge.message)
+                        .ok_or_else(|| AuthError::AuthFailed("MFA cancelled".to_string()))?;
+                    info!("Sending MFA response with the entered passcode");
+                    answer_challenge(&client, url, &form, &passcode, challenge.input_str, retries).await?
+                }
+                pushed => pushed?,
+            }
         }
+    };
 
-        // If empty response with 200 OK, MFA succeeded but we need to retry login
-        // to get the actual JNLP response
-        if challenge_body.is_empty() {
-            info!("MFA accepted, completing login...");
-
-            // Retry login with original credentials - session is now MFA-validated
-            let retry_params: HashMap<&str, String> = [
-                ("user", username.to_string()),
-                ("passwd", password.to_string()),
-                ("jnlpReady", "jnlpReady".to_string()),
-                ("ok", "Login".to_string()),
-                ("direct", "yes".to_string()),
-                ("prot", "https:".to_string()),
-                ("server", gateway.to_string()),
-                ("computer", hostname),
-                ("os-version", "Microsoft Windows 10 Pro".to_string()),
-                ("clientos", "Windows".to_string()),
-                ("clientVer", "4100".to_string()),
-                ("ipv6-support", "yes".to_string()),
-            ]
-            .iter()
-            .cloned()
-            .collect();
+    // If empty response with 200 OK, MFA succeeded but we need to retry login
+    // to get the actual JNLP response
+    if challenge_body.is_empty() {
+        info!("MFA accepted, completing login...");
+
+        // Retry login with original credentials - session is now MFA-validated
+        let retry_body = post_form(&client, url, &form.params(password, None), retries).await?;
+        debug!("Retry login response received ({} bytes)", retry_body.len());
+        debug_bundle::record_response("login", &retry_body);
 
-            let retry_response = client
-                .post(&url)
-                .header("User-Agent", "PAN GlobalProtect")
-                .form(&retry_params)
-                .send()
-                .await?;
+        return parse_jnlp_response(&retry_body, username, gateway);
+    }
 
-            debug!("Retry login status: {}", retry_response.status());
+    parse_jnlp_response(&challenge_body, username, gateway)
+}
 
-            let retry_body = retry_response.text().await?;
-            debug!("Retry login body: {}", retry_body);
+/// What every `login.esp` request sends besides the secret
+struct LoginForm<'a> {
+    username: &'a str,
+    gateway: &'a str,
+    hostname: &'a str,
+    identity: &'a ClientIdentity,
+}
 
-            return parse_jnlp_response(&retry_body, username, gateway);
+impl LoginForm<'_> {
+    /// Form fields with `secret` (password, or passcode for an MFA answer)
+    ///
+    /// Required params per GP protocol doc: user, passwd, ok=Login,
+    /// jnlpReady, direct, server, etc.
+    fn params(&self, secret: &str, input_str: Option<String>) -> HashMap<&'static str, String> {
+        let mut params: HashMap<&str, String> = [
+            ("user", self.username.to_string()),
+            ("passwd", secret.to_string()),
+            ("jnlpReady", "jnlpReady".to_string()),  // Required!
+            ("ok", "Login".to_string()),              // Required!
+            ("direct", "yes".to_string()),            // Required!
+            ("prot", "https:".to_string()),
+            ("server", self.gateway.to_string()),
+            ("computer", self.hostname.to_string()),
+            ("os-version", self.identity.os_version.clone()),
+            ("clientos", self.identity.os.clone()),
+            ("clientVer", self.identity.client_version.clone()),
+            ("ipv6-support", "yes".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        if let Some(input_str) = input_str {
+            params.insert("inputStr", input_str);
         }
+        params
+    }
+}
 
-        return parse_jnlp_response(&challenge_body, username, gateway);
+/// Answer an MFA challenge, returning the gateway's response body
+///
+/// A rejected answer (error response, or another challenge) is
+/// `AuthError::AuthFailed`.
+async fn answer_challenge(
+    client: &Client,
+    url: &str,
+    form: &LoginForm<'_>,
+    passcode: &str,
+    input_str: String,
+    retries: u32,
+) -> Result<String, AuthError> {
+    let challenge_body = post_form(client, url, &form.params(passcode, Some(input_str)), retries).await?;
+    debug!("MFA response received ({} bytes)", challenge_body.len());
+    debug_bundle::record_response("login-mfa", &challenge_body);
+
+    // Check for error response
+    if script_value(&challenge_body, "respStatus") == Some("Error") {
+        let msg = script_value(&challenge_body, "respMsg").unwrap_or("Unknown error");
+        return Err(AuthError::AuthFailed(format!("MFA failed: {}", msg)));
     }
 
-    // No challenge - parse as JNLP directly
-    parse_jnlp_response(&body, username, gateway)
+    // Check for another challenge (wrong passcode, etc.)
+    if let Some(retry_challenge) = parse_challenge(&challenge_body) {
+        return Err(AuthError::AuthFailed(format!(
+            "MFA failed: {}",
+            retry_challenge.message
+        )));
+    }
+    Ok(challenge_body)
 }
 
 /// Helper function to parse MTU from policy XML
@@ -507,20 +1097,185 @@ fn parse_dns_servers(policy: &PolicyXml) -> Vec<IpAddr> {
         .unwrap_or_default()
 }
 
+/// Helper function to parse DNS search suffixes from policy XML
+fn parse_dns_suffixes(policy: &PolicyXml) -> Vec<String> {
+    let mut suffixes: Vec<String> = Vec::new();
+    let members = policy.dns_suffix.iter().flat_map(|s| &s.member);
+    for suffix in members.map(|s| s.trim().trim_matches('.').to_ascii_lowercase()) {
+        if !suffix.is_empty() && !suffixes.contains(&suffix) {
+            suffixes.push(suffix);
+        }
+    }
+    suffixes
+}
+
+/// Helper function to parse split-include routes from policy XML
+fn parse_split_include(policy: &PolicyXml) -> Vec<String> {
+    policy
+        .access_routes
+        .as_ref()
+        .map(|routes| {
+            routes
+                .member
+                .iter()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Helper function to parse a route list (`access-routes` and friends)
+///
+/// Members are CIDR networks or bare addresses (taken as a single host);
+/// anything else is skipped.
+fn parse_route_list(routes: Option<&AccessRoutes>) -> Vec<IpNet> {
+    routes
+        .map(|routes| {
+            routes
+                .member
+                .iter()
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .filter_map(|s| {
+                    let net = s
+                        .parse::<IpNet>()
+                        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from));
+                    if net.is_err() {
+                        debug!("Ignoring unparseable gateway route {:?}", s);
+                    }
+                    net.ok()
+                })
+                .map(|net| net.trunc())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl TunnelConfig {
+    /// Gateway include routes that should be added to the route table
+    ///
+    /// Keeps IPv4 networks no wider than /8 (so a pushed default route can't
+    /// take over all traffic) that don't fall inside an excluded network.
+    pub fn routable_includes(&self) -> Vec<IpNet> {
+        use crate::vpn::routing::MIN_ROUTE_PREFIX;
+
+        self.include_routes
+            .iter()
+            .filter(|net| matches!(net, IpNet::V4(_)) && net.prefix_len() >= MIN_ROUTE_PREFIX)
+            .filter(|net| !self.exclude_routes.iter().any(|ex| ex.contains(*net)))
+            .copied()
+            .collect()
+    }
+
+    /// Host to open the tunnel to: the gateway that issued this config,
+    /// or `requested` when getconfig wasn't redirected
+    pub fn tunnel_gateway<'a>(&'a self, requested: &'a str) -> &'a str {
+        self.gateway_host.as_deref().unwrap_or(requested)
+    }
+
+    /// DNS servers to resolve hosts with: the configured ones if any, else the gateway's
+    pub fn resolvers(&self, configured: &[IpAddr]) -> Vec<IpAddr> {
+        if configured.is_empty() {
+            self.dns_servers.clone()
+        } else {
+            configured.to_vec()
+        }
+    }
+
+    /// Whether `ip` is inside the networks the gateway tunnels: its
+    /// `access-routes` and the on-link subnet
+    ///
+    /// None without access routes, so there's nothing to check against; an
+    /// on-link pool alone says nothing about the hosts behind the gateway.
+    pub fn in_vpn_networks(&self, ip: IpAddr) -> Option<bool> {
+        let networks = self.vpn_networks();
+        if networks.is_empty() {
+            return None;
+        }
+        Some(networks.iter().any(|network| network.contains(&ip)))
+    }
+
+    /// The networks `in_vpn_networks` checks against (empty without access routes)
+    pub fn vpn_networks(&self) -> Vec<IpNet> {
+        if self.include_routes.is_empty() {
+            return Vec::new();
+        }
+        let onlink = self.onlink_network().and_then(|network| network.parse().ok());
+        onlink.into_iter().chain(self.include_routes.iter().copied()).collect()
+    }
+
+    /// Search domains for short names: the configured ones, then the gateway's
+    pub fn search_domains(&self, configured: &[String]) -> Vec<String> {
+        configured
+            .iter()
+            .chain(&self.dns_suffixes)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Helper function to parse ESP parameters from policy XML
+///
+/// Returns `None` (the tunnel stays on SSL) unless the gateway sent a
+/// complete `<ipsec>` section with a cipher we implement.
+fn parse_esp(policy: &PolicyXml) -> Option<EspConfig> {
+    let ipsec = policy.ipsec.as_ref()?;
+    let cipher_name = ipsec.enc_algo.as_deref().unwrap_or("(none)");
+    let Some(cipher) = EspCipher::from_name(cipher_name) else {
+        debug!("Gateway offered unsupported ESP cipher {}", cipher_name);
+        return None;
+    };
+
+    let config = EspConfig {
+        cipher,
+        udp_port: ipsec.udp_port.as_deref()?.trim().parse().ok()?,
+        probe_addr: policy.gw_address.as_deref()?.trim().parse().ok()?,
+        c2s_spi: esp::parse_spi(ipsec.c2s_spi.as_deref()?)?,
+        c2s_key: esp::parse_hex_key(&ipsec.ekey_c2s.as_ref()?.val)?,
+        s2c_spi: esp::parse_spi(ipsec.s2c_spi.as_deref()?)?,
+        s2c_key: esp::parse_hex_key(&ipsec.ekey_s2c.as_ref()?.val)?,
+    };
+    Some(config)
+}
+
 /// Shared implementation for getting tunnel configuration
+///
+/// Some portals answer the first getconfig with a gateway list and no
+/// `ip-address`; the request is then repeated once against the selected
+/// gateway, whose response must carry the tunnel IP.
 async fn getconfig_impl(
     gateway: &str,
-    username: &str,
-    auth_cookie: &str,
-    portal: &str,
-    domain: &str,
+    login: &LoginResponse,
     preferred_ip: Option<IpAddr>,
+    retries: u32,
+    identity: &ClientIdentity,
 ) -> Result<TunnelConfig, AuthError> {
-    let client = Client::builder()
-        .danger_accept_invalid_certs(false)
-        .build()?;
+    let client = identity.client_builder().build()?;
 
-    let url = format!("https://{}/ssl-vpn/getconfig.esp", gateway);
+    let policy = fetch_policy(&client, gateway, login, preferred_ip, retries, identity).await?;
+    if policy.ip_address.is_some() {
+        return tunnel_config_from_policy(&policy, None).inspect(debug_bundle::record_tunnel_config);
+    }
+
+    let Some(selected) = select_gateway(&policy, gateway) else {
+        return Err(AuthError::MissingField("ip-address".to_string()));
+    };
+    info!("Getconfig returned a gateway list; requesting config from {}", selected);
+    let policy = fetch_policy(&client, &selected, login, preferred_ip, retries, identity).await?;
+    tunnel_config_from_policy(&policy, Some(selected)).inspect(debug_bundle::record_tunnel_config)
+}
+
+/// Send one getconfig request and parse the policy it returns
+async fn fetch_policy(
+    client: &Client,
+    gateway: &str,
+    login: &LoginResponse,
+    preferred_ip: Option<IpAddr>,
+    retries: u32,
+    identity: &ClientIdentity,
+) -> Result<PolicyXml, AuthError> {
+    let url = format!("https://{}/ssl-vpn/getconfig.esp", url_host(gateway));
 
     let hostname = hostname::get()
         .ok()
@@ -533,14 +1288,14 @@ async fn getconfig_impl(
 
     // Full parameter set per GP protocol doc
     let params = [
-        ("user", username),
-        ("portal", portal),
-        ("domain", domain),
-        ("authcookie", auth_cookie),
+        ("user", login.username.as_str()),
+        ("portal", login.portal.as_str()),
+        ("domain", login.domain.as_str()),
+        ("authcookie", login.auth_cookie.as_str()),
         ("preferred-ip", preferred.as_str()),
-        ("clientos", "Windows"),
-        ("os-version", "Microsoft Windows 10 Pro"),
-        ("app-version", "4.1.0-10"),
+        ("clientos", identity.os.as_str()),
+        ("os-version", identity.os_version.as_str()),
+        ("app-version", identity.app_version.as_str()),
         ("protocol-version", "p1"),
         ("client-type", "1"),
         ("enc-algo", "aes-256-gcm,aes-128-gcm,aes-128-cbc"),
@@ -548,19 +1303,41 @@ async fn getconfig_impl(
         ("computer", hostname.as_str()),
     ];
 
-    let response = client
-        .post(&url)
-        .header("User-Agent", "PAN GlobalProtect")
-        .form(&params)
-        .send()
-        .await?;
-
-    let body = response.text().await?;
+    let body = post_form(client, &url, &params, retries).await?;
     debug!("Getconfig response received ({} bytes)", body.len());
+    debug_bundle::record_response("getconfig", &body);
 
-    let policy: PolicyXml = quick_xml::de::from_str(&body)
-        .map_err(|e| AuthError::AuthFailed(format!("Invalid getconfig response: {}", e)))?;
+    parse_xml(&body).map_err(|e| match e {
+        AuthError::XmlError(e) => AuthError::AuthFailed(format!("Invalid getconfig response: {}", e)),
+        other => other,
+    })
+}
 
+/// Gateway to repeat getconfig against when a response lists gateways
+///
+/// Takes the first listed gateway other than the one just asked, so a
+/// gateway that lists itself can't send us round in circles.
+fn select_gateway(policy: &PolicyXml, current: &str) -> Option<String> {
+    policy
+        .gateways
+        .as_ref()?
+        .external
+        .list
+        .as_ref()?
+        .entries
+        .iter()
+        .map(|entry| entry.name.trim())
+        .find(|name| !name.is_empty() && !name.eq_ignore_ascii_case(current))
+        .map(str::to_string)
+}
+
+/// Build the tunnel configuration from a getconfig policy
+///
+/// `gateway_host` records which gateway issued it, if not the one connected to.
+fn tunnel_config_from_policy(
+    policy: &PolicyXml,
+    gateway_host: Option<String>,
+) -> Result<TunnelConfig, AuthError> {
     let internal_ip: IpAddr = policy
         .ip_address
         .as_ref()
@@ -573,14 +1350,19 @@ async fn getconfig_impl(
         .as_ref()
         .and_then(|s| s.parse().ok());
 
-    let mtu = parse_mtu(&policy);
-    let dns_servers = parse_dns_servers(&policy);
+    let mtu = parse_mtu(policy);
+    let dns_servers = parse_dns_servers(policy);
+    let dns_suffixes = parse_dns_suffixes(policy);
+    let split_include = parse_split_include(policy);
+    let include_routes = parse_route_list(policy.access_routes.as_ref());
+    let exclude_routes = parse_route_list(policy.exclude_access_routes.as_ref());
+    let esp = parse_esp(policy);
+    let netmask = policy.netmask.as_ref().map(|s| s.trim().to_string());
 
     let timeout_seconds = policy
         .timeout
         .as_ref()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(3600);
+        .and_then(|s| s.trim().parse().ok());
 
     Ok(TunnelConfig {
         mtu,
@@ -588,6 +1370,13 @@ async fn getconfig_impl(
         internal_ip6,
         dns_servers,
         timeout_seconds,
+        netmask,
+        split_include,
+        include_routes,
+        exclude_routes,
+        dns_suffixes,
+        gateway_host,
+        esp,
     })
 }
 
@@ -597,6 +1386,8 @@ async fn getconfig_impl(
 /// * `gateway` - Gateway hostname
 /// * `login` - Login response containing auth cookie and user info
 /// * `preferred_ip` - Optional preferred IP address
+/// * `retries` - Retries if the gateway answers with HTTP 5xx
+/// * `identity` - Client OS/version to report
 ///
 /// # Returns
 /// Tunnel configuration with IP, DNS, MTU settings
@@ -604,22 +1395,17 @@ pub async fn getconfig(
     gateway: &str,
     login: &LoginResponse,
     preferred_ip: Option<IpAddr>,
+    retries: u32,
+    identity: &ClientIdentity,
 ) -> Result<TunnelConfig, AuthError> {
     info!("Getting tunnel configuration");
 
-    getconfig_impl(
-        gateway,
-        &login.username,
-        &login.auth_cookie,
-        &login.portal,
-        &login.domain,
-        preferred_ip,
-    )
-    .await
+    getconfig_impl(gateway, login, preferred_ip, retries, identity).await
 }
 
 /// Get tunnel configuration using raw auth cookie (for daemon mode)
 /// This is used when the parent process has already done auth and saved the cookie
+#[allow(clippy::too_many_arguments)]
 pub async fn getconfig_with_cookie(
     gateway: &str,
     username: &str,
@@ -627,24 +1413,47 @@ pub async fn getconfig_with_cookie(
     portal: &str,
     domain: &str,
     preferred_ip: Option<IpAddr>,
+    retries: u32,
+    identity: &ClientIdentity,
 ) -> Result<TunnelConfig, AuthError> {
     info!("Getting tunnel configuration (daemon mode)");
 
-    getconfig_impl(
-        gateway,
-        username,
-        auth_cookie,
-        portal,
-        domain,
-        preferred_ip,
-    )
-    .await
+    let login = LoginResponse {
+        auth_cookie: auth_cookie.to_string(),
+        username: username.to_string(),
+        domain: domain.to_string(),
+        portal: portal.to_string(),
+        gateway_address: gateway.to_string(),
+        gateways: Vec::new(),
+    };
+    getconfig_impl(gateway, &login, preferred_ip, retries, identity).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_login_from_cookie() {
+        let login = LoginResponse::from_cookie("gw.example.edu", Some("user"), " ec85fe94 \n").unwrap();
+        assert_eq!(login.auth_cookie, "ec85fe94");
+        assert_eq!(login.username, "user");
+        assert_eq!(login.portal, "gw.example.edu");
+
+        let openconnect = "authcookie=ec85fe94&portal=PORTAL-GW&user=jdoe%40example.edu&domain=&computer=host";
+        let login = LoginResponse::from_cookie("gw.example.edu", Some("other"), openconnect).unwrap();
+        assert_eq!(login.auth_cookie, "ec85fe94");
+        assert_eq!(login.username, "jdoe@example.edu");
+        assert_eq!(login.portal, "PORTAL-GW");
+        assert_eq!(login.domain, "");
+
+        assert!(matches!(
+            LoginResponse::from_cookie("gw.example.edu", None, "ec85fe94"),
+            Err(AuthError::MissingField(_))
+        ));
+        assert!(LoginResponse::from_cookie("gw.example.edu", Some("user"), "authcookie=&user=x").is_err());
+    }
+
     #[test]
     fn test_parse_prelogin_password() {
         let xml = r#"
@@ -724,6 +1533,65 @@ mod tests {
         assert_eq!(login.username, "yjk");
         assert_eq!(login.domain, "pmacs");
         assert_eq!(login.gateway_address, "psom_admin_vpn_gateway-N");
+        assert!(login.gateways.is_empty());
+    }
+
+    #[test]
+    fn test_parse_jnlp_multiple_gateways() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<jnlp>
+<application-desc>
+<argument>(auth-cookie)</argument>
+<argument>ec85fe94925569dbaf7f38bfe736da90</argument>
+<argument>(portal)</argument>
+<argument>portal.example.edu</argument>
+<argument>(gateway-address)</argument>
+<argument>gw1.example.edu</argument>
+<argument>(gateway-address)</argument>
+<argument>gw2.example.edu, 203.0.113.7</argument>
+<argument>(preferred-gateway)</argument>
+<argument>gw2.example.edu</argument>
+</application-desc>
+</jnlp>"#;
+
+        let login = parse_jnlp_response(xml, "user", "portal.example.edu").unwrap();
+        assert_eq!(login.gateway_address, "gw1.example.edu");
+        assert_eq!(
+            login.gateways,
+            ["gw2.example.edu", "gw1.example.edu", "gw2.example.edu", "203.0.113.7"]
+        );
+        // Preferred first, no duplicates or IP literals, the login host last
+        assert_eq!(
+            login.gateway_candidates("portal.example.edu"),
+            ["gw2.example.edu", "gw1.example.edu", "portal.example.edu"]
+        );
+        assert_eq!(
+            login.gateway_candidates("GW1.example.edu"),
+            ["gw2.example.edu", "gw1.example.edu"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_choose_gateway_takes_first_reachable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let closed = {
+            let unused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("localhost:{}", unused.local_addr().unwrap().port())
+        };
+        let mut login = parse_jnlp_response(
+            "<jnlp><application-desc><argument>(auth-cookie)</argument><argument>c</argument></application-desc></jnlp>",
+            "user",
+            "vpn.example.edu",
+        )
+        .unwrap();
+
+        login.gateways = vec![closed.clone(), reachable.clone()];
+        assert_eq!(choose_gateway(&login, "vpn.example.edu").await, reachable);
+
+        // Nothing listed answers: stay on the gateway we logged in to
+        login.gateways = vec![closed];
+        assert_eq!(choose_gateway(&login, "vpn.example.edu").await, "vpn.example.edu");
     }
 
     #[test]
@@ -743,10 +1611,229 @@ mod tests {
         let policy: PolicyXml = quick_xml::de::from_str(xml).unwrap();
         assert_eq!(policy.ip_address, Some("10.0.1.100".to_string()));
         assert_eq!(policy.mtu, Some("1400".to_string()));
+        assert!(parse_split_include(&policy).is_empty());
         assert!(policy.dns.is_some());
         assert_eq!(policy.dns.unwrap().member.len(), 2);
     }
 
+    #[test]
+    fn test_parse_getconfig_dns_suffixes() {
+        let xml = r#"
+            <policy>
+                <ip-address>10.0.1.100</ip-address>
+                <dns>
+                    <member>10.0.0.2</member>
+                </dns>
+                <dns-suffix>
+                    <member>pmacs.upenn.edu</member>
+                    <member> UPHS.upenn.edu. </member>
+                    <member>pmacs.upenn.edu</member>
+                </dns-suffix>
+            </policy>
+        "#;
+
+        let policy: PolicyXml = quick_xml::de::from_str(xml).unwrap();
+        let suffixes = parse_dns_suffixes(&policy);
+        assert_eq!(suffixes, vec!["pmacs.upenn.edu", "uphs.upenn.edu"]);
+
+        let no_suffix: PolicyXml =
+            quick_xml::de::from_str("<policy><ip-address>10.0.1.100</ip-address></policy>").unwrap();
+        assert!(parse_dns_suffixes(&no_suffix).is_empty());
+    }
+
+    #[test]
+    fn test_parse_getconfig_netmask_and_access_routes() {
+        let xml = r#"
+            <policy>
+                <ip-address>10.0.1.100</ip-address>
+                <netmask>255.255.255.255</netmask>
+                <access-routes>
+                    <member>172.16.0.0/16</member>
+                    <member> 10.30.0.0/24 </member>
+                </access-routes>
+            </policy>
+        "#;
+
+        let policy: PolicyXml = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(policy.netmask, Some("255.255.255.255".to_string()));
+        assert_eq!(
+            parse_split_include(&policy),
+            vec!["172.16.0.0/16".to_string(), "10.30.0.0/24".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_getconfig_follows_gateway_list() {
+        // Stage 1: the portal lists gateways but assigns no address
+        let portal = r#"
+            <policy>
+                <gateways>
+                    <external>
+                        <list>
+                            <entry name="portal.example.edu">
+                                <description>Portal</description>
+                            </entry>
+                            <entry name="gw1.example.edu">
+                                <description>Primary</description>
+                            </entry>
+                            <entry name="gw2.example.edu">
+                                <description>Secondary</description>
+                            </entry>
+                        </list>
+                    </external>
+                </gateways>
+            </policy>
+        "#;
+        let policy: PolicyXml = quick_xml::de::from_str(portal).unwrap();
+        assert!(matches!(
+            tunnel_config_from_policy(&policy, None),
+            Err(AuthError::MissingField(_))
+        ));
+        let selected = select_gateway(&policy, "portal.example.edu").unwrap();
+        assert_eq!(selected, "gw1.example.edu");
+
+        // Stage 2: the selected gateway hands out the tunnel address
+        let gateway = r#"
+            <policy>
+                <ip-address>10.0.1.100</ip-address>
+                <mtu>1400</mtu>
+            </policy>
+        "#;
+        let policy: PolicyXml = quick_xml::de::from_str(gateway).unwrap();
+        assert_eq!(select_gateway(&policy, &selected), None);
+        let config = tunnel_config_from_policy(&policy, Some(selected)).unwrap();
+        assert_eq!(config.internal_ip.to_string(), "10.0.1.100");
+        assert_eq!(config.gateway_host.as_deref(), Some("gw1.example.edu"));
+    }
+
+    #[test]
+    fn test_gateway_list_naming_only_current_gateway_is_ignored() {
+        let xml = r#"
+            <policy>
+                <gateways><external><list>
+                    <entry name="GW1.example.edu"/>
+                </list></external></gateways>
+            </policy>
+        "#;
+        let policy: PolicyXml = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(select_gateway(&policy, "gw1.example.edu"), None);
+    }
+
+    #[test]
+    fn test_parse_getconfig_include_exclude_routes() {
+        let xml = r#"
+            <policy>
+                <ip-address>10.0.1.100</ip-address>
+                <access-routes>
+                    <member>0.0.0.0/0</member>
+                    <member>172.16.0.0/16</member>
+                    <member>10.30.0.5/24</member>
+                    <member>10.40.1.1</member>
+                    <member>fd00::/64</member>
+                    <member>not-a-network</member>
+                </access-routes>
+                <exclude-access-routes>
+                    <member>10.40.0.0/16</member>
+                </exclude-access-routes>
+            </policy>
+        "#;
+
+        let policy: PolicyXml = quick_xml::de::from_str(xml).unwrap();
+        let config = TunnelConfig {
+            mtu: 1400,
+            internal_ip: "10.0.1.100".parse().unwrap(),
+            internal_ip6: None,
+            dns_servers: vec![],
+            timeout_seconds: Some(3600),
+            netmask: None,
+            split_include: parse_split_include(&policy),
+            include_routes: parse_route_list(policy.access_routes.as_ref()),
+            exclude_routes: parse_route_list(policy.exclude_access_routes.as_ref()),
+            dns_suffixes: vec![],
+            gateway_host: None,
+            esp: None,
+        };
+
+        let net = |s: &str| s.parse::<IpNet>().unwrap();
+        assert_eq!(
+            config.include_routes,
+            vec![
+                net("0.0.0.0/0"),
+                net("172.16.0.0/16"),
+                net("10.30.0.0/24"),
+                net("10.40.1.1/32"),
+                net("fd00::/64"),
+            ]
+        );
+        assert_eq!(config.exclude_routes, vec![net("10.40.0.0/16")]);
+
+        // No default route, no IPv6, nothing inside an excluded network
+        assert_eq!(
+            config.routable_includes(),
+            vec![net("172.16.0.0/16"), net("10.30.0.0/24")]
+        );
+
+        let mut bare = config.clone();
+        bare.include_routes.clear();
+        assert!(bare.vpn_networks().is_empty());
+        assert_eq!(bare.in_vpn_networks("10.30.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_parse_getconfig_ipsec() {
+        let xml = r#"
+            <policy>
+                <ip-address>10.0.1.100</ip-address>
+                <gw-address>10.0.0.1</gw-address>
+                <ipsec>
+                    <udp-port>4501</udp-port>
+                    <ipsec-mode>esp-tunnel</ipsec-mode>
+                    <enc-algo>aes-128-gcm</enc-algo>
+                    <hmac-algo>sha1</hmac-algo>
+                    <c2s-spi>0x1A2B3C4D</c2s-spi>
+                    <s2c-spi>0x5E6F7A8B</s2c-spi>
+                    <ekey-c2s><bits>160</bits><val>000102030405060708090a0b0c0d0e0f10111213</val></ekey-c2s>
+                    <ekey-s2c><bits>160</bits><val>0x131211100f0e0d0c0b0a09080706050403020100</val></ekey-s2c>
+                </ipsec>
+            </policy>
+        "#;
+
+        let policy: PolicyXml = quick_xml::de::from_str(xml).unwrap();
+        let esp = parse_esp(&policy).unwrap();
+        assert_eq!(esp.cipher, EspCipher::Aes128Gcm);
+        assert_eq!(esp.udp_port, 4501);
+        assert_eq!(esp.probe_addr, std::net::Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(esp.c2s_spi, 0x1a2b_3c4d);
+        assert_eq!(esp.s2c_spi, 0x5e6f_7a8b);
+        assert_eq!(esp.c2s_key.len(), 20);
+        assert_eq!(esp.s2c_key[0], 0x13);
+    }
+
+    #[test]
+    fn test_parse_getconfig_ipsec_unsupported_cipher() {
+        let xml = r#"
+            <policy>
+                <ip-address>10.0.1.100</ip-address>
+                <gw-address>10.0.0.1</gw-address>
+                <ipsec>
+                    <udp-port>4501</udp-port>
+                    <enc-algo>aes-128-cbc</enc-algo>
+                    <c2s-spi>0x1A2B3C4D</c2s-spi>
+                    <s2c-spi>0x5E6F7A8B</s2c-spi>
+                    <ekey-c2s><val>000102030405060708090a0b0c0d0e0f</val></ekey-c2s>
+                    <ekey-s2c><val>000102030405060708090a0b0c0d0e0f</val></ekey-s2c>
+                </ipsec>
+            </policy>
+        "#;
+
+        let policy: PolicyXml = quick_xml::de::from_str(xml).unwrap();
+        assert!(parse_esp(&policy).is_none());
+
+        let policy: PolicyXml =
+            quick_xml::de::from_str("<policy><ip-address>10.0.1.100</ip-address></policy>").unwrap();
+        assert!(parse_esp(&policy).is_none());
+    }
+
     #[test]
     fn test_parse_challenge_response() {
         let html = r#"<html>
@@ -765,10 +1852,381 @@ mod tests {
         assert_eq!(challenge.message, "Enter passcode:");
     }
 
+    #[test]
+    fn test_session_length_from_timeout() {
+        let mut config = TunnelConfig {
+            mtu: 1400,
+            internal_ip: "10.0.1.100".parse().unwrap(),
+            internal_ip6: None,
+            dns_servers: vec![],
+            timeout_seconds: Some(16 * 3600),
+            netmask: None,
+            split_include: vec![],
+            include_routes: vec![],
+            exclude_routes: vec![],
+            dns_suffixes: vec![],
+            gateway_host: None,
+            esp: None,
+        };
+        assert_eq!(config.session_length().as_deref(), Some("16 hours"));
+
+        config.timeout_seconds = Some(5400);
+        assert_eq!(config.session_length().as_deref(), Some("1h 30m"));
+
+        config.timeout_seconds = Some(1800);
+        assert_eq!(config.session_length().as_deref(), Some("30 minutes"));
+
+        config.timeout_seconds = None;
+        assert_eq!(config.session_length(), None);
+    }
+
+    #[test]
+    fn test_onlink_network_from_netmask() {
+        let mut config = TunnelConfig {
+            mtu: 1400,
+            internal_ip: "10.0.1.100".parse().unwrap(),
+            internal_ip6: None,
+            dns_servers: vec![],
+            timeout_seconds: None,
+            netmask: Some("255.255.255.0".to_string()),
+            split_include: vec![],
+            include_routes: vec![],
+            exclude_routes: vec![],
+            dns_suffixes: vec![],
+            gateway_host: None,
+            esp: None,
+        };
+        assert_eq!(config.onlink_network().as_deref(), Some("10.0.1.0/24"));
+
+        config.netmask = Some("255.255.255.255".to_string());
+        assert_eq!(config.onlink_network(), None);
+
+        config.netmask = Some("not-a-mask".to_string());
+        assert_eq!(config.onlink_network(), None);
+
+        config.netmask = None;
+        assert_eq!(config.onlink_network(), None);
+    }
+
+    #[test]
+    fn test_resolvers_and_vpn_networks() {
+        let mut config = TunnelConfig {
+            mtu: 1400,
+            internal_ip: "10.0.1.100".parse().unwrap(),
+            internal_ip6: None,
+            dns_servers: vec!["10.0.1.53".parse().unwrap()],
+            timeout_seconds: None,
+            netmask: None,
+            split_include: vec![],
+            include_routes: vec![],
+            exclude_routes: vec![],
+            dns_suffixes: vec![],
+            gateway_host: None,
+            esp: None,
+        };
+        let configured: IpAddr = "172.16.5.5".parse().unwrap();
+        assert_eq!(config.resolvers(&[]), config.dns_servers);
+        assert_eq!(config.resolvers(&[configured]), [configured]);
+
+        // Point-to-point with no access routes: nothing to check against
+        assert_eq!(config.in_vpn_networks(configured), None);
+
+        // An on-link pool alone doesn't say what's behind the gateway
+        config.netmask = Some("255.255.255.0".to_string());
+        assert!(config.vpn_networks().is_empty());
+        assert_eq!(config.in_vpn_networks(configured), None);
+
+        config.include_routes = vec!["172.16.0.0/12".parse().unwrap()];
+        assert_eq!(config.in_vpn_networks("10.0.1.53".parse().unwrap()), Some(true));
+        assert_eq!(config.in_vpn_networks(configured), Some(true));
+        assert_eq!(config.in_vpn_networks("192.168.1.5".parse().unwrap()), Some(false));
+    }
+
     #[test]
     fn test_parse_non_challenge_response() {
         let xml = r#"<jnlp><application-desc></application-desc></jnlp>"#;
         let challenge = parse_challenge(xml);
         assert!(challenge.is_none());
     }
+
+    #[test]
+    fn test_only_server_errors_are_retryable() {
+        assert!(check_status(StatusCode::OK).is_ok());
+        assert!(check_status(StatusCode::FORBIDDEN).is_ok());
+
+        let err = check_status(StatusCode::SERVICE_UNAVAILABLE).unwrap_err();
+        assert!(err.is_retryable());
+        assert!(err.to_string().contains("503"));
+        assert!(!AuthError::AuthFailed("bad password".to_string()).is_retryable());
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off() {
+        assert_eq!(retry_delay(0), Duration::from_secs(1));
+        assert_eq!(retry_delay(1), Duration::from_secs(2));
+        assert_eq!(retry_delay(2), Duration::from_secs(4));
+        assert_eq!(retry_delay(10), RETRY_MAX_DELAY);
+    }
+
+    /// Serve one canned response per connection, returning the listener URL
+    ///
+    /// An empty response leaves its connection hanging.
+    async fn serve(responses: Vec<&'static str>) -> (String, tokio::task::JoinHandle<usize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ssl-vpn/login.esp", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut served = 0;
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                served += 1;
+                if response.is_empty() {
+                    // Never answer, like a push nobody approves
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(30)).await;
+                        drop(stream);
+                    });
+                    continue;
+                }
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+            served
+        });
+        (url, handle)
+    }
+
+    /// A 200 response with `body`, for `serve`
+    fn ok_response(body: &str) -> &'static str {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        Box::leak(response.into_boxed_str())
+    }
+
+    #[tokio::test]
+    async fn test_login_prompts_for_passcode_on_challenge() {
+        let challenge = "<html><body>var respStatus=\"Challenge\";\n\
+            var respMsg=\"Enter a passcode or select push\";\n\
+            thisForm.inputStr.value=\"691e86260039364e\";</body></html>";
+        let jnlp = "<jnlp><application-desc>\
+            <argument>(auth-cookie)</argument><argument>ec85fe94925569dbaf7f38bfe736da90</argument>\
+            <argument>(portal)</argument><argument>portal.example.edu</argument>\
+            </application-desc></jnlp>";
+        let (url, server) = serve(vec![ok_response(challenge), ok_response(jnlp)]).await;
+
+        let asked = std::sync::Mutex::new(Vec::new());
+        let prompt = |message: &str| {
+            asked.lock().unwrap().push(message.to_string());
+            Some("123456".to_string())
+        };
+        let login = login_at(
+            &url,
+            "portal.example.edu",
+            "user",
+            "password",
+            MfaAnswer::Prompt(&prompt),
+            0,
+            &ClientIdentity::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(login.auth_cookie, "ec85fe94925569dbaf7f38bfe736da90");
+        assert_eq!(*asked.lock().unwrap(), ["Enter a passcode or select push"]);
+        assert_eq!(server.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_login_falls_back_to_passcode_when_push_fails() {
+        let challenge = "var respStatus = \"Challenge\"; var respMsg = \"Enter a passcode or select push\";\n\
+            thisForm.inputStr.value = \"abc\";";
+        let denied = "var respStatus = \"Error\"; var respMsg = \"Push denied\";";
+        let jnlp = "<jnlp><application-desc>\
+            <argument>(auth-cookie)</argument><argument>ec85fe94925569dbaf7f38bfe736da90</argument>\
+            </application-desc></jnlp>";
+
+        // The push is left unanswered (an empty canned response), then denied
+        for push_result in ["", ok_response(denied)] {
+            let (url, server) = serve(vec![
+                ok_response(challenge),
+                push_result,
+                ok_response(challenge),
+                ok_response(jnlp),
+            ])
+            .await;
+
+            let asked = std::sync::Mutex::new(0);
+            let prompt = |_: &str| {
+                *asked.lock().unwrap() += 1;
+                Some("123456".to_string())
+            };
+            let login = login_at(
+                &url,
+                "portal.example.edu",
+                "user",
+                "password",
+                MfaAnswer::PushOrPrompt {
+                    factor: "push",
+                    timeout: Duration::from_millis(300),
+                    prompt: &prompt,
+                },
+                0,
+                &ClientIdentity::default(),
+                None,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(login.auth_cookie, "ec85fe94925569dbaf7f38bfe736da90");
+            assert_eq!(*asked.lock().unwrap(), 1);
+            assert_eq!(server.await.unwrap(), 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_cancelled_at_challenge() {
+        let challenge = "var respStatus = \"Challenge\"; thisForm.inputStr.value = \"abc\";";
+        let (url, server) = serve(vec![ok_response(challenge)]).await;
+
+        let err = login_at(
+            &url,
+            "portal.example.edu",
+            "user",
+            "password",
+            MfaAnswer::Prompt(&|_: &str| None),
+            0,
+            &ClientIdentity::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, AuthError::AuthFailed(_)));
+        assert_eq!(server.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_gateway_fallback() {
+        let tried = std::sync::Mutex::new(Vec::new());
+        let attempt = |gateway: String| {
+            tried.lock().unwrap().push(gateway.clone());
+            let result = if gateway == "broken.example.edu" {
+                Err(AuthError::GatewayUnavailable(503))
+            } else {
+                Ok(gateway.len())
+            };
+            async move { result }
+        };
+
+        // The listed gateway fails: the one logged in to gets asked instead
+        let (gateway, _) = with_gateway_fallback("broken.example.edu", "vpn.example.edu", attempt)
+            .await
+            .unwrap();
+        assert_eq!(gateway, "vpn.example.edu");
+        assert_eq!(*tried.lock().unwrap(), ["broken.example.edu", "vpn.example.edu"]);
+
+        // A working pick needs no fallback
+        tried.lock().unwrap().clear();
+        let (gateway, _) = with_gateway_fallback("alt.example.edu", "vpn.example.edu", attempt)
+            .await
+            .unwrap();
+        assert_eq!(gateway, "alt.example.edu");
+        assert_eq!(tried.lock().unwrap().len(), 1);
+
+        // Nothing to fall back to when the pick is the login gateway
+        tried.lock().unwrap().clear();
+        let err = with_gateway_fallback("broken.example.edu", "BROKEN.example.edu", attempt)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::GatewayUnavailable(503)));
+        assert_eq!(tried.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_post_form_retries_server_errors() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok",
+        ])
+        .await;
+
+        let body = post_form(&Client::new(), &url, &[("user", "test")], 1).await.unwrap();
+        assert_eq!(body, "ok");
+        assert_eq!(server.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_post_form_gives_up_and_skips_client_errors() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+        ])
+        .await;
+        let err = post_form(&Client::new(), &url, &[("user", "test")], 0).await.unwrap_err();
+        assert!(matches!(err, AuthError::GatewayUnavailable(502)));
+        assert_eq!(server.await.unwrap(), 1);
+
+        let (url, server) = serve(vec![
+            "HTTP/1.1 403 Forbidden\r\nConnection: close\r\nContent-Length: 6\r\n\r\ndenied",
+        ])
+        .await;
+        let body = post_form(&Client::new(), &url, &[("user", "test")], 3).await.unwrap();
+        assert_eq!(body, "denied");
+        assert_eq!(server.await.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_decode_compressed_body() {
+        use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+        use flate2::Compression;
+        use std::io::Write;
+
+        let xml = "<prelogin-response><status>Success</status></prelogin-response>";
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(xml.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(xml.as_bytes()).unwrap();
+        let zlib = zlib.finish().unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(xml.as_bytes()).unwrap();
+        let raw = raw.finish().unwrap();
+
+        assert_eq!(decode_body(xml.as_bytes(), None).unwrap(), xml);
+        assert_eq!(decode_body(&gzip, Some("gzip")).unwrap(), xml);
+        assert_eq!(decode_body(&zlib, Some("deflate")).unwrap(), xml);
+        assert_eq!(decode_body(&raw, Some("deflate")).unwrap(), xml);
+        // Compressed without saying so
+        assert_eq!(decode_body(&gzip, None).unwrap(), xml);
+
+        let prelogin: PreloginXml = parse_xml(&decode_body(&gzip, Some("gzip")).unwrap()).unwrap();
+        assert_eq!(prelogin.status, "Success");
+
+        assert!(matches!(decode_body(xml.as_bytes(), Some("gzip")), Err(AuthError::DecodeError(_))));
+        assert!(matches!(decode_body(&gzip, Some("br")), Err(AuthError::DecodeError(_))));
+    }
+
+    #[test]
+    fn test_html_error_page() {
+        let page = "<!DOCTYPE html>\n<html><head><title> 502 Bad Gateway </title></head><body>nginx</body></html>";
+        let err = parse_xml::<PreloginXml>(page).unwrap_err();
+        assert!(matches!(&err, AuthError::HtmlResponse(title) if title == "\"502 Bad Gateway\""));
+        assert!(err.to_string().contains("HTML page"));
+
+        assert_eq!(html_summary("<HTML><body>down</body></HTML>").as_deref(), Some("\"untitled\""));
+        assert!(matches!(parse_xml::<PreloginXml>("not xml <"), Err(AuthError::XmlError(_))));
+        assert!(matches!(
+            parse_jnlp_response(page, "user", "gw.example.edu"),
+            Err(AuthError::HtmlResponse(_))
+        ));
+    }
 }
@@ -0,0 +1,273 @@
+//! Debug bundle of gateway responses (`--debug-bundle`)
+//!
+//! When a connect fails against an unfamiliar gateway, the raw prelogin,
+//! login and getconfig responses say far more than any error message. With
+//! a bundle installed, each response is written to the bundle directory as
+//! it arrives, numbered in the order received, together with the parsed
+//! tunnel config, platform information and how long each host lookup took.
+//! `main` adds the connect log.
+//!
+//! Everything passes through `redact` before it touches the disk: cookies,
+//! passwords, MFA challenge tokens and ESP keys never reach the bundle.
+
+use crate::build_info::BuildInfo;
+use crate::gp::auth::TunnelConfig;
+use crate::vpn::routing::DnsTiming;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tracing::warn;
+
+/// Replaces every secret value written to a bundle
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Element names (any part) whose content is secret, e.g. `portal-userauthcookie`
+const SECRET_TAG_PARTS: &[&str] = &["cookie", "passwd", "password", "key", "secret", "token", "spi"];
+
+/// Shortest run of hex digits treated as a cookie or key wherever it appears
+const MIN_HEX_SECRET_LEN: usize = 32;
+
+static BUNDLE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Start writing gateway responses to `dir` for the rest of this process
+///
+/// Creates the directory and writes `platform.json`. Only the first call
+/// takes effect.
+pub fn install(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let platform = serde_json::json!({
+        "build": BuildInfo::current(),
+        "arch": std::env::consts::ARCH,
+        "os_family": std::env::consts::FAMILY,
+    });
+    fs::write(dir.join("platform.json"), serde_json::to_string_pretty(&platform)?)?;
+    let _ = BUNDLE.set(dir.to_path_buf());
+    Ok(())
+}
+
+/// The installed bundle directory, if any
+pub fn dir() -> Option<&'static Path> {
+    BUNDLE.get().map(PathBuf::as_path)
+}
+
+/// Write a gateway response (redacted) as the next numbered `<step>.xml`
+///
+/// Does nothing without a bundle. A write failure is logged, never returned:
+/// the bundle must not be what breaks the connect.
+pub fn record_response(step: &str, body: &str) {
+    let Some(dir) = dir() else {
+        return;
+    };
+    if let Err(e) = write_numbered(dir, step, &redact(body)) {
+        warn!("Debug bundle: failed to write {} response: {}", step, e);
+    }
+}
+
+/// Write the parsed tunnel config as `tunnel-config.json`
+pub fn record_tunnel_config(config: &TunnelConfig) {
+    let Some(dir) = dir() else {
+        return;
+    };
+    let result = serde_json::to_string_pretty(config)
+        .map_err(io::Error::other)
+        .and_then(|json| fs::write(dir.join("tunnel-config.json"), redact(&json)));
+    if let Err(e) = result {
+        warn!("Debug bundle: failed to write tunnel config: {}", e);
+    }
+}
+
+/// Write the time and answering server of each host lookup as `dns-timings.json`
+///
+/// Does nothing without a bundle or when there's nothing to write.
+pub fn record_dns_timings(timings: &[DnsTiming]) {
+    let Some(dir) = dir() else {
+        return;
+    };
+    if timings.is_empty() {
+        return;
+    }
+    let result = serde_json::to_string_pretty(timings)
+        .map_err(io::Error::other)
+        .and_then(|json| fs::write(dir.join("dns-timings.json"), json));
+    if let Err(e) = result {
+        warn!("Debug bundle: failed to write DNS timings: {}", e);
+    }
+}
+
+/// Create `NN-<step>.xml`, numbered after the responses already there
+///
+/// The background daemon writes into the same bundle as the process that
+/// logged in, so numbers come from the directory rather than a counter.
+fn write_numbered(dir: &Path, step: &str, contents: &str) -> io::Result<()> {
+    let mut n = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.split_once('-')?.0.parse::<u32>().ok()
+        })
+        .max()
+        .unwrap_or(0);
+    loop {
+        n += 1;
+        let path = dir.join(format!("{:02}-{}.xml", n, step));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => return file.write_all(contents.as_bytes()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Scrub secrets from a gateway response
+///
+/// Blanks the text of elements named like a secret (and of everything
+/// inside them, for ESP keys nested in `<val>`), JNLP arguments following a
+/// `(...cookie)` label, the MFA challenge token, and any long hex run.
+pub fn redact(body: &str) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut pos = 0;
+    let mut secret_element: Option<&str> = None;
+    let mut redact_next_argument = false;
+
+    while let Some(offset) = body[pos..].find('<') {
+        let tag_start = pos + offset;
+        out.push_str(&redact_text(&body[pos..tag_start], false));
+        let Some(len) = body[tag_start..].find('>') else {
+            pos = tag_start;
+            break;
+        };
+        let tag_end = tag_start + len + 1;
+        out.push_str(&body[tag_start..tag_end]);
+        pos = tag_end;
+
+        let tag = &body[tag_start + 1..tag_end - 1];
+        if let Some(closing) = tag.strip_prefix('/') {
+            if secret_element == Some(closing.trim()) {
+                secret_element = None;
+            }
+            continue;
+        }
+        if tag.starts_with(['?', '!']) || tag.ends_with('/') {
+            continue;
+        }
+        let name = tag.split_whitespace().next().unwrap_or("");
+        if secret_element.is_none() && is_secret_name(name) {
+            secret_element = Some(name);
+        }
+
+        let text_end = body[pos..].find('<').map_or(body.len(), |i| pos + i);
+        let text = &body[pos..text_end];
+        let secret = if name == "argument" {
+            let after_label = redact_next_argument;
+            let value = text.trim();
+            redact_next_argument = value.starts_with('(') && value.contains("cookie");
+            after_label
+        } else {
+            secret_element.is_some()
+        };
+        out.push_str(&redact_text(text, secret));
+        pos = text_end;
+    }
+    out.push_str(&redact_text(&body[pos..], false));
+    redact_challenge_token(&out)
+}
+
+fn is_secret_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SECRET_TAG_PARTS.iter().any(|part| name.contains(part))
+}
+
+/// Element text, blanked if `secret` or if it holds a long hex run
+fn redact_text(text: &str, secret: bool) -> String {
+    if text.trim().is_empty() {
+        return text.to_string();
+    }
+    if secret {
+        return REDACTED.to_string();
+    }
+    text.split_inclusive(|c: char| !c.is_ascii_hexdigit())
+        .map(|piece| {
+            let digits = piece.trim_end_matches(|c: char| !c.is_ascii_hexdigit());
+            if digits.len() >= MIN_HEX_SECRET_LEN {
+                format!("{}{}", REDACTED, &piece[digits.len()..])
+            } else {
+                piece.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Blank `inputStr.value = "..."` in an MFA challenge page
+fn redact_challenge_token(body: &str) -> String {
+    const MARKER: &str = "inputStr.value = \"";
+    let Some(start) = body.find(MARKER).map(|i| i + MARKER.len()) else {
+        return body.to_string();
+    };
+    let Some(len) = body[start..].find('"') else {
+        return body.to_string();
+    };
+    format!("{}{}{}", &body[..start], REDACTED, &body[start + len..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_jnlp_labeled_and_positional() {
+        let labeled = "<jnlp><application-desc>\
+            <argument>(auth-cookie)</argument><argument>not-hex-but-secret</argument>\
+            <argument>(portal)</argument><argument>portal.example.edu</argument>\
+            </application-desc></jnlp>";
+        let redacted = redact(labeled);
+        assert!(!redacted.contains("not-hex-but-secret"));
+        assert!(redacted.contains("<argument>portal.example.edu</argument>"));
+
+        let positional = "<jnlp><application-desc><argument></argument>\
+            <argument>ec85fe94925569dbaf7f38bfe736da90</argument>\
+            <argument>651e643201afcb354d58b58d9412f3a168db1fa4</argument>\
+            <argument>psom_admin_vpn_gateway-N</argument></application-desc></jnlp>";
+        let redacted = redact(positional);
+        assert!(!redacted.contains("ec85fe94"));
+        assert!(!redacted.contains("651e6432"));
+        assert!(redacted.contains("psom_admin_vpn_gateway-N"));
+    }
+
+    #[test]
+    fn test_redact_getconfig_keys() {
+        let policy = "<policy><ip-address>10.0.1.5</ip-address>\
+            <portal-userauthcookie>abc</portal-userauthcookie>\
+            <ipsec><c2s-spi>0x1234abcd</c2s-spi>\
+            <ekey-c2s><bits>128</bits><val>00112233</val></ekey-c2s>\
+            <enc-algo>aes-128-cbc</enc-algo></ipsec></policy>";
+        let redacted = redact(policy);
+        assert!(redacted.contains("<ip-address>10.0.1.5</ip-address>"));
+        assert!(redacted.contains("<portal-userauthcookie>[REDACTED]</portal-userauthcookie>"));
+        assert!(redacted.contains("<c2s-spi>[REDACTED]</c2s-spi>"));
+        assert!(redacted.contains("<val>[REDACTED]</val>"));
+        assert!(!redacted.contains("00112233"));
+        // Back out of the secret element
+        assert!(redacted.contains("<enc-algo>aes-128-cbc</enc-algo>"));
+    }
+
+    #[test]
+    fn test_redact_challenge_and_stray_hex() {
+        let page = "var respStatus = \"Challenge\";\nvar respMsg = \"Enter code\";\n\
+            thisForm.inputStr.value = \"5ef3a9\";\ncookie=0123456789abcdef0123456789abcdef;";
+        let redacted = redact(page);
+        assert!(redacted.contains("inputStr.value = \"[REDACTED]\""));
+        assert!(redacted.contains("cookie=[REDACTED];"));
+        assert!(redacted.contains("Enter code"));
+    }
+
+    #[test]
+    fn test_write_numbered_in_arrival_order() {
+        let dir = tempfile::tempdir().unwrap();
+        write_numbered(dir.path(), "login", "a").unwrap();
+        write_numbered(dir.path(), "getconfig", "b").unwrap();
+        write_numbered(dir.path(), "login", "c").unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("01-login.xml")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.path().join("02-getconfig.xml")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(dir.path().join("03-login.xml")).unwrap(), "c");
+    }
+}
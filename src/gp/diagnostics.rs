@@ -0,0 +1,239 @@
+//! Diagnostics for tunnel setup failures
+//!
+//! When the gateway doesn't answer the tunnel request with `START_TUNNEL`,
+//! the raw response is rarely useful to users. This classifies the common
+//! causes into an actionable message plus a suggested next step.
+
+use std::fmt;
+
+/// Longest excerpt of an unrecognized response kept for the error message
+const SNIPPET_MAX_LEN: usize = 120;
+
+/// Why the gateway didn't start the tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartFailure {
+    /// Gateway rejected the auth cookie (HTTP 401/403 or an explicit message)
+    ExpiredCookie { status: Option<u16> },
+    /// Gateway has no tunnel endpoint (HTTP 404) - likely the wrong host
+    WrongGateway,
+    /// Response came from a GlobalProtect portal rather than a gateway
+    PortalNotGateway,
+    /// TLS failed during the handshake or while reading the response
+    Tls(String),
+    /// Gateway closed the connection without answering
+    ConnectionClosed,
+    /// Anything else; keeps the status line (if any) and a short excerpt
+    Unexpected { status: Option<u16>, snippet: String },
+}
+
+impl StartFailure {
+    /// Classify the gateway's response to the tunnel request
+    pub fn from_response(response: &str) -> Self {
+        let status = http_status(response);
+        let lower = response.to_ascii_lowercase();
+
+        if matches!(status, Some(401 | 403))
+            || lower.contains("invalid authentication cookie")
+            || lower.contains("invalid cookie")
+        {
+            return Self::ExpiredCookie { status };
+        }
+
+        // Portals serve /global-protect/... pages; gateways serve /ssl-vpn/...
+        if lower.contains("/global-protect/") || lower.contains("globalprotect portal") {
+            return Self::PortalNotGateway;
+        }
+
+        if status == Some(404) {
+            return Self::WrongGateway;
+        }
+
+        Self::Unexpected {
+            status,
+            snippet: snippet(response),
+        }
+    }
+
+    /// Classify a TLS or I/O failure from the handshake or response read
+    pub fn from_tls_error(detail: &str) -> Self {
+        Self::Tls(detail.trim().to_string())
+    }
+
+    /// What went wrong, in user terms
+    pub fn message(&self) -> String {
+        match self {
+            Self::ExpiredCookie { status: Some(code) } => {
+                format!("The gateway rejected the login session (HTTP {})", code)
+            }
+            Self::ExpiredCookie { status: None } => {
+                "The gateway rejected the login session".to_string()
+            }
+            Self::WrongGateway => {
+                "The server has no VPN tunnel endpoint (HTTP 404)".to_string()
+            }
+            Self::PortalNotGateway => {
+                "The server answered as a GlobalProtect portal, not a gateway".to_string()
+            }
+            Self::Tls(detail) if is_certificate_error(detail) => {
+                format!("The gateway's certificate was not accepted: {}", detail)
+            }
+            Self::Tls(detail) => format!("TLS error talking to the gateway: {}", detail),
+            Self::ConnectionClosed => {
+                "The gateway closed the connection before starting the tunnel".to_string()
+            }
+            Self::Unexpected {
+                status: Some(code),
+                snippet,
+            } => format!("Unexpected gateway response (HTTP {}): {}", code, snippet),
+            Self::Unexpected {
+                status: None,
+                snippet,
+            } => format!("Unexpected gateway response: {}", snippet),
+        }
+    }
+
+    /// Suggested next step for the user
+    pub fn suggestion(&self) -> &'static str {
+        match self {
+            Self::ExpiredCookie { .. } => {
+                "Reconnect to log in again; if it keeps failing, re-run with --forget-password"
+            }
+            Self::WrongGateway => {
+                "Check that `gateway` in pmacs-vpn.toml is the VPN gateway host (e.g. psomvpn.uphs.upenn.edu)"
+            }
+            Self::PortalNotGateway => {
+                "Set `gateway` in pmacs-vpn.toml to the gateway address, not the portal"
+            }
+            Self::Tls(detail) if is_certificate_error(detail) => {
+                "Check the system clock, and whether a proxy or firewall is intercepting HTTPS"
+            }
+            Self::Tls(_) => "Check your network connection and any proxy or firewall, then retry",
+            Self::ConnectionClosed => {
+                "The session may have been revoked; reconnect, and if it repeats re-run with --forget-password"
+            }
+            Self::Unexpected { .. } => "Re-run with -v to see the full gateway response",
+        }
+    }
+}
+
+impl fmt::Display for StartFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}. Next step: {}", self.message(), self.suggestion())
+    }
+}
+
+/// Status code from an HTTP status line ("HTTP/1.1 401 Unauthorized")
+fn http_status(response: &str) -> Option<u16> {
+    let line = response.lines().next()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// First non-empty line of a response, shortened for display
+fn snippet(response: &str) -> String {
+    let line = response
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("(empty response)");
+    if line.chars().count() > SNIPPET_MAX_LEN {
+        let short: String = line.chars().take(SNIPPET_MAX_LEN).collect();
+        format!("{}...", short)
+    } else {
+        line.to_string()
+    }
+}
+
+fn is_certificate_error(detail: &str) -> bool {
+    let lower = detail.to_ascii_lowercase();
+    lower.contains("certificate") || lower.contains("unknownissuer") || lower.contains("expired")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_unauthorized() {
+        let response = "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n";
+        let failure = StartFailure::from_response(response);
+        assert_eq!(failure, StartFailure::ExpiredCookie { status: Some(401) });
+        assert!(failure.suggestion().contains("--forget-password"));
+    }
+
+    #[test]
+    fn test_classify_forbidden() {
+        let response = "HTTP/1.1 403 Forbidden\r\n\r\n";
+        assert_eq!(
+            StartFailure::from_response(response),
+            StartFailure::ExpiredCookie { status: Some(403) }
+        );
+    }
+
+    #[test]
+    fn test_classify_invalid_cookie_body() {
+        let response = "HTTP/1.1 200 OK\r\n\r\n<response>Invalid authentication cookie</response>";
+        assert_eq!(
+            StartFailure::from_response(response),
+            StartFailure::ExpiredCookie { status: Some(200) }
+        );
+    }
+
+    #[test]
+    fn test_classify_not_found() {
+        let response = "HTTP/1.1 404 Not Found\r\nServer: nginx\r\n\r\n<html>Not Found</html>";
+        let failure = StartFailure::from_response(response);
+        assert_eq!(failure, StartFailure::WrongGateway);
+        assert!(failure.suggestion().contains("gateway"));
+    }
+
+    #[test]
+    fn test_classify_portal() {
+        let response = "HTTP/1.1 302 Found\r\nLocation: /global-protect/login.esp\r\n\r\n";
+        assert_eq!(StartFailure::from_response(response), StartFailure::PortalNotGateway);
+
+        let response = "HTTP/1.1 404 Not Found\r\n\r\n<title>GlobalProtect Portal</title>";
+        assert_eq!(StartFailure::from_response(response), StartFailure::PortalNotGateway);
+    }
+
+    #[test]
+    fn test_classify_tls() {
+        let failure = StartFailure::from_tls_error("invalid peer certificate: UnknownIssuer");
+        assert!(failure.message().contains("certificate"));
+        assert!(failure.suggestion().contains("clock"));
+
+        let failure = StartFailure::from_tls_error("received fatal alert: HandshakeFailure");
+        assert!(failure.message().starts_with("TLS error"));
+    }
+
+    #[test]
+    fn test_classify_unexpected_keeps_short_snippet() {
+        let response = format!("HTTP/1.1 502 Bad Gateway\r\n\r\n{}", "x".repeat(500));
+        match StartFailure::from_response(&response) {
+            StartFailure::Unexpected { status, snippet } => {
+                assert_eq!(status, Some(502));
+                assert_eq!(snippet, "HTTP/1.1 502 Bad Gateway");
+            }
+            other => panic!("unexpected classification: {:?}", other),
+        }
+
+        let garbage = "y".repeat(500);
+        match StartFailure::from_response(&garbage) {
+            StartFailure::Unexpected { status, snippet } => {
+                assert_eq!(status, None);
+                assert!(snippet.len() <= SNIPPET_MAX_LEN + 3);
+            }
+            other => panic!("unexpected classification: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_display_includes_next_step() {
+        let text = StartFailure::ConnectionClosed.to_string();
+        assert!(text.contains("closed the connection"));
+        assert!(text.contains("Next step:"));
+    }
+}
@@ -0,0 +1,498 @@
+//! ESP-over-UDP data channel
+//!
+//! GlobalProtect can carry tunnel traffic as IPsec ESP (tunnel mode) in UDP
+//! datagrams instead of inside the TLS stream, which avoids TCP-over-TCP
+//! meltdown on lossy links. The gateway hands out the SPIs and keys in the
+//! `<ipsec>` section of the getconfig response; there is no IKE.
+//!
+//! Only the AES-GCM ciphers (RFC 4106) are implemented. When the gateway
+//! picks a CBC cipher, or UDP is blocked, the tunnel stays on SSL.
+//!
+//! ```text
+//! [spi:4][seq:4][iv:8][ciphertext: ip packet | padding | pad_len:1 | next_header:1][icv:16]
+//! ```
+//!
+//! Reference: OpenConnect esp.c / gpst.c
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use std::fmt;
+use std::net::Ipv4Addr;
+use thiserror::Error;
+
+/// ESP channel errors
+#[derive(Error, Debug, PartialEq)]
+pub enum EspError {
+    #[error("Invalid ESP key for {0}")]
+    InvalidKey(&'static str),
+
+    #[error("ESP packet too short ({0} bytes)")]
+    TooShort(usize),
+
+    #[error("Unexpected SPI {0:#010x}")]
+    WrongSpi(u32),
+
+    #[error("Replayed or stale sequence number {0}")]
+    Replay(u32),
+
+    #[error("ESP authentication failed")]
+    AuthFailed,
+
+    #[error("Invalid ESP trailer")]
+    BadTrailer,
+
+    #[error("Outbound sequence numbers exhausted")]
+    SequenceExhausted,
+}
+
+const SPI_LEN: usize = 4;
+const SEQ_LEN: usize = 4;
+const IV_LEN: usize = 8;
+const SALT_LEN: usize = 4;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = SPI_LEN + SEQ_LEN + IV_LEN;
+/// Ciphertext (payload plus trailer) is padded to a multiple of this
+const PAD_ALIGN: usize = 4;
+/// Width of the anti-replay window, in packets
+const REPLAY_WINDOW: u32 = 64;
+
+/// ESP next-header values for tunnel mode
+const NEXT_HEADER_IPV4: u8 = 4;
+const NEXT_HEADER_IPV6: u8 = 41;
+/// Dummy packet (RFC 4303 section 2.6); carries nothing
+const NEXT_HEADER_NONE: u8 = 59;
+
+/// ICMP identifier and payload GlobalProtect gateways answer on the ESP channel
+const PROBE_ICMP_ID: u16 = 0x4747;
+const PROBE_PAYLOAD: &[u8; 16] = b"monitor\x00\x00pan ha ";
+
+/// ESP cipher offered by the gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EspCipher {
+    Aes128Gcm,
+    Aes256Gcm,
+}
+
+impl EspCipher {
+    /// Parse the getconfig `enc-algo` value; `None` if unsupported
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "aes-128-gcm" => Some(Self::Aes128Gcm),
+            "aes-256-gcm" => Some(Self::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    /// Key length in bytes, including the 4-byte GCM salt
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes128Gcm => 16 + SALT_LEN,
+            Self::Aes256Gcm => 32 + SALT_LEN,
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Self::Aes128Gcm => &aead::AES_128_GCM,
+            Self::Aes256Gcm => &aead::AES_256_GCM,
+        }
+    }
+}
+
+/// ESP parameters from the getconfig `<ipsec>` section
+#[derive(Clone, PartialEq, Eq)]
+pub struct EspConfig {
+    pub cipher: EspCipher,
+    /// UDP port the gateway accepts ESP on
+    pub udp_port: u16,
+    /// Address the gateway answers probes from (`gw-address`)
+    pub probe_addr: Ipv4Addr,
+    /// SPI and key for client-to-server packets
+    pub c2s_spi: u32,
+    pub c2s_key: Vec<u8>,
+    /// SPI and key for server-to-client packets
+    pub s2c_spi: u32,
+    pub s2c_key: Vec<u8>,
+}
+
+// Keys stay out of debug logs
+impl fmt::Debug for EspConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspConfig")
+            .field("cipher", &self.cipher)
+            .field("udp_port", &self.udp_port)
+            .field("probe_addr", &self.probe_addr)
+            .field("c2s_spi", &format_args!("{:#010x}", self.c2s_spi))
+            .field("s2c_spi", &format_args!("{:#010x}", self.s2c_spi))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parse an SPI as sent by the gateway ("0x1a2b3c4d" or decimal)
+pub fn parse_spi(value: &str) -> Option<u32> {
+    let value = value.trim();
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Decode a hex key value, with or without a "0x" prefix
+pub fn parse_hex_key(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    let hex = value.strip_prefix("0x").unwrap_or(value);
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// One direction's AES-GCM key plus its nonce salt
+struct DirectionKey {
+    key: LessSafeKey,
+    salt: [u8; SALT_LEN],
+}
+
+impl DirectionKey {
+    fn new(cipher: EspCipher, material: &[u8], direction: &'static str) -> Result<Self, EspError> {
+        if material.len() != cipher.key_len() {
+            return Err(EspError::InvalidKey(direction));
+        }
+        let (key, salt) = material.split_at(material.len() - SALT_LEN);
+        let key = UnboundKey::new(cipher.algorithm(), key)
+            .map_err(|_| EspError::InvalidKey(direction))?;
+        Ok(Self {
+            key: LessSafeKey::new(key),
+            salt: salt.try_into().map_err(|_| EspError::InvalidKey(direction))?,
+        })
+    }
+
+    fn nonce(&self, iv: &[u8; IV_LEN]) -> Nonce {
+        let mut nonce = [0u8; SALT_LEN + IV_LEN];
+        nonce[..SALT_LEN].copy_from_slice(&self.salt);
+        nonce[SALT_LEN..].copy_from_slice(iv);
+        Nonce::assume_unique_for_key(nonce)
+    }
+}
+
+/// Sliding anti-replay window (RFC 4303 section 3.4.3)
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// Highest sequence number accepted so far
+    top: u32,
+    /// Bit N set = `top - N` already seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, seq: u32) -> bool {
+        if seq == 0 {
+            return false;
+        }
+        if seq > self.top {
+            return true;
+        }
+        let offset = self.top - seq;
+        offset < REPLAY_WINDOW && self.seen & (1 << offset) == 0
+    }
+
+    /// Record an authenticated sequence number
+    fn accept(&mut self, seq: u32) {
+        if seq > self.top {
+            let shift = seq - self.top;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.top = seq;
+        } else {
+            self.seen |= 1 << (self.top - seq);
+        }
+    }
+}
+
+/// Encrypts outbound and decrypts inbound ESP packets for one session
+pub struct EspSession {
+    c2s_spi: u32,
+    s2c_spi: u32,
+    outbound: DirectionKey,
+    inbound: DirectionKey,
+    /// Last sequence number sent (the first packet uses 1)
+    seq: u32,
+    replay: ReplayWindow,
+}
+
+impl EspSession {
+    pub fn new(config: &EspConfig) -> Result<Self, EspError> {
+        Ok(Self {
+            c2s_spi: config.c2s_spi,
+            s2c_spi: config.s2c_spi,
+            outbound: DirectionKey::new(config.cipher, &config.c2s_key, "client-to-server")?,
+            inbound: DirectionKey::new(config.cipher, &config.s2c_key, "server-to-client")?,
+            seq: 0,
+            replay: ReplayWindow::default(),
+        })
+    }
+
+    /// Wrap an IP packet in an ESP datagram
+    pub fn encrypt(&mut self, packet: &[u8]) -> Result<Vec<u8>, EspError> {
+        let next_header = match packet.first().map(|b| b >> 4) {
+            Some(6) => NEXT_HEADER_IPV6,
+            _ => NEXT_HEADER_IPV4,
+        };
+        self.seq = self.seq.checked_add(1).ok_or(EspError::SequenceExhausted)?;
+        let seq = self.seq;
+        // Sequence numbers never repeat under one key, so they make a unique IV
+        let iv = u64::from(seq).to_be_bytes();
+
+        let pad_len = (PAD_ALIGN - (packet.len() + 2) % PAD_ALIGN) % PAD_ALIGN;
+        let mut out = Vec::with_capacity(HEADER_LEN + packet.len() + pad_len + 2 + TAG_LEN);
+        out.extend_from_slice(&self.c2s_spi.to_be_bytes());
+        out.extend_from_slice(&seq.to_be_bytes());
+        out.extend_from_slice(&iv);
+        out.extend_from_slice(packet);
+        out.extend(1..=pad_len as u8);
+        out.push(pad_len as u8);
+        out.push(next_header);
+
+        let (header, body) = out.split_at_mut(HEADER_LEN);
+        let tag = self
+            .outbound
+            .key
+            .seal_in_place_separate_tag(
+                self.outbound.nonce(&iv),
+                Aad::from(&header[..SPI_LEN + SEQ_LEN]),
+                body,
+            )
+            .map_err(|_| EspError::AuthFailed)?;
+        out.extend_from_slice(tag.as_ref());
+        Ok(out)
+    }
+
+    /// Unwrap an ESP datagram into the inner IP packet
+    ///
+    /// Dummy packets decrypt to an empty payload.
+    pub fn decrypt(&mut self, datagram: &[u8]) -> Result<Vec<u8>, EspError> {
+        if datagram.len() < HEADER_LEN + 2 + TAG_LEN {
+            return Err(EspError::TooShort(datagram.len()));
+        }
+
+        let spi = u32::from_be_bytes(datagram[0..4].try_into().unwrap_or_default());
+        if spi != self.s2c_spi {
+            return Err(EspError::WrongSpi(spi));
+        }
+        let seq = u32::from_be_bytes(datagram[4..8].try_into().unwrap_or_default());
+        if !self.replay.check(seq) {
+            return Err(EspError::Replay(seq));
+        }
+        let iv: [u8; IV_LEN] = datagram[8..HEADER_LEN].try_into().unwrap_or_default();
+
+        let mut body = datagram[HEADER_LEN..].to_vec();
+        let plain_len = self
+            .inbound
+            .key
+            .open_in_place(
+                self.inbound.nonce(&iv),
+                Aad::from(&datagram[..SPI_LEN + SEQ_LEN]),
+                &mut body,
+            )
+            .map_err(|_| EspError::AuthFailed)?
+            .len();
+        self.replay.accept(seq);
+        body.truncate(plain_len);
+
+        let next_header = body.pop().ok_or(EspError::BadTrailer)?;
+        let pad_len = body.pop().ok_or(EspError::BadTrailer)? as usize;
+        if pad_len > body.len() {
+            return Err(EspError::BadTrailer);
+        }
+        body.truncate(body.len() - pad_len);
+
+        match next_header {
+            NEXT_HEADER_IPV4 | NEXT_HEADER_IPV6 => Ok(body),
+            NEXT_HEADER_NONE => Ok(Vec::new()),
+            _ => Err(EspError::BadTrailer),
+        }
+    }
+}
+
+/// ICMP echo request the gateway answers on a working ESP channel
+///
+/// Sent from the tunnel address to the gateway's `gw-address`; also used as
+/// the ESP keepalive.
+pub fn build_probe(src: Ipv4Addr, dst: Ipv4Addr, seq: u16) -> Vec<u8> {
+    const IP_HEADER_LEN: usize = 20;
+    const ICMP_HEADER_LEN: usize = 8;
+    let total_len = IP_HEADER_LEN + ICMP_HEADER_LEN + PROBE_PAYLOAD.len();
+
+    let mut packet = vec![0u8; total_len];
+    packet[0] = 0x45; // IPv4, 5-word header
+    packet[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    packet[4..6].copy_from_slice(&PROBE_ICMP_ID.to_be_bytes());
+    packet[8] = 64; // TTL
+    packet[9] = 1; // ICMP
+    packet[12..16].copy_from_slice(&src.octets());
+    packet[16..20].copy_from_slice(&dst.octets());
+    let ip_sum = internet_checksum(&packet[..IP_HEADER_LEN]);
+    packet[10..12].copy_from_slice(&ip_sum.to_be_bytes());
+
+    let icmp = &mut packet[IP_HEADER_LEN..];
+    icmp[0] = 8; // echo request
+    icmp[4..6].copy_from_slice(&PROBE_ICMP_ID.to_be_bytes());
+    icmp[6..8].copy_from_slice(&seq.to_be_bytes());
+    icmp[ICMP_HEADER_LEN..].copy_from_slice(PROBE_PAYLOAD);
+    let icmp_sum = internet_checksum(icmp);
+    icmp[2..4].copy_from_slice(&icmp_sum.to_be_bytes());
+
+    packet
+}
+
+/// Whether a decrypted packet is the gateway's reply to `build_probe`
+pub fn is_probe_reply(packet: &[u8], probe_addr: Ipv4Addr) -> bool {
+    packet.len() >= 28
+        && packet[0] >> 4 == 4
+        && packet[9] == 1
+        && packet[12..16] == probe_addr.octets()
+        && packet[20] == 0 // echo reply
+        && packet[24..26] == PROBE_ICMP_ID.to_be_bytes()
+}
+
+/// RFC 1071 ones' complement checksum
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(cipher: EspCipher) -> EspConfig {
+        // Same key both ways so a session can decrypt its own output
+        let key: Vec<u8> = (0..cipher.key_len() as u8).collect();
+        EspConfig {
+            cipher,
+            udp_port: 4501,
+            probe_addr: Ipv4Addr::new(10, 0, 0, 1),
+            c2s_spi: 0x1a2b_3c4d,
+            c2s_key: key.clone(),
+            s2c_spi: 0x1a2b_3c4d,
+            s2c_key: key,
+        }
+    }
+
+    #[test]
+    fn test_parse_gateway_values() {
+        assert_eq!(EspCipher::from_name("aes-128-gcm"), Some(EspCipher::Aes128Gcm));
+        assert_eq!(EspCipher::from_name("aes-256-gcm"), Some(EspCipher::Aes256Gcm));
+        assert_eq!(EspCipher::from_name("aes-128-cbc"), None);
+
+        assert_eq!(parse_spi("0x1A2B3C4D"), Some(0x1a2b_3c4d));
+        assert_eq!(parse_spi("42"), Some(42));
+        assert_eq!(parse_spi("0xnothex"), None);
+
+        assert_eq!(parse_hex_key("0x00ff10"), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(parse_hex_key("abc"), None);
+        assert_eq!(parse_hex_key(""), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for cipher in [EspCipher::Aes128Gcm, EspCipher::Aes256Gcm] {
+            let mut session = EspSession::new(&test_config(cipher)).unwrap();
+            for len in [20, 21, 22, 23, 1400] {
+                let mut packet = vec![0xabu8; len];
+                packet[0] = 0x45;
+                let datagram = session.encrypt(&packet).unwrap();
+                assert_eq!((datagram.len() - HEADER_LEN - TAG_LEN) % PAD_ALIGN, 0);
+                assert_eq!(session.decrypt(&datagram).unwrap(), packet);
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_and_next_header() {
+        let mut session = EspSession::new(&test_config(EspCipher::Aes128Gcm)).unwrap();
+        let first = session.encrypt(&[0x45; 20]).unwrap();
+        let second = session.encrypt(&[0x60; 40]).unwrap();
+
+        assert_eq!(&first[0..4], &0x1a2b_3c4du32.to_be_bytes());
+        assert_eq!(&first[4..8], &1u32.to_be_bytes());
+        assert_eq!(&second[4..8], &2u32.to_be_bytes());
+        assert_ne!(&first[8..16], &second[8..16]);
+        assert_eq!(session.decrypt(&second).unwrap(), vec![0x60; 40]);
+    }
+
+    #[test]
+    fn test_rejects_tampering_and_wrong_spi() {
+        let mut session = EspSession::new(&test_config(EspCipher::Aes128Gcm)).unwrap();
+        let mut datagram = session.encrypt(&[0x45; 20]).unwrap();
+        datagram[HEADER_LEN] ^= 1;
+        assert_eq!(session.decrypt(&datagram), Err(EspError::AuthFailed));
+
+        let mut datagram = session.encrypt(&[0x45; 20]).unwrap();
+        datagram[0] ^= 1;
+        assert!(matches!(session.decrypt(&datagram), Err(EspError::WrongSpi(_))));
+
+        assert_eq!(session.decrypt(&[0u8; 10]), Err(EspError::TooShort(10)));
+    }
+
+    #[test]
+    fn test_rejects_replay() {
+        let mut session = EspSession::new(&test_config(EspCipher::Aes128Gcm)).unwrap();
+        let first = session.encrypt(&[0x45; 20]).unwrap();
+        let second = session.encrypt(&[0x45; 20]).unwrap();
+
+        // Out of order is fine once; a repeat is not
+        assert!(session.decrypt(&second).is_ok());
+        assert!(session.decrypt(&first).is_ok());
+        assert_eq!(session.decrypt(&first), Err(EspError::Replay(1)));
+        assert_eq!(session.decrypt(&second), Err(EspError::Replay(2)));
+    }
+
+    #[test]
+    fn test_replay_window_slides() {
+        let mut window = ReplayWindow::default();
+        assert!(!window.check(0));
+        window.accept(1);
+        window.accept(100);
+        assert!(!window.check(1)); // fell out of the window
+        assert!(window.check(99));
+        assert!(!window.check(100));
+        assert!(window.check(101));
+    }
+
+    #[test]
+    fn test_rejects_bad_key_length() {
+        let mut config = test_config(EspCipher::Aes256Gcm);
+        config.c2s_key.truncate(20);
+        assert!(matches!(EspSession::new(&config), Err(EspError::InvalidKey(_))));
+    }
+
+    #[test]
+    fn test_probe_packet() {
+        let src = Ipv4Addr::new(10, 1, 2, 3);
+        let gateway = Ipv4Addr::new(10, 0, 0, 1);
+        let probe = build_probe(src, gateway, 1);
+
+        assert_eq!(probe.len(), 44);
+        assert_eq!(internet_checksum(&probe[..20]), 0);
+        assert_eq!(internet_checksum(&probe[20..]), 0);
+        assert_eq!(&probe[16..20], &gateway.octets());
+        assert!(!is_probe_reply(&probe, gateway)); // a request, not a reply
+
+        // Reply: addresses swapped, ICMP type 0
+        let mut reply = probe.clone();
+        reply[12..16].copy_from_slice(&gateway.octets());
+        reply[16..20].copy_from_slice(&src.octets());
+        reply[20] = 0;
+        assert!(is_probe_reply(&reply, gateway));
+        assert!(!is_probe_reply(&reply, Ipv4Addr::new(10, 0, 0, 2)));
+    }
+}
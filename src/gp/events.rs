@@ -0,0 +1,84 @@
+//! Connection progress events
+//!
+//! Connecting takes several network round trips (plus an MFA approval), so
+//! callers such as the tray or a CLI progress display can pass an optional
+//! channel sender and receive a `ConnectEvent` as each step starts or ends.
+
+use tokio::sync::mpsc;
+
+/// A step in establishing the VPN connection, in the order they occur
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectEvent {
+    /// Querying the gateway's auth method
+    Prelogin,
+    /// Sending credentials
+    LoggingIn,
+    /// Gateway issued an MFA challenge; waiting for the user to approve
+    AwaitingMfa,
+    /// Received tunnel configuration (IP, DNS, MTU)
+    GotConfig,
+    /// SSL tunnel established
+    TunnelUp,
+    /// Routing configured hosts; `done` of `total` finished so far
+    AddingRoutes { done: usize, total: usize },
+    /// Routes and hosts entries are in place; the VPN is usable
+    Ready,
+}
+
+impl ConnectEvent {
+    /// Route progress as "done/total (percent)", for `AddingRoutes`
+    pub fn route_progress(&self) -> Option<String> {
+        match *self {
+            ConnectEvent::AddingRoutes { done, total } if total > 0 => {
+                Some(format!("{}/{} ({}%)", done, total, done * 100 / total))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Send an event if the caller asked for them
+///
+/// A closed receiver is ignored: progress reporting never fails the connect.
+pub async fn emit(events: Option<&mpsc::Sender<ConnectEvent>>, event: ConnectEvent) {
+    if let Some(tx) = events {
+        let _ = tx.send(event).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_emit_delivers_in_order() {
+        let (tx, mut rx) = mpsc::channel(8);
+        emit(Some(&tx), ConnectEvent::Prelogin).await;
+        emit(Some(&tx), ConnectEvent::AddingRoutes { done: 1, total: 3 }).await;
+
+        assert_eq!(rx.recv().await, Some(ConnectEvent::Prelogin));
+        assert_eq!(
+            rx.recv().await,
+            Some(ConnectEvent::AddingRoutes { done: 1, total: 3 })
+        );
+    }
+
+    #[test]
+    fn test_route_progress() {
+        let progress = |done, total| ConnectEvent::AddingRoutes { done, total }.route_progress();
+        assert_eq!(progress(0, 40).as_deref(), Some("0/40 (0%)"));
+        assert_eq!(progress(13, 40).as_deref(), Some("13/40 (32%)"));
+        assert_eq!(progress(40, 40).as_deref(), Some("40/40 (100%)"));
+        assert_eq!(progress(0, 0), None);
+        assert_eq!(ConnectEvent::Ready.route_progress(), None);
+    }
+
+    #[tokio::test]
+    async fn test_emit_without_listener() {
+        emit(None, ConnectEvent::Ready).await;
+
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        emit(Some(&tx), ConnectEvent::Ready).await;
+    }
+}
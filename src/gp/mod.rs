@@ -3,11 +3,20 @@
 /// This module provides native GlobalProtect VPN client functionality,
 /// eliminating the need for OpenConnect as a dependency.
 pub mod auth;
+pub mod debug_bundle;
+pub mod diagnostics;
+pub mod esp;
+pub mod events;
 pub mod packet;
+pub mod pcap;
 pub mod tun;
 pub mod tunnel;
 
-pub use auth::{AuthError, LoginResponse, PreloginResponse, TunnelConfig};
+pub use auth::{AuthError, ClientIdentity, LoginResponse, PreloginResponse, TunnelConfig};
+pub use diagnostics::StartFailure;
+pub use esp::{EspConfig, EspError};
+pub use events::ConnectEvent;
 pub use packet::{FrameError, GpPacket};
+pub use pcap::PcapWriter;
 pub use tun::{TunDevice, TunError};
-pub use tunnel::{SslTunnel, TunnelError};
+pub use tunnel::{SslTunnel, TunnelError, TunnelOptions};
@@ -10,6 +10,10 @@
 //! - Length: payload size in bytes, big-endian (0 = keepalive)
 //! - Type: 0x01000000 00000000 for data, 0x00000000 00000000 for keepalive
 //!
+//! Payloads are always raw IP packets. Unlike AnyConnect's CSTP channel,
+//! the GlobalProtect tunnel request has no compression negotiation and
+//! gateways neither advertise nor accept compressed frames.
+//!
 //! Reference: OpenConnect gpst.c
 
 use thiserror::Error;
@@ -28,6 +32,9 @@ pub enum FrameError {
 
     #[error("Packet length mismatch: expected {expected}, got {actual}")]
     LengthMismatch { expected: usize, actual: usize },
+
+    #[error("Frame length {len} exceeds the maximum of {max}")]
+    Oversized { len: usize, max: usize },
 }
 
 const MAGIC: [u8; 4] = [0x1a, 0x2b, 0x3c, 0x4d];
@@ -35,6 +42,9 @@ const HEADER_SIZE: usize = 16;
 const ETHERTYPE_IPV4: u16 = 0x0800;
 const ETHERTYPE_IPV6: u16 = 0x86dd;
 
+/// Extra bytes an inbound frame may carry beyond the tunnel MTU
+pub const FRAME_LEN_SLACK: usize = 256;
+
 /// A GlobalProtect packet
 #[derive(Debug, Clone, PartialEq)]
 pub struct GpPacket {
@@ -158,6 +168,62 @@ impl GpPacket {
     }
 }
 
+/// Payload length from a frame header, checked before reading the payload
+///
+/// The length field can claim up to 65535 bytes. A corrupt or hostile header
+/// must not make the tunnel allocate that much and then wait for bytes that
+/// never arrive, so anything over `max_len` is a framing error.
+pub fn payload_len(header: &[u8], max_len: usize) -> Result<usize, FrameError> {
+    if header.len() < HEADER_SIZE {
+        return Err(FrameError::TooShort(HEADER_SIZE));
+    }
+    if header[0..4] != MAGIC {
+        return Err(FrameError::BadMagic);
+    }
+    let len = u16::from_be_bytes([header[6], header[7]]) as usize;
+    if len > max_len {
+        return Err(FrameError::Oversized { len, max: max_len });
+    }
+    Ok(len)
+}
+
+/// One-line summary of an IP packet for tracing: "src -> dst PROTO len=N"
+///
+/// Only the IP header is parsed; malformed or truncated packets are
+/// summarized by their length.
+pub fn summarize_ip_packet(data: &[u8]) -> String {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let Some(&first) = data.first() else {
+        return "empty packet".to_string();
+    };
+
+    match first >> 4 {
+        4 if data.len() >= 20 => {
+            let src = Ipv4Addr::new(data[12], data[13], data[14], data[15]);
+            let dst = Ipv4Addr::new(data[16], data[17], data[18], data[19]);
+            format!("{} -> {} {} len={}", src, dst, protocol_name(data[9]), data.len())
+        }
+        6 if data.len() >= 40 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&data[8..24]).unwrap_or_default());
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&data[24..40]).unwrap_or_default());
+            format!("{} -> {} {} len={}", src, dst, protocol_name(data[6]), data.len())
+        }
+        version => format!("non-IP packet (version {}) len={}", version, data.len()),
+    }
+}
+
+/// Name of an IP protocol number (IPv4 protocol / IPv6 next header)
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        1 => "ICMP".to_string(),
+        6 => "TCP".to_string(),
+        17 => "UDP".to_string(),
+        58 => "ICMPv6".to_string(),
+        other => format!("proto={}", other),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -266,4 +332,59 @@ mod tests {
         let result = GpPacket::decode(&frame);
         assert!(matches!(result, Err(FrameError::LengthMismatch { .. })));
     }
+
+    #[test]
+    fn test_payload_len_rejects_oversized() {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[6..8].copy_from_slice(&1400u16.to_be_bytes());
+        assert_eq!(payload_len(&header, 1400 + FRAME_LEN_SLACK).unwrap(), 1400);
+
+        header[6..8].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(matches!(
+            payload_len(&header, 1400 + FRAME_LEN_SLACK),
+            Err(FrameError::Oversized { len: 65535, max: 1656 })
+        ));
+
+        header[0] = 0xff;
+        assert!(matches!(payload_len(&header, usize::MAX), Err(FrameError::BadMagic)));
+    }
+
+    #[test]
+    fn test_summarize_ipv4_packet() {
+        let mut packet = vec![0u8; 60];
+        packet[0] = 0x45;
+        packet[9] = 6; // TCP
+        packet[12..16].copy_from_slice(&[10, 0, 0, 5]);
+        packet[16..20].copy_from_slice(&[172, 16, 38, 40]);
+
+        assert_eq!(
+            summarize_ip_packet(&packet),
+            "10.0.0.5 -> 172.16.38.40 TCP len=60"
+        );
+    }
+
+    #[test]
+    fn test_summarize_ipv6_packet() {
+        let mut packet = vec![0u8; 48];
+        packet[0] = 0x60;
+        packet[6] = 17; // UDP
+        packet[23] = 1; // src ::1
+        packet[24] = 0xfe;
+        packet[25] = 0x80;
+        packet[39] = 2; // dst fe80::2
+
+        assert_eq!(summarize_ip_packet(&packet), "::1 -> fe80::2 UDP len=48");
+    }
+
+    #[test]
+    fn test_summarize_malformed_packets() {
+        assert_eq!(summarize_ip_packet(&[]), "empty packet");
+        assert_eq!(summarize_ip_packet(&[0x45, 0, 0]), "non-IP packet (version 4) len=3");
+
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[9] = 47;
+        assert!(summarize_ip_packet(&packet).contains("proto=47"));
+    }
 }
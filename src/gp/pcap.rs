@@ -0,0 +1,133 @@
+//! Minimal pcap writer for capturing tunnel traffic
+//!
+//! Writes the classic libpcap format (not pcapng) with link type
+//! `LINKTYPE_RAW`, so each record is a bare IPv4/IPv6 packet exactly as it
+//! crosses the tunnel. The result opens directly in Wireshark or tcpdump.
+//!
+//! ```text
+//! global header: [magic:4][major:2][minor:2][thiszone:4][sigfigs:4][snaplen:4][linktype:4]
+//! record header: [ts_sec:4][ts_usec:4][incl_len:4][orig_len:4][data:incl_len]
+//! ```
+//!
+//! All fields are written little-endian; readers detect byte order from the magic.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4; // microsecond timestamps
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_RAW: u32 = 101;
+
+/// Shared handle to a pcap capture file
+///
+/// Cloning is cheap and every clone writes to the same file, so the tunnel
+/// can record packets while the caller keeps a handle to flush on disconnect.
+/// Writes are buffered; buffered records are also flushed when the last
+/// handle is dropped.
+#[derive(Clone)]
+pub struct PcapWriter {
+    inner: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl PcapWriter {
+    /// Create (or truncate) a capture file and write the pcap header
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_header(&mut writer)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Append one raw IP packet, timestamped now
+    pub fn write_packet(&self, data: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut writer = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        write_record(&mut *writer, timestamp, data)
+    }
+
+    /// Flush buffered records to disk
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+    writer.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+    writer.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+    writer.write_all(&0i32.to_le_bytes())?; // thiszone: timestamps are UTC
+    writer.write_all(&0u32.to_le_bytes())?; // sigfigs
+    writer.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+    writer.write_all(&LINKTYPE_RAW.to_le_bytes())
+}
+
+fn write_record<W: Write>(writer: &mut W, timestamp: Duration, data: &[u8]) -> io::Result<()> {
+    let orig_len = data.len() as u32;
+    let incl_len = orig_len.min(PCAP_SNAPLEN);
+    writer.write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+    writer.write_all(&timestamp.subsec_micros().to_le_bytes())?;
+    writer.write_all(&incl_len.to_le_bytes())?;
+    writer.write_all(&orig_len.to_le_bytes())?;
+    writer.write_all(&data[..incl_len as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let mut out = Vec::new();
+        write_header(&mut out).unwrap();
+
+        assert_eq!(out.len(), 24);
+        assert_eq!(&out[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(u16::from_le_bytes([out[4], out[5]]), 2);
+        assert_eq!(u16::from_le_bytes([out[6], out[7]]), 4);
+        assert_eq!(u32_at(&out, 16), 65535);
+        assert_eq!(u32_at(&out, 20), LINKTYPE_RAW);
+    }
+
+    #[test]
+    fn test_record_layout() {
+        let mut out = Vec::new();
+        let packet = [0x45, 0x00, 0x00, 0x14];
+        write_record(&mut out, Duration::new(1_700_000_000, 123_456_000), &packet).unwrap();
+
+        assert_eq!(out.len(), 16 + packet.len());
+        assert_eq!(u32_at(&out, 0), 1_700_000_000);
+        assert_eq!(u32_at(&out, 4), 123_456);
+        assert_eq!(u32_at(&out, 8), 4);
+        assert_eq!(u32_at(&out, 12), 4);
+        assert_eq!(&out[16..], &packet);
+    }
+
+    #[test]
+    fn test_capture_file_flushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tunnel.pcap");
+
+        let capture = PcapWriter::create(&path).unwrap();
+        let handle = capture.clone();
+        handle.write_packet(&[0x45; 20]).unwrap();
+        handle.write_packet(&[0x60; 40]).unwrap();
+        capture.flush().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 24 + (16 + 20) + (16 + 40));
+        assert_eq!(u32_at(&bytes, 24 + 8), 20);
+        assert_eq!(u32_at(&bytes, 24 + 16 + 20 + 8), 40);
+    }
+}
@@ -3,17 +3,31 @@
 //! Provides a simplified async interface to TUN devices on Mac, Linux, and Windows.
 
 use crate::gp::auth::TunnelConfig;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use tun::AbstractDevice;
 
+/// Attempts at creating the device when it reports busy
+const CREATE_ATTEMPTS: u32 = 3;
+
+/// Pause between attempts, giving a torn-down device time to go away
+const CREATE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// First utun unit asked for explicitly on macOS after a busy failure
+/// (low units are usually held by system services)
+const MACOS_RETRY_UNIT_BASE: u32 = 10;
+
 /// TUN device errors
 #[derive(Error, Debug)]
 pub enum TunError {
     #[error("TUN device creation failed: {0}")]
     CreationFailed(String),
 
+    #[error("TUN device creation not permitted (run as root/Administrator): {0}")]
+    PermissionDenied(String),
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -53,7 +67,50 @@ impl TunDevice {
         #[cfg(windows)]
         ensure_wintun_dll()?;
 
+        let mut attempt = 1;
+        loop {
+            let name = retry_device_name(attempt);
+            debug!(
+                "TUN creation attempt {}/{} ({})",
+                attempt,
+                CREATE_ATTEMPTS,
+                name.as_deref().unwrap_or("any unit")
+            );
+            let detail = match Self::create_once(config, name.as_deref()) {
+                Ok(device) => return Ok(device),
+                Err(detail) => detail,
+            };
+
+            match classify_create_error(&detail) {
+                CreateFailure::PermissionDenied => return Err(TunError::PermissionDenied(detail)),
+                CreateFailure::Busy if attempt < CREATE_ATTEMPTS => {
+                    warn!(
+                        "TUN device busy (attempt {}/{}): {}; retrying in {}ms",
+                        attempt,
+                        CREATE_ATTEMPTS,
+                        detail,
+                        CREATE_RETRY_DELAY.as_millis()
+                    );
+                    tokio::time::sleep(CREATE_RETRY_DELAY).await;
+                    attempt += 1;
+                }
+                CreateFailure::Busy => {
+                    return Err(TunError::CreationFailed(format!(
+                        "device still busy after {} attempts: {}",
+                        CREATE_ATTEMPTS, detail
+                    )));
+                }
+                CreateFailure::Other => return Err(TunError::CreationFailed(detail)),
+            }
+        }
+    }
+
+    /// One creation attempt, optionally asking for a specific device name
+    fn create_once(config: &TunnelConfig, name: Option<&str>) -> Result<Self, String> {
         let mut tun_config = tun::Configuration::default();
+        if let Some(name) = name {
+            tun_config.tun_name(name);
+        }
 
         // Set IP address
         tun_config
@@ -73,12 +130,9 @@ impl TunDevice {
             .up();
 
         // Create async device directly (tun 0.8 API)
-        let device = tun::create_as_async(&tun_config)
-            .map_err(|e| TunError::CreationFailed(e.to_string()))?;
+        let device = tun::create_as_async(&tun_config).map_err(|e| e.to_string())?;
 
-        let name = device
-            .tun_name()
-            .map_err(|e| TunError::CreationFailed(e.to_string()))?;
+        let name = device.tun_name().map_err(|e| e.to_string())?;
 
         info!("TUN device created: {}", name);
 
@@ -138,6 +192,45 @@ impl TunDevice {
     }
 }
 
+/// How a TUN creation attempt failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CreateFailure {
+    /// Device (or utun unit) still held, e.g. by the previous session; retry
+    Busy,
+    /// Not running with enough privilege; retrying won't help
+    PermissionDenied,
+    /// Anything else; reported as is
+    Other,
+}
+
+/// Classify a creation error from its message (the tun crate's errors wrap
+/// OS errors differently per platform)
+fn classify_create_error(detail: &str) -> CreateFailure {
+    let lower = detail.to_ascii_lowercase();
+    if lower.contains("permission denied")
+        || lower.contains("operation not permitted")
+        || lower.contains("access is denied")
+    {
+        CreateFailure::PermissionDenied
+    } else if lower.contains("busy") || lower.contains("in use") {
+        CreateFailure::Busy
+    } else {
+        CreateFailure::Other
+    }
+}
+
+/// Device name to ask for on `attempt` (1-based); `None` lets the OS pick
+///
+/// On macOS a retry asks for explicit utun units in case the one the kernel
+/// handed out is the busy one; elsewhere every attempt lets the OS pick.
+fn retry_device_name(attempt: u32) -> Option<String> {
+    if cfg!(target_os = "macos") && attempt > 1 {
+        Some(format!("utun{}", MACOS_RETRY_UNIT_BASE + attempt - 2))
+    } else {
+        None
+    }
+}
+
 /// Embedded wintun.dll for Windows (from wintun.net, see assets/wintun-LICENSE.txt)
 #[cfg(windows)]
 static WINTUN_DLL: &[u8] = include_bytes!("../../assets/wintun.dll");
@@ -180,6 +273,38 @@ mod tests {
         assert!(err.to_string().contains("2000"));
     }
 
+    #[test]
+    fn test_classify_create_errors() {
+        assert_eq!(
+            classify_create_error("Resource busy (os error 16)"),
+            CreateFailure::Busy
+        );
+        assert_eq!(
+            classify_create_error("Device or resource busy (os error 16)"),
+            CreateFailure::Busy
+        );
+        assert_eq!(
+            classify_create_error("Operation not permitted (os error 1)"),
+            CreateFailure::PermissionDenied
+        );
+        assert_eq!(
+            classify_create_error("Permission denied (os error 13)"),
+            CreateFailure::PermissionDenied
+        );
+        assert_eq!(classify_create_error("No such file or directory"), CreateFailure::Other);
+    }
+
+    #[test]
+    fn test_retry_device_name() {
+        assert_eq!(retry_device_name(1), None);
+        if cfg!(target_os = "macos") {
+            assert_eq!(retry_device_name(2).as_deref(), Some("utun10"));
+            assert_eq!(retry_device_name(3).as_deref(), Some("utun11"));
+        } else {
+            assert_eq!(retry_device_name(2), None);
+        }
+    }
+
     // Note: Actual TUN device creation tests require root/admin privileges
     // and are skipped in CI. Manual testing required.
 
@@ -193,7 +318,14 @@ mod tests {
             internal_ip: "10.0.1.100".parse::<IpAddr>().unwrap(),
             internal_ip6: None,
             dns_servers: vec![],
-            timeout_seconds: 3600,
+            timeout_seconds: Some(3600),
+            netmask: None,
+            split_include: vec![],
+            include_routes: vec![],
+            exclude_routes: vec![],
+            dns_suffixes: vec![],
+            gateway_host: None,
+            esp: None,
         };
 
         let result = TunDevice::create(&config).await;
@@ -3,19 +3,24 @@
 //! Establishes an SSL tunnel to the gateway and handles bidirectional packet I/O
 //! between the TUN device and the gateway.
 
-use crate::gp::auth::TunnelConfig;
-use crate::gp::packet::GpPacket;
+use crate::gp::auth::{ClientIdentity, TunnelConfig};
+use crate::gp::diagnostics::StartFailure;
+use crate::gp::esp::{self, EspConfig, EspError, EspSession};
+use crate::gp::packet::{self, summarize_ip_packet, GpPacket, FRAME_LEN_SLACK};
+use crate::gp::pcap::PcapWriter;
 use crate::gp::tun::TunDevice;
+use ring::rand::{SecureRandom, SystemRandom};
 use rustls::RootCertStore;
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::time::interval;
 use tokio_rustls::TlsConnector;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, trace, warn};
 
 /// Tunnel errors
 #[derive(Error, Debug)]
@@ -29,37 +34,333 @@ pub enum TunnelError {
     #[error("TUN device error: {0}")]
     TunError(#[from] crate::gp::tun::TunError),
 
+    /// The OS removed the TUN device (sleep/wake, driver reload). The
+    /// session may still be good; a reconnect creates a new device.
+    #[error("TUN device {0} disappeared")]
+    TunGone(String),
+
     #[error("Packet framing error: {0}")]
     FrameError(#[from] crate::gp::packet::FrameError),
 
     #[error("Tunnel setup failed: {0}")]
     SetupFailed(String),
 
-    #[error("Tunnel disconnected")]
-    Disconnected,
+    /// Gateway didn't start the tunnel; classified for the user
+    #[error("Tunnel setup failed: {0}")]
+    StartFailed(StartFailure),
+
+    /// Gateway closed the connection right after a control frame: an
+    /// intentional logout. The auth cookie is gone, so log in again.
+    #[error("Gateway ended the session (logged out); connect again to log in")]
+    GatewayLogout,
+
+    /// Connection dropped mid-session (network glitch); safe to reconnect
+    #[error("Connection to gateway lost: {0}")]
+    ConnectionLost(String),
 
     #[error("Connection timeout (no data received)")]
     Timeout,
 
     #[error("Session expired")]
     SessionExpired,
+
+    #[error("ESP error: {0}")]
+    EspError(#[from] EspError),
+}
+
+impl TunnelError {
+    /// Whether a dropped tunnel should be re-established automatically
+    ///
+    /// Network failures are worth retrying, as are framing errors (the
+    /// stream can't be resynchronized, but the session is still good) and a
+    /// vanished TUN device (common after sleep); a
+    /// gateway logout or expired session needs a fresh login instead, so
+    /// reconnecting would only trigger repeated MFA prompts.
+    pub fn should_reconnect(&self) -> bool {
+        matches!(
+            self,
+            Self::ConnectionLost(_)
+                | Self::Timeout
+                | Self::IoError(_)
+                | Self::FrameError(_)
+                | Self::TunGone(_)
+        )
+    }
 }
 
 const KEEPALIVE_INTERVAL_SECS: u64 = 30;
 const AGGRESSIVE_KEEPALIVE_SECS: u64 = 10;
 const DEFAULT_INBOUND_TIMEOUT_SECS: u64 = 45; // Faster dead tunnel detection (was 90s)
+
+/// Default random spread of each keepalive interval, in percent either way
+pub const DEFAULT_KEEPALIVE_JITTER_PERCENT: u32 = 10;
+
+/// Largest keepalive jitter accepted; more is capped to this
+pub const MAX_KEEPALIVE_JITTER_PERCENT: u32 = 50;
+
+/// Port the SSL tunnel connects to unless told otherwise
+pub const DEFAULT_TUNNEL_PORT: u16 = 443;
 const SESSION_LIFETIME_SECS: u64 = 16 * 60 * 60; // 16 hours
 const SESSION_WARNING_SECS: u64 = 15 * 60 * 60;  // Warn at 15 hours
+/// An EOF this soon after a zero-length control frame is a gateway logout
+const LOGOUT_CLOSE_WINDOW: Duration = Duration::from_secs(2);
+const ESP_PROBE_ATTEMPTS: u16 = 3;
+const ESP_PROBE_WAIT: Duration = Duration::from_secs(1);
 
 /// SSL tunnel connection to GlobalProtect gateway
 pub struct SslTunnel {
     stream: tokio_rustls::client::TlsStream<TcpStream>,
     tun: TunDevice,
-    keepalive_interval: Duration,
+    /// Negotiated TLS version and cipher suite (see `tls_summary`)
+    tls: String,
+    keepalive: KeepaliveSchedule,
+    /// Whether keepalives are sent at all (see `TunnelOptions::with_keepalive`)
+    keepalive_enabled: bool,
     inbound_timeout: Duration,
     session_start: Instant,
     last_inbound: Instant,
     last_warning_hour: u64,
+    /// Log a summary of every packet at trace level (see `set_packet_tracing`)
+    trace_packets: bool,
+    /// Capture file for packets in both directions (see `set_capture`)
+    capture: Option<PcapWriter>,
+    /// ESP-over-UDP data channel, while enabled and working (see `enable_udp`)
+    esp: Option<EspChannel>,
+}
+
+/// When to send the next SSL keepalive
+///
+/// Data frames already show the gateway the connection is alive, so the
+/// deadline moves forward on every frame sent and keepalives only go out
+/// after a full interval with nothing else sent. With jitter, each keepalive
+/// picks the length of the next interval at random so they don't go out on a
+/// perfectly regular beat.
+#[derive(Debug, Clone, Copy)]
+struct KeepaliveSchedule {
+    interval: Duration,
+    /// Spread of each interval in percent either way (0 = fixed interval)
+    jitter_percent: u32,
+    /// Length of the current interval, `interval` with jitter applied
+    current: Duration,
+    last_sent: Instant,
+}
+
+impl KeepaliveSchedule {
+    fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            jitter_percent: 0,
+            current: interval,
+            last_sent: now,
+        }
+    }
+
+    /// Vary each interval by up to `percent` either way
+    fn with_jitter(mut self, percent: u32) -> Self {
+        self.jitter_percent = percent.min(MAX_KEEPALIVE_JITTER_PERCENT);
+        self.current = self.draw_interval();
+        self
+    }
+
+    /// Record a frame (data or keepalive) sent over SSL
+    fn record_send(&mut self, now: Instant) {
+        self.last_sent = now;
+    }
+
+    /// Record a keepalive sent over SSL; the next interval gets a new length
+    fn record_keepalive(&mut self, now: Instant) {
+        self.record_send(now);
+        self.current = self.draw_interval();
+    }
+
+    /// When a keepalive is due if nothing else is sent first
+    fn next_due(&self) -> Instant {
+        self.last_sent + self.current
+    }
+
+    fn draw_interval(&self) -> Duration {
+        if self.jitter_percent == 0 {
+            return self.interval;
+        }
+        let mut sample = [0u8; 4];
+        if SystemRandom::new().fill(&mut sample).is_err() {
+            // The OS RNG doesn't fail in practice; a fixed interval still works
+            return self.interval;
+        }
+        jittered(self.interval, self.jitter_percent, u32::from_be_bytes(sample))
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_due()
+    }
+}
+
+/// `interval` moved by up to `percent` either way, picked by `sample`
+///
+/// Samples map evenly onto the range in whole milliseconds.
+fn jittered(interval: Duration, percent: u32, sample: u32) -> Duration {
+    let interval_ms = interval.as_millis() as u64;
+    let spread_ms = interval_ms * u64::from(percent) / 100;
+    let offset_ms = u64::from(sample) % (2 * spread_ms + 1);
+    Duration::from_millis(interval_ms - spread_ms + offset_ms)
+}
+
+/// UDP socket and ESP state for the optional UDP data channel
+struct EspChannel {
+    socket: Arc<UdpSocket>,
+    session: EspSession,
+    /// Tunnel address probes are sent from
+    probe_src: Ipv4Addr,
+    /// Gateway address that answers probes
+    probe_addr: Ipv4Addr,
+    probe_seq: u16,
+    last_inbound: Instant,
+}
+
+impl EspChannel {
+    /// Open the UDP socket and confirm the gateway answers ESP probes
+    async fn establish(gateway: &str, config: &EspConfig, probe_src: Ipv4Addr) -> Result<Self, TunnelError> {
+        let gateway_addr = tokio::net::lookup_host((gateway, config.udp_port))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", gateway)))?;
+        let local: SocketAddr = if gateway_addr.is_ipv6() {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(gateway_addr).await?;
+
+        let mut channel = Self {
+            socket: Arc::new(socket),
+            session: EspSession::new(config)?,
+            probe_src,
+            probe_addr: config.probe_addr,
+            probe_seq: 0,
+            last_inbound: Instant::now(),
+        };
+
+        let mut buf = vec![0u8; 2048];
+        for _ in 0..ESP_PROBE_ATTEMPTS {
+            channel.send_probe().await?;
+            let deadline = tokio::time::Instant::now() + ESP_PROBE_WAIT;
+            while let Ok(result) = tokio::time::timeout_at(deadline, channel.socket.recv(&mut buf)).await {
+                let n = result?;
+                match channel.session.decrypt(&buf[..n]) {
+                    Ok(packet) if esp::is_probe_reply(&packet, channel.probe_addr) => {
+                        channel.last_inbound = Instant::now();
+                        return Ok(channel);
+                    }
+                    Ok(_) => {}
+                    Err(e) => debug!("Ignoring ESP datagram during probe: {}", e),
+                }
+            }
+        }
+
+        Err(TunnelError::SetupFailed(
+            "no reply to ESP probes (UDP may be blocked)".to_string(),
+        ))
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<(), TunnelError> {
+        let datagram = self.session.encrypt(packet)?;
+        self.socket.send(&datagram).await?;
+        Ok(())
+    }
+
+    /// Probe the gateway; doubles as the ESP keepalive
+    async fn send_probe(&mut self) -> Result<(), TunnelError> {
+        self.probe_seq = self.probe_seq.wrapping_add(1);
+        let probe = esp::build_probe(self.probe_src, self.probe_addr, self.probe_seq);
+        self.send(&probe).await
+    }
+}
+
+/// Everything needed to establish an SSL tunnel (see `SslTunnel::connect_with`)
+///
+/// `new` takes what every tunnel needs; the rest are optional `with_*`
+/// settings with the same defaults as `SslTunnel::connect`, so new options
+/// can be added without touching existing callers.
+#[derive(Debug, Clone)]
+pub struct TunnelOptions<'a> {
+    gateway: &'a str,
+    port: u16,
+    username: &'a str,
+    auth_cookie: &'a str,
+    config: &'a TunnelConfig,
+    aggressive_keepalive: bool,
+    keepalive: bool,
+    keepalive_jitter_percent: u32,
+    inbound_timeout_secs: Option<u64>,
+    identity: ClientIdentity,
+}
+
+impl<'a> TunnelOptions<'a> {
+    /// Options for a tunnel to `gateway` with the login's username and cookie
+    /// and the tunnel configuration from getconfig
+    pub fn new(
+        gateway: &'a str,
+        username: &'a str,
+        auth_cookie: &'a str,
+        config: &'a TunnelConfig,
+    ) -> Self {
+        Self {
+            gateway,
+            port: DEFAULT_TUNNEL_PORT,
+            username,
+            auth_cookie,
+            config,
+            aggressive_keepalive: false,
+            keepalive: true,
+            keepalive_jitter_percent: DEFAULT_KEEPALIVE_JITTER_PERCENT,
+            inbound_timeout_secs: None,
+            identity: ClientIdentity::default(),
+        }
+    }
+
+    /// Connect the tunnel to `port` on the gateway (default: 443)
+    ///
+    /// TLS still names the bare gateway host, so the certificate is checked
+    /// the same whatever the port.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Use the shorter keepalive interval (10s vs 30s)
+    pub fn with_aggressive_keepalive(mut self, aggressive: bool) -> Self {
+        self.aggressive_keepalive = aggressive;
+        self
+    }
+
+    /// Send keepalives when the tunnel is idle (default: yes)
+    ///
+    /// Off, an idle tunnel stays up only as long as the gateway allows.
+    /// Useful to rule out our keepalives as the cause of a disconnect.
+    pub fn with_keepalive(mut self, enabled: bool) -> Self {
+        self.keepalive = enabled;
+        self
+    }
+
+    /// Vary each keepalive interval at random by up to `percent` either way
+    /// (default: 10, capped at 50; 0 sends them at exact intervals)
+    pub fn with_keepalive_jitter(mut self, percent: u32) -> Self {
+        self.keepalive_jitter_percent = percent;
+        self
+    }
+
+    /// Override the inbound timeout (None uses the default 45s)
+    pub fn with_inbound_timeout_secs(mut self, secs: Option<u64>) -> Self {
+        self.inbound_timeout_secs = secs;
+        self
+    }
+
+    /// Client identity; its User-Agent goes on the tunnel request
+    pub fn with_identity(mut self, identity: ClientIdentity) -> Self {
+        self.identity = identity;
+        self
+    }
 }
 
 impl SslTunnel {
@@ -79,14 +380,17 @@ impl SslTunnel {
         auth_cookie: &str,
         config: &TunnelConfig,
     ) -> Result<Self, TunnelError> {
-        Self::connect_with_options(gateway, username, auth_cookie, config, false, None).await
+        Self::connect_with(TunnelOptions::new(gateway, username, auth_cookie, config)).await
     }
 
     /// Connect with configurable keepalive and timeout behavior
     ///
+    /// Kept for existing callers; new code should use `connect_with`.
+    ///
     /// # Arguments
     /// * `aggressive_keepalive` - Use shorter keepalive interval (10s vs 30s)
     /// * `inbound_timeout_secs` - Override inbound timeout (None uses default 45s)
+    /// * `identity` - Client identity; its User-Agent goes on the tunnel request
     pub async fn connect_with_options(
         gateway: &str,
         username: &str,
@@ -94,26 +398,60 @@ impl SslTunnel {
         config: &TunnelConfig,
         aggressive_keepalive: bool,
         inbound_timeout_secs: Option<u64>,
+        identity: &ClientIdentity,
     ) -> Result<Self, TunnelError> {
+        let options = TunnelOptions::new(gateway, username, auth_cookie, config)
+            .with_aggressive_keepalive(aggressive_keepalive)
+            .with_inbound_timeout_secs(inbound_timeout_secs)
+            .with_identity(identity.clone());
+        Self::connect_with(options).await
+    }
+
+    /// Connect using a set of `TunnelOptions`
+    ///
+    /// # Returns
+    /// Established SSL tunnel ready for packet I/O
+    pub async fn connect_with(options: TunnelOptions<'_>) -> Result<Self, TunnelError> {
+        let TunnelOptions {
+            gateway,
+            port,
+            username,
+            auth_cookie,
+            config,
+            aggressive_keepalive,
+            keepalive,
+            keepalive_jitter_percent,
+            inbound_timeout_secs,
+            identity,
+        } = options;
         info!("Establishing SSL tunnel to {}", gateway);
 
-        // 1. TCP connect to gateway:443 FIRST (before TUN to avoid routing conflicts)
-        info!("TCP connecting to {}:443...", gateway);
-        let tcp = TcpStream::connect((gateway, 443)).await?;
+        // 1. TCP connect to the gateway FIRST (before TUN to avoid routing conflicts)
+        info!("TCP connecting to {}:{}...", gateway, port);
+        let tcp = TcpStream::connect((gateway, port)).await?;
         tcp.set_nodelay(true)?;
         info!("TCP connected");
 
         // 2. TLS handshake
         info!("Starting TLS handshake...");
-        let stream = tls_connect(gateway, tcp).await?;
-        info!("TLS handshake completed");
+        let stream = tls_connect(gateway, tcp).await.map_err(|e| match e {
+            TunnelError::TlsError(detail) => {
+                TunnelError::StartFailed(StartFailure::from_tls_error(&detail))
+            }
+            other => other,
+        })?;
+        let tls = describe_tls(stream.get_ref().1);
+        info!("TLS handshake completed: {}", tls);
 
         // 3. Create TUN device (after TCP/TLS is established)
         info!("Creating TUN device...");
         let tun = TunDevice::create(config).await?;
         info!("TUN device created: {}", tun.name());
 
-        let keepalive_secs = if aggressive_keepalive {
+        let keepalive_secs = if !keepalive {
+            info!("Keepalive disabled; idle timeout is up to the gateway");
+            KEEPALIVE_INTERVAL_SECS
+        } else if aggressive_keepalive {
             info!("Using aggressive keepalive ({}s)", AGGRESSIVE_KEEPALIVE_SECS);
             AGGRESSIVE_KEEPALIVE_SECS
         } else {
@@ -127,15 +465,23 @@ impl SslTunnel {
         let mut tunnel = Self {
             stream,
             tun,
-            keepalive_interval: Duration::from_secs(keepalive_secs),
+            tls,
+            keepalive: KeepaliveSchedule::new(Duration::from_secs(keepalive_secs), now)
+                .with_jitter(keepalive_jitter_percent),
+            keepalive_enabled: keepalive,
             inbound_timeout: Duration::from_secs(timeout_secs),
             session_start: now,
             last_inbound: now,
             last_warning_hour: 0,
+            trace_packets: false,
+            capture: None,
+            esp: None,
         };
 
         // 4. Send tunnel request
-        tunnel.send_tunnel_request(gateway, username, auth_cookie).await?;
+        tunnel
+            .send_tunnel_request(gateway, username, auth_cookie, &identity)
+            .await?;
 
         // 5. Wait for "START_TUNNEL" response
         tunnel.wait_for_start().await?;
@@ -149,24 +495,96 @@ impl SslTunnel {
         self.tun.name()
     }
 
+    /// Negotiated TLS version and cipher suite, e.g.
+    /// "TLS 1.3, TLS_AES_256_GCM_SHA384"
+    pub fn tls_summary(&self) -> &str {
+        &self.tls
+    }
+
+    /// Address of the gateway the tunnel is connected to
+    pub fn gateway_addr(&self) -> io::Result<IpAddr> {
+        Ok(self.stream.get_ref().0.peer_addr()?.ip())
+    }
+
+    /// Log a one-line summary (addresses, protocol, length) of every packet
+    ///
+    /// Very chatty; meant for `--trace-packets` debugging sessions.
+    pub fn set_packet_tracing(&mut self, enabled: bool) {
+        self.trace_packets = enabled;
+    }
+
+    /// Record every packet (both directions) to a pcap capture file
+    ///
+    /// The caller keeps a clone of the writer to flush it on disconnect.
+    pub fn set_capture(&mut self, capture: PcapWriter) {
+        self.capture = Some(capture);
+    }
+
+    /// Switch the data channel to ESP over UDP
+    ///
+    /// Requires ESP parameters from getconfig and a gateway that answers
+    /// probes over UDP. Returns false (the tunnel stays on SSL) otherwise.
+    /// The SSL connection stays up either way, and the tunnel falls back to it
+    /// if the UDP channel later goes quiet.
+    pub async fn enable_udp(&mut self, gateway: &str, config: &TunnelConfig) -> bool {
+        let Some(esp_config) = &config.esp else {
+            info!("Gateway did not offer a supported UDP (ESP) channel; using SSL");
+            return false;
+        };
+        let IpAddr::V4(probe_src) = config.internal_ip else {
+            info!("UDP (ESP) channel needs an IPv4 tunnel address; using SSL");
+            return false;
+        };
+
+        info!("Probing UDP (ESP) channel on port {}...", esp_config.udp_port);
+        match EspChannel::establish(gateway, esp_config, probe_src).await {
+            Ok(channel) => {
+                info!("UDP (ESP) data channel established");
+                self.esp = Some(channel);
+                true
+            }
+            Err(e) => {
+                warn!("UDP (ESP) channel unavailable, using SSL: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Whether packets are currently carried over ESP/UDP
+    pub fn is_udp(&self) -> bool {
+        self.esp.is_some()
+    }
+
+    /// Stop using the UDP channel; traffic continues over SSL
+    fn fall_back_to_ssl(&mut self, reason: &str) {
+        if self.esp.take().is_some() {
+            warn!("UDP (ESP) channel failed ({}); falling back to SSL", reason);
+        }
+    }
+
+    /// Append a packet to the capture file, if capturing
+    ///
+    /// A write error stops the capture rather than the tunnel.
+    fn capture_packet(&mut self, data: &[u8]) {
+        if let Some(capture) = &self.capture
+            && let Err(e) = capture.write_packet(data)
+        {
+            warn!("Packet capture failed, stopping capture: {}", e);
+            self.capture = None;
+        }
+    }
+
     /// Send tunnel connection request
     async fn send_tunnel_request(
         &mut self,
         gateway: &str,
         username: &str,
         auth_cookie: &str,
+        identity: &ClientIdentity,
     ) -> Result<(), TunnelError> {
         debug!("Sending tunnel request for user: {}", username);
 
-        let request = format!(
-            "GET /ssl-tunnel-connect.sslvpn?user={}&authcookie={} HTTP/1.1\r\n\
-             Host: {}\r\n\
-             Connection: keep-alive\r\n\
-             User-Agent: PAN GlobalProtect\r\n\
-             \r\n",
-            username, auth_cookie, gateway
-        );
-
+        let request = tunnel_request(gateway, username, auth_cookie, identity);
         self.stream.write_all(request.as_bytes()).await?;
         self.stream.flush().await?;
 
@@ -178,22 +596,28 @@ impl SslTunnel {
         debug!("Waiting for START_TUNNEL response");
 
         let mut buf = vec![0u8; 4096];
-        let n = self.stream.read(&mut buf).await?;
+        let n = match self.stream.read(&mut buf).await {
+            Ok(n) => n,
+            // rustls reports TLS alerts and decode failures as InvalidData
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Err(TunnelError::StartFailed(StartFailure::from_tls_error(
+                    &e.to_string(),
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         if n == 0 {
-            return Err(TunnelError::SetupFailed(
-                "Connection closed before START_TUNNEL".to_string(),
-            ));
+            return Err(TunnelError::StartFailed(StartFailure::ConnectionClosed));
         }
 
         let response = String::from_utf8_lossy(&buf[..n]);
         debug!("Tunnel response: {}", response);
 
         if !response.contains("START_TUNNEL") {
-            return Err(TunnelError::SetupFailed(format!(
-                "Expected START_TUNNEL, got: {}",
-                response
-            )));
+            let failure = StartFailure::from_response(&response);
+            warn!("Gateway did not start the tunnel: {:?}", failure);
+            return Err(TunnelError::StartFailed(failure));
         }
 
         Ok(())
@@ -239,7 +663,9 @@ impl SslTunnel {
     /// It handles three concurrent operations using tokio::select!:
     /// - Reading packets from TUN and sending to gateway (outbound)
     /// - Reading packets from gateway and writing to TUN (inbound)
-    /// - Sending keepalive packets periodically
+    /// - Reading ESP datagrams when the UDP channel is enabled (inbound)
+    /// - Sending keepalive packets when the SSL channel has been idle (unless
+    ///   disabled with `TunnelOptions::with_keepalive`)
     ///
     /// The async TUN device ensures outbound packets are processed immediately
     /// rather than waiting for network events or keepalive ticks.
@@ -247,9 +673,6 @@ impl SslTunnel {
         info!("Starting tunnel event loop");
 
         let mtu = self.tun.mtu();
-        let mut keepalive = interval(self.keepalive_interval);
-        keepalive.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-
         // Session check timer (every 5 minutes)
         let mut session_check = interval(Duration::from_secs(300));
         session_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
@@ -266,7 +689,17 @@ impl SslTunnel {
         let mut header_buf = [0u8; 16];
         let mut header_pos = 0usize;
 
+        // When the most recent frame from the gateway was a zero-length control frame
+        let mut last_control_frame: Option<Instant> = None;
+
+        let mut esp_socket = self.esp.as_ref().map(|c| Arc::clone(&c.socket));
+        let mut udp_buf = vec![0u8; mtu + 128];
+
         loop {
+            if self.esp.is_none() {
+                esp_socket = None;
+            }
+
             tokio::select! {
                 // Priority 1: Outbound traffic (TUN → Gateway)
                 // Packets from applications destined for VPN network
@@ -274,6 +707,12 @@ impl SslTunnel {
                     match result {
                         Ok(n) if n > 0 => {
                             debug!("TUN read {} bytes (outbound)", n);
+                            if self.trace_packets {
+                                trace!("OUT {}", summarize_ip_packet(&tun_buf[..n]));
+                            }
+                            if self.capture.is_some() {
+                                self.capture_packet(&tun_buf[..n]);
+                            }
                             self.send_packet(&tun_buf[..n]).await?;
                         }
                         Ok(_) => {
@@ -281,7 +720,7 @@ impl SslTunnel {
                         }
                         Err(e) => {
                             error!("TUN read error: {}", e);
-                            return Err(e.into());
+                            return Err(tun_error(self.tun.name(), e));
                         }
                     }
                 }
@@ -293,8 +732,9 @@ impl SslTunnel {
                 result = self.stream.read(&mut header_buf[header_pos..]) => {
                     match result {
                         Ok(0) => {
-                            info!("Tunnel disconnected (EOF)");
-                            return Err(TunnelError::Disconnected);
+                            let reason = classify_close(last_control_frame, Instant::now());
+                            info!("Tunnel disconnected (EOF): {}", reason);
+                            return Err(reason);
                         }
                         Ok(n) => {
                             // Any data from gateway = connection is alive
@@ -309,18 +749,26 @@ impl SslTunnel {
                             // Full header received, reset for next packet
                             header_pos = 0;
 
-                            // Parse length from header
-                            let len = u16::from_be_bytes([header_buf[6], header_buf[7]]) as usize;
+                            // Parse length from header, refusing lengths no real packet has
+                            let len = match packet::payload_len(&header_buf, mtu + FRAME_LEN_SLACK) {
+                                Ok(len) => len,
+                                Err(e) => {
+                                    error!("Bad frame header from gateway: {}", e);
+                                    return Err(e.into());
+                                }
+                            };
 
                             if len == 0 {
-                                // Keepalive packet from gateway
+                                // Keepalive (or logout) control frame from gateway
                                 debug!("Received keepalive from gateway");
+                                last_control_frame = Some(Instant::now());
                                 continue;
                             }
+                            last_control_frame = None;
 
                             // Read the payload (committed read - not in select!)
                             let mut payload = vec![0u8; len];
-                            self.stream.read_exact(&mut payload).await?;
+                            self.stream.read_exact(&mut payload).await.map_err(read_error)?;
 
                             // Decode the full frame
                             let mut frame = Vec::with_capacity(16 + len);
@@ -335,27 +783,30 @@ impl SslTunnel {
                             }
 
                             debug!("Gateway read {} bytes (inbound)", packet.payload.len());
-
-                            // Write to TUN (deliver to local applications)
-                            if !packet.payload.is_empty() {
-                                self.tun.write(&packet.payload).await?;
-                            }
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                            info!("Tunnel disconnected (EOF)");
-                            return Err(TunnelError::Disconnected);
+                            self.deliver_inbound(&packet.payload).await?;
                         }
                         Err(e) => {
                             error!("Gateway read error: {}", e);
-                            return Err(TunnelError::IoError(e));
+                            return Err(read_error(e));
                         }
                     }
                 }
 
-                // Priority 3: Keepalive timer
-                _ = keepalive.tick() => {
-                    debug!("Sending keepalive");
-                    self.send_keepalive().await?;
+                // Inbound ESP datagrams (UDP channel only)
+                result = recv_udp(esp_socket.as_deref(), &mut udp_buf) => {
+                    match result {
+                        Ok(n) => self.handle_esp_datagram(&udp_buf[..n]).await?,
+                        Err(e) => self.fall_back_to_ssl(&e.to_string()),
+                    }
+                }
+
+                // Priority 3: Keepalive, only after an idle interval (never if disabled)
+                _ = tokio::time::sleep_until(self.keepalive.next_due().into()), if self.keepalive_enabled => {
+                    if self.keepalive.is_due(Instant::now()) {
+                        debug!("Sending keepalive");
+                        self.send_keepalive().await?;
+                        self.esp_keepalive().await;
+                    }
                 }
 
                 // Priority 4: Session expiry check
@@ -378,14 +829,81 @@ impl SslTunnel {
         }
     }
 
-    /// Send a packet to the gateway
+    /// Decrypt an ESP datagram and deliver its packet
+    ///
+    /// Undecryptable datagrams are dropped rather than ending the tunnel.
+    async fn handle_esp_datagram(&mut self, datagram: &[u8]) -> Result<(), TunnelError> {
+        let Some(channel) = &mut self.esp else {
+            return Ok(());
+        };
+        let packet = match channel.session.decrypt(datagram) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Dropping ESP datagram: {}", e);
+                return Ok(());
+            }
+        };
+
+        let now = Instant::now();
+        channel.last_inbound = now;
+        self.last_inbound = now;
+
+        if esp::is_probe_reply(&packet, channel.probe_addr) {
+            debug!("Received ESP probe reply");
+            return Ok(());
+        }
+
+        debug!("ESP read {} bytes (inbound)", packet.len());
+        self.deliver_inbound(&packet).await
+    }
+
+    /// Probe the UDP channel, falling back to SSL if it has gone quiet
+    async fn esp_keepalive(&mut self) {
+        let Some(channel) = &mut self.esp else {
+            return;
+        };
+        if channel.last_inbound.elapsed() >= self.inbound_timeout {
+            self.fall_back_to_ssl("no ESP traffic from gateway");
+            return;
+        }
+        if let Err(e) = channel.send_probe().await {
+            self.fall_back_to_ssl(&e.to_string());
+        }
+    }
+
+    /// Write a packet from the gateway to TUN (deliver to local applications)
+    async fn deliver_inbound(&mut self, payload: &[u8]) -> Result<(), TunnelError> {
+        if payload.is_empty() {
+            return Ok(());
+        }
+        if self.trace_packets {
+            trace!("IN  {}", summarize_ip_packet(payload));
+        }
+        if self.capture.is_some() {
+            self.capture_packet(payload);
+        }
+        if let Err(e) = self.tun.write(payload).await {
+            return Err(tun_error(self.tun.name(), e));
+        }
+        Ok(())
+    }
+
+    /// Send a packet to the gateway (over ESP when the UDP channel is up)
     async fn send_packet(&mut self, packet: &[u8]) -> Result<(), TunnelError> {
+        if let Some(channel) = &mut self.esp {
+            match channel.send(packet).await {
+                Ok(()) => return Ok(()),
+                Err(e) => self.fall_back_to_ssl(&e.to_string()),
+            }
+        }
+
         let gp_packet = GpPacket::from_ip_packet(packet.to_vec())
             .ok_or_else(|| TunnelError::SetupFailed("Invalid IP packet".to_string()))?;
 
         let frame = gp_packet.encode();
         self.stream.write_all(&frame).await?;
         self.stream.flush().await?;
+        self.keepalive.record_send(Instant::now());
 
         Ok(())
     }
@@ -396,10 +914,121 @@ impl SslTunnel {
         let frame = keepalive.encode();
         self.stream.write_all(&frame).await?;
         self.stream.flush().await?;
+        self.keepalive.record_keepalive(Instant::now());
         Ok(())
     }
 }
 
+/// Classify a clean close (EOF) from the gateway
+///
+/// Right after a zero-length control frame it's the gateway logging the
+/// session out; anywhere else (mid-transfer) it's a dropped connection.
+fn classify_close(last_control_frame: Option<Instant>, now: Instant) -> TunnelError {
+    match last_control_frame {
+        Some(at) if now.duration_since(at) <= LOGOUT_CLOSE_WINDOW => TunnelError::GatewayLogout,
+        _ => TunnelError::ConnectionLost("gateway closed the connection".to_string()),
+    }
+}
+
+/// Map a gateway read error: resets and truncated streams are network drops
+fn read_error(e: io::Error) -> TunnelError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe => TunnelError::ConnectionLost(e.to_string()),
+        _ => TunnelError::IoError(e),
+    }
+}
+
+/// Map a TUN read/write error, telling a removed device from other failures
+fn tun_error(name: &str, e: crate::gp::tun::TunError) -> TunnelError {
+    match &e {
+        crate::gp::tun::TunError::IoError(io) if is_device_gone(io) => {
+            warn!("TUN device {} is gone ({})", name, io);
+            TunnelError::TunGone(name.to_string())
+        }
+        _ => TunnelError::TunError(e),
+    }
+}
+
+/// Whether a TUN I/O error means the device no longer exists
+fn is_device_gone(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::NotFound {
+        return true;
+    }
+    let Some(code) = e.raw_os_error() else {
+        return false;
+    };
+    #[cfg(unix)]
+    {
+        use nix::errno::Errno;
+        // EIO: Linux, interface deleted or down; ENXIO: macOS utun detached
+        let mut gone = vec![Errno::ENODEV, Errno::ENXIO, Errno::EBADF, Errno::EIO];
+        #[cfg(target_os = "linux")]
+        gone.push(Errno::EBADFD);
+        gone.contains(&Errno::from_raw(code))
+    }
+    #[cfg(windows)]
+    {
+        use windows::Win32::Foundation::{
+            ERROR_DEVICE_NOT_CONNECTED, ERROR_HANDLE_EOF, ERROR_INVALID_HANDLE,
+        };
+        [ERROR_DEVICE_NOT_CONNECTED, ERROR_HANDLE_EOF, ERROR_INVALID_HANDLE]
+            .iter()
+            .any(|err| err.0 as i32 == code)
+    }
+}
+
+/// The HTTP request that asks the gateway to start the SSL tunnel
+fn tunnel_request(gateway: &str, username: &str, auth_cookie: &str, identity: &ClientIdentity) -> String {
+    let mut request = format!(
+        "GET /ssl-tunnel-connect.sslvpn?user={}&authcookie={} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Connection: keep-alive\r\n\
+         User-Agent: {}\r\n",
+        username, auth_cookie, crate::config::url_host(gateway), identity.user_agent
+    );
+    for (name, value) in &identity.headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+/// Receive from the ESP socket, or wait forever when there is none
+async fn recv_udp(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<usize> {
+    match socket {
+        Some(socket) => socket.recv(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// "TLS 1.3, TLS_AES_256_GCM_SHA384" for an established connection
+fn describe_tls(connection: &rustls::ClientConnection) -> String {
+    match (connection.protocol_version(), connection.negotiated_cipher_suite()) {
+        (Some(version), Some(suite)) => tls_summary(version, suite.suite()),
+        _ => "TLS (parameters unknown)".to_string(),
+    }
+}
+
+/// Version and cipher suite by their RFC names
+///
+/// rustls calls the TLS 1.3 suites `TLS13_*`; the IANA name is `TLS_*`.
+fn tls_summary(version: rustls::ProtocolVersion, suite: rustls::CipherSuite) -> String {
+    let version = match version {
+        rustls::ProtocolVersion::TLSv1_2 => "TLS 1.2".to_string(),
+        rustls::ProtocolVersion::TLSv1_3 => "TLS 1.3".to_string(),
+        other => format!("{:?}", other),
+    };
+    let suite = format!("{:?}", suite);
+    let suite = match suite.strip_prefix("TLS13_") {
+        Some(rest) => format!("TLS_{}", rest),
+        None => suite,
+    };
+    format!("{}, {}", version, suite)
+}
+
 /// Establish TLS connection to gateway
 async fn tls_connect(
     gateway: &str,
@@ -439,5 +1068,173 @@ mod tests {
         assert!(interval.as_secs() < 60); // Reasonable keepalive
     }
 
+    #[test]
+    fn test_tls_summary_names() {
+        assert_eq!(
+            tls_summary(
+                rustls::ProtocolVersion::TLSv1_3,
+                rustls::CipherSuite::TLS13_AES_256_GCM_SHA384
+            ),
+            "TLS 1.3, TLS_AES_256_GCM_SHA384"
+        );
+        assert_eq!(
+            tls_summary(
+                rustls::ProtocolVersion::TLSv1_2,
+                rustls::CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+            ),
+            "TLS 1.2, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"
+        );
+    }
+
+    #[test]
+    fn test_tunnel_request_headers() {
+        let mut identity = ClientIdentity::default();
+        let request = tunnel_request("vpn.example.edu", "alice", "c00kie", &identity);
+        assert!(request.starts_with("GET /ssl-tunnel-connect.sslvpn?user=alice&authcookie=c00kie HTTP/1.1\r\n"));
+        assert!(request.contains("User-Agent: PAN GlobalProtect\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+
+        identity.headers.insert("X-GlobalProtect".to_string(), "6.2.1-89".to_string());
+        let request = tunnel_request("vpn.example.edu", "alice", "c00kie", &identity);
+        assert!(request.ends_with("User-Agent: PAN GlobalProtect\r\nX-GlobalProtect: 6.2.1-89\r\n\r\n"));
+
+        let request = tunnel_request("2001:db8::1", "alice", "c00kie", &identity);
+        assert!(request.contains("Host: [2001:db8::1]\r\n"));
+    }
+
+    #[test]
+    fn test_tunnel_options_defaults_and_overrides() {
+        let config: TunnelConfig = serde_json::from_str(
+            r#"{"mtu":1400,"internal_ip":"10.0.1.100","internal_ip6":null,"dns_servers":[],
+                "timeout_seconds":3600,"netmask":null,"split_include":[],"esp":null}"#,
+        )
+        .unwrap();
+
+        let options = TunnelOptions::new("vpn.example.edu", "alice", "cookie", &config);
+        assert!(!options.aggressive_keepalive);
+        assert!(options.keepalive);
+        assert_eq!(options.keepalive_jitter_percent, DEFAULT_KEEPALIVE_JITTER_PERCENT);
+        assert_eq!(options.inbound_timeout_secs, None);
+        assert!(options.identity.is_default());
+
+        let identity = ClientIdentity {
+            os: "Mac".to_string(),
+            ..ClientIdentity::default()
+        };
+        let options = options
+            .with_aggressive_keepalive(true)
+            .with_keepalive(false)
+            .with_keepalive_jitter(0)
+            .with_inbound_timeout_secs(Some(60))
+            .with_identity(identity.clone());
+        assert!(options.aggressive_keepalive);
+        assert!(!options.keepalive);
+        assert_eq!(options.keepalive_jitter_percent, 0);
+        assert_eq!(options.inbound_timeout_secs, Some(60));
+        assert_eq!(options.identity, identity);
+        assert_eq!(options.gateway, "vpn.example.edu");
+    }
+
+    #[test]
+    fn test_close_after_control_frame_is_logout() {
+        let now = Instant::now();
+        let control = now - Duration::from_millis(200);
+        assert!(matches!(classify_close(Some(control), now), TunnelError::GatewayLogout));
+        assert!(!classify_close(Some(control), now).should_reconnect());
+    }
+
+    #[test]
+    fn test_close_mid_transfer_is_network_drop() {
+        let now = Instant::now();
+        let stale_control = now - LOGOUT_CLOSE_WINDOW - Duration::from_secs(1);
+
+        for last in [None, Some(stale_control)] {
+            let error = classify_close(last, now);
+            assert!(matches!(error, TunnelError::ConnectionLost(_)));
+            assert!(error.should_reconnect());
+        }
+
+        let reset = read_error(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(matches!(reset, TunnelError::ConnectionLost(_)));
+        let truncated = read_error(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(truncated, TunnelError::ConnectionLost(_)));
+    }
+
+    #[test]
+    fn test_reconnect_policy() {
+        assert!(TunnelError::Timeout.should_reconnect());
+        assert!(!TunnelError::SessionExpired.should_reconnect());
+        assert!(!TunnelError::GatewayLogout.should_reconnect());
+
+        let oversized = packet::FrameError::Oversized { len: 65535, max: 1656 };
+        assert!(TunnelError::from(oversized).should_reconnect());
+        assert!(TunnelError::TunGone("tun0".to_string()).should_reconnect());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tun_gone_errors() {
+        use crate::gp::tun::TunError;
+        let gone = tun_error("tun0", TunError::IoError(io::Error::from_raw_os_error(nix::libc::ENODEV)));
+        assert!(matches!(gone, TunnelError::TunGone(ref name) if name == "tun0"));
+
+        let other = tun_error("tun0", TunError::IoError(io::Error::from(io::ErrorKind::WouldBlock)));
+        assert!(matches!(other, TunnelError::TunError(_)));
+        assert!(matches!(tun_error("tun0", TunError::InvalidPacketSize(9000)), TunnelError::TunError(_)));
+    }
+
+    #[test]
+    fn test_no_keepalive_under_steady_traffic() {
+        let start = Instant::now();
+        let mut schedule = KeepaliveSchedule::new(Duration::from_secs(AGGRESSIVE_KEEPALIVE_SECS), start);
+
+        // A data frame every second for two minutes never leaves the link idle
+        for second in 1..=120 {
+            let now = start + Duration::from_secs(second);
+            assert!(!schedule.is_due(now), "keepalive due at {}s despite traffic", second);
+            schedule.record_send(now);
+        }
+    }
+
+    #[test]
+    fn test_keepalive_due_after_idle_interval() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(KEEPALIVE_INTERVAL_SECS);
+        let mut schedule = KeepaliveSchedule::new(interval, start);
+
+        assert!(!schedule.is_due(start + interval - Duration::from_millis(1)));
+        assert!(schedule.is_due(start + interval));
+
+        // Traffic just before the deadline pushes it back a full interval
+        let busy = start + interval - Duration::from_secs(1);
+        schedule.record_send(busy);
+        assert!(!schedule.is_due(start + interval));
+        assert_eq!(schedule.next_due(), busy + interval);
+    }
+
+    #[test]
+    fn test_keepalive_jitter() {
+        let interval = Duration::from_secs(KEEPALIVE_INTERVAL_SECS);
+        assert_eq!(jittered(interval, 10, 0), Duration::from_secs(27));
+        assert_eq!(jittered(interval, 10, 3_000), interval);
+        assert_eq!(jittered(interval, 10, 6_000), Duration::from_secs(33));
+        assert_eq!(jittered(interval, 10, 6_001), Duration::from_secs(27));
+        assert_eq!(jittered(interval, 0, u32::MAX), interval);
+
+        let start = Instant::now();
+        let mut schedule = KeepaliveSchedule::new(interval, start).with_jitter(10);
+        for round in 0..20 {
+            let wait = schedule.next_due() - schedule.last_sent;
+            assert!(wait >= Duration::from_secs(27) && wait <= Duration::from_secs(33));
+            schedule.record_keepalive(start + Duration::from_secs(round * 30));
+        }
+
+        // No jitter keeps the exact interval
+        let mut schedule = KeepaliveSchedule::new(interval, start).with_jitter(0);
+        schedule.record_keepalive(start);
+        assert_eq!(schedule.next_due(), start + interval);
+        assert_eq!(KeepaliveSchedule::new(interval, start).with_jitter(90).jitter_percent, 50);
+    }
+
     // Note: Full tunnel tests require real VPN credentials and are tested manually
 }
@@ -12,7 +12,10 @@
 //! - `platform`: Cross-platform routing (macOS, Linux, Windows)
 //! - `vpn`: VPN routing and hosts file management
 //! - `state`: Persistent state for crash recovery
+//! - `status`: Connection status as `pmacs-vpn status` reports it
+//! - `build_info`: Version, commit and capabilities of this build
 
+pub mod build_info;
 pub mod config;
 pub mod credentials;
 pub mod gp;
@@ -21,9 +24,19 @@ pub mod notifications;
 pub mod platform;
 pub mod startup;
 pub mod state;
+pub mod status;
 pub mod tray;
 pub mod vpn;
 
-pub use config::{Config, DuoMethod, Preferences, VpnConfig};
-pub use credentials::{delete_password, get_password, store_password};
-pub use state::{AuthToken, VpnState};
+pub use build_info::BuildInfo;
+pub use config::{Config, DuoMethod, HostEntry, Keepalive, Preferences, Transport, VpnConfig};
+pub use credentials::{delete_password, get_password, read_password_file, store_password};
+pub use state::{
+    AuthToken, ConnectFailure, DaemonHandshake, DisconnectReason, FullTunnelRoutes, LastAddress,
+    ReconnectStatus, RoutesFile, ServicePort, VpnState,
+};
+pub use status::{status, ConnectionHealth, ConnectionMode, ConnectionStatus, StatusReport};
+pub use vpn::{
+    cleanup, cleanup_orphaned, cleanup_orphaned_with, cleanup_with, orphaned_interfaces, roll_back_connect,
+    CleanupReport, ConnectedVpn, DisconnectReport,
+};
@@ -1,18 +1,28 @@
 use clap::{Parser, Subcommand};
 use pmacs_vpn::gp;
+use pmacs_vpn::gp::events::{self, ConnectEvent};
 use pmacs_vpn::vpn::routing::VpnRouter;
 use pmacs_vpn::vpn::hosts::HostsManager;
 use pmacs_vpn::AuthToken;
 use pmacs_vpn::notifications;
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
 #[cfg(unix)]
 use tokio::signal::unix::{signal, SignalKind};
-use tracing::{error, info, warn, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{debug, error, info, warn, Level};
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
-/// Get the config file path (respects XDG_CONFIG_HOME and HOME)
+/// Config file given with `--config` (set once at startup)
+static CONFIG_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Get the config file path (`--config`, else XDG_CONFIG_HOME and HOME)
 fn get_config_path() -> PathBuf {
+    if let Some(path) = CONFIG_OVERRIDE.get() {
+        return path.clone();
+    }
+
     // Try XDG_CONFIG_HOME first
     if let Ok(xdg) = std::env::var("XDG_CONFIG_HOME") {
         return PathBuf::from(xdg).join("pmacs-vpn").join("config.toml");
@@ -32,6 +42,47 @@ fn get_config_path() -> PathBuf {
     PathBuf::from("pmacs-vpn.toml")
 }
 
+/// Arguments that pass a `--config` override on to a spawned child
+fn config_args() -> Vec<std::ffi::OsString> {
+    match CONFIG_OVERRIDE.get() {
+        Some(path) => vec!["--config".into(), path.clone().into_os_string()],
+        None => vec![],
+    }
+}
+
+/// Profile named in the config file (falls back to the default profile)
+fn config_profile() -> String {
+    pmacs_vpn::Config::load(&get_config_path())
+        .ok()
+        .and_then(|c| c.vpn.profile)
+        .unwrap_or_else(|| pmacs_vpn::state::DEFAULT_PROFILE.to_string())
+}
+
+/// The config's `flush_dns_on_disconnect` preference (on without a config)
+fn flush_dns_on_disconnect() -> bool {
+    pmacs_vpn::Config::load(&get_config_path())
+        .map(|c| c.preferences.flush_dns_on_disconnect)
+        .unwrap_or(true)
+}
+
+/// Update the reconnect activity `status` shows for the config's profile
+fn update_reconnect_status(update: impl FnOnce(&mut pmacs_vpn::ReconnectStatus)) {
+    let profile = config_profile();
+    let mut status = pmacs_vpn::ReconnectStatus::load(&profile)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    update(&mut status);
+    if let Err(e) = status.save(&profile) {
+        debug!("Could not record reconnect status: {}", e);
+    }
+}
+
+/// Load the VPN state for the profile in the config file
+fn load_config_state() -> Result<Option<pmacs_vpn::VpnState>, pmacs_vpn::state::StateError> {
+    pmacs_vpn::VpnState::load_profile(&config_profile())
+}
+
 #[derive(Parser)]
 #[command(name = "pmacs-vpn")]
 #[command(about = "Split-tunnel VPN toolkit for PMACS cluster access")]
@@ -43,6 +94,18 @@ struct Cli {
     /// Enable verbose output
     #[arg(short, long, global = true)]
     verbose: bool,
+
+    /// Only print warnings and errors (no progress lines or connect summary)
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Config file to use instead of the default location
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Skip the root/Administrator check (for setups that grant route and hosts access otherwise)
+    #[arg(long, global = true)]
+    no_privilege_check: bool,
 }
 
 #[derive(Subcommand)]
@@ -65,18 +128,115 @@ enum Commands {
         #[arg(short = 'k', long)]
         keep_alive: bool,
 
+        /// Never send keepalives; the gateway alone decides when an idle tunnel ends
+        #[arg(long, conflicts_with = "keep_alive")]
+        no_keepalive: bool,
+
         /// Run VPN in background
         #[arg(short = 'b', long)]
         background: bool,
 
+        /// Log a one-line summary of every tunnel packet (very chatty)
+        #[arg(long)]
+        trace_packets: bool,
+
+        /// Capture tunnel traffic (both directions) to a pcap file
+        #[arg(long, value_name = "PATH")]
+        pcap: Option<PathBuf>,
+
+        /// Carry traffic over ESP/UDP when the gateway offers it (falls back to SSL)
+        #[arg(long)]
+        udp: bool,
+
+        /// Use this gateway for this run instead of the one in the config
+        #[arg(long, value_name = "HOST", value_parser = parse_gateway_arg)]
+        gateway: Option<String>,
+
+        /// Connect the tunnel to this gateway port (login still uses the gateway's HTTPS port)
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+        port: Option<u16>,
+
+        /// Stop an existing connection for this profile before connecting
+        #[arg(long)]
+        force: bool,
+
+        /// Route hosts even if they resolve to public (non-private) addresses
+        #[arg(long)]
+        allow_public_routes: bool,
+
+        /// Skip hosts that resolve outside the networks the gateway tunnels (default: route them with a warning)
+        #[arg(long)]
+        skip_outside_tunnel: bool,
+
+        /// Add routes only; leave the hosts file alone (names resolve via the system resolver)
+        #[arg(long)]
+        no_hosts: bool,
+
+        /// Fail (and clean up) if any configured host can't be routed
+        #[arg(long)]
+        strict: bool,
+
+        /// Send all IPv4 traffic through the VPN, not just the configured hosts
+        #[arg(long)]
+        full_tunnel: bool,
+
+        /// Stop at the first host that can't be routed and remove the routes already added
+        #[arg(long)]
+        atomic: bool,
+
+        /// Send the DUO push/SMS/call to enrolled device N (e.g. 2 sends "push2"; gateway-dependent)
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..))]
+        duo_device: Option<u8>,
+
+        /// Bind routes to this network interface instead of the detected tunnel device
+        #[arg(long, value_name = "NAME")]
+        interface: Option<String>,
+
+        /// Write the gateway's responses (secrets redacted), tunnel config, platform info and log to DIR
+        #[arg(long, value_name = "DIR")]
+        debug_bundle: Option<PathBuf>,
+
+        /// Read the password from the first line of PATH instead of the keychain or a prompt
+        #[arg(long, value_name = "PATH")]
+        password_file: Option<PathBuf>,
+
+        /// Accept a world-readable --password-file
+        #[arg(long, requires = "password_file")]
+        insecure_perms: bool,
+
+        /// Skip login and use this auth cookie (bare, or OpenConnect's `authcookie=...&user=...` form)
+        #[arg(long, value_name = "COOKIE", conflicts_with_all = ["password_file", "save_password", "forget_password"])]
+        auth_cookie: Option<String>,
+
         /// Internal: PID passed from daemon parent (do not use directly)
         #[arg(long, hide = true)]
         _daemon_pid: Option<u32>,
     },
     /// Disconnect from VPN and clean up routes
-    Disconnect,
+    Disconnect {
+        /// Only disconnect this profile (default: all active connections)
+        #[arg(short, long)]
+        profile: Option<String>,
+        /// Without saved state or routes file, resolve the config's hosts again
+        /// and remove routes to those addresses (they may differ from the ones added)
+        #[arg(long)]
+        resolve: bool,
+    },
+    /// Make background connections re-read the config and reroute changed hosts
+    Reload {
+        /// Only reload this profile (default: all active connections)
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
     /// Show current VPN status
-    Status,
+    Status {
+        /// Probe each `host:port` service over the tunnel
+        #[arg(long)]
+        check: bool,
+        /// Print connections, reconnect activity and the last failure as JSON
+        #[arg(long, conflicts_with = "check")]
+        json: bool,
+    },
     /// Generate default config file
     Init,
     /// Delete stored password for a user
@@ -86,7 +246,30 @@ enum Commands {
         user: String,
     },
     /// Run with system tray (GUI mode)
-    Tray,
+    Tray {
+        /// Connect on startup with the saved password (error notification if that fails)
+        #[arg(long)]
+        auto_connect: bool,
+
+        /// Show nothing on startup but the tray icon (for login items)
+        #[arg(long)]
+        start_hidden: bool,
+    },
+    /// Show which config file is in effect (and, with --show, what it resolves to)
+    Config {
+        /// Print the effective configuration: defaults filled in, hosts_file merged into hosts
+        #[arg(long)]
+        show: bool,
+        /// Print the effective configuration as JSON (implies --show)
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show version, git commit, compiler and supported auth methods/transports
+    Version {
+        /// Print as JSON (for support tooling)
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 /// Check if running with admin privileges (Windows)
@@ -102,17 +285,38 @@ fn is_admin() -> bool {
     unsafe { nix::libc::geteuid() == 0 }
 }
 
+/// One-line explanation of how to re-run a command that needs elevation
+fn privilege_error(cmd: &Commands) -> String {
+    let name = match cmd {
+        Commands::Connect { .. } => "connect",
+        Commands::Disconnect { .. } => "disconnect",
+        Commands::Reload { .. } => "reload",
+        Commands::Tray { .. } => "tray",
+        _ => "",
+    };
+    #[cfg(windows)]
+    let how = "re-run from a terminal opened as Administrator (or use scripts\\connect.ps1)";
+    #[cfg(not(windows))]
+    let how = "re-run with sudo";
+    format!(
+        "'pmacs-vpn {}' changes routes and the hosts file, which needs elevated privileges: {} (--no-privilege-check skips this check)",
+        name, how
+    )
+}
+
 /// Commands that require admin privileges
 fn requires_admin(cmd: &Commands) -> bool {
     match cmd {
         // Connect/Disconnect require root on all platforms (TUN device, routes, /etc/hosts)
-        Commands::Connect { .. } | Commands::Disconnect => true,
+        Commands::Connect { .. } | Commands::Disconnect { .. } => true,
+        // Signalling the daemon needs the same rights it runs with
+        Commands::Reload { .. } => true,
 
         // On Windows, tray needs admin upfront (spawns daemon directly)
         #[cfg(windows)]
-        Commands::Tray => true,
+        Commands::Tray { .. } => true,
         #[cfg(not(windows))]
-        Commands::Tray => false,
+        Commands::Tray { .. } => false,
         _ => false,
     }
 }
@@ -122,18 +326,55 @@ fn requires_admin(cmd: &Commands) -> bool {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
+    if let Some(path) = &cli.config {
+        // Init creates the file and version ignores it; everything else needs it to exist
+        if !matches!(cli.command, Commands::Init | Commands::Version { .. }) && !path.exists() {
+            eprintln!("ERROR: Config file not found: {}", path.display());
+            std::process::exit(1);
+        }
+        // Children may run from another directory
+        let path = std::path::absolute(path).unwrap_or_else(|_| path.clone());
+        let _ = CONFIG_OVERRIDE.set(path);
+    }
+
     // Check if we're running as daemon child (for file logging)
     let is_daemon_child = match &cli.command {
         Commands::Connect { _daemon_pid, .. } => _daemon_pid.is_some(),
         _ => false,
     };
+    let trace_packets = matches!(&cli.command, Commands::Connect { trace_packets: true, .. });
+
+    // The debug bundle gets a copy of the log, so it's set up first
+    let bundle_log = match &cli.command {
+        Commands::Connect { debug_bundle: Some(dir), .. } => {
+            let log_name = if is_daemon_child { "daemon.log" } else { "connect.log" };
+            let log = pmacs_vpn::gp::debug_bundle::install(dir)
+                .and_then(|()| std::fs::File::create(dir.join(log_name)));
+            match log {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    eprintln!("ERROR: Can't write debug bundle to {}: {}", dir.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
 
     // Set up logging
     let level = if cli.verbose {
         Level::DEBUG
+    } else if cli.quiet {
+        Level::WARN
     } else {
         Level::INFO
     };
+    // Packet tracing only raises the tunnel module to trace, not reqwest/rustls
+    let filter = if trace_packets {
+        EnvFilter::new(format!("{},pmacs_vpn::gp::tunnel=trace", level))
+    } else {
+        EnvFilter::new(level.to_string())
+    };
 
     if is_daemon_child {
         // Daemon mode: log to file since stdout/stderr are null
@@ -147,62 +388,90 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         // Create parent directory if needed
         if let Some(parent) = log_path.parent() {
-            let _ = std::fs::create_dir_all(parent);
+            let _ = pmacs_vpn::state::create_private_dir(parent);
         }
 
-        // Open log file (truncate on start for clean logs)
-        let log_file = std::fs::File::create(&log_path)
+        // Open log file (truncate on start for clean logs); it names internal hosts
+        let log_file = pmacs_vpn::state::create_private_file(&log_path)
             .expect("Failed to create daemon log file");
 
+        let writer = match bundle_log {
+            Some(bundle) => BoxMakeWriter::new(Mutex::new(log_file).and(Mutex::new(bundle))),
+            None => BoxMakeWriter::new(Mutex::new(log_file)),
+        };
         let subscriber = FmtSubscriber::builder()
-            .with_max_level(level)
+            .with_env_filter(filter)
             .with_target(false)
             .with_ansi(false) // No color codes in log file
-            .with_writer(Mutex::new(log_file))
+            .with_writer(writer)
             .finish();
         tracing::subscriber::set_global_default(subscriber)?;
         info!("Daemon child started, logging to {:?}", log_path);
     } else {
-        // Normal mode: log to stderr
+        // Normal mode: log to stderr (and the debug bundle, without color codes)
+        let ansi = bundle_log.is_none();
+        let writer = match bundle_log {
+            Some(bundle) => BoxMakeWriter::new(std::io::stderr.and(Mutex::new(bundle))),
+            None => BoxMakeWriter::new(std::io::stderr),
+        };
         let subscriber = FmtSubscriber::builder()
-            .with_max_level(level)
+            .with_env_filter(filter)
             .with_target(false)
-            .with_writer(std::io::stderr)
+            .with_ansi(ansi)
+            .with_writer(writer)
             .finish();
         tracing::subscriber::set_global_default(subscriber)?;
     }
+    if let Some(dir) = pmacs_vpn::gp::debug_bundle::dir() {
+        info!("Writing debug bundle to {} (secrets redacted; review before sharing)", dir.display());
+    }
 
-    // Check admin privileges for commands that need it
-    if requires_admin(&cli.command) && !is_admin() {
-        eprintln!("ERROR: This command requires Administrator privileges.\n");
-        #[cfg(windows)]
-        eprintln!("Options:");
-        #[cfg(windows)]
-        eprintln!("  1. Right-click terminal → Run as Administrator");
-        #[cfg(windows)]
-        eprintln!("  2. Use the desktop shortcut: scripts\\connect.ps1");
-        #[cfg(not(windows))]
-        eprintln!("Run with: sudo pmacs-vpn {}", match &cli.command {
-            Commands::Connect { .. } => "connect",
-            Commands::Disconnect => "disconnect",
-            Commands::Tray => "tray",
-            _ => "",
-        });
+    // Check admin privileges up front, rather than failing on every route
+    if requires_admin(&cli.command) && !cli.no_privilege_check && !is_admin() {
+        eprintln!("ERROR: {}", privilege_error(&cli.command));
         std::process::exit(1);
     }
 
     match cli.command {
-        Commands::Connect { user, save_password, forget_password, keep_alive, background, _daemon_pid } => {
+        Commands::Connect { user, save_password, forget_password, keep_alive, no_keepalive, background, trace_packets, pcap, udp, gateway, port, force, allow_public_routes, skip_outside_tunnel, no_hosts, strict, full_tunnel, atomic, duo_device, interface, debug_bundle, password_file, insecure_perms, auth_cookie, _daemon_pid } => {
+            let flags = TunnelFlags {
+                trace_packets,
+                pcap,
+                udp,
+                no_keepalive,
+                gateway,
+                port,
+                no_privilege_check: cli.no_privilege_check,
+                force,
+                allow_public_routes,
+                skip_outside_tunnel,
+                no_hosts,
+                strict,
+                full_tunnel,
+                atomic,
+                quiet: cli.quiet,
+                duo_device,
+                interface,
+                debug_bundle,
+                password_file,
+                insecure_perms,
+                auth_cookie,
+            };
+            let progress = spawn_progress_printer(cli.quiet, cli.verbose);
             // Background mode: do auth in parent, spawn detached child
             if background {
-                match spawn_daemon(&user, save_password, forget_password, keep_alive).await {
-                    Ok(pid) => {
+                match spawn_daemon(&user, save_password, forget_password, keep_alive, &flags, true, Some(&progress)).await {
+                    Ok(None) => {}
+                    Ok(Some(pid)) => {
                         println!("VPN running in background (PID: {})", pid);
-                        println!("Use 'pmacs-vpn status' to check connection");
-                        println!("Use 'pmacs-vpn disconnect' to stop");
+                        if !cli.quiet {
+                            println!("Use 'pmacs-vpn status' to check connection");
+                            println!("Use 'pmacs-vpn disconnect' to stop");
+                        }
                     }
                     Err(e) => {
                         error!("Failed to start background process: {}", e);
+                        record_connect_failure(&e.to_string());
                         std::process::exit(1);
                     }
                 }
@@ -210,18 +479,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // If _daemon_pid is set, we're running as a background daemon child
                 let is_daemon = _daemon_pid.is_some();
                 info!("Connecting to PMACS VPN...");
-                match connect_vpn(user, save_password, forget_password, keep_alive, is_daemon).await {
+                match connect_vpn(user, save_password, forget_password, keep_alive, &flags, is_daemon, Some(&progress)).await {
                     Ok(()) => info!("VPN connection closed"),
                     Err(e) => {
                         error!("VPN connection failed: {}", e);
+                        record_connect_failure(&e.to_string());
+                        if is_daemon {
+                            report_to_spawner(pmacs_vpn::DaemonHandshake::Error {
+                                pid: std::process::id(),
+                                message: e.to_string(),
+                            });
+                        }
                         std::process::exit(1);
                     }
                 }
             }
         }
-        Commands::Disconnect => {
+        Commands::Disconnect { profile, resolve } => {
             info!("Disconnecting from PMACS VPN...");
-            match disconnect_vpn().await {
+            match disconnect_vpn(profile.as_deref(), resolve).await {
                 Ok(()) => println!("Disconnected successfully"),
                 Err(e) => {
                     error!("Disconnect failed: {}", e);
@@ -229,44 +505,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
         }
-        Commands::Status => {
-            if !pmacs_vpn::VpnState::is_active() {
+        Commands::Reload { profile } => {
+            if let Err(e) = reload_daemons(profile.as_deref()) {
+                error!("Reload failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Status { json: true, .. } => {
+            let report = pmacs_vpn::status::status_for_profile(&config_profile());
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Commands::Status { check, .. } => {
+            let report = pmacs_vpn::status::status_for_profile(&config_profile());
+            if let Some(e) = &report.error {
+                println!("Error reading state: {}", e);
+            } else if report.connections.is_empty() {
                 println!("VPN Status: Not connected");
-            } else {
-                match pmacs_vpn::VpnState::load() {
-                    Ok(Some(state)) => {
-                        // If we have a daemon PID, treat stale PID as disconnected.
-                        if let Some(pid) = state.pid {
-                            if !state.is_daemon_running() {
-                                println!("VPN Status: Not connected");
-                                println!("  Note: Found stale state (PID {} is not running)", pid);
-                                println!("  Cleanup: Run 'sudo pmacs-vpn disconnect' to remove stale routes/hosts");
-                                return Ok(());
-                            }
-                        }
-
-                        // Connected (or foreground state without PID)
-                        let mode = if let Some(pid) = state.pid {
-                            format!("Running (PID: {})", pid)
-                        } else {
-                            "Foreground".to_string()
-                        };
-
-                        println!("VPN Status: Connected");
-                        println!("  Mode: {}", mode);
-                        println!("  Tunnel: {}", state.tunnel_device);
-                        println!("  Gateway: {}", state.gateway);
-                        println!("  Connected: {}", state.connected_at);
-                        println!("  Routes: {}", state.routes.len());
-                        for route in &state.routes {
-                            println!("    {} -> {}", route.hostname, route.ip);
-                        }
-                        println!("  Hosts entries: {}", state.hosts_entries.len());
-                    }
-                    Ok(None) => println!("VPN Status: Not connected"),
-                    Err(e) => println!("Error reading state: {}", e),
+            }
+            for (i, connection) in report.connections.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print_connection_status(connection);
+                if check {
+                    check_service_ports(connection);
                 }
             }
+            if let Some(summary) = report.reconnect.as_ref().and_then(|r| r.summary()) {
+                println!("{}", summary);
+            }
+            if let Some(failure) = &report.last_failure {
+                println!("Last connect attempt failed {}: {}", failure.age(), failure.last_error);
+            }
         }
         Commands::Init => {
             info!("Generating default config...");
@@ -284,7 +554,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
         }
-        Commands::Tray => {
+        Commands::Config { show, json } => {
+            let path = get_config_path();
+            if !show && !json {
+                println!("{}", path.display());
+                return Ok(());
+            }
+            let config = match pmacs_vpn::Config::load(&path) {
+                Ok(config) => config.effective(),
+                Err(e) => {
+                    error!("Failed to load config {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            if json {
+                let shown = serde_json::json!({ "path": path, "config": config, "sources": config.sources });
+                println!("{}", serde_json::to_string_pretty(&shown)?);
+            } else {
+                println!("# Effective configuration from {}", path.display());
+                print!("{}", toml::to_string_pretty(&config)?);
+                // Where each setting came from, once there's more than one file
+                if config.sources.files.len() > 1 {
+                    println!();
+                    println!("# Sources:");
+                    for (key, files) in &config.sources.keys {
+                        let files: Vec<String> = files.iter().map(|f| f.display().to_string()).collect();
+                        println!("#   {} = {}", key, files.join(", "));
+                    }
+                }
+            }
+        }
+        Commands::Version { json } => {
+            let info = pmacs_vpn::BuildInfo::current();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                println!("{}", info);
+            }
+        }
+        Commands::Tray { auto_connect, start_hidden } => {
             // On Windows, detach from console by respawning hidden
             #[cfg(windows)]
             {
@@ -300,7 +608,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let cwd = std::env::current_dir().ok();
 
                     let mut cmd = Command::new(&exe);
+                    cmd.args(config_args());
                     cmd.arg("tray");
+                    if auto_connect {
+                        cmd.arg("--auto-connect");
+                    }
+                    if start_hidden {
+                        cmd.arg("--start-hidden");
+                    }
                     cmd.env("PMACS_VPN_TRAY_HIDDEN", "1");
                     cmd.stdin(Stdio::null());
                     cmd.stdout(Stdio::null());
@@ -329,13 +644,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             // On macOS, run tray synchronously on main thread (AppKit requirement)
             #[cfg(target_os = "macos")]
             {
-                run_tray_mode_sync();
+                run_tray_mode_sync(auto_connect, start_hidden);
             }
 
             // On Windows/Linux, run in async context
             #[cfg(not(target_os = "macos"))]
             {
-                run_tray_mode().await;
+                run_tray_mode(auto_connect, start_hidden).await;
             }
         }
     }
@@ -343,35 +658,161 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-/// Cleanup VPN when tray exits (called on Ctrl+C or normal exit)
-fn cleanup_vpn_on_exit() {
-    // Kill daemon if running
-    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
-        if state.pid.is_some() && state.is_daemon_running() {
-            let _ = state.kill_daemon();
+/// Print the status block for one connection
+fn print_connection_status(connection: &pmacs_vpn::ConnectionStatus) {
+    let state = &connection.state;
+    match connection.health {
+        pmacs_vpn::ConnectionHealth::KillSwitch => {
+            println!("VPN Status: Not connected [{}] - kill switch engaged", state.profile_name());
+            println!(
+                "  Traffic to {} routed destination(s) is blocked",
+                connection.route_count()
+            );
+            println!(
+                "  Reconnect, or run 'sudo pmacs-vpn disconnect --profile {}' to restore normal routing",
+                state.profile_name()
+            );
+            return;
+        }
+        pmacs_vpn::ConnectionHealth::Stale => {
+            println!("VPN Status: Not connected [{}]", state.profile_name());
+            if let Some(pid) = state.pid {
+                println!("  Note: Found stale state (PID {} is not running)", pid);
+            }
+            if let Some(device) = &connection.leftover_interface {
+                println!("  Leftover tunnel device: {}", device);
+            }
+            println!(
+                "  Cleanup: Run 'sudo pmacs-vpn disconnect --profile {}' to remove stale routes/hosts",
+                state.profile_name()
+            );
+            return;
+        }
+        pmacs_vpn::ConnectionHealth::Connected => {}
+    }
+
+    let mode = match (connection.mode, state.pid) {
+        (pmacs_vpn::ConnectionMode::Daemon, Some(pid)) => format!("Running (PID: {})", pid),
+        _ => "Foreground".to_string(),
+    };
+
+    println!("VPN Status: Connected [{}]", state.profile_name());
+    println!("  Mode: {}", mode);
+    println!("  Tunnel: {}", state.tunnel_device);
+    println!("  Gateway: {}", state.gateway);
+    if let Some(host) = &state.gateway_host {
+        println!("  Gateway host: {}", host);
+    }
+    if let Some(tls) = &state.tls {
+        println!("  TLS: {}", tls);
+    }
+    if let Some(full) = &state.full_tunnel {
+        println!(
+            "  Full tunnel: yes (gateway {} pinned via {})",
+            full.gateway_pin, full.original_default.gateway
+        );
+    }
+    if let Some(table) = state.route_table {
+        println!("  Route table: {}", table);
+    }
+    println!("  Connected: {}", state.connected_at);
+    if let Some(age) = connection.reconnect.as_ref().and_then(|r| r.last_reconnect_age()) {
+        println!("  Last reconnect: {}", age);
+    }
+    if let Some(netmask) = &state.netmask {
+        println!("  Netmask (gateway): {}", netmask);
+    }
+    if let Some(network) = &state.onlink_network {
+        println!("  On-link subnet: {}", network);
+    }
+    if let Some(address) = &state.ipv6_address {
+        println!("  IPv6 address: {}", address);
+    }
+    if !state.split_include.is_empty() {
+        println!("  Split-include (gateway): {}", state.split_include.join(", "));
+    }
+    println!("  Routes: {}", connection.route_count());
+    for route in &state.routes {
+        match state.host_comment(&route.hostname) {
+            Some(comment) => println!("    {} -> {}  # {}", route.hostname, route.ip, comment),
+            None => println!("    {} -> {}", route.hostname, route.ip),
+        }
+    }
+    for route in &state.network_routes {
+        match state.host_comment(&route.hostname) {
+            Some(comment) => {
+                println!("    {} -> {} (network)  # {}", route.hostname, route.network, comment)
+            }
+            None => println!("    {} -> {} (network)", route.hostname, route.network),
+        }
+    }
+    if state.manage_hosts {
+        println!("  Hosts entries: {}", state.hosts_entries.len());
+    } else {
+        println!("  Hosts entries: not managed (system resolver)");
+    }
+    if let Some(path) = &state.hosts_file_path {
+        println!("  Hosts file: {}", path.display());
+    }
+    if !state.service_ports.is_empty() {
+        println!("  Services:");
+        for service in &state.service_ports {
+            println!("    {}:{} ({})", service.hostname, service.port, service.ip);
         }
     }
-    // Best-effort route/hosts cleanup (sync version)
-    let _ = std::process::Command::new(std::env::current_exe().unwrap())
-        .arg("disconnect")
-        .output();
 }
 
-/// Run the VPN with system tray GUI
-#[cfg(not(target_os = "macos"))]
-async fn run_tray_mode() {
-    use pmacs_vpn::tray::{TrayApp, TrayCommand, VpnStatus};
-    use pmacs_vpn::notifications;
+/// How long `status --check` waits for each service to accept a connection
+const SERVICE_PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
 
-    // Set up Ctrl+C handler to cleanup on exit
-    let _ = ctrlc::set_handler(move || {
-        cleanup_vpn_on_exit();
-        std::process::exit(0);
-    });
+/// Probe the `host:port` services of a connected state (`status --check`)
+fn check_service_ports(connection: &pmacs_vpn::ConnectionStatus) {
+    if !connection.is_connected() {
+        return;
+    }
+    let state = &connection.state;
+    if state.service_ports.is_empty() {
+        println!("  Check: no services to probe (add a port to a host, e.g. \"host:22\")");
+        return;
+    }
+    println!("  Check:");
+    for service in &state.service_ports {
+        match service.probe(SERVICE_PROBE_TIMEOUT) {
+            Ok(elapsed) => println!(
+                "    {}:{} reachable ({} ms)",
+                service.hostname,
+                service.port,
+                elapsed.as_millis()
+            ),
+            Err(e) => println!("    {}:{} unreachable: {}", service.hostname, service.port, e),
+        }
+    }
+}
+
+/// Cleanup VPN when tray exits (called on Ctrl+C or normal exit)
+fn cleanup_vpn_on_exit() {
+    let Ok(Some(state)) = load_config_state() else {
+        return;
+    };
+    // Kill daemon if running; it cleans up after itself
+    if state.pid.is_some() && state.is_daemon_running() {
+        let _ = state.kill_daemon();
+    }
+    // Best-effort cleanup of whatever it left (a no-op if nothing)
+    let report = pmacs_vpn::cleanup(&state, flush_dns_on_disconnect());
+    for failure in &report.failures {
+        error!("Cleanup failed: {}", failure);
+    }
+}
 
-    // Check if we have config and cached credentials for auto-connect
+/// Tray settings at startup: (auto_connect, save_password, duo_method)
+///
+/// The tray connects on startup when `--auto-connect` was given or a password
+/// is cached. Without either, a setup notification is shown unless
+/// `--start-hidden` was given; a failed auto-connect still notifies.
+fn tray_startup_settings(auto_connect: bool, start_hidden: bool) -> (bool, bool, pmacs_vpn::DuoMethod) {
     let config_path = get_config_path();
-    let (auto_connect, save_password, duo_method) = if config_path.exists() {
+    let (has_cached_password, save_password, duo_method) = if config_path.exists() {
         if let Ok(config) = pmacs_vpn::Config::load(&config_path) {
             let has_cached_password = if let Some(ref username) = config.vpn.username {
                 pmacs_vpn::get_password(username).is_some()
@@ -390,11 +831,28 @@ async fn run_tray_mode() {
         (false, true, pmacs_vpn::DuoMethod::default())
     };
 
-    // Show setup notification if no credentials
-    if !auto_connect {
+    // Show setup notification if no credentials (auto-connect reports that itself)
+    if !has_cached_password && !auto_connect && !start_hidden {
         notifications::notify_setup_required();
     }
 
+    (auto_connect || has_cached_password, save_password, duo_method)
+}
+
+/// Run the VPN with system tray GUI
+#[cfg(not(target_os = "macos"))]
+async fn run_tray_mode(auto_connect: bool, start_hidden: bool) {
+    use pmacs_vpn::tray::{TrayApp, TrayCommand, VpnStatus};
+    use pmacs_vpn::notifications;
+
+    // Set up Ctrl+C handler to cleanup on exit
+    let _ = ctrlc::set_handler(move || {
+        cleanup_vpn_on_exit();
+        std::process::exit(0);
+    });
+
+    let (auto_connect, save_password, duo_method) = tray_startup_settings(auto_connect, start_hidden);
+
     // Create tray app with auto-connect setting
     let (app, command_rx, status_tx, command_tx) = TrayApp::new(auto_connect, save_password, duo_method);
 
@@ -442,15 +900,18 @@ async fn run_tray_mode() {
 
                     // Spawn daemon (auth happens in parent, passes token to child)
                     // Use aggressive keepalive for tray mode (10s instead of 30s)
-                    match rt.block_on(spawn_daemon(&None, false, false, true)) {
-                        Ok(pid) => {
+                    match rt.block_on(spawn_daemon(&None, false, false, true, &TunnelFlags::default(), false, None)) {
+                        Ok(None) => {
+                            let _ = status_tx_clone.send(VpnStatus::Disconnected);
+                        }
+                        Ok(Some(pid)) => {
                             info!("VPN started in background (PID {})", pid);
 
                             // Poll for connection status instead of fixed wait
                             let mut connected = false;
                             for _ in 0..60 {  // max 30 seconds (DUO + TUN setup can be slow)
                                 std::thread::sleep(std::time::Duration::from_millis(500));
-                                if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+                                if let Ok(Some(state)) = load_config_state() {
                                     if state.is_daemon_running() {
                                         notifications::notify_connected();
                                         let _ = status_tx_clone.send(VpnStatus::Connected {
@@ -477,23 +938,25 @@ async fn run_tray_mode() {
                     info!("Tray: Received disconnect command");
                     let _ = status_tx_clone.send(VpnStatus::Disconnecting);
 
-                    // Kill daemon and cleanup
-                    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
-                        if state.pid.is_some() && state.is_daemon_running() {
-                            let _ = state.kill_daemon();
-                        }
-                    }
-
-                    // Cleanup routes and hosts
-                    match rt.block_on(disconnect_vpn()) {
-                        Ok(()) => {
-                            let _ = status_tx_clone.send(VpnStatus::Disconnected);
+                    // Stop the daemon, or clean up what a dead one left
+                    let status = match pmacs_vpn::ConnectedVpn::load(&config_profile()) {
+                        Ok(Some(vpn)) => {
+                            let vpn = vpn.with_dns_flush(flush_dns_on_disconnect());
+                            let report = rt.block_on(vpn.disconnect());
+                            if report.is_clean() {
+                                VpnStatus::Disconnected
+                            } else {
+                                error!("Disconnect incomplete: {}", report.failures.join("; "));
+                                VpnStatus::Error(report.failures.join("; "))
+                            }
                         }
+                        Ok(None) => VpnStatus::Disconnected,
                         Err(e) => {
                             error!("Disconnect error: {}", e);
-                            let _ = status_tx_clone.send(VpnStatus::Error(e.to_string()));
+                            VpnStatus::Error(e.to_string())
                         }
-                    }
+                    };
+                    let _ = status_tx_clone.send(status);
                 }
                 TrayCommand::ShowStatus => {
                     info!("Tray: Show status requested");
@@ -526,11 +989,11 @@ async fn run_tray_mode() {
                 TrayCommand::Exit => {
                     info!("Tray: Exit requested");
                     // Cleanup if connected
-                    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+                    if let Ok(Some(state)) = load_config_state() {
                         if state.pid.is_some() && state.is_daemon_running() {
                             let _ = state.kill_daemon();
                         }
-                        let _ = rt.block_on(disconnect_vpn());
+                        let _ = rt.block_on(disconnect_vpn(Some(&config_profile()), false));
                     }
                     break;
                 }
@@ -539,14 +1002,14 @@ async fn run_tray_mode() {
                     let _ = status_tx_clone.send(VpnStatus::Connecting);
 
                     // Kill existing daemon if running
-                    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+                    if let Ok(Some(state)) = load_config_state() {
                         if state.pid.is_some() && state.is_daemon_running() {
                             let _ = state.kill_daemon();
                         }
                     }
 
                     // Cleanup routes and hosts
-                    let _ = rt.block_on(disconnect_vpn());
+                    let _ = rt.block_on(disconnect_vpn(Some(&config_profile()), false));
 
                     // Now connect (same as Connect handler)
                     let config_path = get_config_path();
@@ -567,13 +1030,16 @@ async fn run_tray_mode() {
                     }
 
                     // Use aggressive keepalive for tray mode
-                    match rt.block_on(spawn_daemon(&None, false, false, true)) {
-                        Ok(pid) => {
+                    match rt.block_on(spawn_daemon(&None, false, false, true, &TunnelFlags::default(), false, None)) {
+                        Ok(None) => {
+                            let _ = status_tx_clone.send(VpnStatus::Disconnected);
+                        }
+                        Ok(Some(pid)) => {
                             info!("VPN reconnected in background (PID {})", pid);
                             let mut connected = false;
                             for _ in 0..60 {
                                 std::thread::sleep(std::time::Duration::from_millis(500));
-                                if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+                                if let Ok(Some(state)) = load_config_state() {
                                     if state.is_daemon_running() {
                                         notifications::notify_connected();
                                         let _ = status_tx_clone.send(VpnStatus::Connected {
@@ -600,12 +1066,12 @@ async fn run_tray_mode() {
                     info!("Tray: Auto-reconnect attempt {}", attempt);
 
                     // Cleanup stale state
-                    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+                    if let Ok(Some(state)) = load_config_state() {
                         if state.pid.is_some() {
                             let _ = state.kill_daemon();
                         }
                     }
-                    let _ = rt.block_on(disconnect_vpn());
+                    let _ = rt.block_on(disconnect_vpn(Some(&config_profile()), false));
 
                     // Check for cached credentials
                     let config_path = get_config_path();
@@ -613,6 +1079,7 @@ async fn run_tray_mode() {
                         Ok(c) => c,
                         Err(e) => {
                             error!("Auto-reconnect failed: config error: {}", e);
+                            update_reconnect_status(|status| status.stop());
                             let _ = status_tx_clone.send(VpnStatus::Error("Config error".to_string()));
                             continue;
                         }
@@ -621,6 +1088,7 @@ async fn run_tray_mode() {
                     let username = config.vpn.username.clone().unwrap_or_default();
                     if username.is_empty() || pmacs_vpn::get_password(&username).is_none() {
                         error!("Auto-reconnect failed: no cached credentials");
+                        update_reconnect_status(|status| status.stop());
                         let _ = status_tx_clone.send(VpnStatus::Error(
                             "Cannot auto-reconnect - no saved credentials".to_string()
                         ));
@@ -628,13 +1096,17 @@ async fn run_tray_mode() {
                     }
 
                     // Attempt to spawn daemon (aggressive keepalive for tray mode)
-                    match rt.block_on(spawn_daemon(&None, false, false, true)) {
-                        Ok(pid) => {
+                    match rt.block_on(spawn_daemon(&None, false, false, true, &TunnelFlags::default(), false, None)) {
+                        Ok(None) => {
+                            update_reconnect_status(|status| status.stop());
+                            let _ = status_tx_clone.send(VpnStatus::Disconnected);
+                        }
+                        Ok(Some(pid)) => {
                             info!("Auto-reconnect: VPN started (PID {})", pid);
                             let mut connected = false;
                             for _ in 0..60 {
                                 std::thread::sleep(std::time::Duration::from_millis(500));
-                                if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+                                if let Ok(Some(state)) = load_config_state() {
                                     if state.is_daemon_running() {
                                         notifications::notify_connected();
                                         let _ = status_tx_clone.send(VpnStatus::Connected {
@@ -645,7 +1117,10 @@ async fn run_tray_mode() {
                                     }
                                 }
                             }
-                            if !connected {
+                            if connected {
+                                update_reconnect_status(|status| status.reconnected());
+                            } else {
+                                update_reconnect_status(|status| status.stop());
                                 let _ = status_tx_clone.send(VpnStatus::Error(
                                     "Auto-reconnect timeout".to_string()
                                 ));
@@ -653,6 +1128,7 @@ async fn run_tray_mode() {
                         }
                         Err(e) => {
                             error!("Auto-reconnect failed: {}", e);
+                            update_reconnect_status(|status| status.stop());
                             let _ = status_tx_clone.send(VpnStatus::Error(format!("Auto-reconnect failed: {}", e)));
                         }
                     }
@@ -662,7 +1138,7 @@ async fn run_tray_mode() {
     });
 
     // Check initial VPN state
-    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+    if let Ok(Some(state)) = load_config_state() {
         if state.is_daemon_running() {
             let _ = status_tx.send(VpnStatus::Connected {
                 ip: state.gateway.to_string(),
@@ -693,48 +1169,62 @@ async fn run_tray_mode() {
         loop {
             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
 
-            if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
-                if state.pid.is_some() {
-                    if state.is_daemon_running() {
-                        WAS_CONNECTED.store(true, Ordering::Relaxed);
+            match daemon_exit_reason(&WAS_CONNECTED) {
+                None => {
+                    if WAS_CONNECTED.load(Ordering::Relaxed) {
                         RECONNECT_ATTEMPTS.store(0, Ordering::Relaxed); // Reset on successful connection
-                    } else if WAS_CONNECTED.swap(false, Ordering::Relaxed) {
-                        // Daemon died unexpectedly (was connected, now dead)
-                        let current_attempt = RECONNECT_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
-
-                        if auto_reconnect_enabled && current_attempt < max_attempts {
-                            info!(
-                                "Health monitor: Daemon died, attempting reconnect ({}/{})",
-                                current_attempt + 1,
-                                max_attempts
-                            );
-
-                            // Calculate backoff delay: base * 2^attempt (capped at 60s)
-                            let delay = std::cmp::min(base_delay * (1 << current_attempt), 60);
-
-                            notifications::notify_reconnecting(current_attempt + 1, max_attempts);
-                            let _ = status_tx_health.send(VpnStatus::Reconnecting {
-                                attempt: current_attempt + 1,
-                                max_attempts,
-                            });
-
-                            // Wait with backoff before reconnecting
-                            tokio::time::sleep(tokio::time::Duration::from_secs(delay as u64)).await;
-
-                            // Trigger reconnect via command channel
-                            let _ = command_tx_health.send(TrayCommand::AutoReconnect {
-                                attempt: current_attempt + 1,
-                            });
+                    }
+                }
+                Some(pmacs_vpn::DisconnectReason::SessionEnded) => {
+                    // Reconnecting would only re-prompt for MFA; the user decides
+                    info!("Health monitor: Gateway ended the session, not reconnecting");
+                    notifications::notify_session_ended();
+                    let _ = status_tx_health.send(VpnStatus::Error(
+                        "Session ended by gateway - connect to log in again".to_string(),
+                    ));
+                    RECONNECT_ATTEMPTS.store(0, Ordering::Relaxed);
+                    update_reconnect_status(|status| status.stop());
+                }
+                Some(pmacs_vpn::DisconnectReason::ConnectionLost) => {
+                    // Daemon died or its tunnel dropped (was connected, now gone)
+                    let current_attempt = RECONNECT_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+
+                    if auto_reconnect_enabled && current_attempt < max_attempts {
+                        info!(
+                            "Health monitor: Daemon died, attempting reconnect ({}/{})",
+                            current_attempt + 1,
+                            max_attempts
+                        );
+
+                        // Calculate backoff delay: base * 2^attempt (capped at 60s)
+                        let delay = std::cmp::min(base_delay * (1 << current_attempt), 60);
+
+                        notifications::notify_reconnecting(current_attempt + 1, max_attempts);
+                        update_reconnect_status(|status| {
+                            status.schedule(current_attempt + 1, max_attempts, delay as u64)
+                        });
+                        let _ = status_tx_health.send(VpnStatus::Reconnecting {
+                            attempt: current_attempt + 1,
+                            max_attempts,
+                        });
+
+                        // Wait with backoff before reconnecting
+                        tokio::time::sleep(tokio::time::Duration::from_secs(delay as u64)).await;
+
+                        // Trigger reconnect via command channel
+                        let _ = command_tx_health.send(TrayCommand::AutoReconnect {
+                            attempt: current_attempt + 1,
+                        });
+                    } else {
+                        info!("Health monitor: Daemon died, max reconnect attempts reached or disabled");
+                        if auto_reconnect_enabled {
+                            notifications::notify_reconnect_failed();
                         } else {
-                            info!("Health monitor: Daemon died, max reconnect attempts reached or disabled");
-                            if auto_reconnect_enabled {
-                                notifications::notify_reconnect_failed();
-                            } else {
-                                notifications::notify_unexpected_disconnect();
-                            }
-                            let _ = status_tx_health.send(VpnStatus::Disconnected);
-                            RECONNECT_ATTEMPTS.store(0, Ordering::Relaxed);
+                            notifications::notify_unexpected_disconnect();
                         }
+                        let _ = status_tx_health.send(VpnStatus::Disconnected);
+                        RECONNECT_ATTEMPTS.store(0, Ordering::Relaxed);
+                        update_reconnect_status(|status| status.stop());
                     }
                 }
             }
@@ -767,7 +1257,7 @@ async fn run_tray_mode() {
 /// Run tray mode synchronously on the main thread (required for macOS)
 /// This creates its own tokio runtime for async operations.
 #[cfg(target_os = "macos")]
-fn run_tray_mode_sync() {
+fn run_tray_mode_sync(auto_connect: bool, start_hidden: bool) {
     use pmacs_vpn::tray::{TrayApp, TrayCommand, VpnStatus};
     use pmacs_vpn::notifications;
 
@@ -777,30 +1267,8 @@ fn run_tray_mode_sync() {
         std::process::exit(0);
     });
 
-    // Check config and credentials
     let config_path = get_config_path();
-    let (auto_connect, save_password, duo_method) = if config_path.exists() {
-        if let Ok(config) = pmacs_vpn::Config::load(&config_path) {
-            let has_cached_password = if let Some(ref username) = config.vpn.username {
-                pmacs_vpn::get_password(username).is_some()
-            } else {
-                false
-            };
-            (
-                has_cached_password,
-                config.preferences.save_password,
-                config.preferences.duo_method.clone(),
-            )
-        } else {
-            (false, true, pmacs_vpn::DuoMethod::default())
-        }
-    } else {
-        (false, true, pmacs_vpn::DuoMethod::default())
-    };
-
-    if !auto_connect {
-        notifications::notify_setup_required();
-    }
+    let (auto_connect, save_password, duo_method) = tray_startup_settings(auto_connect, start_hidden);
 
     // Create tray app
     let (app, command_rx, status_tx, _command_tx) = TrayApp::new(auto_connect, save_password, duo_method);
@@ -862,7 +1330,7 @@ fn run_tray_mode_sync() {
                     info!("Tray: Received disconnect command");
                     let _ = status_tx_clone.send(VpnStatus::Disconnecting);
 
-                    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+                    if let Ok(Some(state)) = load_config_state() {
                         if state.pid.is_some() && state.is_daemon_running() {
                             let _ = state.kill_daemon();
                         }
@@ -890,7 +1358,7 @@ fn run_tray_mode_sync() {
                 }
                 TrayCommand::Exit => {
                     info!("Tray: Exit requested");
-                    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+                    if let Ok(Some(state)) = load_config_state() {
                         if state.pid.is_some() && state.is_daemon_running() {
                             let _ = state.kill_daemon();
                         }
@@ -908,7 +1376,7 @@ fn run_tray_mode_sync() {
     });
 
     // Check initial state
-    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
+    if let Ok(Some(state)) = load_config_state() {
         if state.is_daemon_running() {
             let _ = status_tx.send(VpnStatus::Connected {
                 ip: state.gateway.to_string(),
@@ -919,21 +1387,23 @@ fn run_tray_mode_sync() {
     // Spawn health monitor
     let status_tx_health = status_tx.clone();
     rt.spawn(async move {
-        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::atomic::AtomicBool;
         static WAS_CONNECTED: AtomicBool = AtomicBool::new(false);
 
         loop {
             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
-                if state.pid.is_some() {
-                    if state.is_daemon_running() {
-                        WAS_CONNECTED.store(true, Ordering::Relaxed);
-                    } else if WAS_CONNECTED.swap(false, Ordering::Relaxed) {
-                        info!("Health monitor: Daemon died unexpectedly");
-                        notifications::notify_error("VPN disconnected unexpectedly");
-                        let _ = status_tx_health.send(VpnStatus::Disconnected);
-                    }
+            match daemon_exit_reason(&WAS_CONNECTED) {
+                Some(pmacs_vpn::DisconnectReason::SessionEnded) => {
+                    info!("Health monitor: Gateway ended the session");
+                    notifications::notify_session_ended();
+                    let _ = status_tx_health.send(VpnStatus::Disconnected);
+                }
+                Some(pmacs_vpn::DisconnectReason::ConnectionLost) => {
+                    info!("Health monitor: Daemon died unexpectedly");
+                    notifications::notify_error("VPN disconnected unexpectedly");
+                    let _ = status_tx_health.send(VpnStatus::Disconnected);
                 }
+                None => {}
             }
         }
     });
@@ -943,29 +1413,49 @@ fn run_tray_mode_sync() {
     app.run();
 }
 
+/// Check whether the tray's daemon has gone away since the last check, and why
+///
+/// A crashed daemon leaves its state behind; one whose tunnel dropped cleans
+/// up and leaves a `DisconnectReason`. A user disconnect leaves neither.
+fn daemon_exit_reason(
+    was_connected: &std::sync::atomic::AtomicBool,
+) -> Option<pmacs_vpn::DisconnectReason> {
+    use std::sync::atomic::Ordering;
+
+    let recorded = || pmacs_vpn::DisconnectReason::take(&config_profile()).ok().flatten();
+    match load_config_state() {
+        Ok(Some(state)) if state.pid.is_some() => {
+            if state.is_daemon_running() {
+                was_connected.store(true, Ordering::Relaxed);
+                None
+            } else if was_connected.swap(false, Ordering::Relaxed) {
+                Some(recorded().unwrap_or(pmacs_vpn::DisconnectReason::ConnectionLost))
+            } else {
+                None
+            }
+        }
+        Ok(None) if was_connected.swap(false, Ordering::Relaxed) => recorded(),
+        _ => None,
+    }
+}
+
 /// Spawn VPN as a detached background process (daemon mode)
 /// Does authentication FIRST in parent, then passes token to child
+///
+/// `interactive` is false from the tray, which has no terminal to prompt in.
 async fn spawn_daemon(
     user: &Option<String>,
     save_password: bool,
     forget_password: bool,
     keep_alive: bool,
-) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+    flags: &TunnelFlags,
+    interactive: bool,
+    progress: Option<&tokio::sync::mpsc::Sender<ConnectEvent>>,
+) -> Result<Option<u32>, Box<dyn std::error::Error + Send + Sync>> {
     use std::process::Command;
 
-    // Check if VPN is already connected
-    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
-        if state.pid.is_some() && state.is_daemon_running() {
-            println!("VPN is already running (PID: {:?})", state.pid);
-            println!("Use 'pmacs-vpn disconnect' first, or 'pmacs-vpn status' to check.");
-            return Err("VPN already connected".into());
-        } else if state.pid.is_some() {
-            // Daemon was running but is now dead - clean up stale state
-            println!("Cleaning up stale VPN state from previous session...");
-            // Can't call async cleanup from here easily, just delete state
-            let _ = pmacs_vpn::VpnState::delete();
-        }
-    }
+    // Refuse to stack a second tunnel on a live one (unless --force)
+    prepare_for_connect(flags.force).await?;
 
     // 1. Load config (daemon mode requires existing config)
     let config_path = get_config_path();
@@ -985,6 +1475,10 @@ async fn spawn_daemon(
         println!("No config found. Run 'pmacs-vpn connect' first to set up.");
         return Err("No config file".into());
     };
+    if on_internal_network(&config).await {
+        return Ok(None);
+    }
+    let (gateway, port) = flags.gateway(&config)?;
 
     // 2. Get username
     let (username, username_was_prompted) = if let Some(u) = user.clone() {
@@ -995,97 +1489,137 @@ async fn spawn_daemon(
         (prompt("Username", None), true)  // prompted
     };
 
-    // 3. Handle --forget-password
-    if forget_password {
-        if let Err(e) = pmacs_vpn::delete_password(&username) {
-            warn!("Could not delete stored password: {}", e);
-        } else {
-            info!("Deleted stored password for {}", username);
+    let login = if let Some(cookie) = &flags.auth_cookie {
+        // Logged in elsewhere: no password, prelogin or DUO
+        info!("Using the auth cookie from --auth-cookie, skipping login");
+        gp::LoginResponse::from_cookie(&gateway, Some(&username), cookie).map_err(|e| e.to_string())?
+    } else {
+        // 3. Handle --forget-password
+        if forget_password {
+            if let Err(e) = pmacs_vpn::delete_password(&username) {
+                warn!("Could not delete stored password: {}", e);
+            } else {
+                info!("Deleted stored password for {}", username);
+            }
         }
-    }
 
-    // 4. Get password (from keychain or prompt)
-    let (mut password, mut was_cached) = get_vpn_password(&username, forget_password)
-        .map_err(|e| e.to_string())?;
+        // 4. Get password (from --password-file, keychain or prompt)
+        let (mut password, mut was_cached) = get_vpn_password(&username, forget_password, flags)
+            .map_err(|e| e.to_string())?;
 
-    // 5. Do auth flow
-    println!("Authenticating...");
-    let prelogin = gp::auth::prelogin(&config.vpn.gateway).await?;
-    info!("Auth method: {:?}", prelogin.auth_method);
+        // 5. Do auth flow
+        info!("Authenticating...");
+        events::emit(progress, ConnectEvent::Prelogin).await;
+        let prelogin = gp::auth::prelogin(&gateway, &config.client).await?;
+        info!("Auth method: {:?}", prelogin.auth_method);
 
-    // Get DUO method from config
-    let duo_method = &config.preferences.duo_method;
+        // Get DUO method from config
+        let duo_method = &config.preferences.duo_method;
 
-    // Login loop with password retry on auth failure
-    let login = loop {
-        let duo_passcode = if *duo_method == pmacs_vpn::DuoMethod::Passcode {
-            let code = rpassword::prompt_password("DUO passcode: ")?;
-            Some(code)
-        } else {
-            None
+        // Login loop with password retry on auth failure
+        let login = loop {
+            info!("Logging in ({})...", duo_method.description());
+            if *duo_method == pmacs_vpn::DuoMethod::Push {
+                notifications::notify_duo_push();
+            }
+            let duo_str = duo_method.auth_str_for_device(flags.duo_device);
+            let mfa = mfa_answer(duo_method, duo_str.as_deref(), interactive);
+
+            events::emit(progress, ConnectEvent::LoggingIn).await;
+            match gp::auth::login_with_events(
+                &gateway,
+                &username,
+                &password,
+                mfa,
+                config.preferences.gateway_retries,
+                &config.client,
+                progress,
+            )
+            .await
+            {
+                Ok(login) => break login,
+                Err(gp::AuthError::AuthFailed(msg)) => {
+                    // Nobody to re-prompt when the password comes from a file
+                    if let Some(path) = &flags.password_file {
+                        return Err(format!(
+                            "Login failed with the password from {}: {}",
+                            path.display(),
+                            msg
+                        )
+                        .into());
+                    }
+                    eprintln!("Login failed: {}", msg);
+                    if was_cached {
+                        eprintln!("(Saved password may be stale)");
+                    }
+                    eprintln!();
+                    let prompt = format!("Password for {}: ", username);
+                    password = rpassword::prompt_password(&prompt)?;
+                    was_cached = false;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
         };
+        info!("Login successful!");
 
-        println!("Logging in ({})...", duo_method.description());
-        if *duo_method == pmacs_vpn::DuoMethod::Push {
-            notifications::notify_duo_push();
-        }
-        let duo_str = duo_passcode.as_deref().or_else(|| duo_method.as_auth_str());
+        // 6. Save password if requested or offer to save
+        let should_save = prompt_save_password(save_password, was_cached)
+            .map_err(|e| e.to_string())?;
 
-        match gp::auth::login(&config.vpn.gateway, &username, &password, duo_str).await {
-            Ok(login) => break login,
-            Err(gp::AuthError::AuthFailed(msg)) => {
-                eprintln!("Login failed: {}", msg);
-                if was_cached {
-                    eprintln!("(Saved password may be stale)");
-                }
-                eprintln!();
-                let prompt = format!("Password for {}: ", username);
-                password = rpassword::prompt_password(&prompt)?;
-                was_cached = false;
-                continue;
+        if should_save {
+            match pmacs_vpn::store_password(&username, &password) {
+                Ok(()) => info!("VPN password saved to Keychain"),
+                Err(e) => warn!("Failed to store password: {}", e),
             }
-            Err(e) => return Err(e.into()),
         }
-    };
-    println!("Login successful!");
 
-    // 6. Save password if requested or offer to save
-    let should_save = prompt_save_password(save_password, was_cached)
-        .map_err(|e| e.to_string())?;
-
-    if should_save {
-        match pmacs_vpn::store_password(&username, &password) {
-            Ok(()) => println!("VPN password saved to Keychain"),
-            Err(e) => warn!("Failed to store password: {}", e),
+        // Save username to config if it was prompted
+        if username_was_prompted {
+            let mut updated_config = config.clone();
+            updated_config.vpn.username = Some(username.clone());
+            if let Err(e) = updated_config.save(&config_path) {
+                warn!("Failed to save username to config: {}", e);
+            }
         }
-    }
 
-    // Save username to config if it was prompted
-    if username_was_prompted {
-        let mut updated_config = config.clone();
-        updated_config.vpn.username = Some(username.clone());
-        if let Err(e) = updated_config.save(&config_path) {
-            warn!("Failed to save username to config: {}", e);
-        }
-    }
+        login
+    };
 
-    // 7. Save auth token for daemon
+    // 7. Save auth token for daemon (for the gateway the login response points at, if any)
+    let requested = gateway;
+    let mut gateway = gp::auth::choose_gateway(&login, &requested).await;
+    if flags.auth_cookie.is_some() {
+        // Find out now, not from the child, if the gateway won't take the cookie
+        let (login, retries, client) = (&login, config.preferences.gateway_retries, &config.client);
+        (gateway, _) = gp::auth::with_gateway_fallback(&gateway, &requested, |gateway| async move {
+            gp::auth::getconfig(&gateway, login, None, retries, client).await
+        })
+        .await
+        .map_err(cookie_rejected)?;
+    }
+    let profile = config.vpn.profile.as_deref().unwrap_or(pmacs_vpn::state::DEFAULT_PROFILE);
     let token = AuthToken::new(
-        config.vpn.gateway.clone(),
+        gateway.clone(),
         login.username.clone(),
         login.auth_cookie.clone(),
         login.portal.clone(),
         login.domain.clone(),
-        config.hosts.clone(),
+        config.ordered_hosts(),
         keep_alive,
-    );
-    token.save()?;
+    )
+    .with_login_gateway(requested);
+    token.save(profile)?;
 
     // 8. Spawn daemon child (it will read the token file)
     let exe = std::env::current_exe()?;
     let mut cmd = Command::new(&exe);
+    cmd.args(config_args());
     cmd.arg("connect");
     cmd.arg("--daemon-pid=1");
+    cmd.args(flags.daemon_args()?);
+    // The token carries only the gateway host
+    cmd.arg(format!("--port={}", port));
 
     // Set working directory (needed for config file access)
     if let Ok(cwd) = std::env::current_dir() {
@@ -1108,10 +1642,81 @@ async fn spawn_daemon(
         cmd.creation_flags(CREATE_NEW_PROCESS_GROUP | CREATE_NO_WINDOW);
     }
 
-    let child = cmd.spawn()?;
+    // A handshake left by an earlier daemon would look like this one's
+    let _ = pmacs_vpn::DaemonHandshake::take(profile);
+
+    let mut child = cmd.spawn()?;
     let pid = child.id();
 
-    Ok(pid)
+    wait_for_daemon(&mut child, profile).await?;
+    Ok(Some(pid))
+}
+
+/// Whether to skip connecting because `skip_if_onnet` is set and its probe
+/// host answers without the VPN
+async fn on_internal_network(config: &pmacs_vpn::Config) -> bool {
+    let prefs = &config.preferences;
+    let Some(probe) = prefs.onnet_probe.as_deref().filter(|_| prefs.skip_if_onnet) else {
+        return false;
+    };
+    let timeout = std::time::Duration::from_secs(prefs.onnet_probe_timeout_secs.into());
+    if pmacs_vpn::vpn::routing::is_on_network(probe, timeout).await {
+        println!("{} is reachable without the VPN; already on the PMACS network, not connecting", probe);
+        return true;
+    }
+    info!("{} isn't reachable directly; connecting", probe);
+    false
+}
+
+/// How long `spawn_daemon` waits for the child to report it is connected
+const DAEMON_READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Wait for a spawned daemon's handshake, failing if it reports an error or exits
+///
+/// A daemon still connecting at the timeout is left running; `status` shows
+/// how it turned out.
+async fn wait_for_daemon(
+    child: &mut std::process::Child,
+    profile: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let started = std::time::Instant::now();
+    loop {
+        if let Ok(Some(handshake)) = pmacs_vpn::DaemonHandshake::take(profile) {
+            if handshake.pid() != child.id() {
+                debug!("Ignoring handshake from PID {}", handshake.pid());
+            } else {
+                return match handshake {
+                    pmacs_vpn::DaemonHandshake::Ready { .. } => Ok(()),
+                    pmacs_vpn::DaemonHandshake::Error { message, .. } => {
+                        Err(format!("background connect failed: {}", message).into())
+                    }
+                };
+            }
+        }
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(format!(
+                "background process exited ({}) before connecting; see ~/.pmacs-vpn/daemon.log",
+                status
+            )
+            .into());
+        }
+        if started.elapsed() >= DAEMON_READY_TIMEOUT {
+            warn!(
+                "Background process (PID {}) is still connecting after {}s; check 'pmacs-vpn status'",
+                child.id(),
+                DAEMON_READY_TIMEOUT.as_secs()
+            );
+            return Ok(());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+    }
+}
+
+/// Tell the parent that spawned this daemon how the connect went
+fn report_to_spawner(handshake: pmacs_vpn::DaemonHandshake) {
+    if let Err(e) = handshake.save(&config_profile()) {
+        warn!("Failed to write daemon handshake: {}", e);
+    }
 }
 
 /// Prompt for input with optional default value
@@ -1136,9 +1741,61 @@ fn prompt(label: &str, default: Option<&str>) -> String {
     }
 }
 
-/// Get VPN password from keychain or prompt user
-/// Returns (password, was_cached) where was_cached indicates if password came from keychain
-fn get_vpn_password(username: &str, forget_password: bool) -> Result<(String, bool), String> {
+/// Error for a getconfig that failed with an `--auth-cookie` login
+fn cookie_rejected(error: gp::AuthError) -> String {
+    format!(
+        "The gateway did not accept the auth cookie (expired, or for another gateway or user?): {}",
+        error
+    )
+}
+
+/// How to answer the gateway's MFA challenge with `duo_str` (the method's
+/// factor, None for a passcode)
+///
+/// A passcode is asked for only once the gateway challenges. A push that's
+/// denied or never approved falls back to a passcode when the caller is
+/// `interactive` (the CLI, not the tray) and there's a terminal to type it in.
+fn mfa_answer<'a>(
+    duo_method: &pmacs_vpn::DuoMethod,
+    duo_str: Option<&'a str>,
+    interactive: bool,
+) -> gp::auth::MfaAnswer<'a> {
+    use std::io::IsTerminal;
+    let can_prompt = interactive && std::io::stdin().is_terminal();
+    match duo_str {
+        Some(factor) if *duo_method == pmacs_vpn::DuoMethod::Push && can_prompt => {
+            gp::auth::MfaAnswer::PushOrPrompt {
+                factor,
+                timeout: gp::auth::PUSH_TIMEOUT,
+                prompt: &prompt_passcode,
+            }
+        }
+        Some(factor) => gp::auth::MfaAnswer::Passcode(factor),
+        None => gp::auth::MfaAnswer::Prompt(&prompt_passcode),
+    }
+}
+
+/// Ask for the DUO passcode the gateway's challenge `message` wants
+fn prompt_passcode(message: &str) -> Option<String> {
+    let message = message.trim().trim_end_matches(':');
+    let prompt = if message.is_empty() { "DUO passcode" } else { message };
+    rpassword::prompt_password(format!("{}: ", prompt)).ok()
+}
+
+/// Get VPN password from `--password-file`, keychain, or prompt user
+/// Returns (password, was_cached) where was_cached indicates if password came from
+/// keychain or file (so there's no offer to save it)
+fn get_vpn_password(
+    username: &str,
+    forget_password: bool,
+    flags: &TunnelFlags,
+) -> Result<(String, bool), String> {
+    if let Some(path) = &flags.password_file {
+        let password = pmacs_vpn::read_password_file(path, flags.insecure_perms)?;
+        info!("Using password from {}", path.display());
+        return Ok((password, true));
+    }
+
     #[cfg(target_os = "macos")]
     {
         // On macOS, accessing the keychain may trigger a system dialog.
@@ -1194,32 +1851,21 @@ fn prompt_save_password(save_password_flag: bool, was_cached: bool) -> Result<bo
 }
 
 /// Connect to VPN using native GlobalProtect implementation
-async fn connect_vpn(user: Option<String>, save_password: bool, forget_password: bool, keep_alive: bool, is_daemon: bool) -> Result<(), Box<dyn std::error::Error>> {
+async fn connect_vpn(user: Option<String>, save_password: bool, forget_password: bool, keep_alive: bool, flags: &TunnelFlags, is_daemon: bool, progress: Option<&tokio::sync::mpsc::Sender<ConnectEvent>>) -> Result<(), Box<dyn std::error::Error>> {
     // Check if we're a daemon child with an auth token
     if is_daemon {
-        if let Some(token) = AuthToken::load()? {
+        let profile = config_profile();
+        if let Some(token) = AuthToken::load(&profile)? {
             // Delete token immediately (one-time use)
-            AuthToken::delete()?;
-            return connect_vpn_with_token(token).await;
+            AuthToken::delete(&profile)?;
+            return connect_vpn_with_token(token, flags, progress).await;
         }
         // No token but is_daemon? That's an error
         return Err("Daemon mode requires auth token from parent".into());
     }
 
-    // Check if VPN is already connected
-    if let Ok(Some(state)) = pmacs_vpn::VpnState::load() {
-        if state.pid.is_some() && state.is_daemon_running() {
-            println!("VPN is already running (PID: {:?})", state.pid);
-            println!("Use 'pmacs-vpn disconnect' first, or 'pmacs-vpn status' to check.");
-            return Ok(());
-        } else if state.pid.is_some() {
-            // Daemon was running but is now dead - clean up stale state
-            println!("Cleaning up stale VPN state from previous session...");
-            cleanup_vpn(&state).await?;
-        }
-        // If no PID, it was a foreground session that didn't clean up properly
-        // Proceed with new connection, routes will be overwritten
-    }
+    // Refuse to stack a second tunnel on a live one (unless --force)
+    prepare_for_connect(flags.force).await?;
 
     // Normal interactive flow
     // 1. Load or create config interactively
@@ -1248,9 +1894,16 @@ async fn connect_vpn(user: Option<String>, save_password: bool, forget_password:
                 gateway: "psomvpn.uphs.upenn.edu".to_string(),
                 protocol: "gp".to_string(),
                 username: Some(username_input),
+                profile: None,
+                port: None,
             },
-            hosts: vec!["prometheus.pmacs.upenn.edu".to_string()],
-            preferences: pmacs_vpn::Preferences::default(),
+            priority_hosts: vec![],
+            hosts: vec!["prometheus.pmacs.upenn.edu".into()],
+            hosts_file: None,
+            file_hosts: vec![],
+            search_domains: vec![],
+            dns_servers: vec![],
+            ..Default::default()
         };
 
         // Auto-save config
@@ -1265,6 +1918,10 @@ async fn connect_vpn(user: Option<String>, save_password: bool, forget_password:
         config.save(&config_path)?;
         println!("Config saved to pmacs-vpn.toml\n");
     }
+    if on_internal_network(&config).await {
+        return Ok(());
+    }
+    let (gateway, port) = flags.gateway(&config)?;
 
     // 2. Get username (from arg, config, or prompt)
     let (username, username_was_prompted) = if let Some(u) = user {
@@ -1275,112 +1932,179 @@ async fn connect_vpn(user: Option<String>, save_password: bool, forget_password:
         (prompt("Username", None), true)  // prompted, should save
     };
 
-    // 3. Handle --forget-password: delete stored password before prompting
-    if forget_password {
-        if let Err(e) = pmacs_vpn::delete_password(&username) {
-            warn!("Could not delete stored password: {}", e);
-        } else {
-            info!("Deleted stored password for {}", username);
+    let login = if let Some(cookie) = &flags.auth_cookie {
+        // Logged in elsewhere: no password, prelogin or DUO
+        info!("Using the auth cookie from --auth-cookie, skipping login");
+        gp::LoginResponse::from_cookie(&gateway, Some(&username), cookie)?
+    } else {
+        // 3. Handle --forget-password: delete stored password before prompting
+        if forget_password {
+            if let Err(e) = pmacs_vpn::delete_password(&username) {
+                warn!("Could not delete stored password: {}", e);
+            } else {
+                info!("Deleted stored password for {}", username);
+            }
         }
-    }
 
-    // 4. Get password (from keychain or prompt)
-    let (mut password, mut was_cached) = get_vpn_password(&username, forget_password)?;
+        // 4. Get password (from --password-file, keychain or prompt)
+        let (mut password, mut was_cached) = get_vpn_password(&username, forget_password, flags)?;
 
-    // 5. Auth flow
-    println!("Authenticating...");
-    let prelogin = gp::auth::prelogin(&config.vpn.gateway).await?;
-    info!("Auth method: {:?}", prelogin.auth_method);
+        // 5. Auth flow
+        info!("Authenticating...");
+        events::emit(progress, ConnectEvent::Prelogin).await;
+        let prelogin = gp::auth::prelogin(&gateway, &config.client).await?;
+        info!("Auth method: {:?}", prelogin.auth_method);
 
-    // Get DUO method from config
-    let duo_method = &config.preferences.duo_method;
+        // Get DUO method from config
+        let duo_method = &config.preferences.duo_method;
 
-    // Login loop with password retry on auth failure
-    let login = loop {
-        let duo_passcode = if *duo_method == pmacs_vpn::DuoMethod::Passcode {
-            let code = rpassword::prompt_password("DUO passcode: ")?;
-            Some(code)
-        } else {
-            None
+        // Login loop with password retry on auth failure
+        let login = loop {
+            info!("Logging in ({})...", duo_method.description());
+            if *duo_method == pmacs_vpn::DuoMethod::Push {
+                notifications::notify_duo_push();
+            }
+            let duo_str = duo_method.auth_str_for_device(flags.duo_device);
+            let mfa = mfa_answer(duo_method, duo_str.as_deref(), true);
+
+            events::emit(progress, ConnectEvent::LoggingIn).await;
+            match gp::auth::login_with_events(
+                &gateway,
+                &username,
+                &password,
+                mfa,
+                config.preferences.gateway_retries,
+                &config.client,
+                progress,
+            )
+            .await
+            {
+                Ok(login) => break login,
+                Err(gp::AuthError::AuthFailed(msg)) => {
+                    // Nobody to re-prompt when the password comes from a file
+                    if let Some(path) = &flags.password_file {
+                        return Err(format!(
+                            "Login failed with the password from {}: {}",
+                            path.display(),
+                            msg
+                        )
+                        .into());
+                    }
+                    eprintln!("Login failed: {}", msg);
+                    if was_cached {
+                        eprintln!("(Saved password may be stale)");
+                    }
+                    eprintln!();
+                    let prompt = format!("Password for {}: ", username);
+                    password = rpassword::prompt_password(&prompt)?;
+                    was_cached = false;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
         };
+        info!("Login successful!");
 
-        println!("Logging in ({})...", duo_method.description());
-        if *duo_method == pmacs_vpn::DuoMethod::Push {
-            notifications::notify_duo_push();
-        }
-        let duo_str = duo_passcode.as_deref().or_else(|| duo_method.as_auth_str());
+        // 6. Save password if requested or offer to save
+        let should_save = prompt_save_password(save_password, was_cached)
+            .map_err(|e| e.to_string())?;
 
-        match gp::auth::login(&config.vpn.gateway, &username, &password, duo_str).await {
-            Ok(login) => break login,
-            Err(gp::AuthError::AuthFailed(msg)) => {
-                eprintln!("Login failed: {}", msg);
-                if was_cached {
-                    eprintln!("(Saved password may be stale)");
-                }
-                eprintln!();
-                let prompt = format!("Password for {}: ", username);
-                password = rpassword::prompt_password(&prompt)?;
-                was_cached = false;
-                continue;
+        if should_save {
+            match pmacs_vpn::store_password(&username, &password) {
+                Ok(()) => info!("VPN password saved to Keychain"),
+                Err(e) => warn!("Failed to store password: {}", e),
             }
-            Err(e) => return Err(e.into()),
         }
-    };
-    println!("Login successful!");
 
-    // 6. Save password if requested or offer to save
-    let should_save = prompt_save_password(save_password, was_cached)
-        .map_err(|e| e.to_string())?;
-
-    if should_save {
-        match pmacs_vpn::store_password(&username, &password) {
-            Ok(()) => println!("VPN password saved to Keychain"),
-            Err(e) => warn!("Failed to store password: {}", e),
+        // Save username to config if it was prompted (not from --user or config)
+        if username_was_prompted {
+            let mut updated_config = config.clone();
+            updated_config.vpn.username = Some(username.clone());
+            if let Err(e) = updated_config.save(&config_path) {
+                warn!("Failed to save username to config: {}", e);
+            }
         }
-    }
 
-    // Save username to config if it was prompted (not from --user or config)
-    if username_was_prompted {
-        let mut updated_config = config.clone();
-        updated_config.vpn.username = Some(username.clone());
-        if let Err(e) = updated_config.save(&config_path) {
-            warn!("Failed to save username to config: {}", e);
-        }
-    }
+        login
+    };
 
-    println!("Getting tunnel configuration...");
-    let tunnel_config = gp::auth::getconfig(&config.vpn.gateway, &login, None).await?;
+    // The login response may point at other gateways
+    let requested = gateway;
+    let gateway = gp::auth::choose_gateway(&login, &requested).await;
+
+    info!("Getting tunnel configuration...");
+    let profile_name = config.vpn.profile.as_deref().unwrap_or(pmacs_vpn::state::DEFAULT_PROFILE);
+    let preferred_ip = last_assigned_ip(profile_name);
+    let (login_ref, retries, client) = (&login, config.preferences.gateway_retries, &config.client);
+    let (gateway, tunnel_config) = gp::auth::with_gateway_fallback(&gateway, &requested, |gateway| async move {
+        gp::auth::getconfig(&gateway, login_ref, preferred_ip, retries, client).await
+    })
+    .await
+    .map_err(|e| match flags.auth_cookie {
+        Some(_) => cookie_rejected(e),
+        None => e.to_string(),
+    })?;
+    record_assigned_ip(profile_name, preferred_ip, tunnel_config.internal_ip);
+    events::emit(progress, ConnectEvent::GotConfig).await;
     info!(
         "Tunnel config: IP={} MTU={}",
         tunnel_config.internal_ip, tunnel_config.mtu
     );
 
-    // 6. Create tunnel
-    println!("Establishing tunnel...");
-    let mut tunnel = gp::tunnel::SslTunnel::connect_with_options(
-        &config.vpn.gateway,
-        &login.username,
-        &login.auth_cookie,
-        &tunnel_config,
-        keep_alive,
-        Some(config.preferences.inbound_timeout_secs as u64),
-    )
-    .await?;
+    if !flags.full_tunnel(Some(&config)) {
+        check_route_set(
+            config.ordered_hosts().len(),
+            &tunnel_config,
+            config.preferences.gateway_routes,
+            config.preferences.require_hosts,
+        )?;
+    }
+
+    // 6. Create tunnel (on the gateway getconfig was redirected to, if any)
+    let gateway = tunnel_config.tunnel_gateway(&gateway).to_string();
+    info!("Establishing tunnel...");
+    let options = gp::TunnelOptions::new(&gateway, &login.username, &login.auth_cookie, &tunnel_config)
+        .with_port(port)
+        .with_aggressive_keepalive(keep_alive)
+        .with_keepalive(flags.keepalive(config.preferences.keepalive))
+        .with_keepalive_jitter(config.preferences.keepalive_jitter_percent)
+        .with_inbound_timeout_secs(Some(config.preferences.inbound_timeout_secs as u64))
+        .with_identity(config.client.clone());
+    let mut tunnel = gp::SslTunnel::connect_with(options).await?;
+    tunnel.set_packet_tracing(flags.trace_packets);
+    let capture = start_capture(&mut tunnel, flags.pcap.as_deref())?;
+    events::emit(progress, ConnectEvent::TunnelUp).await;
+    if flags.udp || config.preferences.transport == pmacs_vpn::Transport::Udp {
+        if tunnel.enable_udp(&gateway, &tunnel_config).await {
+            info!("Using UDP (ESP) data channel");
+        } else {
+            info!("UDP data channel unavailable, using SSL");
+        }
+    }
 
     // 7. Prepare state and router
     let gateway_ip = tunnel_config.internal_ip.to_string();
     let tun_name = tunnel.tun_name().to_string();
+    let tls = tunnel.tls_summary().to_string();
+    let gateway_addr = tunnel.gateway_addr();
+    let route_interface = flags.route_interface(&tun_name)?;
     let internal_ip = tunnel_config.internal_ip;
-    let dns_servers = tunnel_config.dns_servers.clone();
-    let hosts_to_route = config.hosts.clone();
-
-    println!("Connected! Press Ctrl+C to disconnect.");
-    println!("  TUN device: {}", tun_name);
-    println!("  Internal IP: {}", internal_ip);
-    if keep_alive {
-        println!("  Keep-alive: aggressive (10s interval)");
+    let dns_servers = resolvers(&tunnel_config, &config.dns_servers);
+    let hosts_to_route = config.ordered_hosts();
+
+    if !flags.quiet {
+        println!("Connected! Press Ctrl+C to disconnect.");
+        println!("  TUN device: {}", tun_name);
+        println!("  Internal IP: {}", internal_ip);
+        if !flags.keepalive(config.preferences.keepalive) {
+            println!("  Keep-alive: off");
+        } else if keep_alive {
+            println!("  Keep-alive: aggressive (10s interval)");
+        }
+        if let Some(length) = tunnel_config.session_length() {
+            println!("  Session expires in: {}", length);
+        }
     }
-    println!("  Session expires in: 16 hours");
 
     // 7. Start tunnel in background FIRST, then add routes
     // This is critical: DNS queries need the tunnel running to forward packets!
@@ -1392,30 +2116,53 @@ async fn connect_vpn(user: Option<String>, save_password: bool, forget_password:
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 
     // 10. Now add routes (the tunnel is running and can forward DNS queries)
-    println!("Adding routes...");
+    info!("Adding routes...");
     // Use interface-aware routing for proper Windows TUN support
-    let router = VpnRouter::with_interface(gateway_ip, tun_name.clone())?;
+    // DNS queries are bound to the tunnel so they don't depend on routing state
+    let route_table = config.preferences.route_table;
+    let ipv6_address =
+        configure_tunnel_ipv6(&tun_name, config.preferences.ipv6, tunnel_config.internal_ip6);
+    let router = VpnRouter::with_interface(gateway_ip, route_interface.clone())?
+        .with_dns_binding(internal_ip)
+        .with_search_domains(&tunnel_config.search_domains(&config.search_domains))
+        .with_route_table(route_table)
+        .with_public_routes(flags.allow_public_routes)
+        .with_tunnel_networks(tunnel_config.vpn_networks(), flags.skip_outside_tunnel)
+        .with_private_preference(config.preferences.prefer_private)
+        .with_ipv6(ipv6_address.is_some())
+        .with_resolve_cache(pmacs_vpn::vpn::routing::DEFAULT_RESOLVE_CACHE_TTL)
+        .with_dns_timings(pmacs_vpn::gp::debug_bundle::dir().is_some());
 
     let mut state = pmacs_vpn::VpnState::new(tun_name, internal_ip);
+    state.set_route_interface(route_interface);
+    state.set_ipv6_address(ipv6_address);
+    state.set_gateway_network(
+        tunnel_config.netmask.clone(),
+        tunnel_config.split_include.clone(),
+    );
+    state.set_gateway_host(Some(gateway.clone()));
+    state.set_tls(tls);
+    state.set_route_table(route_table);
 
     // First add routes to VPN DNS servers
+    let mut dns_routes = Vec::new();
     if !dns_servers.is_empty() {
         info!("VPN DNS servers: {:?}", dns_servers);
-        println!("  Adding routes to VPN DNS servers first...");
+        info!("Adding routes to VPN DNS servers first...");
         for dns_server in &dns_servers {
             let dns_ip = dns_server.to_string();
             match router.add_ip_route(&dns_ip) {
                 Ok(_) => {
                     info!("Added route to DNS server: {}", dns_ip);
-                    println!("    Route to DNS: {}", dns_ip);
+                    dns_routes.push(*dns_server);
                 }
                 Err(e) => {
                     warn!("Failed to add route to DNS {}: {}", dns_ip, e);
                 }
             }
         }
-        println!(
-            "  Using VPN DNS: {}",
+        info!(
+            "Using VPN DNS: {}",
             dns_servers
                 .iter()
                 .map(|ip| ip.to_string())
@@ -1426,45 +2173,101 @@ async fn connect_vpn(user: Option<String>, save_password: bool, forget_password:
         warn!("No VPN DNS servers provided, using system DNS");
     }
 
-    let mut hosts_map = std::collections::HashMap::new();
-    for host in &hosts_to_route {
-        // Try VPN DNS first, fall back to system DNS
-        let result = if !dns_servers.is_empty() {
-            router.add_host_route_with_dns(host, &dns_servers)
-        } else {
-            router.add_host_route(host)
-        };
-
-        match result {
-            Ok(ip) => {
-                state.add_route(host.clone(), ip);
-                state.add_hosts_entry(host.clone(), ip);
-                hosts_map.insert(host.clone(), ip);
-                println!("  Added route: {} -> {}", host, ip);
-            }
-            Err(e) => {
-                error!("Failed to add route for {}: {}", host, e);
-                println!("  WARN: Could not route {} - {}", host, e);
-                println!("        Try: pmacs-vpn connect -v for more details");
+    // Per-host DNS overrides are reached through the tunnel too
+    for dns_ip in override_dns_servers(&hosts_to_route, &dns_servers) {
+        match router.add_ip_route(&dns_ip.to_string()) {
+            Ok(_) => {
+                info!("Added route to DNS server: {} (host override)", dns_ip);
+                dns_routes.push(dns_ip);
             }
+            Err(e) => warn!("Failed to add route to DNS {}: {}", dns_ip, e),
+        }
+    }
+
+    // With --full-tunnel, everything else goes through the tunnel too
+    if flags.full_tunnel(Some(&config))
+        && let Err(e) = route_everything(&router, &mut state, gateway_addr)
+    {
+        pmacs_vpn::roll_back_connect(&router, &state, &dns_routes);
+        tunnel_handle.abort();
+        finish_capture(capture);
+        return Err(e.into());
+    }
+
+    // Then hosts, priority_hosts first (see Config::ordered_hosts)
+    let mut routes = HostRoutes::default();
+    add_onlink_route(&router, &mut state, &tunnel_config, &mut routes.routed_networks);
+    let total = hosts_to_route.len();
+    let mut failed_hosts = Vec::new();
+    for (done, entry) in hosts_to_route.iter().enumerate() {
+        if flags.atomic && !failed_hosts.is_empty() {
+            break;
+        }
+        events::emit(progress, ConnectEvent::AddingRoutes { done, total }).await;
+        if let Err(e) = route_host(&router, &mut state, &mut routes, entry, &dns_servers) {
+            error!("{}", e);
+            failed_hosts.push(entry.name().to_string());
         }
     }
 
-    // 11. Update hosts file
-    let hosts_mgr = HostsManager::new();
-    hosts_mgr.add_entries(&hosts_map)?;
+    pmacs_vpn::gp::debug_bundle::record_dns_timings(&router.dns_timings());
 
-    // 12. Save state for cleanup (include PID if running as daemon)
-    if is_daemon {
-        state.set_pid(std::process::id());
+    // With --atomic, the first failure undoes this connect's routes
+    if let Err(e) = roll_back_routes_on_failure(&router, &state, &dns_routes, &failed_hosts, flags.atomic) {
+        tunnel_handle.abort();
+        finish_capture(capture);
+        return Err(e.into());
+    }
+    events::emit(progress, ConnectEvent::AddingRoutes { done: total, total }).await;
+
+    // Then networks the gateway's split tunnel includes
+    if config.preferences.gateway_routes {
+        for network in add_gateway_routes(&router, &mut state, &tunnel_config, &mut routes.routed_networks) {
+            info!("Added route: {} (from gateway)", network);
+        }
     }
+
+    // 11. Update hosts file (section labeled by profile, if any)
+    let hosts_file_path = config.preferences.hosts_file_path.clone();
+    let manage_hosts = flags.manage_hosts(Some(&config));
+    if manage_hosts {
+        let hosts_mgr = HostsManager::for_path(hosts_file_path.as_deref())
+            .with_label(config.vpn.profile.clone())
+            .with_comments(state.host_comments.clone());
+        hosts_mgr.add_entries(&routes.hosts_map)?;
+    } else {
+        info!("Not managing the hosts file; names resolve through the system resolver");
+    }
+    if config.preferences.manage_resolver {
+        let domains = tunnel_config.search_domains(&config.search_domains);
+        state.set_resolver(configure_resolver(&state, &dns_servers, &domains));
+    }
+    state.set_profile(config.vpn.profile.clone());
+    state.set_manage_hosts(manage_hosts);
+    state.set_hosts_file_path(hosts_file_path);
+
+    // 12. Save state for cleanup; the PID lets a second connect see we're alive
+    state.set_pid(std::process::id());
     state.save()?;
+    let _ = pmacs_vpn::ConnectFailure::clear(state.profile_name());
+    if let Err(e) = pmacs_vpn::RoutesFile::from_state(&state).save() {
+        warn!("Failed to write routes file: {}", e);
+    }
+
+    // With --strict, any host that didn't route fails the whole connect
+    if let Err(e) = check_routed_hosts(total, &failed_hosts, flags.strict) {
+        tunnel_handle.abort();
+        finish_capture(capture);
+        cleanup_vpn(&state)?;
+        return Err(e.into());
+    }
 
-    println!("Routes configured. VPN is ready.");
+    events::emit(progress, ConnectEvent::Ready).await;
+    info!("Routes configured. VPN is ready.");
 
     // Show one-time tip about Touch ID on macOS
     #[cfg(target_os = "macos")]
-    {
+    if !flags.quiet {
         // Check if Touch ID for sudo is configured
         if let Ok(pam_sudo) = std::fs::read_to_string("/etc/pam.d/sudo") {
             if !pam_sudo.contains("pam_tid.so") {
@@ -1476,6 +2279,8 @@ async fn connect_vpn(user: Option<String>, save_password: bool, forget_password:
     }
 
     // 13. Wait for tunnel completion or shutdown signal
+    let tunnel_task = tunnel_handle.abort_handle();
+    let mut tunnel_dropped = false;
     let result = {
         #[cfg(unix)]
         {
@@ -1484,25 +2289,22 @@ async fn connect_vpn(user: Option<String>, save_password: bool, forget_password:
 
             tokio::select! {
                 result = tunnel_handle => {
-                    match result {
-                        Ok(Ok(())) => Ok(()),
-                        Ok(Err(e)) => Err(Box::new(e) as Box<dyn std::error::Error>),
-                        Err(e) => Err(Box::new(e) as Box<dyn std::error::Error>),
-                    }
+                    tunnel_dropped = true;
+                    tunnel_ended(result, None)
                 }
                 _ = tokio::signal::ctrl_c() => {
                     info!("Received interrupt signal");
-                    println!("\nDisconnecting...");
+                    info!("Disconnecting...");
                     Ok(())
                 }
                 _ = sigterm.recv() => {
                     info!("Received SIGTERM");
-                    println!("\nDisconnecting...");
+                    info!("Disconnecting...");
                     Ok(())
                 }
                 _ = sighup.recv() => {
                     info!("Received SIGHUP");
-                    println!("\nDisconnecting...");
+                    info!("Disconnecting...");
                     Ok(())
                 }
             }
@@ -1511,72 +2313,144 @@ async fn connect_vpn(user: Option<String>, save_password: bool, forget_password:
         {
             tokio::select! {
                 result = tunnel_handle => {
-                    match result {
-                        Ok(Ok(())) => Ok(()),
-                        Ok(Err(e)) => Err(Box::new(e) as Box<dyn std::error::Error>),
-                        Err(e) => Err(Box::new(e) as Box<dyn std::error::Error>),
-                    }
+                    tunnel_dropped = true;
+                    tunnel_ended(result, None)
                 }
                 _ = tokio::signal::ctrl_c() => {
                     info!("Received interrupt signal");
-                    println!("\nDisconnecting...");
+                    info!("Disconnecting...");
                     Ok(())
                 }
             }
         }
     };
 
-    // 12. Cleanup
-    cleanup_vpn(&state).await?;
+    finish_capture(capture);
+
+    // 12. Cleanup, or keep routed hosts blocked if the tunnel dropped
+    if tunnel_dropped && config.preferences.kill_switch {
+        engage_kill_switch(&mut state)?;
+        warn!("Tunnel dropped. Kill switch engaged: traffic to VPN hosts is blocked.");
+        warn!("Reconnect, or run 'pmacs-vpn disconnect' to restore normal routing.");
+    } else {
+        disconnect_here(state, tunnel_task).await?;
+    }
 
     result
 }
 
 /// Connect to VPN using pre-authenticated token (daemon child)
-async fn connect_vpn_with_token(token: AuthToken) -> Result<(), Box<dyn std::error::Error>> {
+async fn connect_vpn_with_token(
+    token: AuthToken,
+    flags: &TunnelFlags,
+    progress: Option<&tokio::sync::mpsc::Sender<ConnectEvent>>,
+) -> Result<(), Box<dyn std::error::Error>> {
     info!("Daemon: connecting with auth token...");
 
-    // Load config for timeout settings
+    // Load config for timeout settings and profile name
     let config_path = get_config_path();
-    let inbound_timeout = if config_path.exists() {
-        pmacs_vpn::Config::load(&config_path)
-            .map(|c| c.preferences.inbound_timeout_secs as u64)
-            .unwrap_or(45)
+    let config = if config_path.exists() {
+        pmacs_vpn::Config::load(&config_path).ok()
     } else {
-        45 // default
+        None
     };
-
-    // Get tunnel config using the auth cookie
-    let tunnel_config = gp::auth::getconfig_with_cookie(
-        &token.gateway,
-        &token.username,
-        &token.auth_cookie,
-        &token.portal,
-        &token.domain,
-        None,
-    ).await?;
+    let inbound_timeout = config
+        .as_ref()
+        .map(|c| c.preferences.inbound_timeout_secs as u64)
+        .unwrap_or(45); // default
+    let profile = config.as_ref().and_then(|c| c.vpn.profile.clone());
+    let kill_switch = config.as_ref().is_some_and(|c| c.preferences.kill_switch);
+    let transport = config
+        .as_ref()
+        .map(|c| c.preferences.transport)
+        .unwrap_or_default();
+    let keepalive = config
+        .as_ref()
+        .map(|c| c.preferences.keepalive)
+        .unwrap_or_default();
+    let keepalive_jitter = config
+        .as_ref()
+        .map(|c| c.preferences.keepalive_jitter_percent)
+        .unwrap_or(gp::tunnel::DEFAULT_KEEPALIVE_JITTER_PERCENT);
+    let search_domains = config
+        .as_ref()
+        .map(|c| c.search_domains.clone())
+        .unwrap_or_default();
+    let configured_dns = config
+        .as_ref()
+        .map(|c| c.dns_servers.clone())
+        .unwrap_or_default();
+    let gateway_retries = config
+        .as_ref()
+        .map(|c| c.preferences.gateway_retries)
+        .unwrap_or(gp::auth::DEFAULT_GATEWAY_RETRIES);
+    let gateway_routes = config.as_ref().is_none_or(|c| c.preferences.gateway_routes);
+    let require_hosts = config.as_ref().is_some_and(|c| c.preferences.require_hosts);
+    let route_table = config.as_ref().and_then(|c| c.preferences.route_table);
+    let ipv6 = config.as_ref().is_some_and(|c| c.preferences.ipv6);
+    let prefer_private = config.as_ref().is_none_or(|c| c.preferences.prefer_private);
+    let hosts_file_path = config.as_ref().and_then(|c| c.preferences.hosts_file_path.clone());
+    let manage_resolver = config.as_ref().is_some_and(|c| c.preferences.manage_resolver);
+    let identity = config.as_ref().map(|c| c.client.clone()).unwrap_or_default();
+
+    // Get tunnel config using the auth cookie, asking for the last address again
+    let profile_name = profile.as_deref().unwrap_or(pmacs_vpn::state::DEFAULT_PROFILE);
+    let preferred_ip = last_assigned_ip(profile_name);
+    let login_gateway = token.login_gateway.clone().unwrap_or_else(|| token.gateway.clone());
+    let (gateway, tunnel_config) = gp::auth::with_gateway_fallback(&token.gateway, &login_gateway, |gateway| {
+        let (token, identity) = (&token, &identity);
+        async move {
+            gp::auth::getconfig_with_cookie(
+                &gateway,
+                &token.username,
+                &token.auth_cookie,
+                &token.portal,
+                &token.domain,
+                preferred_ip,
+                gateway_retries,
+                identity,
+            )
+            .await
+        }
+    })
+    .await?;
+    record_assigned_ip(profile_name, preferred_ip, tunnel_config.internal_ip);
+    events::emit(progress, ConnectEvent::GotConfig).await;
     info!(
         "Tunnel config: IP={} MTU={}",
         tunnel_config.internal_ip, tunnel_config.mtu
     );
 
-    // Create tunnel
-    let mut tunnel = gp::tunnel::SslTunnel::connect_with_options(
-        &token.gateway,
-        &token.username,
-        &token.auth_cookie,
-        &tunnel_config,
-        token.keep_alive,
-        Some(inbound_timeout),
-    )
-    .await?;
+    if !flags.full_tunnel(config.as_ref()) {
+        check_route_set(token.hosts.len(), &tunnel_config, gateway_routes, require_hosts)?;
+    }
+
+    // Create tunnel (on the gateway getconfig was redirected to, if any)
+    let tunnel_gateway = tunnel_config.tunnel_gateway(&gateway).to_string();
+    let options = gp::TunnelOptions::new(&tunnel_gateway, &token.username, &token.auth_cookie, &tunnel_config)
+        .with_port(flags.port.unwrap_or(gp::tunnel::DEFAULT_TUNNEL_PORT))
+        .with_aggressive_keepalive(token.keep_alive)
+        .with_keepalive(flags.keepalive(keepalive))
+        .with_keepalive_jitter(keepalive_jitter)
+        .with_inbound_timeout_secs(Some(inbound_timeout))
+        .with_identity(identity);
+    let mut tunnel = gp::SslTunnel::connect_with(options).await?;
+    tunnel.set_packet_tracing(flags.trace_packets);
+    let capture = start_capture(&mut tunnel, flags.pcap.as_deref())?;
+    events::emit(progress, ConnectEvent::TunnelUp).await;
+    if flags.udp || transport == pmacs_vpn::Transport::Udp {
+        tunnel.enable_udp(&tunnel_gateway, &tunnel_config).await;
+    }
 
     // Prepare state and router
     let gateway_ip = tunnel_config.internal_ip.to_string();
     let tun_name = tunnel.tun_name().to_string();
+    let tls = tunnel.tls_summary().to_string();
+    let gateway_addr = tunnel.gateway_addr();
+    let route_interface = flags.route_interface(&tun_name)?;
     let internal_ip = tunnel_config.internal_ip;
-    let dns_servers = tunnel_config.dns_servers.clone();
-    let hosts_to_route = token.hosts.clone();
+    let dns_servers = resolvers(&tunnel_config, &configured_dns);
+    let mut hosts_to_route = token.hosts.clone();
 
     info!("Daemon: tunnel established, TUN={}", tun_name);
 
@@ -1588,145 +2462,1326 @@ async fn connect_vpn_with_token(token: AuthToken) -> Result<(), Box<dyn std::err
     // Give the tunnel a moment to start
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 
-    // Add routes
-    let router = VpnRouter::with_interface(gateway_ip, tun_name.clone())?;
+    // Add routes (DNS queries bound to the tunnel)
+    let ipv6_address = configure_tunnel_ipv6(&tun_name, ipv6, tunnel_config.internal_ip6);
+    let router = VpnRouter::with_interface(gateway_ip, route_interface.clone())?
+        .with_dns_binding(internal_ip)
+        .with_search_domains(&tunnel_config.search_domains(&search_domains))
+        .with_route_table(route_table)
+        .with_public_routes(flags.allow_public_routes)
+        .with_tunnel_networks(tunnel_config.vpn_networks(), flags.skip_outside_tunnel)
+        .with_private_preference(prefer_private)
+        .with_ipv6(ipv6_address.is_some())
+        .with_resolve_cache(pmacs_vpn::vpn::routing::DEFAULT_RESOLVE_CACHE_TTL)
+        .with_dns_timings(pmacs_vpn::gp::debug_bundle::dir().is_some());
     let mut state = pmacs_vpn::VpnState::new(tun_name, internal_ip);
-
-    // Route to DNS servers first
-    for dns_server in &dns_servers {
+    state.set_route_interface(route_interface);
+    state.set_ipv6_address(ipv6_address);
+    state.set_gateway_network(
+        tunnel_config.netmask.clone(),
+        tunnel_config.split_include.clone(),
+    );
+    state.set_gateway_host(Some(tunnel_gateway.clone()));
+    state.set_tls(tls);
+    state.set_route_table(route_table);
+
+    // Route to DNS servers first (including per-host overrides)
+    let mut dns_routes = Vec::new();
+    for dns_server in dns_servers
+        .iter()
+        .copied()
+        .chain(override_dns_servers(&hosts_to_route, &dns_servers))
+    {
         let dns_ip = dns_server.to_string();
-        if let Err(e) = router.add_ip_route(&dns_ip) {
-            warn!("Failed to add route to DNS {}: {}", dns_ip, e);
+        match router.add_ip_route(&dns_ip) {
+            Ok(_) => dns_routes.push(dns_server),
+            Err(e) => warn!("Failed to add route to DNS {}: {}", dns_ip, e),
         }
     }
 
-    // Route to target hosts
-    let mut hosts_map = std::collections::HashMap::new();
-    for host in &hosts_to_route {
-        let result = if !dns_servers.is_empty() {
-            router.add_host_route_with_dns(host, &dns_servers)
-        } else {
-            router.add_host_route(host)
-        };
+    // With --full-tunnel, everything else goes through the tunnel too
+    if flags.full_tunnel(config.as_ref())
+        && let Err(e) = route_everything(&router, &mut state, gateway_addr)
+    {
+        pmacs_vpn::roll_back_connect(&router, &state, &dns_routes);
+        tunnel_handle.abort();
+        finish_capture(capture);
+        return Err(e.into());
+    }
 
-        match result {
-            Ok(ip) => {
-                state.add_route(host.clone(), ip);
-                state.add_hosts_entry(host.clone(), ip);
-                hosts_map.insert(host.clone(), ip);
-                info!("Added route: {} -> {}", host, ip);
-            }
-            Err(e) => {
-                error!("Failed to add route for {}: {}", host, e);
-            }
+    // Route to target hosts, priority_hosts first (see Config::ordered_hosts)
+    let mut routes = HostRoutes::default();
+    add_onlink_route(&router, &mut state, &tunnel_config, &mut routes.routed_networks);
+    let total = hosts_to_route.len();
+    let mut failed_hosts = Vec::new();
+    for (done, entry) in hosts_to_route.iter().enumerate() {
+        if flags.atomic && !failed_hosts.is_empty() {
+            break;
+        }
+        events::emit(progress, ConnectEvent::AddingRoutes { done, total }).await;
+        if let Err(e) = route_host(&router, &mut state, &mut routes, entry, &dns_servers) {
+            error!("{}", e);
+            failed_hosts.push(entry.name().to_string());
+        }
+    }
+
+    pmacs_vpn::gp::debug_bundle::record_dns_timings(&router.dns_timings());
+
+    // With --atomic, the first failure undoes this connect's routes
+    if let Err(e) = roll_back_routes_on_failure(&router, &state, &dns_routes, &failed_hosts, flags.atomic) {
+        tunnel_handle.abort();
+        finish_capture(capture);
+        return Err(e.into());
+    }
+    events::emit(progress, ConnectEvent::AddingRoutes { done: total, total }).await;
+
+    // Then networks the gateway's split tunnel includes
+    if gateway_routes {
+        for network in add_gateway_routes(&router, &mut state, &tunnel_config, &mut routes.routed_networks) {
+            info!("Added gateway route: {}", network);
         }
     }
 
-    // Update hosts file
-    let hosts_mgr = HostsManager::new();
-    hosts_mgr.add_entries(&hosts_map)?;
+    // Update hosts file (section labeled by profile, if any)
+    let manage_hosts = flags.manage_hosts(config.as_ref());
+    if manage_hosts {
+        let hosts_mgr = HostsManager::for_path(hosts_file_path.as_deref())
+            .with_label(profile.clone())
+            .with_comments(state.host_comments.clone());
+        hosts_mgr.add_entries(&routes.hosts_map)?;
+    } else {
+        info!("Daemon: not managing the hosts file");
+    }
+    if manage_resolver {
+        let domains = tunnel_config.search_domains(&search_domains);
+        state.set_resolver(configure_resolver(&state, &dns_servers, &domains));
+    }
+    state.set_profile(profile);
+    state.set_manage_hosts(manage_hosts);
+    state.set_hosts_file_path(hosts_file_path);
 
     // Save state with PID
     state.set_pid(std::process::id());
     state.save()?;
+    // A reason left by an earlier session no longer applies
+    let _ = pmacs_vpn::DisconnectReason::take(state.profile_name());
+    let _ = pmacs_vpn::ConnectFailure::clear(state.profile_name());
+    if let Err(e) = pmacs_vpn::RoutesFile::from_state(&state).save() {
+        warn!("Failed to write routes file: {}", e);
+    }
 
+    // With --strict, any host that didn't route fails the whole connect
+    if let Err(e) = check_routed_hosts(total, &failed_hosts, flags.strict) {
+        tunnel_handle.abort();
+        finish_capture(capture);
+        cleanup_vpn(&state)?;
+        return Err(e.into());
+    }
+
+    events::emit(progress, ConnectEvent::Ready).await;
     info!("Daemon: VPN ready");
+    report_to_spawner(pmacs_vpn::DaemonHandshake::Ready {
+        pid: std::process::id(),
+    });
 
-    // Wait for tunnel completion or shutdown signal
+    // Wait for tunnel completion or shutdown signal, reloading hosts on request
+    let mut tunnel_handle = tunnel_handle;
+    let tunnel_task = tunnel_handle.abort_handle();
+    let mut tunnel_dropped = false;
     let result = {
         #[cfg(unix)]
         {
             let mut sigterm = signal(SignalKind::terminate())?;
             let mut sighup = signal(SignalKind::hangup())?;
 
-            tokio::select! {
-                result = tunnel_handle => {
-                    match result {
-                        Ok(Ok(())) => Ok(()),
-                        Ok(Err(e)) => Err(Box::new(e) as Box<dyn std::error::Error>),
-                        Err(e) => Err(Box::new(e) as Box<dyn std::error::Error>),
+            loop {
+                tokio::select! {
+                    result = &mut tunnel_handle => {
+                        tunnel_dropped = true;
+                        break tunnel_ended(result, Some(state.profile_name()));
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        info!("Daemon: received shutdown signal");
+                        break Ok(());
+                    }
+                    _ = sigterm.recv() => {
+                        info!("Daemon: received SIGTERM");
+                        break Ok(());
+                    }
+                    _ = sighup.recv() => {
+                        info!("Daemon: received SIGHUP, reloading config");
+                        if let Err(e) = reload_hosts(&router, &mut state, &mut routes, &mut hosts_to_route, &dns_servers) {
+                            error!("Daemon: config reload failed, keeping current routes: {}", e);
+                        }
                     }
-                }
-                _ = tokio::signal::ctrl_c() => {
-                    info!("Daemon: received shutdown signal");
-                    Ok(())
-                }
-                _ = sigterm.recv() => {
-                    info!("Daemon: received SIGTERM");
-                    Ok(())
-                }
-                _ = sighup.recv() => {
-                    info!("Daemon: received SIGHUP");
-                    Ok(())
                 }
             }
         }
         #[cfg(not(unix))]
         {
-            tokio::select! {
-                result = tunnel_handle => {
-                    match result {
-                        Ok(Ok(())) => Ok(()),
-                        Ok(Err(e)) => Err(Box::new(e) as Box<dyn std::error::Error>),
-                        Err(e) => Err(Box::new(e) as Box<dyn std::error::Error>),
+            let mut requests = pmacs_vpn::state::DaemonRequests::listen();
+
+            loop {
+                tokio::select! {
+                    result = &mut tunnel_handle => {
+                        tunnel_dropped = true;
+                        break tunnel_ended(result, Some(state.profile_name()));
                     }
-                }
-                _ = tokio::signal::ctrl_c() => {
-                    info!("Daemon: received shutdown signal");
-                    Ok(())
+                    _ = tokio::signal::ctrl_c() => {
+                        info!("Daemon: received shutdown signal");
+                        break Ok(());
+                    }
+                    request = requests.next() => match request {
+                        pmacs_vpn::state::DaemonRequest::Shutdown => {
+                            info!("Daemon: received shutdown request");
+                            break Ok(());
+                        }
+                        pmacs_vpn::state::DaemonRequest::Reload => {
+                            info!("Daemon: received reload request, reloading config");
+                            if let Err(e) = reload_hosts(&router, &mut state, &mut routes, &mut hosts_to_route, &dns_servers) {
+                                error!("Daemon: config reload failed, keeping current routes: {}", e);
+                            }
+                        }
+                    },
                 }
             }
         }
     };
 
-    // Cleanup
-    cleanup_vpn(&state).await?;
+    finish_capture(capture);
+
+    // Cleanup, or keep routed hosts blocked if the tunnel dropped
+    if tunnel_dropped && kill_switch {
+        engage_kill_switch(&mut state)?;
+    } else {
+        disconnect_here(state, tunnel_task).await?;
+    }
 
     result
 }
 
+/// Whether the in-place route counter stands in for per-host log lines
+static ROUTE_COUNTER: AtomicBool = AtomicBool::new(false);
+
+/// Log a line about one host's route: at info level, or debug while the
+/// route counter is showing (a log line would break it up)
+fn log_route(message: std::fmt::Arguments) {
+    if ROUTE_COUNTER.load(Ordering::Relaxed) {
+        debug!("{}", message);
+    } else {
+        info!("{}", message);
+    }
+}
+
+/// Print the connect progress that the step-by-step output doesn't cover
+///
+/// Prints nothing with `--quiet`. On a terminal at the default log level,
+/// routing shows as an in-place counter instead of a line per host; with
+/// `--verbose` the per-host lines are back and the counter is off. Returns
+/// the sender to hand to the connect flow.
+fn spawn_progress_printer(quiet: bool, verbose: bool) -> tokio::sync::mpsc::Sender<ConnectEvent> {
+    use std::io::{IsTerminal, Write};
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    // An in-place counter only makes sense on a terminal, and only if no log
+    // lines land in the middle of it
+    let counter = !quiet && !verbose && std::io::stdout().is_terminal() && std::io::stderr().is_terminal();
+    ROUTE_COUNTER.store(counter, Ordering::Relaxed);
+    tokio::spawn(async move {
+        let mut counting = false;
+        while let Some(event) = rx.recv().await {
+            match event {
+                ConnectEvent::AwaitingMfa if !quiet => println!("Waiting for MFA approval..."),
+                ConnectEvent::AddingRoutes { done, total } if counter && total > 0 => {
+                    let mut stdout = std::io::stdout();
+                    let _ = write!(stdout, "\rRouting hosts: {}", event.route_progress().unwrap_or_default());
+                    let _ = stdout.flush();
+                    counting = done < total;
+                    if !counting {
+                        println!();
+                    }
+                }
+                other => debug!("Connect progress: {:?}", other),
+            }
+        }
+        // The connect failed partway through
+        if counting {
+            println!();
+        }
+    });
+    tx
+}
+
+/// Per-run options from `connect` flags, forwarded to the daemon child
+#[derive(Debug, Clone, Default)]
+struct TunnelFlags {
+    /// Log a summary of every packet (`--trace-packets`)
+    trace_packets: bool,
+    /// Capture file (`--pcap`)
+    pcap: Option<PathBuf>,
+    /// Prefer the ESP/UDP data channel (`--udp`)
+    udp: bool,
+    /// Don't send keepalives (`--no-keepalive`)
+    no_keepalive: bool,
+    /// Gateway override for this run (`--gateway`)
+    gateway: Option<String>,
+    /// Tunnel port (`--port`); the parent always passes the child the one it resolved
+    port: Option<u16>,
+    /// Skip the elevation check (`--no-privilege-check`)
+    no_privilege_check: bool,
+    /// Replace a running connection (`--force`); handled before the child starts
+    force: bool,
+    /// Route hosts that resolve to public addresses (`--allow-public-routes`)
+    allow_public_routes: bool,
+    /// Skip hosts outside the gateway's tunneled networks (`--skip-outside-tunnel`)
+    skip_outside_tunnel: bool,
+    /// Leave the hosts file alone (`--no-hosts`)
+    no_hosts: bool,
+    /// Fail the connect if any host can't be routed (`--strict`)
+    strict: bool,
+    /// Route all IPv4 traffic through the tunnel (`--full-tunnel`)
+    full_tunnel: bool,
+    /// Roll back and fail at the first host that can't be routed (`--atomic`)
+    atomic: bool,
+    /// Skip progress output (`--quiet`); the daemon child prints nothing anyway
+    quiet: bool,
+    /// DUO device to send the factor to (`--duo-device`); login happens before the child starts
+    duo_device: Option<u8>,
+    /// Interface to bind routes to instead of the TUN device (`--interface`)
+    interface: Option<String>,
+    /// Debug bundle directory (`--debug-bundle`); installed before logging starts
+    debug_bundle: Option<PathBuf>,
+    /// Password file (`--password-file`); read before the child starts
+    password_file: Option<PathBuf>,
+    /// Accept a world-readable password file (`--insecure-perms`)
+    insecure_perms: bool,
+    /// Auth cookie from another login (`--auth-cookie`); only the parent uses it
+    auth_cookie: Option<String>,
+}
+
+impl TunnelFlags {
+    /// Command-line arguments that reproduce these flags in the daemon child
+    fn daemon_args(&self) -> std::io::Result<Vec<std::ffi::OsString>> {
+        let mut args = Vec::new();
+        if self.trace_packets {
+            args.push("--trace-packets".into());
+        }
+        if let Some(path) = &self.pcap {
+            // The child may not share our idea of "relative"
+            args.push("--pcap".into());
+            args.push(std::path::absolute(path)?.into_os_string());
+        }
+        if self.udp {
+            args.push("--udp".into());
+        }
+        if self.no_keepalive {
+            args.push("--no-keepalive".into());
+        }
+        if self.no_privilege_check {
+            args.push("--no-privilege-check".into());
+        }
+        if self.allow_public_routes {
+            args.push("--allow-public-routes".into());
+        }
+        if self.skip_outside_tunnel {
+            args.push("--skip-outside-tunnel".into());
+        }
+        if self.no_hosts {
+            args.push("--no-hosts".into());
+        }
+        if self.strict {
+            args.push("--strict".into());
+        }
+        if self.full_tunnel {
+            args.push("--full-tunnel".into());
+        }
+        if self.atomic {
+            args.push("--atomic".into());
+        }
+        if let Some(interface) = &self.interface {
+            args.push("--interface".into());
+            args.push(interface.into());
+        }
+        if let Some(dir) = &self.debug_bundle {
+            // The child does getconfig and adds its log to the same bundle
+            args.push("--debug-bundle".into());
+            args.push(std::path::absolute(dir)?.into_os_string());
+        }
+        // No --gateway: the child gets the gateway from the auth token
+        // No --port: spawn_daemon passes the port it resolved
+        Ok(args)
+    }
+
+    /// Whether to send keepalives: not with `--no-keepalive` or `keepalive = "off"`
+    fn keepalive(&self, preference: pmacs_vpn::Keepalive) -> bool {
+        !self.no_keepalive && preference == pmacs_vpn::Keepalive::On
+    }
+
+    /// Whether to route everything: with `--full-tunnel` or `full_tunnel = true`
+    fn full_tunnel(&self, config: Option<&pmacs_vpn::Config>) -> bool {
+        self.full_tunnel || config.is_some_and(|c| c.preferences.full_tunnel)
+    }
+
+    /// Whether to write the hosts file: not with `--no-hosts` or `manage_hosts = false`
+    fn manage_hosts(&self, config: Option<&pmacs_vpn::Config>) -> bool {
+        !self.no_hosts && config.is_none_or(|c| c.preferences.manage_hosts)
+    }
+
+    /// Interface to bind routes to: `--interface` if given (it must exist), else `tun_name`
+    fn route_interface(&self, tun_name: &str) -> Result<String, String> {
+        let Some(interface) = &self.interface else {
+            return Ok(tun_name.to_string());
+        };
+        if !pmacs_vpn::platform::interface_exists(interface) {
+            return Err(format!("Interface {} (from --interface) does not exist", interface));
+        }
+        if interface != tun_name {
+            info!("Binding routes to {} instead of the tunnel device {}", interface, tun_name);
+        }
+        Ok(interface.clone())
+    }
+
+    /// Gateway host and tunnel port for this run
+    ///
+    /// The gateway is the `--gateway` override, else the config's. The port
+    /// is `--port`, else one written after that gateway (`host:4443`), else
+    /// the config's `port`, else 443.
+    fn gateway(&self, config: &pmacs_vpn::Config) -> Result<(String, u16), pmacs_vpn::config::ConfigError> {
+        let gateway = self.gateway.as_deref().unwrap_or(&config.vpn.gateway);
+        let (host, gateway_port) = pmacs_vpn::config::split_gateway_port(gateway)?;
+        let port = self
+            .port
+            .or(gateway_port)
+            .or(config.vpn.port)
+            .unwrap_or(gp::tunnel::DEFAULT_TUNNEL_PORT);
+        Ok((host.to_string(), port))
+    }
+}
+
+/// Clap value parser for `--gateway`
+fn parse_gateway_arg(value: &str) -> Result<String, String> {
+    pmacs_vpn::config::parse_gateway_host(value).map_err(|e| e.to_string())
+}
+
+/// Log how many configured hosts were routed, e.g.
+/// "Routed 27/30 hosts; failed: a, b, c"
+///
+/// Failures are logged as a warning so they show even with `--quiet`. With
+/// `strict`, any failure is returned as an error carrying the same summary.
+fn check_routed_hosts(total: usize, failed: &[String], strict: bool) -> Result<(), String> {
+    let mut summary = format!("Routed {}/{} hosts", total - failed.len(), total);
+    if failed.is_empty() {
+        info!("{}", summary);
+        return Ok(());
+    }
+    summary.push_str(&format!("; failed: {}", failed.join(", ")));
+    warn!("{}", summary);
+    if strict {
+        return Err(format!("{} (--strict)", summary));
+    }
+    Ok(())
+}
+
+/// Point the system resolver at the VPN DNS (`manage_resolver`)
+///
+/// Failing to is only a warning: the routes and hosts file still work.
+fn configure_resolver(
+    state: &pmacs_vpn::VpnState,
+    dns_servers: &[std::net::IpAddr],
+    domains: &[String],
+) -> Option<pmacs_vpn::vpn::resolver::ResolverSetup> {
+    if !cfg!(target_os = "linux") {
+        warn!("manage_resolver is only supported on Linux; leaving the resolver alone");
+        return None;
+    }
+    let manager = pmacs_vpn::vpn::resolver::ResolverManager::new(state.tunnel_device.clone());
+    match manager.apply(dns_servers, domains) {
+        Ok(setup) => {
+            info!("System resolver ({:?}) now uses the VPN DNS", setup.backend);
+            Some(setup)
+        }
+        Err(e) => {
+            warn!("Failed to configure the system resolver: {}", e);
+            None
+        }
+    }
+}
+
+/// DNS servers to resolve hosts with: `dns_servers` from the config if set, else the gateway's
+///
+/// Warns about configured servers outside the networks the gateway
+/// described, since the tunnel may not reach them.
+fn resolvers(tunnel_config: &gp::TunnelConfig, configured: &[std::net::IpAddr]) -> Vec<std::net::IpAddr> {
+    if !configured.is_empty() {
+        info!("Using configured DNS servers instead of the gateway's");
+    }
+    for &server in configured {
+        if tunnel_config.in_vpn_networks(server) == Some(false) {
+            warn!(
+                "DNS server {} (dns_servers in config) is outside the VPN's networks and may not be reachable",
+                server
+            );
+        }
+    }
+    tunnel_config.resolvers(configured)
+}
+
+/// Full-tunnel mode: route all IPv4 traffic through the tunnel
+///
+/// The current default route is captured first and recorded in the state
+/// with the gateway pin, so cleanup removes exactly what was added and puts
+/// the default route back should it have gone missing meanwhile.
+fn route_everything(
+    router: &VpnRouter,
+    state: &mut pmacs_vpn::VpnState,
+    gateway_addr: std::io::Result<std::net::IpAddr>,
+) -> Result<(), String> {
+    let gateway_addr = match gateway_addr {
+        Ok(addr) if addr.is_ipv4() => addr,
+        Ok(addr) => return Err(format!("Full tunnel needs an IPv4 gateway connection (connected to {})", addr)),
+        Err(e) => return Err(format!("Full tunnel: gateway address unknown: {}", e)),
+    };
+    let original = pmacs_vpn::platform::default_route().map_err(|e| format!("Full tunnel: {}", e))?;
+    router
+        .add_full_tunnel(gateway_addr, &original)
+        .map_err(|e| format!("Full tunnel: {}", e))?;
+    info!(
+        "Full tunnel: all IPv4 traffic goes through the VPN (gateway {} pinned via {})",
+        gateway_addr, original.gateway
+    );
+    state.set_full_tunnel(pmacs_vpn::FullTunnelRoutes {
+        original_default: original,
+        gateway_pin: gateway_addr,
+    });
+    Ok(())
+}
+
+/// Under `atomic`, remove every route this connect added if any host failed
+///
+/// Runs before the state is saved or the hosts file written, so the routes
+/// `state` records, the DNS server routes and the tunnel's IPv6 address are
+/// all there is to undo. Returns the error to abort the connect with.
+fn roll_back_routes_on_failure(
+    router: &VpnRouter,
+    state: &pmacs_vpn::VpnState,
+    dns_routes: &[std::net::IpAddr],
+    failed: &[String],
+    atomic: bool,
+) -> Result<(), String> {
+    let Some(host) = failed.first().filter(|_| atomic) else {
+        return Ok(());
+    };
+    let removed = pmacs_vpn::roll_back_connect(router, state, dns_routes);
+    Err(format!(
+        "Failed to route {}; removed the {} route(s) added before it (--atomic)",
+        host,
+        removed.len()
+    ))
+}
+
+/// Tunnel address the gateway last gave this profile, to ask for again
+fn last_assigned_ip(profile: &str) -> Option<std::net::IpAddr> {
+    pmacs_vpn::LastAddress::load(profile).ok().flatten()
+}
+
+/// Remember the tunnel address for the next connect
+///
+/// `preferred-ip` is only a hint; a gateway that hands out a different
+/// address is fine, just worth a note in the log.
+fn record_assigned_ip(profile: &str, preferred: Option<std::net::IpAddr>, assigned: std::net::IpAddr) {
+    if let Some(preferred) = preferred
+        && preferred != assigned
+    {
+        info!("Gateway assigned {} (previous address {} not available)", assigned, preferred);
+    }
+    let address = pmacs_vpn::LastAddress { internal_ip: assigned };
+    if let Err(e) = address.save(profile) {
+        warn!("Failed to record tunnel address: {}", e);
+    }
+}
+
+/// Remember why a connect failed so `status` can report it later
+fn record_connect_failure(message: &str) {
+    if let Err(e) = pmacs_vpn::ConnectFailure::new(message).save(&config_profile()) {
+        warn!("Failed to record connect failure: {}", e);
+    }
+}
+
+/// Leave the reason a daemon's tunnel ended for the tray's health monitor
+fn record_disconnect(profile: &str, error: &gp::TunnelError) {
+    let reason = pmacs_vpn::DisconnectReason::from_error(error);
+    info!("Daemon: tunnel ended ({:?}): {}", reason, error);
+    if let Err(e) = reason.save(profile) {
+        warn!("Failed to record disconnect reason: {}", e);
+    }
+}
+
+/// Turn how the tunnel task ended into the connect's result
+///
+/// A tunnel only stops by failing, so a task that returns cleanly or panics
+/// has died on its own. Both become errors too: the process then cleans up
+/// and exits non-zero (recording the message for `status`) instead of
+/// carrying on with no tunnel, so the tray or a service manager can start it
+/// again. The daemon (`daemon_profile`) also leaves a disconnect reason; a
+/// dead task counts as a lost connection, which the tray reconnects.
+fn tunnel_ended(
+    result: Result<Result<(), gp::TunnelError>, tokio::task::JoinError>,
+    daemon_profile: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let message = match result {
+        Ok(Err(e)) => {
+            if let Some(profile) = daemon_profile {
+                record_disconnect(profile, &e);
+            }
+            return Err(Box::new(e));
+        }
+        Ok(Ok(())) => "Tunnel task stopped without an error".to_string(),
+        Err(e) if e.is_panic() => format!("Tunnel task panicked: {}", panic_message(e.into_panic())),
+        Err(e) => format!("Tunnel task ended: {}", e),
+    };
+    error!("{}", message);
+    if let Some(profile) = daemon_profile
+        && let Err(e) = pmacs_vpn::DisconnectReason::ConnectionLost.save(profile)
+    {
+        warn!("Failed to record disconnect reason: {}", e);
+    }
+    Err(message.into())
+}
+
+/// The message a panic was raised with, if it was a string
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "no message".to_string())
+}
+
+/// Open the `--pcap` capture file, if requested, and attach it to the tunnel
+///
+/// Returns a handle to the same file so it can be flushed on disconnect.
+fn start_capture(
+    tunnel: &mut gp::tunnel::SslTunnel,
+    path: Option<&Path>,
+) -> Result<Option<gp::PcapWriter>, Box<dyn std::error::Error>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let capture = gp::PcapWriter::create(path)
+        .map_err(|e| format!("Failed to create capture file {}: {}", path.display(), e))?;
+    info!("Capturing tunnel packets to {}", path.display());
+    tunnel.set_capture(capture.clone());
+    Ok(Some(capture))
+}
+
+/// Flush buffered capture records once the tunnel is done
+fn finish_capture(capture: Option<gp::PcapWriter>) {
+    if let Some(capture) = capture
+        && let Err(e) = capture.flush()
+    {
+        warn!("Failed to flush packet capture: {}", e);
+    }
+}
+
 /// Disconnect from VPN and clean up
-async fn disconnect_vpn() -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(state) = pmacs_vpn::VpnState::load()? {
-        // Kill daemon process if running
+///
+/// With a profile, only that connection is torn down; otherwise every
+/// active connection is.
+async fn disconnect_vpn(profile: Option<&str>, resolve: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let states = match profile {
+        Some(p) => pmacs_vpn::VpnState::load_profile(p)?.into_iter().collect(),
+        None => pmacs_vpn::VpnState::load_all()?,
+    };
+
+    if states.is_empty() {
+        let profile = profile.map_or_else(config_profile, str::to_string);
+        if !disconnect_without_state(&profile, resolve)? {
+            println!("VPN is not connected");
+        }
+        return Ok(());
+    }
+
+    for state in states {
         if state.pid.is_some() {
             if state.is_daemon_running() {
-                info!("Stopping VPN daemon (PID: {:?})", state.pid);
-                state.kill_daemon()?;
-                // Give it a moment to clean up
-                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                info!("Stopping VPN daemon [{}] (PID: {:?})", state.profile_name(), state.pid);
             } else {
                 info!("Daemon process not running, cleaning up stale state");
             }
         }
 
-        cleanup_vpn(&state).await?;
+        // Stops the daemon (which cleans up after itself), or cleans up here
+        let report = pmacs_vpn::ConnectedVpn::from_state(state)
+            .with_dns_flush(flush_dns_on_disconnect())
+            .disconnect()
+            .await;
+        check_cleanup(&report.failures, report.state_deleted)?;
+    }
+    Ok(())
+}
+
+/// Clean up after a connection on `profile` whose state file is gone
+///
+/// What it set up is rebuilt from the routes file or, with `resolve`, by
+/// resolving the config's hosts again, and removed on a best-effort basis.
+/// Resolving runs before the hosts section is removed, so names it still
+/// lists resolve to the addresses that were routed; anything else may
+/// resolve differently now, which is why it's opt-in. Returns false if
+/// there was nothing to go on.
+fn disconnect_without_state(profile: &str, resolve: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    let config = pmacs_vpn::Config::load(&get_config_path()).ok();
+    let mut state = if let Some(file) = pmacs_vpn::RoutesFile::load(profile)? {
+        println!("No VPN state for [{}], removing the routes in its routes file...", profile);
+        file.to_state()
+    } else if resolve {
+        let config = config
+            .as_ref()
+            .ok_or("--resolve needs a config file listing the hosts that were routed")?;
+        println!("No VPN state for [{}], resolving the config's hosts to find its routes...", profile);
+        resolved_state(config)?
     } else {
+        return Ok(false);
+    };
+    if let Some(config) = &config {
+        state.manage_hosts = config.preferences.manage_hosts;
+        state.hosts_file_path = config.preferences.hosts_file_path.clone();
+        state.route_table = config.preferences.route_table;
+    }
+
+    let report = pmacs_vpn::cleanup_orphaned(&state, flush_dns_on_disconnect());
+    let total = state.routes.len() + state.network_routes.len();
+    println!(
+        "Removed {} of {} route(s){}",
+        report.routes_removed.len(),
+        total,
+        if report.hosts_restored { " and the hosts file entries" } else { "" }
+    );
+    check_cleanup(&report.failures, report.state_deleted)?;
+    Ok(true)
+}
+
+/// The routes a connect with `config` would add, from resolving its hosts
+/// now (see `disconnect_without_state`)
+///
+/// Routes carry no interface, so they're removed without checking where
+/// they point. Hosts that don't resolve are skipped with a warning.
+fn resolved_state(config: &pmacs_vpn::Config) -> Result<pmacs_vpn::VpnState, Box<dyn std::error::Error>> {
+    let router = VpnRouter::new(config.vpn.gateway.clone())?
+        .with_search_domains(&config.search_domains)
+        .with_private_preference(config.preferences.prefer_private);
+    let mut state = pmacs_vpn::VpnState::new(String::new(), std::net::Ipv4Addr::UNSPECIFIED.into());
+    state.set_profile(config.vpn.profile.clone());
+
+    for entry in config.ordered_hosts() {
+        let resolved = pmacs_vpn::config::parse_host_entry(entry.name())
+            .map_err(|e| e.to_string())
+            .and_then(|(host, prefix)| {
+                let host_dns = pmacs_vpn::vpn::routing::dns_servers_for(entry.dns, &config.dns_servers);
+                let (name, ip) = router.resolve_with_search(host, &host_dns).map_err(|e| e.to_string())?;
+                let network = prefix
+                    .map(|prefix| pmacs_vpn::vpn::routing::network_cidr(ip, prefix))
+                    .transpose()
+                    .map_err(|e| e.to_string())?;
+                Ok((router.hosts_file_names(host, &name), ip, network))
+            });
+        let (names, ip, network) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                warn!("Skipping {}: {}", entry.name(), e);
+                continue;
+            }
+        };
+        match network {
+            Some(network) if !state.network_routes.iter().any(|r| r.network == network) => {
+                state.add_network_route(entry.name().to_string(), network);
+            }
+            Some(_) => {}
+            None if !state.routes.iter().any(|r| r.ip == ip) => {
+                state.add_route(entry.name().to_string(), ip);
+            }
+            None => {}
+        }
+        for name in names {
+            state.add_hosts_entry(name, ip);
+        }
+    }
+    Ok(state)
+}
+
+/// Make way for a new connection on the config's profile
+///
+/// A session that is still running blocks the connect unless `force` is set,
+/// in which case it is stopped first. Whatever a dead session left behind
+/// (routes, hosts entries, kill switch blackholes) is cleaned up.
+async fn prepare_for_connect(force: bool) -> Result<(), String> {
+    let Ok(Some(state)) = load_config_state() else {
+        return Ok(());
+    };
+    let profile = state.profile_name().to_string();
+
+    if let Some(pid) = state.pid
+        && state.is_daemon_running()
+    {
+        if !force {
+            return Err(format!(
+                "VPN is already connected [{}] (PID {}). Run 'pmacs-vpn disconnect' first, or connect with --force to replace it",
+                profile, pid
+            ));
+        }
+        println!("Stopping existing connection [{}] (PID {})...", profile, pid);
+        state.kill_daemon().map_err(|e| e.to_string())?;
+        // A graceful shutdown cleans up after itself
+        let remaining = pmacs_vpn::VpnState::load_profile(&profile).map_err(|e| e.to_string())?;
+        let Some(state) = remaining else {
+            return Ok(());
+        };
+        return cleanup_vpn(&state).map_err(|e| e.to_string());
+    }
+
+    if state.kill_switch_active {
+        // Blackhole routes would block the new tunnel's routes
+        println!("Releasing kill switch from previous session...");
+    } else {
+        println!("Cleaning up stale VPN state from previous session...");
+    }
+    cleanup_vpn(&state).map_err(|e| e.to_string())
+}
+
+/// Ask background connections to reload the config (see `reload_hosts`)
+fn reload_daemons(profile: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let states = match profile {
+        Some(p) => pmacs_vpn::VpnState::load_profile(p)?.into_iter().collect(),
+        None => pmacs_vpn::VpnState::load_all()?,
+    };
+    if states.is_empty() {
         println!("VPN is not connected");
+        return Ok(());
+    }
+
+    for state in &states {
+        if state.request_reload()? {
+            println!(
+                "Asked [{}] (PID {}) to reload its config",
+                state.profile_name(),
+                state.pid.unwrap_or_default()
+            );
+        } else {
+            println!(
+                "[{}] is not a running background connection; reconnect to apply config changes",
+                state.profile_name()
+            );
+        }
     }
     Ok(())
 }
 
-/// Clean up routes, hosts, and state
-async fn cleanup_vpn(state: &pmacs_vpn::VpnState) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Cleaning up VPN state...");
+/// Record hosts file entries for every name a routed host is known by
+fn add_hosts_names(
+    state: &mut pmacs_vpn::VpnState,
+    hosts_map: &mut std::collections::HashMap<String, Vec<std::net::IpAddr>>,
+    names: &[String],
+    ip: std::net::IpAddr,
+) {
+    for name in names {
+        state.add_hosts_entry(name.clone(), ip);
+        hosts_map.entry(name.clone()).or_default().push(ip);
+    }
+}
+
+/// Warn, or refuse with `require_hosts`, when a connect would route nothing
+///
+/// Checked once the gateway's split-tunnel networks are known and before the
+/// TUN device exists, so a refused connect leaves nothing to clean up.
+fn check_route_set(
+    hosts: usize,
+    tunnel_config: &gp::TunnelConfig,
+    gateway_routes: bool,
+    require_hosts: bool,
+) -> Result<(), String> {
+    if hosts > 0 {
+        return Ok(());
+    }
+    let gateway_networks = if gateway_routes {
+        tunnel_config.routable_includes().len()
+    } else {
+        0
+    };
+    if gateway_networks > 0 {
+        warn!(
+            "No hosts configured; only the {} network(s) the gateway includes will be routed",
+            gateway_networks
+        );
+        return Ok(());
+    }
+    let message = "No hosts to route: `hosts` is empty and the gateway includes no networks";
+    if require_hosts {
+        return Err(format!("{} (require_hosts is set)", message));
+    }
+    warn!("{}; the tunnel will carry no traffic", message);
+    Ok(())
+}
+
+/// Label recorded in the state for routes the gateway pushed
+const GATEWAY_ROUTE_LABEL: &str = "(gateway)";
+
+/// Routes and hosts entries the daemon added for configured hosts
+///
+/// Kept for the life of the daemon so a config reload can add and remove
+/// hosts without redoing the rest.
+#[derive(Default)]
+struct HostRoutes {
+    /// Hosts file names and the addresses they're written with
+    hosts_map: std::collections::HashMap<String, Vec<std::net::IpAddr>>,
+    routed_ips: std::collections::HashSet<std::net::IpAddr>,
+    /// Routed networks, including the on-link subnet and gateway routes
+    routed_networks: std::collections::HashSet<String>,
+    /// Hosts file names each configured host was written under
+    names: std::collections::HashMap<String, Vec<String>>,
+}
+
+impl HostRoutes {
+    /// Whether `route_host` succeeded for this config entry
+    fn is_routed(&self, entry: &pmacs_vpn::HostEntry) -> bool {
+        pmacs_vpn::config::parse_host_entry(entry.name())
+            .is_ok_and(|(host, _)| self.names.contains_key(host))
+    }
 
-    // Remove hosts entries
-    let hosts_mgr = HostsManager::new();
-    if let Err(e) = hosts_mgr.remove_entries() {
-        error!("Failed to remove hosts entries: {}", e);
+    /// A configured host (other than those just removed) with an address matching `pred`
+    fn host_using(&self, pred: impl Fn(std::net::IpAddr) -> bool) -> Option<String> {
+        self.names
+            .iter()
+            .find(|(_, names)| {
+                names
+                    .iter()
+                    .filter_map(|name| self.hosts_map.get(name))
+                    .flatten()
+                    .any(|&ip| pred(ip))
+            })
+            .map(|(host, _)| host.clone())
     }
+}
 
-    // Remove routes using stored IPs (don't resolve - VPN may be down)
-    let router = VpnRouter::new(state.gateway.to_string())?;
+/// Route one configured host, recording it in `state` and `routes`
+fn route_host(
+    router: &VpnRouter,
+    state: &mut pmacs_vpn::VpnState,
+    routes: &mut HostRoutes,
+    entry: &pmacs_vpn::HostEntry,
+    dns_servers: &[std::net::IpAddr],
+) -> Result<(), String> {
+    let (host, prefix) = pmacs_vpn::config::parse_host_entry(entry.name()).map_err(|e| e.to_string())?;
+    let host = host.to_string();
+
+    let host_dns = pmacs_vpn::vpn::routing::dns_servers_for(entry.dns, dns_servers);
+    let (names, resolved) = match router.resolve_with_search(&host, &host_dns) {
+        Ok((name, ip)) => (router.hosts_file_names(&host, &name), Ok(ip)),
+        Err(e) => (vec![], Err(e)),
+    };
+    if let (Some(port), Ok(ip)) = (entry.port, &resolved) {
+        state.add_service_port(host.clone(), *ip, port);
+    }
+    if let Some(comment) = &entry.comment {
+        state.add_host_comment(&names, comment);
+    }
+
+    // host/prefix: route the network containing the host (once per network)
+    if let Some(prefix) = prefix {
+        let (ip, network) = resolved
+            .and_then(|ip| {
+                let network = pmacs_vpn::vpn::routing::network_cidr(ip, prefix)?;
+                if routes.routed_networks.insert(network.clone()) {
+                    router.add_network_route(&network)?;
+                    state.add_network_route(host.clone(), network.clone());
+                }
+                Ok((ip, network))
+            })
+            .map_err(|e| format!("Failed to add network route for {}: {}", host, e))?;
+        add_hosts_names(state, &mut routes.hosts_map, &names, ip);
+        routes.names.insert(host.clone(), names);
+        log_route(format_args!("Added network route: {} -> {} (via {})", host, ip, network));
+        return Ok(());
+    }
+
+    // Hosts sharing an IP only need one route, but each gets a hosts entry
+    if let Ok(ip) = resolved
+        && !routes.routed_ips.insert(ip)
+    {
+        log_route(format_args!("{} resolves to already-routed {}, skipping route", host, ip));
+        add_hosts_names(state, &mut routes.hosts_map, &names, ip);
+        routes.names.insert(host, names);
+        return Ok(());
+    }
+    let ip = resolved
+        .and_then(|ip| router.add_ip_route(&ip.to_string()))
+        .map_err(|e| format!("Failed to add route for {}: {} (run with -v for details)", host, e))?;
+    state.add_route(host.clone(), ip);
+    add_hosts_names(state, &mut routes.hosts_map, &names, ip);
+    routes.names.insert(host.clone(), names);
+    log_route(format_args!("Added route: {} -> {}", host, ip));
+    Ok(())
+}
+
+/// Undo `route_host` for a host dropped from the config
+///
+/// A route another configured host still needs is handed over to it
+/// instead of being removed.
+fn unroute_host(
+    router: &VpnRouter,
+    state: &mut pmacs_vpn::VpnState,
+    routes: &mut HostRoutes,
+    host: &str,
+) {
+    let names = routes.names.remove(host).unwrap_or_default();
+    for name in &names {
+        routes.hosts_map.remove(name);
+    }
+    let (ip_routes, network_routes) = state.remove_host(host, &names);
+
+    for route in ip_routes {
+        if let Some(other) = routes.host_using(|ip| ip == route.ip) {
+            state.add_route(other, route.ip);
+            continue;
+        }
+        routes.routed_ips.remove(&route.ip);
+        match router.remove_ip_route(&route.ip.to_string()) {
+            Ok(()) => info!("Removed route: {} -> {}", host, route.ip),
+            Err(e) => warn!("Failed to remove route for {} ({}): {}", host, route.ip, e),
+        }
+    }
+    for route in network_routes {
+        let network = &route.network;
+        if let Some(other) =
+            routes.host_using(|ip| pmacs_vpn::vpn::routing::network_contains(network, ip))
+        {
+            state.add_network_route(other, route.network);
+            continue;
+        }
+        routes.routed_networks.remove(network);
+        match router.remove_ip_route(network) {
+            Ok(()) => info!("Removed network route: {} ({})", host, network),
+            Err(e) => warn!("Failed to remove route for {} ({}): {}", host, network, e),
+        }
+    }
+}
+
+/// Replace a routed host's comment and service port, leaving its routes be
+fn update_host_details(
+    state: &mut pmacs_vpn::VpnState,
+    routes: &HostRoutes,
+    entry: &pmacs_vpn::HostEntry,
+) {
+    let Ok((host, _)) = pmacs_vpn::config::parse_host_entry(entry.name()) else {
+        return;
+    };
+    let names = routes.names.get(host).cloned().unwrap_or_default();
+    for name in &names {
+        state.host_comments.remove(name);
+    }
+    if let Some(comment) = &entry.comment {
+        state.add_host_comment(&names, comment);
+    }
+
+    let ip = names
+        .iter()
+        .filter_map(|name| routes.hosts_map.get(name))
+        .flatten()
+        .next()
+        .copied();
+    state.service_ports.retain(|p| p.hostname != host);
+    if let (Some(port), Some(ip)) = (entry.port, ip) {
+        state.add_service_port(host.to_string(), ip, port);
+    }
+}
+
+/// Re-read the config and apply host changes to the running daemon
+///
+/// Hosts no longer configured lose their routes and hosts entries, new ones
+/// are routed (as are any that failed last time), and a changed comment or
+/// port is recorded without touching routes. The hosts file, state and routes
+/// files are then rewritten. The tunnel stays up; other settings only take
+/// effect on reconnect.
+fn reload_hosts(
+    router: &VpnRouter,
+    state: &mut pmacs_vpn::VpnState,
+    routes: &mut HostRoutes,
+    current: &mut Vec<pmacs_vpn::HostEntry>,
+    dns_servers: &[std::net::IpAddr],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = pmacs_vpn::Config::load(&get_config_path())?;
+    let hosts = config.ordered_hosts();
+    let pmacs_vpn::config::HostChanges { removed, added, updated } =
+        pmacs_vpn::config::HostChanges::between(current, &hosts, |h| routes.is_routed(h));
+    if removed.is_empty() && added.is_empty() && updated.is_empty() {
+        info!("Daemon: reload found no host changes");
+        return Ok(());
+    }
+
+    for entry in &removed {
+        if let Ok((host, _)) = pmacs_vpn::config::parse_host_entry(entry.name()) {
+            unroute_host(router, state, routes, host);
+        }
+        info!("Daemon: reload removed {}", entry.name());
+    }
+
+    // Answers may have changed since connect
+    router.clear_cache();
+    let routed_dns = override_dns_servers(current, dns_servers);
+    for dns_ip in override_dns_servers(&added, dns_servers)
+        .into_iter()
+        .filter(|ip| !routed_dns.contains(ip))
+    {
+        if let Err(e) = router.add_ip_route(&dns_ip.to_string()) {
+            warn!("Failed to add route to DNS {}: {}", dns_ip, e);
+        }
+    }
+    for entry in &added {
+        match route_host(router, state, routes, entry, dns_servers) {
+            Ok(()) => info!("Daemon: reload added {}", entry.name()),
+            Err(e) => error!("Daemon: reload could not add {}: {}", entry.name(), e),
+        }
+    }
+    for entry in &updated {
+        update_host_details(state, routes, entry);
+        info!("Daemon: reload updated {}", entry.name());
+    }
+    *current = hosts;
+
+    if state.manage_hosts {
+        HostsManager::for_path(state.hosts_file_path.as_deref())
+            .with_label(state.profile.clone())
+            .with_comments(state.host_comments.clone())
+            .add_entries(&routes.hosts_map)?;
+    }
+    state.save()?;
+    pmacs_vpn::RoutesFile::from_state(state).save()?;
+    info!(
+        "Daemon: reload applied ({} added, {} removed)",
+        added.len(),
+        removed.len()
+    );
+    Ok(())
+}
+
+/// Give the tunnel its IPv6 address when the `ipv6` preference is on
+///
+/// Returns the address if it was assigned; IPv6 hosts are only routed then.
+fn configure_tunnel_ipv6(
+    tun_name: &str,
+    enabled: bool,
+    address: Option<std::net::Ipv6Addr>,
+) -> Option<std::net::Ipv6Addr> {
+    if !enabled {
+        return None;
+    }
+    let Some(address) = address else {
+        info!("ipv6 is enabled but the gateway assigned no IPv6 address");
+        return None;
+    };
+
+    #[cfg(windows)]
+    {
+        match pmacs_vpn::platform::add_interface_ipv6(tun_name, address) {
+            Ok(()) => {
+                info!("Tunnel IPv6 address: {}", address);
+                Some(address)
+            }
+            Err(e) => {
+                warn!("Failed to assign IPv6 address {} to {}: {}", address, tun_name, e);
+                None
+            }
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        warn!(
+            "ipv6 is only supported on Windows so far; not assigning {} to {}",
+            address, tun_name
+        );
+        None
+    }
+}
+
+/// Label for the tunnel subnet route in the state file
+const SUBNET_ROUTE_LABEL: &str = "(on-link subnet)";
+
+/// Route the tunnel subnet the gateway's netmask describes
+///
+/// Other VPN addresses in that subnet are then reachable without a route
+/// each. Nothing to do for the usual /32 netmask.
+fn add_onlink_route(
+    router: &VpnRouter,
+    state: &mut pmacs_vpn::VpnState,
+    tunnel_config: &gp::TunnelConfig,
+    routed_networks: &mut std::collections::HashSet<String>,
+) {
+    let Some(network) = tunnel_config.onlink_network() else {
+        return;
+    };
+    if !routed_networks.insert(network.clone()) {
+        return;
+    }
+    match router.add_network_route(&network) {
+        Ok(()) => {
+            info!("Added route: {} (on-link subnet)", network);
+            state.add_network_route(SUBNET_ROUTE_LABEL.to_string(), network.clone());
+            state.set_onlink_network(Some(network));
+        }
+        Err(e) => warn!("Failed to add on-link subnet route {}: {}", network, e),
+    }
+}
+
+/// Route the networks the gateway's split tunnel includes
+///
+/// Additive to the configured hosts: networks already routed for a
+/// `host/prefix` entry are skipped. Returns the networks that were routed.
+fn add_gateway_routes(
+    router: &VpnRouter,
+    state: &mut pmacs_vpn::VpnState,
+    tunnel_config: &gp::TunnelConfig,
+    routed_networks: &mut std::collections::HashSet<String>,
+) -> Vec<String> {
+    if !tunnel_config.exclude_routes.is_empty() {
+        info!("Gateway excludes: {:?}", tunnel_config.exclude_routes);
+    }
+    let mut added = Vec::new();
+    for net in tunnel_config.routable_includes() {
+        let network = net.to_string();
+        if !routed_networks.insert(network.clone()) {
+            continue;
+        }
+        match router.add_network_route(&network) {
+            Ok(()) => {
+                state.add_network_route(GATEWAY_ROUTE_LABEL.to_string(), network.clone());
+                added.push(network);
+            }
+            Err(e) => warn!("Failed to add gateway route {}: {}", network, e),
+        }
+    }
+    added
+}
+
+/// Per-host DNS override servers that aren't already tunnel DNS servers
+fn override_dns_servers(
+    hosts: &[pmacs_vpn::HostEntry],
+    dns_servers: &[std::net::IpAddr],
+) -> Vec<std::net::IpAddr> {
+    let mut servers = Vec::new();
+    for dns in hosts.iter().filter_map(|h| h.dns) {
+        if !dns_servers.contains(&dns) && !servers.contains(&dns) {
+            servers.push(dns);
+        }
+    }
+    servers
+}
+
+/// Replace the tunnel routes with blackhole routes after the tunnel drops
+///
+/// Hosts entries and the state file are left in place so the next connect
+/// or `disconnect` removes everything through `cleanup_vpn`.
+fn engage_kill_switch(state: &mut pmacs_vpn::VpnState) -> Result<(), Box<dyn std::error::Error>> {
+    warn!(
+        "Tunnel dropped, engaging kill switch for {} route(s)",
+        state.routes.len() + state.network_routes.len()
+    );
+
+    let router = VpnRouter::new(state.gateway.to_string())?.with_route_table(state.route_table);
     for route in &state.routes {
-        if let Err(e) = router.remove_ip_route(&route.ip.to_string()) {
-            error!("Failed to remove route for {} ({}): {}", route.hostname, route.ip, e);
+        let ip = route.ip.to_string();
+        // The tunnel route normally disappears with the TUN device
+        if let Err(e) = router.remove_ip_route(&ip) {
+            debug!("Tunnel route for {} already gone: {}", ip, e);
+        }
+        if let Err(e) = router.add_blackhole_route(&ip) {
+            error!("Failed to block {} ({}): {}", route.hostname, route.ip, e);
+        }
+    }
+    for route in &state.network_routes {
+        if let Err(e) = router.remove_ip_route(&route.network) {
+            debug!("Tunnel route for {} already gone: {}", route.network, e);
+        }
+        if let Err(e) = router.add_blackhole_route(&route.network) {
+            error!("Failed to block {} ({}): {}", route.hostname, route.network, e);
         }
     }
 
-    // Delete state file
-    pmacs_vpn::VpnState::delete()?;
+    state.kill_switch_active = true;
+    state.save()?;
+
+    Ok(())
+}
+
+/// Clean up routes, hosts, and state
+fn cleanup_vpn(state: &pmacs_vpn::VpnState) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Cleaning up VPN state...");
+    let report = pmacs_vpn::cleanup(state, flush_dns_on_disconnect());
+    check_cleanup(&report.failures, report.state_deleted)
+}
+
+/// Stop the tunnel task running in this process, then clean up after it
+async fn disconnect_here(
+    state: pmacs_vpn::VpnState,
+    tunnel: tokio::task::AbortHandle,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Cleaning up VPN state...");
+    let report = pmacs_vpn::ConnectedVpn::from_state(state)
+        .with_tunnel_task(tunnel)
+        .with_dns_flush(flush_dns_on_disconnect())
+        .disconnect()
+        .await;
+    check_cleanup(&report.failures, report.state_deleted)
+}
 
+/// Log what a cleanup couldn't undo; an error if the state survived
+fn check_cleanup(failures: &[String], state_deleted: bool) -> Result<(), Box<dyn std::error::Error>> {
+    for failure in failures {
+        error!("Cleanup failed: {}", failure);
+    }
+    if !state_deleted {
+        return Err("VPN state could not be removed".into());
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn join<F: std::future::Future<Output = Result<(), gp::TunnelError>> + Send + 'static>(
+        task: F,
+    ) -> Result<Result<(), gp::TunnelError>, tokio::task::JoinError> {
+        tokio::spawn(task).await
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_ended_cleanly_is_an_error() {
+        let err = tunnel_ended(join(async { Ok(()) }).await, None).unwrap_err();
+        assert_eq!(err.to_string(), "Tunnel task stopped without an error");
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_ended_by_panic() {
+        let result = join(async { panic!("tunnel broke") }).await;
+        let err = tunnel_ended(result, None).unwrap_err();
+        assert_eq!(err.to_string(), "Tunnel task panicked: tunnel broke");
+
+        let result = join(async { std::panic::panic_any(format!("read {} bytes", 3)) }).await;
+        let err = tunnel_ended(result, None).unwrap_err();
+        assert_eq!(err.to_string(), "Tunnel task panicked: read 3 bytes");
+
+        let result = join(async { std::panic::panic_any(42) }).await;
+        let err = tunnel_ended(result, None).unwrap_err();
+        assert_eq!(err.to_string(), "Tunnel task panicked: no message");
+    }
+
+    #[tokio::test]
+    async fn test_daemon_tunnel_ended_records_connection_lost() {
+        let home = tempfile::TempDir::new().unwrap();
+        // SAFETY: the only test here that reads HOME
+        unsafe { std::env::set_var("HOME", home.path()) };
+
+        assert!(tunnel_ended(join(async { Ok(()) }).await, Some("lab")).is_err());
+        assert_eq!(
+            pmacs_vpn::DisconnectReason::take("lab").unwrap(),
+            Some(pmacs_vpn::DisconnectReason::ConnectionLost)
+        );
+    }
+}
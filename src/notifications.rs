@@ -107,6 +107,11 @@ pub fn notify_reconnect_failed() {
     show_notification("PMACS VPN", "Auto-reconnect failed - max attempts reached");
 }
 
+/// Notify that the gateway logged the session out (no auto-reconnect)
+pub fn notify_session_ended() {
+    show_notification("PMACS VPN", "Session ended by gateway - connect to log in again");
+}
+
 /// Notify unexpected disconnection
 pub fn notify_unexpected_disconnect() {
     show_notification("PMACS VPN", "VPN disconnected unexpectedly");
@@ -1,24 +1,110 @@
 //! Linux-specific routing implementation
+//!
+//! Routes normally go in the main table. With a dedicated table (the
+//! `route_table` preference), each route is added to that table together
+//! with an `ip rule` sending traffic for the destination to it, so the main
+//! table is never touched. Deleting the route removes its rule too.
 
-use super::{PlatformError, RoutingManager};
+use super::{DefaultRoute, PlatformError, RoutingManager};
 use std::process::Command;
 
 pub struct LinuxRoutingManager {
     interface_name: Option<String>,
+    table: Option<u32>,
 }
 
 impl LinuxRoutingManager {
     pub fn new() -> Self {
         Self {
             interface_name: None,
+            table: None,
         }
     }
 
     pub fn with_interface(interface_name: String) -> Self {
         Self {
             interface_name: Some(interface_name),
+            table: None,
         }
     }
+
+    /// Put routes in routing table `table` (with a policy rule per destination)
+    pub fn with_table(mut self, table: u32) -> Self {
+        self.table = Some(table);
+        self
+    }
+
+    /// `ip route add` arguments for a route to `destination`
+    fn add_args(&self, destination: &str, gateway: &str) -> Vec<String> {
+        let mut args = vec!["route".to_string(), "add".to_string(), destination.to_string()];
+        match &self.interface_name {
+            Some(iface) => args.extend(["dev".to_string(), iface.clone()]),
+            None => args.extend(["via".to_string(), gateway.to_string()]),
+        }
+        self.push_table(&mut args);
+        args
+    }
+
+    /// `ip route delete` arguments for `destination`
+    fn delete_args(&self, destination: &str) -> Vec<String> {
+        let mut args = vec!["route".to_string(), "delete".to_string(), destination.to_string()];
+        self.push_table(&mut args);
+        args
+    }
+
+    /// `ip route add blackhole` arguments for `destination`
+    fn blackhole_args(&self, destination: &str) -> Vec<String> {
+        let mut args = vec![
+            "route".to_string(),
+            "add".to_string(),
+            "blackhole".to_string(),
+            destination.to_string(),
+        ];
+        self.push_table(&mut args);
+        args
+    }
+
+    /// `ip route show exact` arguments for `destination`, in its address family
+    fn show_args(&self, destination: &str) -> Vec<String> {
+        let family = if super::is_ipv6_destination(destination) { "-6" } else { "-4" };
+        let mut args = vec![
+            family.to_string(),
+            "route".to_string(),
+            "show".to_string(),
+            "exact".to_string(),
+            destination.to_string(),
+        ];
+        self.push_table(&mut args);
+        args
+    }
+
+    /// `ip rule` arguments (`add` or `del`) steering `destination` to `table`
+    fn rule_args(verb: &str, destination: &str, table: u32) -> Vec<String> {
+        vec![
+            "rule".to_string(),
+            verb.to_string(),
+            "to".to_string(),
+            destination.to_string(),
+            "lookup".to_string(),
+            table.to_string(),
+        ]
+    }
+
+    fn push_table(&self, args: &mut Vec<String>) {
+        if let Some(table) = self.table {
+            args.extend(["table".to_string(), table.to_string()]);
+        }
+    }
+
+    /// Send `destination` to our table, replacing any rule left from before
+    fn add_rule(&self, destination: &str) -> Result<(), PlatformError> {
+        let Some(table) = self.table else {
+            return Ok(());
+        };
+        // `ip rule add` happily duplicates rules, so drop an existing one first
+        let _ = run_ip(&Self::rule_args("del", destination, table));
+        run_ip(&Self::rule_args("add", destination, table)).map_err(PlatformError::AddRouteError)
+    }
 }
 
 impl Default for LinuxRoutingManager {
@@ -27,39 +113,171 @@ impl Default for LinuxRoutingManager {
     }
 }
 
-impl RoutingManager for LinuxRoutingManager {
-    fn add_route(&self, destination: &str, gateway: &str) -> Result<(), PlatformError> {
-        let output = if let Some(ref iface) = self.interface_name {
-            Command::new("ip")
-                .args(["route", "add", destination, "dev", iface])
-                .output()
-                .map_err(|e| PlatformError::AddRouteError(e.to_string()))?
-        } else {
-            Command::new("ip")
-                .args(["route", "add", destination, "via", gateway])
-                .output()
-                .map_err(|e| PlatformError::AddRouteError(e.to_string()))?
+/// Run `ip` with the given arguments, returning stderr on failure
+fn run_ip(args: &[String]) -> Result<(), String> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    Ok(())
+}
+
+/// The main table's IPv4 default route (`ip -4 route show default`)
+pub fn default_route() -> Result<DefaultRoute, PlatformError> {
+    let output = Command::new("ip")
+        .args(["-4", "route", "show", "default"])
+        .output()
+        .map_err(|e| PlatformError::DefaultRouteError(e.to_string()))?;
+    if !output.status.success() {
+        return Err(PlatformError::DefaultRouteError(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    parse_default_route(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| PlatformError::DefaultRouteError("no default gateway".to_string()))
+}
+
+/// Device of the first route in `ip route show` output (`dev <interface>`)
+///
+/// `Some("")` for a route without a device, such as a blackhole route.
+fn parse_route_dev(output: &str) -> Option<String> {
+    let line = output.lines().find(|line| !line.trim().is_empty())?;
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let dev = words.iter().position(|w| *w == "dev").and_then(|i| words.get(i + 1));
+    Some(dev.map_or_else(String::new, |dev| dev.to_string()))
+}
+
+/// First `default via <gateway> dev <interface> ...` line
+fn parse_default_route(output: &str) -> Option<DefaultRoute> {
+    output.lines().find_map(|line| {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let after = |key: &str| {
+            let i = words.iter().position(|w| *w == key)?;
+            words.get(i + 1).copied()
         };
+        Some(DefaultRoute {
+            gateway: after("via")?.parse().ok()?,
+            interface: after("dev").map(str::to_string),
+        })
+    })
+}
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(PlatformError::AddRouteError(stderr.to_string()));
+impl RoutingManager for LinuxRoutingManager {
+    fn add_route(&self, destination: &str, gateway: &str) -> Result<(), PlatformError> {
+        run_ip(&self.add_args(destination, gateway)).map_err(PlatformError::AddRouteError)?;
+        if let Err(e) = self.add_rule(destination) {
+            // Don't leave a route in a table nothing points to
+            let _ = run_ip(&self.delete_args(destination));
+            return Err(e);
         }
-
         Ok(())
     }
 
     fn delete_route(&self, destination: &str) -> Result<(), PlatformError> {
+        let result = run_ip(&self.delete_args(destination)).map_err(PlatformError::DeleteRouteError);
+        // The route may already be gone with the TUN device; the rule isn't
+        if let Some(table) = self.table {
+            let _ = run_ip(&Self::rule_args("del", destination, table));
+        }
+        result
+    }
+
+    fn add_blackhole_route(&self, destination: &str) -> Result<(), PlatformError> {
+        run_ip(&self.blackhole_args(destination)).map_err(PlatformError::AddRouteError)?;
+        self.add_rule(destination)
+    }
+
+    fn route_interface(&self, destination: &str) -> Result<Option<String>, PlatformError> {
         let output = Command::new("ip")
-            .args(["route", "delete", destination])
+            .args(self.show_args(destination))
             .output()
-            .map_err(|e| PlatformError::DeleteRouteError(e.to_string()))?;
-
+            .map_err(|e| PlatformError::RouteLookupError(e.to_string()))?;
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(PlatformError::DeleteRouteError(stderr.to_string()));
+            return Err(PlatformError::RouteLookupError(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
         }
+        Ok(parse_route_dev(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_main_table_args_unchanged() {
+        let manager = LinuxRoutingManager::with_interface("tun0".to_string());
+        assert_eq!(
+            manager.add_args("172.16.38.40", "10.0.0.1"),
+            ["route", "add", "172.16.38.40", "dev", "tun0"]
+        );
+        assert_eq!(manager.delete_args("172.16.38.0/24"), ["route", "delete", "172.16.38.0/24"]);
+
+        let manager = LinuxRoutingManager::new();
+        assert_eq!(
+            manager.add_args("172.16.38.40", "10.0.0.1"),
+            ["route", "add", "172.16.38.40", "via", "10.0.0.1"]
+        );
+        assert_eq!(manager.blackhole_args("172.16.38.40"), ["route", "add", "blackhole", "172.16.38.40"]);
+    }
+
+    #[test]
+    fn test_route_lookup() {
+        let manager = LinuxRoutingManager::new().with_table(100);
+        assert_eq!(
+            manager.show_args("172.16.38.40"),
+            ["-4", "route", "show", "exact", "172.16.38.40", "table", "100"]
+        );
+        assert_eq!(LinuxRoutingManager::new().show_args("fd00::1")[0], "-6");
+
+        assert_eq!(
+            parse_route_dev("172.16.38.40 dev tun0 scope link \n"),
+            Some("tun0".to_string())
+        );
+        assert_eq!(parse_route_dev("blackhole 172.16.38.40 \n"), Some(String::new()));
+        assert_eq!(parse_route_dev(""), None);
+    }
+
+    #[test]
+    fn test_parse_default_route() {
+        let output = "default via 192.168.1.1 dev wlp2s0 proto dhcp src 192.168.1.50 metric 600\n\
+                      default via 10.0.0.1 dev eth0 metric 700\n";
+        assert_eq!(
+            parse_default_route(output),
+            Some(DefaultRoute {
+                gateway: "192.168.1.1".parse().unwrap(),
+                interface: Some("wlp2s0".to_string()),
+            })
+        );
+        // A default route without a next hop (e.g. through a PPP link) doesn't pin anything
+        assert_eq!(parse_default_route("default dev ppp0 scope link\n"), None);
+        assert_eq!(parse_default_route(""), None);
+    }
+
+    #[test]
+    fn test_table_args() {
+        let manager = LinuxRoutingManager::with_interface("tun0".to_string()).with_table(100);
+        assert_eq!(
+            manager.add_args("172.16.38.40", "10.0.0.1"),
+            ["route", "add", "172.16.38.40", "dev", "tun0", "table", "100"]
+        );
+        assert_eq!(
+            manager.delete_args("172.16.38.40"),
+            ["route", "delete", "172.16.38.40", "table", "100"]
+        );
+        assert_eq!(
+            manager.blackhole_args("172.16.38.40"),
+            ["route", "add", "blackhole", "172.16.38.40", "table", "100"]
+        );
+        assert_eq!(
+            LinuxRoutingManager::rule_args("add", "172.16.38.0/24", 100),
+            ["rule", "add", "to", "172.16.38.0/24", "lookup", "100"]
+        );
     }
 }
@@ -12,9 +12,12 @@
 //!
 //! # Delete route
 //! route -n delete -host 172.16.38.40
+//!
+//! # Kill switch: drop traffic to host
+//! route -n add -host 172.16.38.40 127.0.0.1 -blackhole
 //! ```
 
-use super::{PlatformError, RoutingManager};
+use super::{DefaultRoute, PlatformError, RoutingManager};
 use std::process::Command;
 use tracing::{debug, warn};
 
@@ -42,6 +45,62 @@ impl Default for MacRoutingManager {
     }
 }
 
+/// The IPv4 default route (`route -n get default`)
+pub fn default_route() -> Result<DefaultRoute, PlatformError> {
+    let output = Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .map_err(|e| PlatformError::DefaultRouteError(e.to_string()))?;
+    if !output.status.success() {
+        return Err(PlatformError::DefaultRouteError(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    parse_default_route(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| PlatformError::DefaultRouteError("no default gateway".to_string()))
+}
+
+/// The `gateway:` and `interface:` lines of `route -n get default`
+fn parse_default_route(output: &str) -> Option<DefaultRoute> {
+    let field = |key: &str| {
+        output.lines().find_map(|line| {
+            let (name, value) = line.trim().split_once(':')?;
+            (name == key).then(|| value.trim().to_string())
+        })
+    };
+    Some(DefaultRoute {
+        gateway: field("gateway")?.parse().ok()?,
+        interface: field("interface"),
+    })
+}
+
+/// The route `route -n get` reports for `destination`, if it's that exact route
+///
+/// `route get` answers with the best match, e.g. the default route once
+/// ours is gone; anything not for the destination itself counts as no route.
+fn parse_route_get(output: &str, destination: &str) -> Option<String> {
+    let field = |key: &str| {
+        output.lines().find_map(|line| {
+            let (name, value) = line.trim().split_once(':')?;
+            (name == key).then(|| value.trim().to_string())
+        })
+    };
+    let address = destination.split('/').next().unwrap_or(destination);
+    if field("destination")? != address {
+        return None;
+    }
+    Some(field("interface").unwrap_or_default())
+}
+
+/// `route` flag for a destination: `-net` for CIDR networks, `-host` otherwise
+fn dest_kind(destination: &str) -> &'static str {
+    if destination.contains('/') {
+        "-net"
+    } else {
+        "-host"
+    }
+}
+
 impl RoutingManager for MacRoutingManager {
     /// Add a route for a host through a tunnel interface
     ///
@@ -52,13 +111,13 @@ impl RoutingManager for MacRoutingManager {
         let output = if let Some(ref interface) = self.interface_name {
             debug!("Adding route: {} via interface {}", destination, interface);
             Command::new("route")
-                .args(["-n", "add", "-host", destination, "-interface", interface])
+                .args(["-n", "add", dest_kind(destination), destination, "-interface", interface])
                 .output()
                 .map_err(|e| PlatformError::AddRouteError(e.to_string()))?
         } else {
             debug!("Adding route: {} via gateway {}", destination, gateway);
             Command::new("route")
-                .args(["-n", "add", "-host", destination, gateway])
+                .args(["-n", "add", dest_kind(destination), destination, gateway])
                 .output()
                 .map_err(|e| PlatformError::AddRouteError(e.to_string()))?
         };
@@ -87,7 +146,7 @@ impl RoutingManager for MacRoutingManager {
         debug!("Deleting route: {}", destination);
 
         let output = Command::new("route")
-            .args(["-n", "delete", "-host", destination])
+            .args(["-n", "delete", dest_kind(destination), destination])
             .output()
             .map_err(|e| PlatformError::DeleteRouteError(e.to_string()))?;
 
@@ -106,4 +165,42 @@ impl RoutingManager for MacRoutingManager {
 
         Ok(())
     }
+
+    /// Interface of the route for exactly `destination` (`route -n get`)
+    fn route_interface(&self, destination: &str) -> Result<Option<String>, PlatformError> {
+        let mut args = vec!["-n", "get"];
+        if super::is_ipv6_destination(destination) {
+            args.push("-inet6");
+        }
+        args.extend([dest_kind(destination), destination]);
+        let output = Command::new("route")
+            .args(&args)
+            .output()
+            .map_err(|e| PlatformError::RouteLookupError(e.to_string()))?;
+        if !output.status.success() {
+            // "not in table": nothing routes there at all
+            return Ok(None);
+        }
+        Ok(parse_route_get(&String::from_utf8_lossy(&output.stdout), destination))
+    }
+
+    /// Add a blackhole route so traffic to a host is dropped
+    ///
+    /// # Arguments
+    /// * `destination` - IP address to block
+    fn add_blackhole_route(&self, destination: &str) -> Result<(), PlatformError> {
+        debug!("Adding blackhole route: {}", destination);
+
+        let output = Command::new("route")
+            .args(["-n", "add", dest_kind(destination), destination, "127.0.0.1", "-blackhole"])
+            .output()
+            .map_err(|e| PlatformError::AddRouteError(e.to_string()))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(PlatformError::AddRouteError(stderr.to_string()));
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,138 @@
+//! In-memory routing manager for tests
+//!
+//! Records every route request instead of touching the OS routing table, so
+//! route orchestration can be tested without root. Hand one to
+//! `VpnRouter::with_routing_manager`; clones share the same log, so the test
+//! keeps a clone to inspect what was requested. Built for unit tests and with
+//! the `testing` feature (integration tests: `cargo test --features testing`).
+
+use super::{PlatformError, RoutingManager};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// A route request seen by `MockRoutingManager`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteCall {
+    Add { destination: String, gateway: String },
+    Delete { destination: String },
+    Blackhole { destination: String },
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    calls: Vec<RouteCall>,
+    failing: HashSet<String>,
+    /// Routes moved to another interface behind our back (`repoint`)
+    repointed: HashMap<String, String>,
+}
+
+/// Routing manager that records calls (and can be told to fail some)
+#[derive(Debug, Clone, Default)]
+pub struct MockRoutingManager {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockRoutingManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail every request for `destination` with a platform error
+    ///
+    /// Failed requests aren't recorded: `calls` is what took effect.
+    pub fn fail_on(&self, destination: &str) {
+        self.lock().failing.insert(destination.to_string());
+    }
+
+    /// Have the route for `destination` point at `interface` from now on,
+    /// as if something else had replaced it
+    pub fn repoint(&self, destination: &str, interface: &str) {
+        self.lock()
+            .repointed
+            .insert(destination.to_string(), interface.to_string());
+    }
+
+    /// Every successful request so far, in order
+    pub fn calls(&self) -> Vec<RouteCall> {
+        self.lock().calls.clone()
+    }
+
+    /// Destinations currently routed: added (or blackholed) and not deleted
+    pub fn routes(&self) -> Vec<String> {
+        let mut routes: Vec<String> = Vec::new();
+        for call in &self.lock().calls {
+            match call {
+                RouteCall::Add { destination, .. } | RouteCall::Blackhole { destination } => {
+                    if !routes.contains(destination) {
+                        routes.push(destination.clone());
+                    }
+                }
+                RouteCall::Delete { destination } => routes.retain(|r| r != destination),
+            }
+        }
+        routes
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record `call`, unless its destination should fail
+    fn record(&self, call: RouteCall, destination: &str) -> Result<(), String> {
+        let mut state = self.lock();
+        if state.failing.contains(destination) {
+            return Err(format!("mock failure for {}", destination));
+        }
+        state.calls.push(call);
+        Ok(())
+    }
+}
+
+impl RoutingManager for MockRoutingManager {
+    fn add_route(&self, destination: &str, gateway: &str) -> Result<(), PlatformError> {
+        let call = RouteCall::Add {
+            destination: destination.to_string(),
+            gateway: gateway.to_string(),
+        };
+        self.record(call, destination).map_err(PlatformError::AddRouteError)
+    }
+
+    fn delete_route(&self, destination: &str) -> Result<(), PlatformError> {
+        let call = RouteCall::Delete {
+            destination: destination.to_string(),
+        };
+        self.record(call, destination).map_err(PlatformError::DeleteRouteError)
+    }
+
+    fn add_blackhole_route(&self, destination: &str) -> Result<(), PlatformError> {
+        let call = RouteCall::Blackhole {
+            destination: destination.to_string(),
+        };
+        self.record(call, destination).map_err(PlatformError::AddRouteError)
+    }
+
+    /// `tun0` for every route still in place, unless repointed
+    fn route_interface(&self, destination: &str) -> Result<Option<String>, PlatformError> {
+        if let Some(interface) = self.lock().repointed.get(destination) {
+            return Ok(Some(interface.clone()));
+        }
+        let routed = self.routes().iter().any(|r| r == destination);
+        Ok(routed.then(|| "tun0".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes_follow_adds_and_deletes() {
+        let mock = MockRoutingManager::new();
+        let handle = mock.clone();
+        mock.add_route("172.16.38.40", "10.0.1.100").unwrap();
+        mock.add_route("172.16.38.0/24", "10.0.1.100").unwrap();
+        mock.delete_route("172.16.38.40").unwrap();
+        assert_eq!(handle.routes(), ["172.16.38.0/24"]);
+        assert_eq!(handle.calls().len(), 3);
+    }
+}
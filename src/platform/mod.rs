@@ -9,6 +9,11 @@ pub mod linux;
 #[cfg(target_os = "windows")]
 pub mod windows;
 
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
+
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -19,14 +24,45 @@ pub enum PlatformError {
     DeleteRouteError(String),
     #[error("Failed to update hosts file: {0}")]
     HostsError(String),
+    #[error("Failed to configure interface address: {0}")]
+    AddressError(String),
+    #[error("Failed to flush DNS cache: {0}")]
+    DnsFlushError(String),
+    #[error("Failed to read the default route: {0}")]
+    DefaultRouteError(String),
+    #[error("Failed to look up route: {0}")]
+    RouteLookupError(String),
+    #[error("Failed to remove interface: {0}")]
+    InterfaceError(String),
     #[error("Unsupported platform")]
     UnsupportedPlatform,
 }
 
 /// Platform-agnostic routing interface
+///
+/// Destinations are a host IP ("172.16.38.40") or an IPv4 network in CIDR
+/// form ("172.16.38.0/24"). IPv6 host IPs are only routed on Windows, with
+/// the `ipv6` preference.
 pub trait RoutingManager {
     fn add_route(&self, destination: &str, gateway: &str) -> Result<(), PlatformError>;
     fn delete_route(&self, destination: &str) -> Result<(), PlatformError>;
+    /// Add a route that silently drops traffic to `destination` (kill switch)
+    ///
+    /// Removed with `delete_route` like any other host route.
+    fn add_blackhole_route(&self, destination: &str) -> Result<(), PlatformError>;
+    /// Interface the route for exactly `destination` currently points at
+    ///
+    /// `Ok(None)` if there is no such route. Platforms that can't tell
+    /// return an error, and callers carry on as if unchecked.
+    fn route_interface(&self, destination: &str) -> Result<Option<String>, PlatformError> {
+        let _ = destination;
+        Err(PlatformError::UnsupportedPlatform)
+    }
+}
+
+/// Whether a route destination (IP or CIDR network) is IPv6
+fn is_ipv6_destination(destination: &str) -> bool {
+    destination.contains(':')
 }
 
 /// Get the appropriate routing manager for the current platform
@@ -52,12 +88,213 @@ pub fn get_routing_manager() -> Result<Box<dyn RoutingManager>, PlatformError> {
     }
 }
 
+/// Get a routing manager that sends routes to the given next-hop gateway
+///
+/// For routes that must leave over the physical network (the gateway pin
+/// in full-tunnel mode) rather than the tunnel. On Linux, `table` puts them
+/// in that routing table like every other route.
+pub fn get_routing_manager_via_gateway(
+    table: Option<u32>,
+) -> Result<Box<dyn RoutingManager>, PlatformError> {
+    #[cfg(target_os = "linux")]
+    {
+        let manager = linux::LinuxRoutingManager::new();
+        Ok(match table {
+            Some(table) => Box::new(manager.with_table(table)),
+            None => Box::new(manager),
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let _ = table;
+        Ok(Box::new(mac::MacRoutingManager::new()))
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let _ = table;
+        Ok(Box::new(windows::WindowsRoutingManager::via_gateway()))
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+    {
+        let _ = table;
+        Err(PlatformError::UnsupportedPlatform)
+    }
+}
+
+/// The system's IPv4 default route
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DefaultRoute {
+    /// Next hop (the LAN router)
+    pub gateway: IpAddr,
+    /// Interface it leaves through (Windows: the interface's address)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+}
+
+/// Look up the current IPv4 default route
+pub fn default_route() -> Result<DefaultRoute, PlatformError> {
+    #[cfg(target_os = "macos")]
+    {
+        mac::default_route()
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        linux::default_route()
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        windows::default_route()
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+    {
+        Err(PlatformError::UnsupportedPlatform)
+    }
+}
+
 /// Get the interface index for a given name (Windows only)
 #[cfg(target_os = "windows")]
 pub fn get_interface_index(name: &str) -> Option<u32> {
     windows::get_interface_index(name)
 }
 
+/// Whether a network interface with this name exists
+pub fn interface_exists(name: &str) -> bool {
+    #[cfg(unix)]
+    {
+        nix::net::if_::if_nametoindex(name).is_ok()
+    }
+    #[cfg(target_os = "windows")]
+    {
+        windows::get_interface_index(name).is_some()
+    }
+}
+
+/// Whether `name` looks like a TUN device the tun crate creates: `tunN` on
+/// Linux, `utunN` on macOS
+pub fn is_tunnel_interface_name(name: &str) -> bool {
+    let prefix = if cfg!(target_os = "macos") { "utun" } else { "tun" };
+    name.strip_prefix(prefix)
+        .is_some_and(|unit| !unit.is_empty() && unit.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// TUN devices present on the system (Windows: wintun adapters)
+///
+/// Other VPN software's devices are listed too; check the state before
+/// treating one as ours.
+pub fn tunnel_interfaces() -> Vec<String> {
+    #[cfg(unix)]
+    {
+        match nix::net::if_::if_nameindex() {
+            Ok(interfaces) => interfaces
+                .iter()
+                .map(|i| i.name().to_string_lossy().into_owned())
+                .filter(|name| is_tunnel_interface_name(name))
+                .collect(),
+            Err(e) => {
+                tracing::debug!("Listing interfaces failed: {}", e);
+                vec![]
+            }
+        }
+    }
+    #[cfg(target_os = "windows")]
+    {
+        windows::wintun_adapters()
+    }
+}
+
+/// Addresses currently assigned to interface `name` (none if it's gone)
+///
+/// A device left behind by a crash keeps the tunnel address it was given,
+/// which is how cleanup tells ours apart from another VPN's `tun0`.
+pub fn interface_addresses(name: &str) -> Vec<std::net::IpAddr> {
+    #[cfg(unix)]
+    {
+        match nix::ifaddrs::getifaddrs() {
+            Ok(addrs) => addrs
+                .filter(|a| a.interface_name == name)
+                .filter_map(|a| {
+                    let address = a.address?;
+                    if let Some(v4) = address.as_sockaddr_in() {
+                        Some(std::net::IpAddr::V4(v4.ip()))
+                    } else {
+                        address.as_sockaddr_in6().map(|v6| std::net::IpAddr::V6(v6.ip()))
+                    }
+                })
+                .collect(),
+            Err(e) => {
+                tracing::debug!("Listing interface addresses failed: {}", e);
+                vec![]
+            }
+        }
+    }
+    #[cfg(target_os = "windows")]
+    {
+        windows::interface_addresses(name)
+    }
+}
+
+/// Remove a TUN device whose process is gone
+///
+/// Linux deletes persistent devices with `ip link`. On Windows the wintun
+/// adapter's device node is removed. A macOS utun device can't be removed
+/// from outside; it goes away when the last process holding it exits.
+pub fn remove_tunnel_interface(name: &str) -> Result<(), PlatformError> {
+    #[cfg(target_os = "linux")]
+    {
+        let output = std::process::Command::new("ip")
+            .args(["link", "delete", name])
+            .output()
+            .map_err(|e| PlatformError::InterfaceError(format!("ip link delete {}: {}", name, e)))?;
+        if !output.status.success() {
+            return Err(PlatformError::InterfaceError(format!(
+                "ip link delete {}: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+    #[cfg(target_os = "windows")]
+    {
+        windows::remove_wintun_adapter(name)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        Err(PlatformError::InterfaceError(format!(
+            "{} is still held by a process; it goes away when that process exits",
+            name
+        )))
+    }
+}
+
+/// Get the IPv4 address assigned to a named adapter (Windows only)
+#[cfg(target_os = "windows")]
+pub fn get_interface_ipv4(name: &str) -> Option<std::net::Ipv4Addr> {
+    windows::get_interface_ipv4(name)
+}
+
+/// Assign an IPv6 address to a named adapter (Windows only)
+#[cfg(target_os = "windows")]
+pub fn add_interface_ipv6(name: &str, addr: std::net::Ipv6Addr) -> Result<(), PlatformError> {
+    let index = windows::get_interface_index(name)
+        .ok_or_else(|| PlatformError::AddressError(format!("interface {} not found", name)))?;
+    windows::add_interface_ipv6(index, addr)
+}
+
+/// Remove an IPv6 address from whichever adapter has it (Windows only)
+///
+/// Ok(false) if no adapter has it any more (e.g. the TUN adapter is gone).
+#[cfg(target_os = "windows")]
+pub fn remove_interface_ipv6(addr: std::net::Ipv6Addr) -> Result<bool, PlatformError> {
+    windows::remove_interface_ipv6(addr)
+}
+
 /// Get a routing manager bound to a specific interface (for TUN devices)
 ///
 /// On Windows, this looks up the interface index for proper routing.
@@ -93,6 +330,67 @@ pub fn get_routing_manager_for_interface(
     }
 }
 
+/// Get a routing manager that keeps routes in routing table `table`
+///
+/// Only Linux has per-table routing (with a policy rule per destination);
+/// elsewhere this is the same as the manager without a table.
+pub fn get_routing_manager_in_table(
+    interface_name: Option<&str>,
+    table: u32,
+) -> Result<Box<dyn RoutingManager>, PlatformError> {
+    #[cfg(target_os = "linux")]
+    {
+        let manager = match interface_name {
+            Some(name) => linux::LinuxRoutingManager::with_interface(name.to_string()),
+            None => linux::LinuxRoutingManager::new(),
+        };
+        Ok(Box::new(manager.with_table(table)))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = table;
+        match interface_name {
+            Some(name) => get_routing_manager_for_interface(name),
+            None => get_routing_manager(),
+        }
+    }
+}
+
+/// Commands that flush the system resolver cache, run in order
+fn dns_flush_commands() -> &'static [&'static [&'static str]] {
+    if cfg!(target_os = "macos") {
+        &[&["dscacheutil", "-flushcache"], &["killall", "-HUP", "mDNSResponder"]]
+    } else if cfg!(target_os = "windows") {
+        &[&["ipconfig", "/flushdns"]]
+    } else {
+        // systemd-resolved; other resolvers don't cache by default
+        &[&["resolvectl", "flush-caches"]]
+    }
+}
+
+/// Flush the system DNS resolver cache
+///
+/// Run after disconnecting so names cached while the tunnel was up (or
+/// while it was going down) aren't served stale afterwards.
+pub fn flush_dns_cache() -> Result<(), PlatformError> {
+    for command in dns_flush_commands() {
+        let output = std::process::Command::new(command[0])
+            .args(&command[1..])
+            .output()
+            .map_err(|e| PlatformError::DnsFlushError(format!("{}: {}", command[0], e)))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(PlatformError::DnsFlushError(format!(
+                "{}: {}",
+                command.join(" "),
+                stderr.trim()
+            )));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -112,6 +410,33 @@ mod tests {
         assert_eq!(err.to_string(), "Unsupported platform");
     }
 
+    #[test]
+    fn test_dns_flush_commands() {
+        let commands = dns_flush_commands();
+        assert!(!commands.is_empty());
+        #[cfg(target_os = "linux")]
+        assert_eq!(commands, &[&["resolvectl", "flush-caches"][..]]);
+        #[cfg(target_os = "macos")]
+        assert_eq!(commands[1], &["killall", "-HUP", "mDNSResponder"][..]);
+    }
+
+    #[test]
+    fn test_tunnel_interface_names() {
+        let unit = if cfg!(target_os = "macos") { "utun" } else { "tun" };
+        assert!(is_tunnel_interface_name(&format!("{}0", unit)));
+        assert!(is_tunnel_interface_name(&format!("{}12", unit)));
+        assert!(!is_tunnel_interface_name(unit));
+        assert!(!is_tunnel_interface_name(&format!("{}x", unit)));
+        assert!(!is_tunnel_interface_name("eth0"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_interface_exists() {
+        assert!(interface_exists("lo"));
+        assert!(!interface_exists("pmacs-no-such0"));
+    }
+
     #[test]
     fn test_get_routing_manager_returns_ok() {
         // On supported platforms (macOS, Linux, Windows), this should succeed
@@ -1,18 +1,39 @@
 //! Windows-specific routing implementation
 
-use super::{PlatformError, RoutingManager};
+use super::{DefaultRoute, PlatformError, RoutingManager};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::process::Command;
 use tracing::{debug, info, warn};
 
+/// Interface index of the "Software Loopback Interface 1" adapter
+const LOOPBACK_INTERFACE_INDEX: &str = "1";
+
 pub struct WindowsRoutingManager {
     /// Interface index for the TUN device (if known)
     interface_index: Option<u32>,
+    /// TUN adapter name, used to look up its address when the index is unknown
+    interface_name: Option<String>,
+    /// Use the gateway passed to `add_route` as the next hop (see `via_gateway`)
+    via_gateway: bool,
 }
 
 impl WindowsRoutingManager {
     pub fn new() -> Self {
         Self {
             interface_index: None,
+            interface_name: None,
+            via_gateway: false,
+        }
+    }
+
+    /// Route through the gateway passed to `add_route`, e.g. the LAN router
+    ///
+    /// Otherwise the gateway is ignored: for tunnel routes it's the TUN
+    /// address, which is no usable next hop (see `fallback_next_hop`).
+    pub fn via_gateway() -> Self {
+        Self {
+            via_gateway: true,
+            ..Self::new()
         }
     }
 
@@ -29,6 +50,20 @@ impl WindowsRoutingManager {
         }
         Self {
             interface_index: index,
+            interface_name: Some(interface_name.to_string()),
+            via_gateway: false,
+        }
+    }
+
+    /// Next hop to use when the interface index is unknown
+    ///
+    /// `route add` needs an on-link next hop. The gateway we're handed may
+    /// not be one, but the TUN adapter's own address always is; failing that,
+    /// 0.0.0.0 lets Windows pick the on-link interface for the destination.
+    fn fallback_next_hop(&self) -> Ipv4Addr {
+        match self.interface_name.as_deref().and_then(get_interface_ipv4) {
+            Some(ip) => ip,
+            None => Ipv4Addr::UNSPECIFIED,
         }
     }
 }
@@ -41,6 +76,20 @@ impl Default for WindowsRoutingManager {
 
 impl RoutingManager for WindowsRoutingManager {
     fn add_route(&self, destination: &str, gateway: &str) -> Result<(), PlatformError> {
+        // `route` only takes IPv4 masks; IPv6 host routes go through IP Helper
+        if let Ok(v6) = destination.parse::<Ipv6Addr>() {
+            let if_index = self.interface_index.ok_or_else(|| {
+                PlatformError::AddRouteError(format!(
+                    "IPv6 route {} needs the TUN interface index",
+                    destination
+                ))
+            })?;
+            debug!("Adding IPv6 route {} via interface {} (on-link)", v6, if_index);
+            return add_ipv6_route(if_index, v6);
+        }
+
+        let (address, mask) = split_destination(destination);
+
         // If we have an interface index, use it for proper routing
         // Otherwise fall back to gateway-based routing
         let output = if let Some(if_index) = self.interface_index {
@@ -55,9 +104,9 @@ impl RoutingManager for WindowsRoutingManager {
             Command::new("route")
                 .args([
                     "add",
-                    destination,
+                    address.as_str(),
                     "mask",
-                    "255.255.255.255",
+                    mask.as_str(),
                     "0.0.0.0", // On-link: no gateway, use interface directly
                     "metric",
                     "1", // Low metric = high priority
@@ -66,10 +115,35 @@ impl RoutingManager for WindowsRoutingManager {
                 ])
                 .output()
                 .map_err(|e| PlatformError::AddRouteError(e.to_string()))?
-        } else {
+        } else if self.via_gateway {
             debug!("Adding route {} via gateway {}", destination, gateway);
             Command::new("route")
-                .args(["add", destination, "mask", "255.255.255.255", gateway])
+                .args(["add", address.as_str(), "mask", mask.as_str(), gateway, "metric", "1"])
+                .output()
+                .map_err(|e| PlatformError::AddRouteError(e.to_string()))?
+        } else {
+            let next_hop = self.fallback_next_hop();
+            if next_hop.is_unspecified() {
+                warn!(
+                    "Adding route {} on-link (0.0.0.0): TUN address unknown, ignoring gateway {}",
+                    destination, gateway
+                );
+            } else {
+                info!(
+                    "Adding route {} via TUN address {} (interface index unknown)",
+                    destination, next_hop
+                );
+            }
+            Command::new("route")
+                .args([
+                    "add",
+                    address.as_str(),
+                    "mask",
+                    mask.as_str(),
+                    &next_hop.to_string(),
+                    "metric",
+                    "1",
+                ])
                 .output()
                 .map_err(|e| PlatformError::AddRouteError(e.to_string()))?
         };
@@ -90,8 +164,13 @@ impl RoutingManager for WindowsRoutingManager {
     }
 
     fn delete_route(&self, destination: &str) -> Result<(), PlatformError> {
+        if let Ok(v6) = destination.parse::<Ipv6Addr>() {
+            return delete_ipv6_route(v6);
+        }
+
+        let (address, mask) = split_destination(destination);
         let output = Command::new("route")
-            .args(["delete", destination])
+            .args(["delete", address.as_str(), "mask", mask.as_str()])
             .output()
             .map_err(|e| PlatformError::DeleteRouteError(e.to_string()))?;
 
@@ -102,6 +181,347 @@ impl RoutingManager for WindowsRoutingManager {
 
         Ok(())
     }
+
+    /// Interface alias of the route for exactly `destination` (`Get-NetRoute`)
+    fn route_interface(&self, destination: &str) -> Result<Option<String>, PlatformError> {
+        let prefix = if destination.contains('/') {
+            destination.to_string()
+        } else if super::is_ipv6_destination(destination) {
+            format!("{}/128", destination)
+        } else {
+            format!("{}/32", destination)
+        };
+        let output = Command::new("powershell")
+            .args([
+                "-NoProfile",
+                "-Command",
+                &format!(
+                    "Get-NetRoute -DestinationPrefix '{}' -ErrorAction SilentlyContinue | Select-Object -First 1 -ExpandProperty InterfaceAlias",
+                    prefix
+                ),
+            ])
+            .output()
+            .map_err(|e| PlatformError::RouteLookupError(e.to_string()))?;
+        if !output.status.success() {
+            return Err(PlatformError::RouteLookupError(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        let alias = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok((!alias.is_empty()).then_some(alias))
+    }
+
+    fn add_blackhole_route(&self, destination: &str) -> Result<(), PlatformError> {
+        // Windows has no blackhole route type. Send the host on-link to the
+        // loopback interface (index 1) so packets never reach a real adapter.
+        debug!("Adding blackhole route {} via loopback", destination);
+        if let Ok(v6) = destination.parse::<Ipv6Addr>() {
+            return add_ipv6_route(LOOPBACK_INTERFACE_INDEX.parse().unwrap_or(1), v6);
+        }
+        let (address, mask) = split_destination(destination);
+        let output = Command::new("route")
+            .args([
+                "add",
+                address.as_str(),
+                "mask",
+                mask.as_str(),
+                "0.0.0.0",
+                "metric",
+                "1",
+                "if",
+                LOOPBACK_INTERFACE_INDEX,
+            ])
+            .output()
+            .map_err(|e| PlatformError::AddRouteError(e.to_string()))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let msg = if stderr.trim().is_empty() {
+                stdout.to_string()
+            } else {
+                stderr.to_string()
+            };
+            return Err(PlatformError::AddRouteError(msg));
+        }
+
+        Ok(())
+    }
+}
+
+/// The IPv4 default route with the lowest metric (`route print -4 0.0.0.0`)
+pub fn default_route() -> Result<DefaultRoute, PlatformError> {
+    let output = Command::new("route")
+        .args(["print", "-4", "0.0.0.0"])
+        .output()
+        .map_err(|e| PlatformError::DefaultRouteError(e.to_string()))?;
+    if !output.status.success() {
+        return Err(PlatformError::DefaultRouteError(
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        ));
+    }
+    parse_default_route(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| PlatformError::DefaultRouteError("no default gateway".to_string()))
+}
+
+/// Lowest-metric `0.0.0.0  0.0.0.0  <gateway>  <interface>  <metric>` row
+///
+/// Persistent routes are listed with the same columns minus the interface;
+/// those rows don't parse and are skipped, as are on-link defaults.
+fn parse_default_route(output: &str) -> Option<DefaultRoute> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            let [destination, mask, gateway, interface, metric] = columns[..] else {
+                return None;
+            };
+            if destination != "0.0.0.0" || mask != "0.0.0.0" {
+                return None;
+            }
+            let route = DefaultRoute {
+                gateway: gateway.parse().ok()?,
+                interface: Some(interface.to_string()),
+            };
+            Some((metric.parse::<u32>().ok()?, route))
+        })
+        .min_by_key(|(metric, _)| *metric)
+        .map(|(_, route)| route)
+}
+
+/// Split a destination into the address and mask `route` expects
+///
+/// "172.16.38.0/24" becomes ("172.16.38.0", "255.255.255.0"); a bare host IP
+/// gets a /32 mask.
+fn split_destination(destination: &str) -> (String, String) {
+    let (address, prefix) = match destination.split_once('/') {
+        Some((address, prefix)) => (address, prefix.parse::<u32>().unwrap_or(32).min(32)),
+        None => (destination, 32),
+    };
+    let mask = std::net::Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix).unwrap_or(0));
+    (address.to_string(), mask.to_string())
+}
+
+/// IPv6 address in the form IP Helper rows take
+fn sockaddr_v6(addr: Ipv6Addr) -> windows::Win32::Networking::WinSock::SOCKADDR_INET {
+    use windows::Win32::Networking::WinSock::{
+        AF_INET6, IN6_ADDR, IN6_ADDR_0, SOCKADDR_IN6, SOCKADDR_INET,
+    };
+
+    let mut sockaddr = SOCKADDR_INET::default();
+    sockaddr.Ipv6 = SOCKADDR_IN6 {
+        sin6_family: AF_INET6,
+        sin6_addr: IN6_ADDR {
+            u: IN6_ADDR_0 {
+                Byte: addr.octets(),
+            },
+        },
+        ..Default::default()
+    };
+    sockaddr
+}
+
+/// The IPv6 address in an IP Helper row, if it holds one
+fn sockaddr_ipv6(sockaddr: &windows::Win32::Networking::WinSock::SOCKADDR_INET) -> Option<Ipv6Addr> {
+    use windows::Win32::Networking::WinSock::AF_INET6;
+
+    unsafe {
+        (sockaddr.si_family == AF_INET6).then(|| Ipv6Addr::from(sockaddr.Ipv6.sin6_addr.u.Byte))
+    }
+}
+
+/// Treat "already exists" as success, and anything else as `error`
+fn check_win32(
+    result: windows::Win32::Foundation::WIN32_ERROR,
+    error: fn(String) -> PlatformError,
+) -> Result<(), PlatformError> {
+    use windows::Win32::Foundation::{ERROR_OBJECT_ALREADY_EXISTS, ERROR_SUCCESS};
+
+    if result == ERROR_SUCCESS || result == ERROR_OBJECT_ALREADY_EXISTS {
+        Ok(())
+    } else {
+        Err(error(format!("IP Helper error {}", result.0)))
+    }
+}
+
+/// Assign an IPv6 address (/128) to the adapter with this interface index
+pub fn add_interface_ipv6(if_index: u32, addr: Ipv6Addr) -> Result<(), PlatformError> {
+    use windows::Win32::NetworkManagement::IpHelper::{
+        CreateUnicastIpAddressEntry, InitializeUnicastIpAddressEntry, MIB_UNICASTIPADDRESS_ROW,
+    };
+
+    let mut row = MIB_UNICASTIPADDRESS_ROW::default();
+    unsafe { InitializeUnicastIpAddressEntry(&mut row) };
+    row.InterfaceIndex = if_index;
+    row.Address = sockaddr_v6(addr);
+    row.OnLinkPrefixLength = 128;
+    let result = unsafe { CreateUnicastIpAddressEntry(&row) };
+    check_win32(result, PlatformError::AddressError)?;
+    info!("Assigned IPv6 address {} to interface {}", addr, if_index);
+    Ok(())
+}
+
+/// Remove an IPv6 address from every adapter that has it
+///
+/// Ok(false) if none does.
+pub fn remove_interface_ipv6(addr: Ipv6Addr) -> Result<bool, PlatformError> {
+    use windows::Win32::NetworkManagement::IpHelper::{
+        DeleteUnicastIpAddressEntry, FreeMibTable, GetUnicastIpAddressTable,
+        MIB_UNICASTIPADDRESS_TABLE,
+    };
+    use windows::Win32::Networking::WinSock::AF_INET6;
+
+    let mut table: *mut MIB_UNICASTIPADDRESS_TABLE = std::ptr::null_mut();
+    let result = unsafe { GetUnicastIpAddressTable(AF_INET6, &mut table) };
+    check_win32(result, PlatformError::AddressError)?;
+
+    let mut removed = false;
+    let mut outcome = Ok(());
+    unsafe {
+        let rows = std::slice::from_raw_parts((*table).Table.as_ptr(), (*table).NumEntries as usize);
+        for row in rows.iter().filter(|row| sockaddr_ipv6(&row.Address) == Some(addr)) {
+            match check_win32(DeleteUnicastIpAddressEntry(row), PlatformError::AddressError) {
+                Ok(()) => removed = true,
+                Err(e) => outcome = Err(e),
+            }
+        }
+        FreeMibTable(table as *const _);
+    }
+    outcome.map(|()| removed)
+}
+
+/// Add an on-link /128 route for an IPv6 host on this interface
+fn add_ipv6_route(if_index: u32, destination: Ipv6Addr) -> Result<(), PlatformError> {
+    use windows::Win32::NetworkManagement::IpHelper::{
+        CreateIpForwardEntry2, InitializeIpForwardEntry, MIB_IPFORWARD_ROW2,
+    };
+
+    let mut row = MIB_IPFORWARD_ROW2::default();
+    unsafe { InitializeIpForwardEntry(&mut row) };
+    row.InterfaceIndex = if_index;
+    row.DestinationPrefix.Prefix = sockaddr_v6(destination);
+    row.DestinationPrefix.PrefixLength = 128;
+    // Unspecified next hop: on-link, like the IPv4 routes on wintun
+    row.NextHop = sockaddr_v6(Ipv6Addr::UNSPECIFIED);
+    row.Metric = 1;
+    let result = unsafe { CreateIpForwardEntry2(&row) };
+    check_win32(result, PlatformError::AddRouteError)
+}
+
+/// Delete every /128 route to this IPv6 host
+///
+/// The interface isn't needed: cleanup runs without one, so matching rows
+/// are looked up in the forwarding table.
+fn delete_ipv6_route(destination: Ipv6Addr) -> Result<(), PlatformError> {
+    use windows::Win32::NetworkManagement::IpHelper::{
+        DeleteIpForwardEntry2, FreeMibTable, GetIpForwardTable2, MIB_IPFORWARD_TABLE2,
+    };
+    use windows::Win32::Networking::WinSock::AF_INET6;
+
+    let mut table: *mut MIB_IPFORWARD_TABLE2 = std::ptr::null_mut();
+    let result = unsafe { GetIpForwardTable2(AF_INET6, &mut table) };
+    check_win32(result, PlatformError::DeleteRouteError)?;
+
+    let mut found = false;
+    let mut outcome = Ok(());
+    unsafe {
+        let rows = std::slice::from_raw_parts((*table).Table.as_ptr(), (*table).NumEntries as usize);
+        let matching = rows.iter().filter(|row| {
+            row.DestinationPrefix.PrefixLength == 128
+                && sockaddr_ipv6(&row.DestinationPrefix.Prefix) == Some(destination)
+        });
+        for row in matching {
+            found = true;
+            if let Err(e) = check_win32(DeleteIpForwardEntry2(row), PlatformError::DeleteRouteError) {
+                outcome = Err(e);
+            }
+        }
+        FreeMibTable(table as *const _);
+    }
+    if !found {
+        return Err(PlatformError::DeleteRouteError(format!(
+            "no route to {}",
+            destination
+        )));
+    }
+    outcome
+}
+
+/// Get the IPv4 address assigned to an adapter, via IP Helper
+///
+/// Matches the adapter's friendly name case-insensitively, the same way the
+/// interface index lookup falls back to a partial match for Wintun adapters.
+pub fn get_interface_ipv4(name: &str) -> Option<Ipv4Addr> {
+    use windows::Win32::Foundation::{ERROR_BUFFER_OVERFLOW, ERROR_SUCCESS};
+    use windows::Win32::NetworkManagement::IpHelper::{
+        GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST,
+        GetAdaptersAddresses, IP_ADAPTER_ADDRESSES_LH,
+    };
+    use windows::Win32::Networking::WinSock::{AF_INET, SOCKADDR_IN};
+
+    let flags = GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER;
+    let name_lower = name.to_lowercase();
+
+    // The required size can grow between calls if adapters come and go
+    let mut size: u32 = 16 * 1024;
+    let mut buffer: Vec<u64> = Vec::new();
+    let mut filled = false;
+    for _ in 0..3 {
+        // u64 elements keep the buffer aligned for IP_ADAPTER_ADDRESSES_LH
+        buffer.resize((size as usize).div_ceil(8), 0);
+        let result = unsafe {
+            GetAdaptersAddresses(
+                AF_INET.0 as u32,
+                flags,
+                None,
+                Some(buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH),
+                &mut size,
+            )
+        };
+        if result == ERROR_SUCCESS.0 {
+            filled = true;
+            break;
+        }
+        if result != ERROR_BUFFER_OVERFLOW.0 {
+            debug!("GetAdaptersAddresses failed with error {}", result);
+            return None;
+        }
+    }
+
+    if !filled {
+        debug!("GetAdaptersAddresses kept asking for a larger buffer");
+        return None;
+    }
+
+    let mut adapter = buffer.as_ptr() as *const IP_ADAPTER_ADDRESSES_LH;
+    while !adapter.is_null() {
+        let current = unsafe { &*adapter };
+        let friendly = if current.FriendlyName.is_null() {
+            String::new()
+        } else {
+            unsafe { current.FriendlyName.to_string() }.unwrap_or_default()
+        };
+        if friendly.to_lowercase().contains(&name_lower) {
+            let mut unicast = current.FirstUnicastAddress;
+            while !unicast.is_null() {
+                let entry = unsafe { &*unicast };
+                let sockaddr = entry.Address.lpSockaddr;
+                if !sockaddr.is_null() && unsafe { (*sockaddr).sa_family } == AF_INET {
+                    let sin = unsafe { &*(sockaddr as *const SOCKADDR_IN) };
+                    // S_addr is in network byte order, so its in-memory bytes are the octets
+                    let octets = unsafe { sin.sin_addr.S_un.S_addr }.to_ne_bytes();
+                    let ip = Ipv4Addr::from(octets);
+                    debug!("Interface {} has IPv4 address {}", friendly, ip);
+                    return Some(ip);
+                }
+                unicast = entry.Next;
+            }
+        }
+        adapter = current.Next;
+    }
+
+    debug!("Could not find an IPv4 address for interface {}", name);
+    None
 }
 
 /// Get the interface index for a given adapter name
@@ -127,6 +547,71 @@ pub fn get_interface_index(name: &str) -> Option<u32> {
     None
 }
 
+/// Names of the wintun adapters present, including hidden ones left by a
+/// crashed process
+pub fn wintun_adapters() -> Vec<String> {
+    let output = match Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-Command",
+            "Get-NetAdapter -IncludeHidden -ErrorAction SilentlyContinue | Where-Object { $_.DriverFileName -eq 'wintun.sys' } | Select-Object -ExpandProperty Name",
+        ])
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            debug!("PowerShell Get-NetAdapter failed listing wintun adapters");
+            return vec![];
+        }
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Addresses assigned to the adapter named `name`
+pub fn interface_addresses(name: &str) -> Vec<IpAddr> {
+    let script = format!(
+        "Get-NetIPAddress -InterfaceAlias '{}' -ErrorAction SilentlyContinue | Select-Object -ExpandProperty IPAddress",
+        name.replace('\'', "''")
+    );
+    let output = match Command::new("powershell").args(["-NoProfile", "-Command", &script]).output() {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            debug!("PowerShell Get-NetIPAddress failed for {}", name);
+            return vec![];
+        }
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        // Link-local addresses come with a %zone suffix
+        .filter_map(|line| line.trim().split('%').next()?.parse().ok())
+        .collect()
+}
+
+/// Remove a wintun adapter's device node (the adapter goes with it)
+pub fn remove_wintun_adapter(name: &str) -> Result<(), PlatformError> {
+    let script = format!(
+        "$a = Get-NetAdapter -Name '{}' -IncludeHidden -ErrorAction Stop; pnputil /remove-device $a.PnPDeviceID | Out-Null; exit $LASTEXITCODE",
+        name.replace('\'', "''")
+    );
+    let output = Command::new("powershell")
+        .args(["-NoProfile", "-Command", &script])
+        .output()
+        .map_err(|e| PlatformError::InterfaceError(format!("{}: {}", name, e)))?;
+    if !output.status.success() {
+        return Err(PlatformError::InterfaceError(format!(
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
 fn try_get_netadapter_index(name: &str) -> Option<u32> {
     let output = Command::new("powershell")
         .args([
@@ -1,10 +1,20 @@
 //! VPN state persistence
 //!
 //! Tracks active routes and hosts entries to enable cleanup after crashes
-//! or unexpected termination. State is stored in `~/.pmacs-vpn/state.json`.
+//! or unexpected termination. State is stored in `~/.pmacs-vpn/state.json`
+//! for the default profile and `~/.pmacs-vpn/state-<profile>.json` for named
+//! profiles, so several connections can be tracked at once.
 //!
 //! Also handles auth tokens for daemon mode (parent does auth, child uses token).
 //!
+//! The state directory holds internal addresses and, briefly, the session
+//! cookie, so it is created 0700 and every file in it 0600 on Unix (through
+//! `create_private_dir` and `create_private_file`). On Windows it lives in
+//! the user profile, whose ACL already keeps other users out. Under `sudo`
+//! with the user's HOME kept (the macOS default), both hand what they create
+//! to the user who ran sudo, so `status`, the tray and scripts reading
+//! `routes.json` still work without root.
+//!
 //! # State File Format
 //!
 //! ```json
@@ -22,12 +32,22 @@
 //! }
 //! ```
 
+use crate::config::HostEntry;
+use crate::vpn::resolver::ResolverSetup;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::io::{self, Write};
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+/// Profile name used when the config doesn't set one
+pub const DEFAULT_PROFILE: &str = "default";
+
 #[derive(Error, Debug)]
 pub enum StateError {
     #[error("Failed to read state file: {0}")]
@@ -36,6 +56,8 @@ pub enum StateError {
     ParseError(#[from] serde_json::Error),
     #[error("State directory not found: {0}")]
     DirectoryError(String),
+    #[error("Failed to signal daemon: {0}")]
+    SignalError(String),
 }
 
 /// A route entry (hostname to IP mapping)
@@ -43,6 +65,46 @@ pub enum StateError {
 pub struct RouteEntry {
     pub hostname: String,
     pub ip: IpAddr,
+    /// Interface the route points at, to check it's still ours before deleting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+}
+
+/// A service port to probe for a `host:port` config entry
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServicePort {
+    pub hostname: String,
+    pub ip: IpAddr,
+    pub port: u16,
+}
+
+impl ServicePort {
+    /// Open (and drop) a TCP connection to the service, returning how long it took
+    pub fn probe(&self, timeout: Duration) -> std::io::Result<Duration> {
+        let started = Instant::now();
+        std::net::TcpStream::connect_timeout(&(self.ip, self.port).into(), timeout)?;
+        Ok(started.elapsed())
+    }
+}
+
+/// A network route added for a `host/prefix` config entry
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NetworkRouteEntry {
+    pub hostname: String,
+    /// Routed network in CIDR form (e.g. "172.16.38.0/24")
+    pub network: String,
+    /// Interface the route points at (see `RouteEntry::interface`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+}
+
+/// Full-tunnel routing (`full_tunnel`), undone on cleanup
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FullTunnelRoutes {
+    /// Default route when we connected; left in place, restored if it vanished
+    pub original_default: crate::platform::DefaultRoute,
+    /// VPN gateway's public address, pinned to the original default route
+    pub gateway_pin: IpAddr,
 }
 
 /// Persisted VPN state
@@ -56,6 +118,9 @@ pub struct VpnState {
     pub gateway: IpAddr,
     /// Active routes
     pub routes: Vec<RouteEntry>,
+    /// Active network routes (from `host/prefix` entries)
+    #[serde(default)]
+    pub network_routes: Vec<NetworkRouteEntry>,
     /// Hosts file entries we added
     pub hosts_entries: Vec<RouteEntry>,
     /// When the VPN was connected
@@ -63,6 +128,57 @@ pub struct VpnState {
     /// Process ID of the VPN daemon (if running in background)
     #[serde(default)]
     pub pid: Option<u32>,
+    /// Start time of the daemon process, to detect PID reuse
+    #[serde(default)]
+    pub pid_start: Option<String>,
+    /// Profile name (labels the hosts file section we own)
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Routes are currently blackholed because the tunnel dropped
+    #[serde(default)]
+    pub kill_switch_active: bool,
+    /// Netmask the gateway pushed for our tunnel address
+    #[serde(default)]
+    pub netmask: Option<String>,
+    /// Tunnel subnet routed on-link, from the pushed netmask (None for /32)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub onlink_network: Option<String>,
+    /// IPv6 address we gave the tunnel (`ipv6` preference; removed on cleanup)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_address: Option<std::net::Ipv6Addr>,
+    /// Split-include networks the gateway pushed
+    #[serde(default)]
+    pub split_include: Vec<String>,
+    /// Gateway host this connection logged in to (reflects `--gateway`)
+    #[serde(default)]
+    pub gateway_host: Option<String>,
+    /// Negotiated TLS version and cipher suite of the tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<String>,
+    /// Set when all traffic goes through the tunnel (`full_tunnel`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_tunnel: Option<FullTunnelRoutes>,
+    /// Interface routes are bound to, when not `tunnel_device` (`--interface`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route_interface: Option<String>,
+    /// Routing table the routes went into (Linux `route_table`; None = main)
+    #[serde(default)]
+    pub route_table: Option<u32>,
+    /// Whether we wrote a hosts file section (false with `manage_hosts = false`)
+    #[serde(default = "default_true")]
+    pub manage_hosts: bool,
+    /// Hosts file the entries went into (`hosts_file_path`; None = system file)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hosts_file_path: Option<PathBuf>,
+    /// System resolver changes to undo (`manage_resolver`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolver: Option<ResolverSetup>,
+    /// Service ports from `host:port` entries, for `status --check`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub service_ports: Vec<ServicePort>,
+    /// Config comments by hostname, for the hosts file and `status`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub host_comments: HashMap<String, String>,
 }
 
 impl Default for VpnState {
@@ -72,9 +188,27 @@ impl Default for VpnState {
             tunnel_device: String::new(),
             gateway: "0.0.0.0".parse().unwrap(),
             routes: vec![],
+            network_routes: vec![],
             hosts_entries: vec![],
             connected_at: String::new(),
             pid: None,
+            pid_start: None,
+            profile: None,
+            kill_switch_active: false,
+            netmask: None,
+            onlink_network: None,
+            ipv6_address: None,
+            split_include: vec![],
+            gateway_host: None,
+            tls: None,
+            full_tunnel: None,
+            route_interface: None,
+            route_table: None,
+            manage_hosts: true,
+            hosts_file_path: None,
+            resolver: None,
+            service_ports: vec![],
+            host_comments: HashMap::new(),
         }
     }
 }
@@ -87,25 +221,113 @@ impl VpnState {
             tunnel_device,
             gateway,
             routes: vec![],
+            network_routes: vec![],
             hosts_entries: vec![],
             connected_at: chrono_lite_now(),
             pid: None,
+            pid_start: None,
+            profile: None,
+            kill_switch_active: false,
+            netmask: None,
+            onlink_network: None,
+            ipv6_address: None,
+            split_include: vec![],
+            gateway_host: None,
+            tls: None,
+            full_tunnel: None,
+            route_interface: None,
+            route_table: None,
+            manage_hosts: true,
+            hosts_file_path: None,
+            resolver: None,
+            service_ports: vec![],
+            host_comments: HashMap::new(),
         }
     }
 
-    /// Add a route entry
+    /// Add a route entry (through `route_interface`)
     pub fn add_route(&mut self, hostname: String, ip: IpAddr) {
-        self.routes.push(RouteEntry { hostname, ip });
+        let interface = self.route_interface().map(str::to_string);
+        self.routes.push(RouteEntry { hostname, ip, interface });
+    }
+
+    /// Add a network route entry (through `route_interface`)
+    pub fn add_network_route(&mut self, hostname: String, network: String) {
+        let interface = self.route_interface().map(str::to_string);
+        self.network_routes.push(NetworkRouteEntry {
+            hostname,
+            network,
+            interface,
+        });
     }
 
     /// Add a hosts entry
     pub fn add_hosts_entry(&mut self, hostname: String, ip: IpAddr) {
-        self.hosts_entries.push(RouteEntry { hostname, ip });
+        self.hosts_entries.push(RouteEntry {
+            hostname,
+            ip,
+            interface: None,
+        });
+    }
+
+    /// Record the interface routes are bound to, if not the tunnel device
+    pub fn set_route_interface(&mut self, interface: String) {
+        self.route_interface = (interface != self.tunnel_device).then_some(interface);
+    }
+
+    /// Interface routes point at: `--interface` if given, else the tunnel device
+    pub fn route_interface(&self) -> Option<&str> {
+        self.route_interface
+            .as_deref()
+            .or(Some(self.tunnel_device.as_str()).filter(|d| !d.is_empty()))
+    }
+
+    /// Record a service port to probe for a routed host
+    pub fn add_service_port(&mut self, hostname: String, ip: IpAddr, port: u16) {
+        self.service_ports.push(ServicePort { hostname, ip, port });
+    }
+
+    /// Forget a configured host: its routes, hosts entries, service ports and comment
+    ///
+    /// `names` are the hosts file names it was written under. Returns the
+    /// routes it owned, for the caller to remove from the routing table.
+    pub fn remove_host(
+        &mut self,
+        host: &str,
+        names: &[String],
+    ) -> (Vec<RouteEntry>, Vec<NetworkRouteEntry>) {
+        let (routes, kept) = std::mem::take(&mut self.routes)
+            .into_iter()
+            .partition(|r| r.hostname == host);
+        self.routes = kept;
+        let (network_routes, kept) = std::mem::take(&mut self.network_routes)
+            .into_iter()
+            .partition(|r| r.hostname == host);
+        self.network_routes = kept;
+
+        self.hosts_entries.retain(|e| !names.contains(&e.hostname));
+        self.service_ports.retain(|p| p.hostname != host);
+        for name in names {
+            self.host_comments.remove(name);
+        }
+        (routes, network_routes)
     }
 
-    /// Get the state file path
+    /// Remember the config comment for a host, under each name it's known by
+    pub fn add_host_comment(&mut self, names: &[String], comment: &str) {
+        for name in names {
+            self.host_comments.insert(name.clone(), comment.to_string());
+        }
+    }
+
+    /// Config comment for a host, if it had one
+    pub fn host_comment(&self, hostname: &str) -> Option<&str> {
+        self.host_comments.get(hostname).map(String::as_str)
+    }
+
+    /// Get the state directory (`~/.pmacs-vpn`), creating it if needed
     /// Works on both Unix (HOME) and Windows (USERPROFILE/LOCALAPPDATA)
-    pub fn state_file_path() -> Result<PathBuf, StateError> {
+    fn state_dir() -> Result<PathBuf, StateError> {
         // Try in order: HOME (Unix), USERPROFILE (Windows), LOCALAPPDATA (Windows)
         let home = std::env::var("HOME")
             .or_else(|_| std::env::var("USERPROFILE"))
@@ -120,15 +342,51 @@ impl VpnState {
 
         // Create directory if it doesn't exist
         if !state_dir.exists() {
-            fs::create_dir_all(&state_dir)?;
+            create_private_dir(&state_dir)?;
         }
 
-        Ok(state_dir.join("state.json"))
+        // Files from older versions (or copied in) may be readable by anyone
+        static CHECKED: Once = Once::new();
+        CHECKED.call_once(|| {
+            // Created by root by an older version
+            if let Err(e) = give_to_sudo_user(&state_dir) {
+                tracing::warn!("Could not hand {} to the sudo user: {}", state_dir.display(), e);
+            }
+            for path in exposed_paths(&state_dir) {
+                tracing::warn!(
+                    "{} is readable by other users; run `chmod -R go-rwx {}`",
+                    path.display(),
+                    state_dir.display()
+                );
+            }
+        });
+
+        Ok(state_dir)
     }
 
-    /// Load state from disk
+    /// Get the state file path for the default profile
+    pub fn state_file_path() -> Result<PathBuf, StateError> {
+        Self::state_file_path_for(DEFAULT_PROFILE)
+    }
+
+    /// Get the state file path for a profile
+    pub fn state_file_path_for(profile: &str) -> Result<PathBuf, StateError> {
+        Ok(Self::state_dir()?.join(state_file_name(profile)))
+    }
+
+    /// Profile this state belongs to
+    pub fn profile_name(&self) -> &str {
+        self.profile.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
+
+    /// Load state for the default profile
     pub fn load() -> Result<Option<Self>, StateError> {
-        let path = Self::state_file_path()?;
+        Self::load_profile(DEFAULT_PROFILE)
+    }
+
+    /// Load state for a profile
+    pub fn load_profile(profile: &str) -> Result<Option<Self>, StateError> {
+        let path = Self::state_file_path_for(profile)?;
 
         if !path.exists() {
             return Ok(None);
@@ -139,131 +397,672 @@ impl VpnState {
         Ok(Some(state))
     }
 
-    /// Save state to disk
+    /// Load every saved connection state (one per profile)
+    ///
+    /// Unreadable state files are skipped with a warning.
+    pub fn load_all() -> Result<Vec<Self>, StateError> {
+        let mut states = Vec::new();
+        let mut names: Vec<String> = fs::read_dir(Self::state_dir()?)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| is_state_file_name(name))
+            .collect();
+        names.sort();
+
+        for name in names {
+            let path = Self::state_dir()?.join(&name);
+            match fs::read_to_string(&path)
+                .map_err(StateError::from)
+                .and_then(|c| serde_json::from_str::<VpnState>(&c).map_err(StateError::from))
+            {
+                Ok(state) => states.push(state),
+                Err(e) => tracing::warn!("Skipping unreadable state file {}: {}", name, e),
+            }
+        }
+
+        Ok(states)
+    }
+
+    /// Save state to disk (file chosen by this state's profile)
     /// Uses atomic write (temp file + rename) to prevent corruption on crash
     pub fn save(&self) -> Result<(), StateError> {
-        let path = Self::state_file_path()?;
+        let path = Self::state_file_path_for(self.profile_name())?;
         let content = serde_json::to_string_pretty(self)?;
-
-        // Write to temp file first for atomic operation
-        let temp_path = path.with_extension("tmp");
-        fs::write(&temp_path, &content)?;
-
-        // Atomic rename
-        fs::rename(&temp_path, &path)?;
+        write_private(&path, &content)?;
         Ok(())
     }
 
-    /// Delete state file (on clean disconnect)
+    /// Delete the default profile's state file (on clean disconnect)
     pub fn delete() -> Result<(), StateError> {
-        let path = Self::state_file_path()?;
+        Self::delete_profile(DEFAULT_PROFILE)
+    }
+
+    /// Delete a profile's state file (on clean disconnect)
+    pub fn delete_profile(profile: &str) -> Result<(), StateError> {
+        let path = Self::state_file_path_for(profile)?;
         if path.exists() {
             fs::remove_file(&path)?;
         }
         Ok(())
     }
 
-    /// Check if there's an active state (for status command)
+    /// Check if there's any active state (for status command)
     pub fn is_active() -> bool {
-        Self::load().ok().flatten().is_some()
+        Self::load_all().map(|s| !s.is_empty()).unwrap_or(false)
     }
 
-    /// Set the daemon PID
+    /// Set the daemon PID (and remember its start time)
     pub fn set_pid(&mut self, pid: u32) {
         self.pid = Some(pid);
+        self.pid_start = process_start_time(pid);
+    }
+
+    /// Set the profile name
+    pub fn set_profile(&mut self, profile: Option<String>) {
+        self.profile = profile;
+    }
+
+    /// Record the network settings the gateway pushed (for status/diagnostics)
+    pub fn set_gateway_network(&mut self, netmask: Option<String>, split_include: Vec<String>) {
+        self.netmask = netmask;
+        self.split_include = split_include;
+    }
+
+    /// Record the tunnel subnet routed on-link (its route is in `network_routes`)
+    pub fn set_onlink_network(&mut self, network: Option<String>) {
+        self.onlink_network = network;
+    }
+
+    /// Record the IPv6 address assigned to the tunnel
+    pub fn set_ipv6_address(&mut self, address: Option<std::net::Ipv6Addr>) {
+        self.ipv6_address = address;
+    }
+
+    /// Record the routing table routes are added to (needed for cleanup)
+    pub fn set_route_table(&mut self, table: Option<u32>) {
+        self.route_table = table;
+    }
+
+    /// Record whether the hosts file was edited (cleanup skips it if not)
+    pub fn set_manage_hosts(&mut self, manage: bool) {
+        self.manage_hosts = manage;
+    }
+
+    /// Record which hosts file the entries went into (needed for cleanup)
+    pub fn set_hosts_file_path(&mut self, path: Option<PathBuf>) {
+        self.hosts_file_path = path;
+    }
+
+    /// Record the system resolver changes (needed for cleanup)
+    pub fn set_resolver(&mut self, setup: Option<ResolverSetup>) {
+        self.resolver = setup;
+    }
+
+    /// Record which gateway host the connection went through
+    pub fn set_gateway_host(&mut self, host: Option<String>) {
+        self.gateway_host = host;
+    }
+
+    /// Record the full-tunnel routes, so cleanup removes them
+    pub fn set_full_tunnel(&mut self, routes: FullTunnelRoutes) {
+        self.full_tunnel = Some(routes);
+    }
+
+    /// Record the tunnel's TLS version and cipher suite, for `status`
+    pub fn set_tls(&mut self, summary: String) {
+        self.tls = Some(summary);
     }
 
     /// Check if the daemon process is still running
-    #[cfg(windows)]
+    ///
+    /// The PID alone isn't enough: after a reboot or PID reuse an unrelated
+    /// process can hold it. When a start time was recorded it must match too.
     pub fn is_daemon_running(&self) -> bool {
-        use std::process::Command;
-
-        if let Some(pid) = self.pid {
-            // Use tasklist to check if process exists
-            let output = Command::new("tasklist")
-                .args(["/FI", &format!("PID eq {}", pid), "/NH"])
-                .output();
-
-            match output {
-                Ok(out) => {
-                    let stdout = String::from_utf8_lossy(&out.stdout);
-                    stdout.contains(&pid.to_string())
+        let Some(pid) = self.pid else {
+            return false;
+        };
+
+        if !is_process_alive(pid) {
+            return false;
+        }
+
+        match &self.pid_start {
+            Some(expected) => {
+                let actual = process_start_time(pid);
+                if actual.as_deref() != Some(expected.as_str()) {
+                    tracing::debug!(
+                        "PID {} start time {:?} doesn't match recorded {}, treating as not ours",
+                        pid,
+                        actual,
+                        expected
+                    );
+                    return false;
                 }
-                Err(_) => false,
+                true
             }
-        } else {
-            false
+            // State written by an older version: liveness is all we can check
+            None => true,
         }
     }
 
-    /// Check if the daemon process is still running
-    #[cfg(not(windows))]
-    pub fn is_daemon_running(&self) -> bool {
-        use std::process::Command;
-
-        if let Some(pid) = self.pid {
-            // Use kill -0 to check if process exists (doesn't actually send signal)
-            Command::new("kill")
-                .args(["-0", &pid.to_string()])
-                .status()
-                .map(|s| s.success())
-                .unwrap_or(false)
-        } else {
-            false
+    /// Ask the daemon to re-read the config and reroute changed hosts
+    ///
+    /// Sends SIGHUP (the reload event on Windows). False if the daemon isn't
+    /// running or isn't listening for reloads.
+    pub fn request_reload(&self) -> Result<bool, StateError> {
+        match self.pid {
+            Some(pid) if self.is_daemon_running() => request_reload(pid),
+            _ => Ok(false),
         }
     }
 
-    /// Kill the daemon process
-    #[cfg(windows)]
+    /// Stop the daemon process, giving it time to clean up first
     pub fn kill_daemon(&self) -> Result<(), StateError> {
-        if let Some(pid) = self.pid {
-            // Use Windows API directly for better reliability
-            use windows::Win32::Foundation::CloseHandle;
-            use windows::Win32::System::Threading::{
-                OpenProcess, TerminateProcess, PROCESS_TERMINATE,
-            };
-
-            unsafe {
-                match OpenProcess(PROCESS_TERMINATE, false, pid) {
-                    Ok(handle) => {
-                        let result = TerminateProcess(handle, 1);
-                        let _ = CloseHandle(handle);
-                        if result.is_err() {
-                            tracing::warn!("TerminateProcess failed for PID {}", pid);
-                        } else {
-                            tracing::info!("Terminated daemon process {}", pid);
-                        }
-                    }
-                    Err(e) => {
-                        // Process might already be dead
-                        tracing::debug!("Could not open process {}: {}", pid, e);
-                    }
+        self.stop_daemon(DAEMON_STOP_TIMEOUT)
+    }
+
+    /// Ask the daemon to shut down, forcing it if it's still alive after `grace`
+    ///
+    /// The graceful request (SIGTERM, or the shutdown event on Windows) lets
+    /// the daemon remove its routes, hosts entries and kill-switch routes.
+    pub fn stop_daemon(&self, grace: Duration) -> Result<(), StateError> {
+        if !self.is_daemon_running() {
+            tracing::warn!("Daemon PID {:?} is not our process, not killing it", self.pid);
+            return Ok(());
+        }
+        let Some(pid) = self.pid else {
+            return Ok(());
+        };
+
+        if request_shutdown(pid)? && wait_for_exit(pid, grace) {
+            tracing::info!("Daemon process {} shut down cleanly", pid);
+            return Ok(());
+        }
+
+        tracing::warn!("Daemon process {} did not exit within {:?}, forcing", pid, grace);
+        force_kill(pid)
+    }
+}
+
+/// How long `kill_daemon` waits for a graceful exit before forcing it
+const DAEMON_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Poll until process `pid` exits; false if it's still alive after `timeout`
+fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while is_process_alive(pid) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+/// Send SIGTERM; the daemon cleans up and exits
+#[cfg(not(windows))]
+fn request_shutdown(pid: u32) -> Result<bool, StateError> {
+    signal_process(pid, "-TERM")
+}
+
+/// Send SIGHUP; the daemon reloads its config
+#[cfg(not(windows))]
+fn request_reload(pid: u32) -> Result<bool, StateError> {
+    signal_process(pid, "-HUP")
+}
+
+/// Send SIGKILL after the daemon ignored SIGTERM
+#[cfg(not(windows))]
+fn force_kill(pid: u32) -> Result<(), StateError> {
+    signal_process(pid, "-KILL")?;
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn signal_process(pid: u32, signal: &str) -> Result<bool, StateError> {
+    use std::process::Command;
+
+    let status = Command::new("kill")
+        .args([signal, &pid.to_string()])
+        .status()
+        .map_err(StateError::ReadError)?;
+
+    if !status.success() {
+        // Process might already be dead, which is fine
+        tracing::warn!("kill {} returned non-zero for PID {}", signal, pid);
+    }
+    Ok(status.success())
+}
+
+/// Name of the event the daemon with this PID waits on for shutdown
+#[cfg(windows)]
+fn shutdown_event_name(pid: u32) -> windows::core::HSTRING {
+    windows::core::HSTRING::from(format!("Local\\pmacs-vpn-shutdown-{}", pid))
+}
+
+/// Name of the event the daemon with this PID waits on for config reloads
+#[cfg(windows)]
+fn reload_event_name(pid: u32) -> windows::core::HSTRING {
+    windows::core::HSTRING::from(format!("Local\\pmacs-vpn-reload-{}", pid))
+}
+
+/// Set the daemon's shutdown event; false if it isn't listening for one
+#[cfg(windows)]
+fn request_shutdown(pid: u32) -> Result<bool, StateError> {
+    set_event(&shutdown_event_name(pid), pid)
+}
+
+/// Set the daemon's reload event; false if it isn't listening for one
+#[cfg(windows)]
+fn request_reload(pid: u32) -> Result<bool, StateError> {
+    set_event(&reload_event_name(pid), pid)
+}
+
+/// Set a named event the daemon with this PID created
+#[cfg(windows)]
+fn set_event(name: &windows::core::HSTRING, pid: u32) -> Result<bool, StateError> {
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::System::Threading::{OpenEventW, SetEvent, EVENT_MODIFY_STATE};
+
+    unsafe {
+        match OpenEventW(EVENT_MODIFY_STATE, false, name) {
+            Ok(handle) => {
+                let result = SetEvent(handle);
+                let _ = CloseHandle(handle);
+                result.map_err(|e| StateError::SignalError(e.to_string()))?;
+                Ok(true)
+            }
+            Err(e) => {
+                // Daemon from an older version, or already exiting
+                tracing::debug!("No event {} for PID {}: {}", name, pid, e);
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Terminate the daemon outright (skips its cleanup)
+#[cfg(windows)]
+fn force_kill(pid: u32) -> Result<(), StateError> {
+    // Use Windows API directly for better reliability
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::System::Threading::{OpenProcess, TerminateProcess, PROCESS_TERMINATE};
+
+    unsafe {
+        match OpenProcess(PROCESS_TERMINATE, false, pid) {
+            Ok(handle) => {
+                let result = TerminateProcess(handle, 1);
+                let _ = CloseHandle(handle);
+                if result.is_err() {
+                    tracing::warn!("TerminateProcess failed for PID {}", pid);
+                } else {
+                    tracing::info!("Terminated daemon process {}", pid);
                 }
             }
+            Err(e) => {
+                // Process might already be dead
+                tracing::debug!("Could not open process {}: {}", pid, e);
+            }
         }
-        Ok(())
     }
+    Ok(())
+}
 
-    /// Kill the daemon process
-    #[cfg(not(windows))]
-    pub fn kill_daemon(&self) -> Result<(), StateError> {
-        use std::process::Command;
+/// A request `kill_daemon` or `request_reload` sent this daemon (Windows)
+#[cfg(windows)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonRequest {
+    Shutdown,
+    Reload,
+}
 
-        if let Some(pid) = self.pid {
-            let status = Command::new("kill")
-                .args(["-TERM", &pid.to_string()])
-                .status()
-                .map_err(StateError::ReadError)?;
+/// Shutdown and reload requests for this process (Windows)
+///
+/// Windows has no SIGTERM or SIGHUP, so the daemon creates named events
+/// instead. A single thread waits on both for the life of the daemon;
+/// dropping this sets the shutdown event so that thread exits too, rather
+/// than holding up the process after the runtime is gone.
+#[cfg(windows)]
+pub struct DaemonRequests {
+    requests: tokio::sync::mpsc::UnboundedReceiver<DaemonRequest>,
+}
 
-            if !status.success() {
-                // Process might already be dead, which is fine
-                tracing::warn!("kill returned non-zero for PID {}", pid);
+#[cfg(windows)]
+impl DaemonRequests {
+    /// Create the events and start waiting on them
+    ///
+    /// If the events can't be created, no request ever arrives.
+    pub fn listen() -> Self {
+        use windows::Win32::Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0};
+        use windows::Win32::System::Threading::{CreateEventW, WaitForMultipleObjects, INFINITE};
+
+        let (tx, requests) = tokio::sync::mpsc::unbounded_channel();
+        let pid = std::process::id();
+        // The shutdown event stays set once set; the reload event resets itself
+        let shutdown = unsafe { CreateEventW(None, true, false, &shutdown_event_name(pid)) };
+        let reload = unsafe { CreateEventW(None, false, false, &reload_event_name(pid)) };
+        let (shutdown, reload) = match (shutdown, reload) {
+            (Ok(shutdown), Ok(reload)) => (shutdown, reload),
+            (shutdown, reload) => {
+                for handle in [shutdown.as_ref().ok(), reload.as_ref().ok()].into_iter().flatten() {
+                    let _ = unsafe { CloseHandle(*handle) };
+                }
+                tracing::warn!("Could not create shutdown/reload events; use Ctrl+C to stop");
+                return Self { requests };
             }
+        };
+
+        // HANDLE isn't Send; pass the raw values to the thread
+        let raw = [shutdown.0 as isize, reload.0 as isize];
+        std::thread::spawn(move || {
+            let handles = raw.map(|h| HANDLE(h as *mut _));
+            loop {
+                let woke = unsafe { WaitForMultipleObjects(&handles, false, INFINITE) };
+                if woke.0 == WAIT_OBJECT_0.0 + 1 {
+                    if tx.send(DaemonRequest::Reload).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                // Shutdown, or the wait failed; either way stop listening
+                let _ = tx.send(DaemonRequest::Shutdown);
+                break;
+            }
+            for handle in handles {
+                let _ = unsafe { CloseHandle(handle) };
+            }
+        });
+        Self { requests }
+    }
+
+    /// Wait for the next request (cancel-safe)
+    pub async fn next(&mut self) -> DaemonRequest {
+        match self.requests.recv().await {
+            Some(request) => request,
+            None => std::future::pending().await,
         }
-        Ok(())
     }
 }
 
+#[cfg(windows)]
+impl Drop for DaemonRequests {
+    fn drop(&mut self) {
+        // Wakes the waiting thread; a no-op if it has already exited
+        let _ = request_shutdown(std::process::id());
+    }
+}
+
+/// Check whether a process with this PID exists
+#[cfg(windows)]
+fn is_process_alive(pid: u32) -> bool {
+    use std::process::Command;
+
+    // Use tasklist to check if process exists
+    let output = Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+        .output();
+
+    match output {
+        Ok(out) => {
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            stdout.contains(&pid.to_string())
+        }
+        Err(_) => false,
+    }
+}
+
+/// Check whether a process with this PID exists
+#[cfg(not(windows))]
+fn is_process_alive(pid: u32) -> bool {
+    use std::process::Command;
+
+    // Use kill -0 to check if process exists (doesn't actually send signal)
+    Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+/// Start time of a process, as an opaque token for identity checks
+///
+/// Linux: `starttime` (clock ticks since boot) from `/proc/<pid>/stat`.
+#[cfg(target_os = "linux")]
+fn process_start_time(pid: u32) -> Option<String> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_proc_stat_starttime(&stat)
+}
+
+/// Extract `starttime` (field 22) from a `/proc/<pid>/stat` line
+///
+/// The command name (field 2) may contain spaces and parentheses, so fields
+/// are counted from the last `)`.
+#[cfg(target_os = "linux")]
+fn parse_proc_stat_starttime(stat: &str) -> Option<String> {
+    let after_comm = &stat[stat.rfind(')')? + 1..];
+    // Field 3 (state) is the first after the command name
+    after_comm.split_whitespace().nth(19).map(str::to_string)
+}
+
+/// Start time of a process, as an opaque token for identity checks
+///
+/// macOS and other Unix: `ps -o lstart=`.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_start_time(pid: u32) -> Option<String> {
+    use std::process::Command;
+
+    let output = Command::new("ps")
+        .args(["-o", "lstart=", "-p", &pid.to_string()])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let start = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if start.is_empty() { None } else { Some(start) }
+}
+
+/// Start time of a process, as an opaque token for identity checks
+///
+/// Windows: process creation time (FILETIME) from `GetProcessTimes`.
+#[cfg(windows)]
+fn process_start_time(pid: u32) -> Option<String> {
+    use windows::Win32::Foundation::{CloseHandle, FILETIME};
+    use windows::Win32::System::Threading::{
+        GetProcessTimes, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    let mut creation = FILETIME::default();
+    let mut exit = FILETIME::default();
+    let mut kernel = FILETIME::default();
+    let mut user = FILETIME::default();
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid).ok()?;
+        let result = GetProcessTimes(handle, &mut creation, &mut exit, &mut kernel, &mut user);
+        let _ = CloseHandle(handle);
+        result.ok()?;
+    }
+
+    let ticks = ((creation.dwHighDateTime as u64) << 32) | creation.dwLowDateTime as u64;
+    Some(ticks.to_string())
+}
+
+/// State file name for a profile
+///
+/// The default profile keeps the original `state.json` name; other profiles
+/// are escaped to filename-safe characters (see `profile_file_name`).
+fn state_file_name(profile: &str) -> String {
+    profile_file_name("state", profile)
+}
+
+/// `<stem>.json` for the default profile, `<stem>-<profile>.json` otherwise
+///
+/// ASCII letters, digits and `-` are kept; every other byte becomes `_XX`
+/// (hex), so distinct profiles such as `lab.1` and `lab_1` never share a file.
+fn profile_file_name(stem: &str, profile: &str) -> String {
+    if profile == DEFAULT_PROFILE {
+        return format!("{}.json", stem);
+    }
+
+    let mut safe = String::with_capacity(profile.len());
+    for byte in profile.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' {
+            safe.push(byte as char);
+        } else {
+            safe.push_str(&format!("_{:02X}", byte));
+        }
+    }
+    format!("{}-{}.json", stem, safe)
+}
+
+/// Path of a profile's `<stem>` file in the state directory
+fn profile_path(stem: &str, profile: &str) -> Result<PathBuf, StateError> {
+    Ok(VpnState::state_dir()?.join(profile_file_name(stem, profile)))
+}
+
+/// Write `value` as JSON to a private file (atomically)
+fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<(), StateError> {
+    write_private(path, &serde_json::to_string(value)?)?;
+    Ok(())
+}
+
+/// Read JSON from `path`; None if the file doesn't exist
+fn load_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, StateError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+/// Like `load_json`, but remove the file once read, even if it won't parse
+fn take_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, StateError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)?;
+    fs::remove_file(path)?;
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Create `dir` (and missing parents) accessible only to its owner (0700 on Unix)
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)?;
+    give_to_sudo_user(dir)
+}
+
+/// Create (or truncate) `path` readable only by its owner (0600 on Unix)
+///
+/// An existing file's permissions are tightened too.
+pub fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        give_to_sudo_user(path)?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    options.open(path)
+}
+
+/// Give `path` to the user who ran sudo, if that's whose directory it's in
+///
+/// A no-op unless running as root under sudo; see `sudo_owner`.
+fn give_to_sudo_user(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let parent_owner = fs::metadata(parent)?.uid();
+        let sudo_uid = std::env::var("SUDO_UID").ok();
+        let sudo_gid = std::env::var("SUDO_GID").ok();
+        let euid = nix::unistd::geteuid().as_raw();
+        if let Some((uid, gid)) =
+            sudo_owner(euid, sudo_uid.as_deref(), sudo_gid.as_deref(), parent_owner)
+        {
+            std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Owner for a file root creates in a directory owned by `parent_owner`
+///
+/// Under sudo (`SUDO_UID`/`SUDO_GID` set, running as root) in the invoking
+/// user's own directory, that user; the mode stays private to them. None
+/// otherwise, e.g. when sudo reset HOME to root's.
+#[cfg(unix)]
+fn sudo_owner(
+    euid: u32,
+    sudo_uid: Option<&str>,
+    sudo_gid: Option<&str>,
+    parent_owner: u32,
+) -> Option<(u32, u32)> {
+    if euid != 0 {
+        return None;
+    }
+    let uid: u32 = sudo_uid?.parse().ok()?;
+    let gid: u32 = sudo_gid?.parse().ok()?;
+    (uid != 0 && uid == parent_owner).then_some((uid, gid))
+}
+
+/// Write `contents` to a private file atomically (temp file, then rename)
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    create_private_file(&temp_path)?.write_all(contents.as_bytes())?;
+    fs::rename(&temp_path, path)
+}
+
+/// `dir` and the files in it that other users can read (Unix; none elsewhere)
+fn exposed_paths(dir: &Path) -> Vec<PathBuf> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let world_readable = |path: &Path| {
+            fs::metadata(path).is_ok_and(|m| m.permissions().mode() & 0o004 != 0)
+        };
+        let files = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.is_file());
+        std::iter::once(dir.to_path_buf())
+            .chain(files)
+            .filter(|path| world_readable(path))
+            .collect()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Vec::new()
+    }
+}
+
+/// Whether a file in the state directory is a connection state file
+fn is_state_file_name(name: &str) -> bool {
+    name == "state.json" || (name.starts_with("state-") && name.ends_with(".json"))
+}
+
 /// Simple timestamp without heavy chrono dependency
 fn chrono_lite_now() -> String {
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -276,7 +1075,9 @@ fn chrono_lite_now() -> String {
 }
 
 /// Auth token for passing credentials from parent to daemon child
-/// Stored temporarily in ~/.pmacs-vpn/auth-token.json
+/// Stored temporarily in ~/.pmacs-vpn/auth-token.json (auth-token-<profile>.json
+/// for named profiles), so daemons for two profiles started at once each get
+/// their own
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthToken {
     /// Gateway URL
@@ -290,11 +1091,15 @@ pub struct AuthToken {
     /// Domain from login
     pub domain: String,
     /// Hosts to route
-    pub hosts: Vec<String>,
+    pub hosts: Vec<HostEntry>,
     /// Use aggressive keepalive
     pub keep_alive: bool,
     /// Created timestamp (for expiry check)
     pub created_at: u64,
+    /// Gateway logged in to, if `gateway` is another the login listed; the
+    /// daemon falls back to it should `gateway` fail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub login_gateway: Option<String>,
 }
 
 impl AuthToken {
@@ -305,7 +1110,7 @@ impl AuthToken {
         auth_cookie: String,
         portal: String,
         domain: String,
-        hosts: Vec<String>,
+        hosts: Vec<HostEntry>,
         keep_alive: bool,
     ) -> Self {
         use std::time::{SystemTime, UNIX_EPOCH};
@@ -323,52 +1128,34 @@ impl AuthToken {
             hosts,
             keep_alive,
             created_at,
+            login_gateway: None,
         }
     }
 
-    /// Get the auth token file path
-    fn token_file_path() -> Result<PathBuf, StateError> {
-        let home = std::env::var("HOME")
-            .or_else(|_| std::env::var("USERPROFILE"))
-            .or_else(|_| std::env::var("LOCALAPPDATA"))
-            .map_err(|_| {
-                StateError::DirectoryError("HOME/USERPROFILE/LOCALAPPDATA not set".into())
-            })?;
-
-        let state_dir = PathBuf::from(home).join(".pmacs-vpn");
-        if !state_dir.exists() {
-            fs::create_dir_all(&state_dir)?;
-        }
+    /// Fall back to `gateway` (the one logged in to) if `self.gateway` fails
+    pub fn with_login_gateway(mut self, gateway: impl Into<String>) -> Self {
+        let gateway = gateway.into();
+        self.login_gateway = (!gateway.eq_ignore_ascii_case(&self.gateway)).then_some(gateway);
+        self
+    }
 
-        Ok(state_dir.join("auth-token.json"))
+    /// Get the auth token file path for a profile
+    fn token_file_path(profile: &str) -> Result<PathBuf, StateError> {
+        profile_path("auth-token", profile)
     }
 
     /// Save auth token (called by parent before spawning daemon)
     /// Uses atomic write (write to temp, then rename) with restrictive permissions
-    pub fn save(&self) -> Result<PathBuf, StateError> {
-        let path = Self::token_file_path()?;
+    pub fn save(&self, profile: &str) -> Result<PathBuf, StateError> {
+        let path = Self::token_file_path(profile)?;
         let content = serde_json::to_string_pretty(self)?;
-
-        // Write to temp file first for atomic operation
-        let temp_path = path.with_extension("tmp");
-        fs::write(&temp_path, &content)?;
-
-        // Set restrictive permissions (0600) on Unix before rename
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let perms = std::fs::Permissions::from_mode(0o600);
-            fs::set_permissions(&temp_path, perms)?;
-        }
-
-        // Atomic rename
-        fs::rename(&temp_path, &path)?;
+        write_private(&path, &content)?;
         Ok(path)
     }
 
     /// Load auth token (called by daemon child)
-    pub fn load() -> Result<Option<Self>, StateError> {
-        let path = Self::token_file_path()?;
+    pub fn load(profile: &str) -> Result<Option<Self>, StateError> {
+        let path = Self::token_file_path(profile)?;
         if !path.exists() {
             return Ok(None);
         }
@@ -385,7 +1172,7 @@ impl AuthToken {
 
         if now - token.created_at > 300 {
             // Token expired, delete it
-            let _ = Self::delete();
+            let _ = Self::delete(profile);
             return Ok(None);
         }
 
@@ -393,13 +1180,426 @@ impl AuthToken {
     }
 
     /// Delete auth token file (called after daemon starts)
-    pub fn delete() -> Result<(), StateError> {
-        let path = Self::token_file_path()?;
+    pub fn delete(profile: &str) -> Result<(), StateError> {
+        let path = Self::token_file_path(profile)?;
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Current `routes.json` schema version
+pub const ROUTES_FILE_VERSION: u32 = 1;
+
+/// Machine-readable list of routed hosts for other tools (mount scripts, hooks)
+///
+/// Written to `~/.pmacs-vpn/routes.json` (`routes-<profile>.json` for named
+/// profiles) whenever routes change and removed on disconnect. Unlike
+/// `VpnState`, this format is a stable interface: fields are only added, and
+/// `version` is bumped on incompatible changes.
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "profile": "default",
+///   "updated_at": "1705314600",
+///   "routes": {
+///     "prometheus.pmacs.upenn.edu": {
+///       "ips": ["172.16.38.40"],
+///       "interface": "utun9"
+///     }
+///   }
+/// }
+/// ```
+///
+/// Hosts reached through a `host/prefix` network route also carry
+/// `"network": "172.16.38.0/24"`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoutesFile {
+    /// Schema version (`ROUTES_FILE_VERSION`)
+    pub version: u32,
+    /// Profile of the connection these routes belong to
+    pub profile: String,
+    /// Unix timestamp (seconds) of the last update
+    pub updated_at: String,
+    /// Routed hosts, keyed by hostname
+    pub routes: BTreeMap<String, RoutedHost>,
+}
+
+/// A routed host in `RoutesFile`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoutedHost {
+    /// Addresses the hostname resolved to
+    pub ips: Vec<IpAddr>,
+    /// Tunnel interface the traffic is routed through
+    pub interface: String,
+    /// Network route covering the host, if routed by `host/prefix`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+}
+
+impl RoutesFile {
+    /// Build the routes file for a connection's current state
+    pub fn from_state(state: &VpnState) -> Self {
+        let mut routes: BTreeMap<String, RoutedHost> = BTreeMap::new();
+
+        // Every routed host has a hosts entry, including ones sharing a route
+        for entry in &state.hosts_entries {
+            let host = routes.entry(entry.hostname.clone()).or_insert_with(|| RoutedHost {
+                ips: vec![],
+                interface: state.tunnel_device.clone(),
+                network: None,
+            });
+            if !host.ips.contains(&entry.ip) {
+                host.ips.push(entry.ip);
+            }
+            if host.network.is_none() {
+                host.network = state
+                    .network_routes
+                    .iter()
+                    .find(|r| crate::vpn::routing::network_contains(&r.network, entry.ip))
+                    .map(|r| r.network.clone());
+            }
+        }
+
+        Self {
+            version: ROUTES_FILE_VERSION,
+            profile: state.profile_name().to_string(),
+            updated_at: chrono_lite_now(),
+            routes,
+        }
+    }
+
+    /// Rebuild the routes and hosts entries of the connection it describes
+    ///
+    /// For cleaning up when the state file is gone (see
+    /// `cleanup_orphaned`). Hosts sharing an address or network
+    /// give one route; everything else the state records is left unset.
+    pub fn to_state(&self) -> VpnState {
+        let interface = self.routes.values().map(|host| host.interface.clone()).next();
+        let gateway = IpAddr::from([0, 0, 0, 0]);
+        let mut state = VpnState::new(interface.unwrap_or_default(), gateway);
+        state.set_profile(Some(self.profile.clone()).filter(|p| p != DEFAULT_PROFILE));
+
+        for (hostname, host) in &self.routes {
+            let interface = Some(host.interface.clone());
+            match &host.network {
+                Some(network) if !state.network_routes.iter().any(|r| &r.network == network) => {
+                    state.network_routes.push(NetworkRouteEntry {
+                        hostname: hostname.clone(),
+                        network: network.clone(),
+                        interface,
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    for ip in &host.ips {
+                        if !state.routes.iter().any(|r| r.ip == *ip) {
+                            state.routes.push(RouteEntry {
+                                hostname: hostname.clone(),
+                                ip: *ip,
+                                interface: interface.clone(),
+                            });
+                        }
+                    }
+                }
+            }
+            for ip in &host.ips {
+                state.add_hosts_entry(hostname.clone(), *ip);
+            }
+        }
+        state
+    }
+
+    /// Get the routes file path for a profile
+    pub fn path_for(profile: &str) -> Result<PathBuf, StateError> {
+        profile_path("routes", profile)
+    }
+
+    /// Write the routes file (atomically)
+    pub fn save(&self) -> Result<PathBuf, StateError> {
+        let path = Self::path_for(&self.profile)?;
+        let content = serde_json::to_string_pretty(self)?;
+        write_private(&path, &content)?;
+        Ok(path)
+    }
+
+    /// Load the routes file for a profile
+    pub fn load(profile: &str) -> Result<Option<Self>, StateError> {
+        load_json(&Self::path_for(profile)?)
+    }
+
+    /// Remove the routes file for a profile (on disconnect)
+    pub fn delete(profile: &str) -> Result<(), StateError> {
+        let path = Self::path_for(profile)?;
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a background connection's tunnel ended
+///
+/// The daemon writes this (`disconnect.json` / `disconnect-<profile>.json`)
+/// before cleaning up, so the tray can tell a network drop, which it
+/// reconnects, from a gateway logout, which needs a fresh login.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    /// Network drop or dead tunnel; reconnecting is safe
+    ConnectionLost,
+    /// Gateway logged the session out or it expired; log in again
+    SessionEnded,
+}
+
+impl DisconnectReason {
+    /// Classify how a tunnel ended
+    pub fn from_error(error: &crate::gp::TunnelError) -> Self {
+        if error.should_reconnect() {
+            Self::ConnectionLost
+        } else {
+            Self::SessionEnded
+        }
+    }
+
+    /// Get the reason file path for a profile
+    pub fn path_for(profile: &str) -> Result<PathBuf, StateError> {
+        profile_path("disconnect", profile)
+    }
+
+    /// Record the reason for a profile's disconnect
+    pub fn save(self, profile: &str) -> Result<(), StateError> {
+        save_json(&Self::path_for(profile)?, &self)
+    }
+
+    /// Read and remove a profile's recorded reason, if any
+    pub fn take(profile: &str) -> Result<Option<Self>, StateError> {
+        take_json(&Self::path_for(profile)?)
+    }
+}
+
+/// How a background daemon's connect attempt turned out
+///
+/// The daemon child writes this (`handshake.json` / `handshake-<profile>.json`)
+/// once its routes are up, or when it gives up, and the parent that spawned
+/// it waits for it before reporting success.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DaemonHandshake {
+    /// Tunnel and routes are up
+    Ready { pid: u32 },
+    /// The connect failed; the daemon is exiting
+    Error { pid: u32, message: String },
+}
+
+impl DaemonHandshake {
+    /// PID of the daemon that wrote this
+    pub fn pid(&self) -> u32 {
+        match self {
+            Self::Ready { pid } | Self::Error { pid, .. } => *pid,
+        }
+    }
+
+    /// Get the handshake file path for a profile
+    pub fn path_for(profile: &str) -> Result<PathBuf, StateError> {
+        profile_path("handshake", profile)
+    }
+
+    /// Report the outcome for a profile
+    pub fn save(&self, profile: &str) -> Result<(), StateError> {
+        save_json(&Self::path_for(profile)?, self)
+    }
+
+    /// Read and remove a profile's handshake, if the daemon has written one
+    pub fn take(profile: &str) -> Result<Option<Self>, StateError> {
+        take_json(&Self::path_for(profile)?)
+    }
+}
+
+/// The most recent failed connect attempt for a profile
+///
+/// Written (`last-error.json` / `last-error-<profile>.json`) whenever a
+/// connect fails, including in the background daemon before any tunnel comes
+/// up, so `status` can say why nothing is connected. Cleared once a connect
+/// succeeds.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConnectFailure {
+    /// What went wrong
+    pub last_error: String,
+    /// When it failed (seconds since the Unix epoch)
+    pub failed_at: u64,
+}
+
+impl ConnectFailure {
+    /// Failure with the given message, timestamped now
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            last_error: message.into(),
+            failed_at: unix_now(),
+        }
+    }
+
+    /// Get the failure file path for a profile
+    pub fn path_for(profile: &str) -> Result<PathBuf, StateError> {
+        profile_path("last-error", profile)
+    }
+
+    /// Record this failure for a profile, replacing any earlier one
+    pub fn save(&self, profile: &str) -> Result<(), StateError> {
+        save_json(&Self::path_for(profile)?, self)
+    }
+
+    /// Read a profile's last failure, if any
+    pub fn load(profile: &str) -> Result<Option<Self>, StateError> {
+        load_json(&Self::path_for(profile)?)
+    }
+
+    /// Forget a profile's last failure (after a successful connect)
+    pub fn clear(profile: &str) -> Result<(), StateError> {
+        let path = Self::path_for(profile)?;
         if path.exists() {
             fs::remove_file(&path)?;
         }
         Ok(())
     }
+
+    /// How long ago the attempt failed, e.g. "2m ago"
+    pub fn age(&self) -> String {
+        format_age(unix_now().saturating_sub(self.failed_at))
+    }
+}
+
+/// Auto-reconnect activity for a profile
+///
+/// The tray's health monitor writes this (`reconnect.json` /
+/// `reconnect-<profile>.json`) as it schedules attempts. The connection state
+/// is gone while a reconnect is pending, so this lives in its own file and
+/// `status` reads it alongside the state.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReconnectStatus {
+    /// Current attempt (1-based); 0 when no reconnect is under way
+    pub attempt: u32,
+    pub max_attempts: u32,
+    /// Backoff before the current attempt, in seconds
+    pub backoff_secs: u64,
+    /// When the current attempt starts (seconds since the Unix epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<u64>,
+    /// When a reconnect last succeeded (seconds since the Unix epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_reconnect_at: Option<u64>,
+}
+
+impl ReconnectStatus {
+    /// Get the reconnect file path for a profile
+    pub fn path_for(profile: &str) -> Result<PathBuf, StateError> {
+        profile_path("reconnect", profile)
+    }
+
+    /// Record this status for a profile
+    pub fn save(&self, profile: &str) -> Result<(), StateError> {
+        save_json(&Self::path_for(profile)?, self)
+    }
+
+    /// Read a profile's reconnect status, if any was recorded
+    pub fn load(profile: &str) -> Result<Option<Self>, StateError> {
+        load_json(&Self::path_for(profile)?)
+    }
+
+    /// Attempt `attempt` of `max_attempts` will start after `backoff_secs`
+    pub fn schedule(&mut self, attempt: u32, max_attempts: u32, backoff_secs: u64) {
+        self.attempt = attempt;
+        self.max_attempts = max_attempts;
+        self.backoff_secs = backoff_secs;
+        self.next_attempt_at = Some(unix_now() + backoff_secs);
+    }
+
+    /// The reconnect succeeded
+    pub fn reconnected(&mut self) {
+        self.stop();
+        self.last_reconnect_at = Some(unix_now());
+    }
+
+    /// No further attempts (gave up, or the session needs a fresh login)
+    pub fn stop(&mut self) {
+        self.attempt = 0;
+        self.backoff_secs = 0;
+        self.next_attempt_at = None;
+    }
+
+    /// Whether a reconnect is scheduled or in progress
+    pub fn is_reconnecting(&self) -> bool {
+        self.attempt > 0
+    }
+
+    /// "Reconnecting: attempt 2/5, next in 4s", or None when not reconnecting
+    pub fn summary(&self) -> Option<String> {
+        if !self.is_reconnecting() {
+            return None;
+        }
+        let progress = format!("Reconnecting: attempt {}/{}", self.attempt, self.max_attempts);
+        Some(match self.next_attempt_at {
+            Some(at) if at > unix_now() => format!("{}, next in {}s", progress, at - unix_now()),
+            _ => format!("{}, in progress", progress),
+        })
+    }
+
+    /// How long ago the last reconnect succeeded, e.g. "5m ago"
+    pub fn last_reconnect_age(&self) -> Option<String> {
+        self.last_reconnect_at
+            .map(|at| format_age(unix_now().saturating_sub(at)))
+    }
+}
+
+/// Tunnel address the gateway last assigned to a profile
+///
+/// The connection state (whose `gateway` is this address) is deleted on
+/// disconnect, so the address is also kept in `last-ip.json` /
+/// `last-ip-<profile>.json`. The next connect sends it as getconfig's
+/// `preferred-ip`, so a reconnect usually gets the same address back.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LastAddress {
+    pub internal_ip: IpAddr,
+}
+
+impl LastAddress {
+    /// Get the address file path for a profile
+    pub fn path_for(profile: &str) -> Result<PathBuf, StateError> {
+        profile_path("last-ip", profile)
+    }
+
+    /// Remember the address assigned to a profile
+    pub fn save(self, profile: &str) -> Result<(), StateError> {
+        save_json(&Self::path_for(profile)?, &self)
+    }
+
+    /// Address last assigned to a profile, if any
+    pub fn load(profile: &str) -> Result<Option<IpAddr>, StateError> {
+        let address: Option<Self> = load_json(&Self::path_for(profile)?)?;
+        Ok(address.map(|a| a.internal_ip))
+    }
+}
+
+/// Seconds since the Unix epoch
+fn unix_now() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Compact "<n><unit> ago" for an age in seconds
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s ago", secs),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
 }
 
 #[cfg(test)]
@@ -422,6 +1622,23 @@ mod tests {
         assert!(!state.connected_at.is_empty());
     }
 
+    #[test]
+    fn test_service_port_probe() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut service = ServicePort {
+            hostname: "a.example.com".to_string(),
+            ip: "127.0.0.1".parse().unwrap(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        assert!(service.probe(Duration::from_secs(1)).is_ok());
+
+        drop(listener);
+        assert!(service.probe(Duration::from_secs(1)).is_err());
+
+        service.port = 0;
+        assert!(service.probe(Duration::from_secs(1)).is_err());
+    }
+
     #[test]
     fn test_add_route() {
         let mut state = VpnState::default();
@@ -431,6 +1648,68 @@ mod tests {
         assert_eq!(state.routes[0].hostname, "test.example.com");
     }
 
+    #[test]
+    fn test_routes_record_their_interface() {
+        let mut state = VpnState::new("tun0".to_string(), "10.0.0.1".parse().unwrap());
+        state.add_route("a".to_string(), "172.16.38.40".parse().unwrap());
+        state.set_route_interface("tun0".to_string());
+        assert_eq!(state.route_interface, None);
+
+        state.set_route_interface("wg0".to_string());
+        state.add_network_route("b".to_string(), "172.16.38.0/24".to_string());
+        assert_eq!(state.routes[0].interface.as_deref(), Some("tun0"));
+        assert_eq!(state.network_routes[0].interface.as_deref(), Some("wg0"));
+
+        // State files from before interfaces were recorded still load
+        let json = r#"{"hostname": "a", "ip": "172.16.38.40"}"#;
+        let entry: RouteEntry = serde_json::from_str(json).unwrap();
+        assert_eq!(entry.interface, None);
+    }
+
+    #[test]
+    fn test_add_network_route() {
+        let mut state = VpnState::default();
+        state.add_network_route("a.example.com".to_string(), "172.16.38.0/24".to_string());
+        assert_eq!(state.network_routes.len(), 1);
+        assert_eq!(state.network_routes[0].network, "172.16.38.0/24");
+
+        let json = serde_json::to_string(&state).unwrap();
+        let parsed: VpnState = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.network_routes, state.network_routes);
+    }
+
+    #[test]
+    fn test_remove_host() {
+        let mut state = VpnState::default();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let names = vec!["a".to_string(), "a.example.com".to_string()];
+        state.add_route("a".to_string(), ip);
+        state.add_route("b".to_string(), "10.0.0.2".parse().unwrap());
+        state.add_network_route("a".to_string(), "172.16.38.0/24".to_string());
+        for name in &names {
+            state.add_hosts_entry(name.clone(), ip);
+        }
+        state.add_hosts_entry("b".to_string(), "10.0.0.2".parse().unwrap());
+        state.add_service_port("a".to_string(), ip, 22);
+        state.add_host_comment(&names, "monitoring");
+
+        let (routes, network_routes) = state.remove_host("a", &names);
+        assert_eq!(
+            routes,
+            vec![RouteEntry {
+                hostname: "a".to_string(),
+                ip,
+                interface: None,
+            }]
+        );
+        assert_eq!(network_routes.len(), 1);
+        assert_eq!(state.routes.len(), 1);
+        assert!(state.network_routes.is_empty());
+        assert_eq!(state.hosts_entries.len(), 1);
+        assert!(state.service_ports.is_empty());
+        assert!(state.host_comments.is_empty());
+    }
+
     #[test]
     fn test_add_hosts_entry() {
         let mut state = VpnState::default();
@@ -462,10 +1741,12 @@ mod tests {
         let entry1 = RouteEntry {
             hostname: "test.example.com".to_string(),
             ip: "10.0.0.1".parse().unwrap(),
+            interface: None,
         };
         let entry2 = RouteEntry {
             hostname: "test.example.com".to_string(),
             ip: "10.0.0.1".parse().unwrap(),
+            interface: None,
         };
         assert_eq!(entry1, entry2);
     }
@@ -512,6 +1793,298 @@ mod tests {
 
         let parsed: VpnState = serde_json::from_str(json).unwrap();
         assert!(parsed.pid.is_none());
+        assert!(!parsed.kill_switch_active);
+        assert!(parsed.netmask.is_none());
+        assert!(parsed.split_include.is_empty());
+        assert!(parsed.network_routes.is_empty());
+        assert!(parsed.gateway_host.is_none());
+        assert!(parsed.route_table.is_none());
+        // Older connections always wrote the hosts file
+        assert!(parsed.manage_hosts);
+        assert!(parsed.hosts_file_path.is_none());
+    }
+
+    #[test]
+    fn test_gateway_host_roundtrip() {
+        let mut state = VpnState::new("utun9".to_string(), "10.0.0.1".parse().unwrap());
+        state.set_gateway_host(Some("vpn2.example.com".to_string()));
+
+        let json = serde_json::to_string(&state).unwrap();
+        let parsed: VpnState = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.gateway_host.as_deref(), Some("vpn2.example.com"));
+    }
+
+    #[test]
+    fn test_kill_switch_state_roundtrip() {
+        let mut state = VpnState::new("utun9".to_string(), "10.0.0.1".parse().unwrap());
+        state.kill_switch_active = true;
+
+        let json = serde_json::to_string(&state).unwrap();
+        let parsed: VpnState = serde_json::from_str(&json).unwrap();
+        assert!(parsed.kill_switch_active);
+    }
+
+    #[test]
+    fn test_disconnect_reason_from_tunnel_error() {
+        use crate::gp::TunnelError;
+
+        let lost = TunnelError::ConnectionLost("reset".to_string());
+        assert_eq!(DisconnectReason::from_error(&lost), DisconnectReason::ConnectionLost);
+        assert_eq!(
+            DisconnectReason::from_error(&TunnelError::GatewayLogout),
+            DisconnectReason::SessionEnded
+        );
+        assert_eq!(
+            serde_json::to_string(&DisconnectReason::SessionEnded).unwrap(),
+            "\"session_ended\""
+        );
+        assert_eq!(profile_file_name("disconnect", "lab"), "disconnect-lab.json");
+    }
+
+    #[test]
+    fn test_connect_failure_age_and_file_name() {
+        assert_eq!(format_age(5), "5s ago");
+        assert_eq!(format_age(150), "2m ago");
+        assert_eq!(format_age(7200), "2h ago");
+        assert_eq!(format_age(3 * 86400), "3d ago");
+
+        let failure = ConnectFailure::new("MFA timed out");
+        assert!(failure.age().ends_with("s ago"));
+        let json = serde_json::to_string(&failure).unwrap();
+        assert!(json.contains("\"last_error\":\"MFA timed out\""));
+        assert_eq!(profile_file_name("last-error", "lab"), "last-error-lab.json");
+    }
+
+    #[test]
+    fn test_daemon_handshake_format() {
+        let ready = DaemonHandshake::Ready { pid: 4242 };
+        assert_eq!(serde_json::to_string(&ready).unwrap(), r#"{"status":"ready","pid":4242}"#);
+
+        let error: DaemonHandshake =
+            serde_json::from_str(r#"{"status":"error","pid":7,"message":"getconfig failed"}"#).unwrap();
+        assert_eq!(error.pid(), 7);
+        assert_eq!(
+            error,
+            DaemonHandshake::Error {
+                pid: 7,
+                message: "getconfig failed".to_string()
+            }
+        );
+        assert_eq!(profile_file_name("handshake", DEFAULT_PROFILE), "handshake.json");
+    }
+
+    #[test]
+    fn test_reconnect_status_summary() {
+        let mut status = ReconnectStatus::default();
+        assert_eq!(status.summary(), None);
+
+        status.schedule(2, 5, 60);
+        let summary = status.summary().unwrap();
+        assert!(summary.starts_with("Reconnecting: attempt 2/5, next in "), "{}", summary);
+
+        status.next_attempt_at = Some(0);
+        assert_eq!(status.summary().unwrap(), "Reconnecting: attempt 2/5, in progress");
+
+        status.reconnected();
+        assert_eq!(status.summary(), None);
+        assert!(status.last_reconnect_age().unwrap().ends_with("s ago"));
+        assert_eq!(profile_file_name("reconnect", "lab"), "reconnect-lab.json");
+    }
+
+    #[test]
+    fn test_last_address_format() {
+        let address = LastAddress {
+            internal_ip: "10.0.1.100".parse().unwrap(),
+        };
+        let json = serde_json::to_string(&address).unwrap();
+        assert_eq!(json, r#"{"internal_ip":"10.0.1.100"}"#);
+        assert_eq!(serde_json::from_str::<LastAddress>(&json).unwrap(), address);
+        assert_eq!(profile_file_name("last-ip", DEFAULT_PROFILE), "last-ip.json");
+    }
+
+    #[test]
+    fn test_set_pid_records_start_time() {
+        let mut state = VpnState::default();
+        state.set_pid(std::process::id());
+        assert!(state.pid_start.is_some());
+        assert!(state.is_daemon_running());
+    }
+
+    #[test]
+    fn test_is_daemon_running_detects_pid_reuse() {
+        let mut state = VpnState::default();
+        state.set_pid(std::process::id());
+
+        // Same PID, different process start time: not our daemon
+        state.pid_start = Some("not-the-recorded-start".to_string());
+        assert!(!state.is_daemon_running());
+        // And kill_daemon must leave it alone
+        assert!(state.kill_daemon().is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_proc_stat_starttime() {
+        let stat = "1234 (my (odd) daemon) S 1 1234 1234 0 -1 4194560 100 0 0 0 \
+                    5 3 0 0 20 0 1 0 987654 12345678 300";
+        assert_eq!(parse_proc_stat_starttime(stat), Some("987654".to_string()));
+        assert_eq!(parse_proc_stat_starttime("garbage"), None);
+    }
+
+    /// Spawn a stand-in daemon that writes `marker` from its SIGTERM handler
+    #[cfg(unix)]
+    fn spawn_fake_daemon(
+        marker: &std::path::Path,
+        on_term: &str,
+    ) -> (u32, std::thread::JoinHandle<()>) {
+        let script = format!(
+            "trap '{}' TERM; echo ready; while :; do sleep 0.05; done",
+            on_term.replace("$MARKER", &marker.display().to_string())
+        );
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", &script])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // Wait for the trap to be installed
+        let mut line = String::new();
+        std::io::BufRead::read_line(
+            &mut std::io::BufReader::new(child.stdout.take().unwrap()),
+            &mut line,
+        )
+        .unwrap();
+
+        let pid = child.id();
+        let waiter = std::thread::spawn(move || {
+            // Reap the child so it doesn't linger as a zombie
+            let _ = child.wait();
+        });
+        (pid, waiter)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stop_daemon_runs_cleanup_on_graceful_shutdown() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let marker = dir.path().join("cleaned-up");
+        let (pid, waiter) = spawn_fake_daemon(&marker, "touch $MARKER; exit 0");
+
+        let mut state = VpnState::default();
+        state.set_pid(pid);
+        state.stop_daemon(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+
+        assert!(marker.exists(), "SIGTERM handler (cleanup) did not run");
+        assert!(!state.is_daemon_running());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stop_daemon_escalates_when_ignored() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let marker = dir.path().join("saw-term");
+        let (pid, waiter) = spawn_fake_daemon(&marker, "touch $MARKER");
+
+        let mut state = VpnState::default();
+        state.set_pid(pid);
+        state.stop_daemon(Duration::from_millis(300)).unwrap();
+        waiter.join().unwrap();
+
+        assert!(marker.exists());
+        assert!(!state.is_daemon_running());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_files_and_exposed_check() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = tempfile::TempDir::new().unwrap();
+        let dir = temp.path().join("state");
+        create_private_dir(&dir).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+
+        let path = dir.join("state.json");
+        write_private(&path, "{}").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+        assert!(exposed_paths(&dir).is_empty());
+
+        // Left over from before: reported, then fixed by the next save
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(exposed_paths(&dir), std::slice::from_ref(&path));
+        write_private(&path, "{}").unwrap();
+        assert!(exposed_paths(&dir).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sudo_owner() {
+        // sudo with the user's HOME kept: their files stay theirs
+        assert_eq!(sudo_owner(0, Some("501"), Some("20"), 501), Some((501, 20)));
+        // Not root, or not under sudo
+        assert_eq!(sudo_owner(501, Some("501"), Some("20"), 501), None);
+        assert_eq!(sudo_owner(0, None, None, 0), None);
+        assert_eq!(sudo_owner(0, Some("501"), None, 501), None);
+        assert_eq!(sudo_owner(0, Some("x"), Some("20"), 501), None);
+        // sudo reset HOME to root's, or sudo from root itself
+        assert_eq!(sudo_owner(0, Some("501"), Some("20"), 0), None);
+        assert_eq!(sudo_owner(0, Some("0"), Some("0"), 0), None);
+    }
+
+    #[test]
+    fn test_routes_file_from_state() {
+        let mut state = VpnState::new("utun9".to_string(), "10.0.0.1".parse().unwrap());
+        state.set_profile(Some("lab".to_string()));
+        state.add_route("a.example.com".to_string(), "172.16.38.40".parse().unwrap());
+        state.add_hosts_entry("a.example.com".to_string(), "172.16.38.40".parse().unwrap());
+        // Shares a.example.com's route
+        state.add_hosts_entry("b.example.com".to_string(), "172.16.38.40".parse().unwrap());
+        state.add_network_route("c.example.com".to_string(), "10.30.1.0/24".to_string());
+        state.add_hosts_entry("c.example.com".to_string(), "10.30.1.7".parse().unwrap());
+
+        let file = RoutesFile::from_state(&state);
+        assert_eq!(file.version, ROUTES_FILE_VERSION);
+        assert_eq!(file.profile, "lab");
+        assert_eq!(file.routes.len(), 3);
+
+        let a = &file.routes["a.example.com"];
+        assert_eq!(a.ips, vec!["172.16.38.40".parse::<IpAddr>().unwrap()]);
+        assert_eq!(a.interface, "utun9");
+        assert!(a.network.is_none());
+        assert_eq!(file.routes["b.example.com"].ips, a.ips);
+        assert_eq!(file.routes["c.example.com"].network.as_deref(), Some("10.30.1.0/24"));
+
+        let rebuilt = file.to_state();
+        assert_eq!(rebuilt.profile_name(), "lab");
+        assert_eq!(rebuilt.tunnel_device, "utun9");
+        assert_eq!(rebuilt.routes.len(), 1);
+        assert_eq!(rebuilt.routes[0].interface.as_deref(), Some("utun9"));
+        assert_eq!(rebuilt.network_routes.len(), 1);
+        assert_eq!(rebuilt.network_routes[0].network, "10.30.1.0/24");
+        assert_eq!(rebuilt.hosts_entries.len(), 3);
+    }
+
+    #[test]
+    fn test_routes_file_schema() {
+        let mut state = VpnState::new("utun9".to_string(), "10.0.0.1".parse().unwrap());
+        state.add_hosts_entry("a.example.com".to_string(), "172.16.38.40".parse().unwrap());
+
+        let json = serde_json::to_value(RoutesFile::from_state(&state)).unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["profile"], "default");
+        assert!(json["updated_at"].is_string());
+        assert_eq!(json["routes"]["a.example.com"]["ips"][0], "172.16.38.40");
+        assert_eq!(json["routes"]["a.example.com"]["interface"], "utun9");
+        assert!(json["routes"]["a.example.com"].get("network").is_none());
+    }
+
+    #[test]
+    fn test_routes_file_name_per_profile() {
+        assert_eq!(profile_file_name("routes", DEFAULT_PROFILE), "routes.json");
+        assert_eq!(profile_file_name("routes", "lab"), "routes-lab.json");
+        assert!(!is_state_file_name("routes-lab.json"));
     }
 
     #[test]
@@ -519,4 +2092,28 @@ mod tests {
         let state = VpnState::default();
         assert!(!state.is_daemon_running());
     }
+
+    #[test]
+    fn test_state_file_name_per_profile() {
+        assert_eq!(state_file_name(DEFAULT_PROFILE), "state.json");
+        assert_eq!(state_file_name("lab"), "state-lab.json");
+        assert_eq!(state_file_name("../evil profile"), "state-_2E_2E_2Fevil_20profile.json");
+        assert_eq!(state_file_name("my-lab2"), "state-my-lab2.json");
+        assert_ne!(state_file_name("lab.1"), state_file_name("lab_1"));
+        assert_ne!(state_file_name("lab_1"), state_file_name("lab_5F1"));
+
+        assert!(is_state_file_name("state.json"));
+        assert!(is_state_file_name("state-lab.json"));
+        assert!(!is_state_file_name("state.tmp"));
+        assert!(!is_state_file_name("auth-token.json"));
+    }
+
+    #[test]
+    fn test_profile_name_defaults() {
+        let mut state = VpnState::default();
+        assert_eq!(state.profile_name(), DEFAULT_PROFILE);
+
+        state.set_profile(Some("lab".to_string()));
+        assert_eq!(state.profile_name(), "lab");
+    }
 }
@@ -0,0 +1,170 @@
+//! Connection status for `pmacs-vpn status` and library users
+//!
+//! Everything `status` shows comes from the files in the state directory, so
+//! embedders can get the same picture with `status()` without running the
+//! CLI. The report serializes to what `status --json` prints.
+
+use crate::state::{ConnectFailure, ReconnectStatus, VpnState, DEFAULT_PROFILE};
+use crate::vpn::orphaned_interfaces;
+use serde::Serialize;
+
+/// Where a connection with saved state stands
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionHealth {
+    /// The tunnel is up (or the state has no daemon to check)
+    Connected,
+    /// Its daemon is gone; routes and hosts entries may be left behind
+    Stale,
+    /// The tunnel dropped and its routes are blackholed
+    KillSwitch,
+}
+
+/// How a connection runs
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionMode {
+    /// Background daemon (`connect --background` or the tray)
+    Daemon,
+    /// In the terminal that ran `connect`
+    Foreground,
+}
+
+/// One connection's status, with the state it was read from
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStatus {
+    pub health: ConnectionHealth,
+    pub mode: ConnectionMode,
+    /// Whether the recorded daemon process is still ours and alive
+    pub daemon_running: bool,
+    /// Reconnect activity recorded for the connection's profile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reconnect: Option<ReconnectStatus>,
+    /// Tunnel device the dead process left behind (`disconnect` removes it)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leftover_interface: Option<String>,
+    #[serde(flatten)]
+    pub state: VpnState,
+}
+
+impl ConnectionStatus {
+    /// Status of a connection from its saved state
+    pub fn from_state(state: VpnState) -> Self {
+        let daemon_running = state.is_daemon_running();
+        let health = if state.kill_switch_active {
+            ConnectionHealth::KillSwitch
+        } else if state.pid.is_some() && !daemon_running {
+            ConnectionHealth::Stale
+        } else {
+            ConnectionHealth::Connected
+        };
+        let mode = if state.pid.is_some() {
+            ConnectionMode::Daemon
+        } else {
+            ConnectionMode::Foreground
+        };
+        Self {
+            health,
+            mode,
+            daemon_running,
+            reconnect: None,
+            leftover_interface: None,
+            state,
+        }
+    }
+
+    /// Whether the tunnel is up
+    pub fn is_connected(&self) -> bool {
+        self.health == ConnectionHealth::Connected
+    }
+
+    /// Host and network routes in place (or blackholed)
+    pub fn route_count(&self) -> usize {
+        self.state.routes.len() + self.state.network_routes.len()
+    }
+}
+
+/// Every connection plus one profile's reconnect and failure records
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusReport {
+    /// Whether any connection's tunnel is up
+    pub active: bool,
+    pub connections: Vec<ConnectionStatus>,
+    /// Reconnect activity of the profile the report was made for
+    pub reconnect: Option<ReconnectStatus>,
+    /// Last failed connect of that profile, if the next hasn't succeeded yet
+    pub last_failure: Option<ConnectFailure>,
+    /// Why the saved state couldn't be read, if it couldn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Status of every connection, with the default profile's reconnect and
+/// failure records
+pub fn status() -> StatusReport {
+    status_for_profile(DEFAULT_PROFILE)
+}
+
+/// Status of every connection, with `profile`'s reconnect and failure records
+pub fn status_for_profile(profile: &str) -> StatusReport {
+    let (states, error) = match VpnState::load_all() {
+        Ok(states) => (states, None),
+        Err(e) => (vec![], Some(e.to_string())),
+    };
+    let orphaned = orphaned_interfaces(&states);
+    let connections: Vec<ConnectionStatus> = states
+        .into_iter()
+        .map(|state| {
+            let reconnect = ReconnectStatus::load(state.profile_name()).ok().flatten();
+            let status = ConnectionStatus::from_state(state);
+            let leftover_interface = Some(status.state.tunnel_device.clone())
+                .filter(|device| !status.daemon_running && orphaned.contains(device));
+            ConnectionStatus {
+                reconnect,
+                leftover_interface,
+                ..status
+            }
+        })
+        .collect();
+    StatusReport {
+        active: connections.iter().any(ConnectionStatus::is_connected),
+        connections,
+        reconnect: ReconnectStatus::load(profile).ok().flatten(),
+        last_failure: ConnectFailure::load(profile).ok().flatten(),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_health() {
+        let mut state = VpnState::new("utun9".to_string(), "10.0.0.1".parse().unwrap());
+        state.add_route("a.example.com".to_string(), "172.16.38.40".parse().unwrap());
+        state.add_network_route("b.example.com".to_string(), "10.30.1.0/24".to_string());
+
+        let status = ConnectionStatus::from_state(state.clone());
+        assert_eq!(status.health, ConnectionHealth::Connected);
+        assert_eq!(status.mode, ConnectionMode::Foreground);
+        assert!(!status.daemon_running);
+        assert_eq!(status.route_count(), 2);
+
+        state.kill_switch_active = true;
+        let status = ConnectionStatus::from_state(state);
+        assert_eq!(status.health, ConnectionHealth::KillSwitch);
+        assert!(!status.is_connected());
+    }
+
+    #[test]
+    fn test_connection_status_json_keeps_state_fields() {
+        let state = VpnState::new("utun9".to_string(), "10.0.0.1".parse().unwrap());
+        let json = serde_json::to_value(ConnectionStatus::from_state(state)).unwrap();
+        assert_eq!(json["health"], "connected");
+        assert_eq!(json["mode"], "foreground");
+        assert_eq!(json["tunnel_device"], "utun9");
+        assert_eq!(json["gateway"], "10.0.0.1");
+        assert!(json.get("reconnect").is_none());
+    }
+}
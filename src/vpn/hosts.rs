@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::net::IpAddr;
+use std::path::Path;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,6 +18,10 @@ const HOSTS_MARKER_END: &str = "# END pmacs-vpn";
 
 pub struct HostsManager {
     path: String,
+    /// Optional label appended to the markers (e.g. `# BEGIN pmacs-vpn [lab]`)
+    label: Option<String>,
+    /// Trailing comments by hostname (the config's per-host `comment`)
+    comments: HashMap<String, String>,
 }
 
 impl HostsManager {
@@ -27,65 +32,185 @@ impl HostsManager {
             } else {
                 "/etc/hosts".to_string()
             },
+            label: None,
+            comments: HashMap::new(),
         }
     }
 
     pub fn with_path(path: String) -> Self {
-        Self { path }
+        Self {
+            path,
+            label: None,
+            comments: HashMap::new(),
+        }
+    }
+
+    /// Manager for `path` (the `hosts_file_path` preference), or the system
+    /// hosts file when unset
+    pub fn for_path(path: Option<&Path>) -> Self {
+        match path {
+            Some(path) => Self::with_path(path.to_string_lossy().to_string()),
+            None => Self::new(),
+        }
+    }
+
+    /// Label the managed section so several profiles can share one hosts file
+    ///
+    /// Only the section with a matching label is rewritten or removed; an
+    /// unlabeled manager keeps using the plain `# BEGIN pmacs-vpn` markers.
+    pub fn with_label(mut self, label: Option<String>) -> Self {
+        self.label = label.filter(|l| !l.trim().is_empty());
+        self
     }
 
-    pub fn add_entries(&self, entries: &HashMap<String, IpAddr>) -> Result<(), HostsError> {
-        let content = fs::read_to_string(&self.path)?;
+    /// Append `# comment` to the lines of the named hosts
+    pub fn with_comments(mut self, comments: HashMap<String, String>) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    fn marker_start(&self) -> String {
+        match &self.label {
+            Some(label) => format!("{} [{}]", HOSTS_MARKER_START, label.trim()),
+            None => HOSTS_MARKER_START.to_string(),
+        }
+    }
+
+    fn marker_end(&self) -> String {
+        match &self.label {
+            Some(label) => format!("{} [{}]", HOSTS_MARKER_END, label.trim()),
+            None => HOSTS_MARKER_END.to_string(),
+        }
+    }
+
+    /// Write the managed section, one line per address
+    ///
+    /// A hostname may map to several addresses (e.g. dual-stack v4 + v6);
+    /// each is written as its own `ip<TAB>hostname` line, followed by
+    /// `# comment` if the host has one.
+    pub fn add_entries(&self, entries: &HashMap<String, Vec<IpAddr>>) -> Result<(), HostsError> {
+        let content = self.read()?;
         let new_content = self.update_content(&content, entries);
         fs::write(&self.path, new_content)?;
         Ok(())
     }
 
     pub fn remove_entries(&self) -> Result<(), HostsError> {
-        let content = fs::read_to_string(&self.path)?;
+        let content = self.read()?;
         let new_content = self.remove_managed_section(&content);
-        fs::write(&self.path, new_content)?;
+        // Without our section there's nothing to write back
+        if new_content != content {
+            fs::write(&self.path, new_content)?;
+        }
         Ok(())
     }
 
-    fn update_content(&self, content: &str, entries: &HashMap<String, IpAddr>) -> String {
+    /// Current hosts file content; a missing file reads as empty
+    ///
+    /// Fresh containers and some Windows images have no hosts file at all.
+    /// Writing the managed section creates it.
+    fn read(&self) -> Result<String, HostsError> {
+        match fs::read_to_string(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            result => Ok(result?),
+        }
+    }
+
+    /// `content` with the managed section replaced by `entries`
+    ///
+    /// The section goes after the last non-blank line, one blank line below
+    /// it; trailing blank lines stay after the section. A file without a
+    /// final newline keeps going without one. Nothing else is touched, so
+    /// `remove_managed_section` gives back exactly the file we started from.
+    fn update_content(&self, content: &str, entries: &HashMap<String, Vec<IpAddr>>) -> String {
+        let eol = detect_line_ending(content);
         let cleaned = self.remove_managed_section(content);
-        let mut result = cleaned.trim_end().to_string();
-
-        if !entries.is_empty() {
-            result.push_str("\n\n");
-            result.push_str(HOSTS_MARKER_START);
-            result.push('\n');
-            for (hostname, ip) in entries {
-                result.push_str(&format!("{}\t{}\n", ip, hostname));
-            }
-            result.push_str(HOSTS_MARKER_END);
-            result.push('\n');
+        if entries.values().all(|ips| ips.is_empty()) {
+            return cleaned;
         }
 
+        let lines: Vec<&str> = cleaned.split_inclusive('\n').collect();
+        let body_len = lines.iter().rposition(|l| !l.trim().is_empty()).map_or(0, |i| i + 1);
+        let mut result = lines[..body_len].concat();
+        let final_newline = result.is_empty() || result.ends_with('\n');
+        if !result.is_empty() {
+            if !final_newline {
+                result.push_str(eol);
+            }
+            // A blank line between the existing entries and ours
+            result.push_str(eol);
+        }
+        result.push_str(&self.marker_start());
+        result.push_str(eol);
+        for (hostname, ips) in entries {
+            for ip in ips {
+                result.push_str(&format!("{}\t{}", ip, hostname));
+                if let Some(comment) = self.comments.get(hostname) {
+                    result.push_str(&format!(" # {}", comment));
+                }
+                result.push_str(eol);
+            }
+        }
+        result.push_str(&self.marker_end());
+        if final_newline {
+            result.push_str(eol);
+        }
+        result.push_str(&lines[body_len..].concat());
         result
     }
 
+    /// `content` without the managed section
+    ///
+    /// Also drops the blank line `update_content` puts before the section,
+    /// and the newline it added to a file that ended without one (the end
+    /// marker is then the last line, with no newline of its own). Other lines
+    /// keep their exact bytes, line endings included.
     fn remove_managed_section(&self, content: &str) -> String {
-        let mut result = String::new();
+        let marker_start = self.marker_start();
+        let marker_end = self.marker_end();
+        let mut kept: Vec<&str> = vec![];
         let mut in_managed_section = false;
 
-        for line in content.lines() {
-            if line.trim() == HOSTS_MARKER_START {
+        for line in content.split_inclusive('\n') {
+            if line.trim() == marker_start {
                 in_managed_section = true;
+                if kept.last().is_some_and(|l| l.trim_end_matches(['\r', '\n']).is_empty()) {
+                    kept.pop();
+                }
                 continue;
             }
-            if line.trim() == HOSTS_MARKER_END {
+            if line.trim() == marker_end {
                 in_managed_section = false;
+                if !line.ends_with('\n')
+                    && let Some(last) = kept.last_mut()
+                {
+                    *last = last.trim_end_matches(['\r', '\n']);
+                }
                 continue;
             }
             if !in_managed_section {
-                result.push_str(line);
-                result.push('\n');
+                kept.push(line);
             }
         }
 
-        result
+        kept.concat()
+    }
+}
+
+/// Pick the line ending to write back, based on what the file already uses
+///
+/// Uses whichever of CRLF or LF is more common. Files without any line breaks
+/// get the platform convention (CRLF on Windows).
+fn detect_line_ending(content: &str) -> &'static str {
+    let crlf = content.matches("\r\n").count();
+    let lf = content.matches('\n').count() - crlf;
+
+    if crlf == 0 && lf == 0 {
+        if cfg!(windows) { "\r\n" } else { "\n" }
+    } else if crlf > lf {
+        "\r\n"
+    } else {
+        "\n"
     }
 }
 
@@ -119,6 +244,10 @@ mod tests {
     fn test_with_path() {
         let manager = HostsManager::with_path("/custom/path".to_string());
         assert_eq!(manager.path, "/custom/path");
+
+        let manager = HostsManager::for_path(Some(Path::new("/custom/path")));
+        assert_eq!(manager.path, "/custom/path");
+        assert_eq!(HostsManager::for_path(None).path, HostsManager::new().path);
     }
 
     #[test]
@@ -129,7 +258,7 @@ mod tests {
         let mut entries = HashMap::new();
         entries.insert(
             "test.example.com".to_string(),
-            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))],
         );
 
         let result = manager.update_content(original, &entries);
@@ -140,6 +269,28 @@ mod tests {
         assert!(result.contains("# END pmacs-vpn"));
     }
 
+    #[test]
+    fn test_update_content_writes_comments() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut comments = HashMap::new();
+        comments.insert("prometheus.example.com".to_string(), "monitoring".to_string());
+        let manager = create_test_manager(&temp_dir, "hosts", "").with_comments(comments);
+
+        let mut entries = HashMap::new();
+        entries.insert(
+            "prometheus.example.com".to_string(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))],
+        );
+        entries.insert(
+            "grafana.example.com".to_string(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))],
+        );
+
+        let result = manager.update_content("127.0.0.1\tlocalhost\n", &entries);
+        assert!(result.contains("10.0.0.1\tprometheus.example.com # monitoring\n"));
+        assert!(result.contains("10.0.0.2\tgrafana.example.com\n"));
+    }
+
     #[test]
     fn test_update_content_empty_entries() {
         let manager = HostsManager::with_path(String::new());
@@ -165,7 +316,7 @@ mod tests {
         let mut entries = HashMap::new();
         entries.insert(
             "new.example.com".to_string(),
-            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))],
         );
 
         let result = manager.update_content(original, &entries);
@@ -214,7 +365,7 @@ mod tests {
         let mut entries = HashMap::new();
         entries.insert(
             "test.example.com".to_string(),
-            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)),
+            vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100))],
         );
 
         manager.add_entries(&entries).unwrap();
@@ -225,6 +376,28 @@ mod tests {
         assert!(content.contains("# END pmacs-vpn"));
     }
 
+    #[test]
+    fn test_missing_hosts_file_is_created() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = HostsManager::with_path(temp_dir.path().join("hosts").to_string_lossy().into_owned());
+
+        // Nothing to remove, and nothing created
+        manager.remove_entries().unwrap();
+        assert!(!Path::new(&manager.path).exists());
+
+        let mut entries = HashMap::new();
+        entries.insert(
+            "test.example.com".to_string(),
+            vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100))],
+        );
+        manager.add_entries(&entries).unwrap();
+
+        let content = fs::read_to_string(&manager.path).unwrap();
+        assert!(content.starts_with("# BEGIN pmacs-vpn"));
+        assert!(content.contains("192.168.1.100\ttest.example.com"));
+        assert!(content.contains("# END pmacs-vpn"));
+    }
+
     #[test]
     fn test_remove_entries_file_operations() {
         let temp_dir = TempDir::new().unwrap();
@@ -240,6 +413,12 @@ mod tests {
         assert!(content.contains("127.0.0.1\tlocalhost"));
         assert!(!content.contains("# BEGIN pmacs-vpn"));
         assert!(!content.contains("test.example.com"));
+
+        // Already clean: the file is left alone
+        let modified = fs::metadata(&manager.path).unwrap().modified().unwrap();
+        manager.remove_entries().unwrap();
+        assert_eq!(fs::read_to_string(&manager.path).unwrap(), content);
+        assert_eq!(fs::metadata(&manager.path).unwrap().modified().unwrap(), modified);
     }
 
     #[test]
@@ -250,7 +429,7 @@ mod tests {
         let mut entries = HashMap::new();
         entries.insert(
             "ipv6.example.com".to_string(),
-            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            vec![IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))],
         );
 
         let result = manager.update_content(original, &entries);
@@ -258,6 +437,127 @@ mod tests {
         assert!(result.contains("2001:db8::1\tipv6.example.com"));
     }
 
+    #[test]
+    fn test_dual_stack_entries() {
+        let manager = HostsManager::with_path(String::new());
+        let original = "127.0.0.1\tlocalhost\n";
+
+        let mut entries = HashMap::new();
+        entries.insert(
+            "dual.example.com".to_string(),
+            vec![
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ],
+        );
+
+        let result = manager.update_content(original, &entries);
+
+        assert!(result.contains("10.0.0.1\tdual.example.com"));
+        assert!(result.contains("2001:db8::1\tdual.example.com"));
+
+        // Marker-based removal still strips every line
+        let cleaned = manager.remove_managed_section(&result);
+        assert!(!cleaned.contains("dual.example.com"));
+    }
+
+    #[test]
+    fn test_preserves_crlf_line_endings() {
+        let manager = HostsManager::with_path(String::new());
+        let original = "127.0.0.1\tlocalhost\r\n::1\tlocalhost\r\n";
+
+        let mut entries = HashMap::new();
+        entries.insert(
+            "test.example.com".to_string(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))],
+        );
+
+        let result = manager.update_content(original, &entries);
+
+        assert!(result.contains("# BEGIN pmacs-vpn\r\n"));
+        assert!(result.contains("10.0.0.1\ttest.example.com\r\n"));
+        assert!(result.contains("# END pmacs-vpn\r\n"));
+        // No bare LF anywhere
+        assert_eq!(result.matches('\n').count(), result.matches("\r\n").count());
+
+        let cleaned = manager.remove_managed_section(&result);
+        assert_eq!(cleaned, original);
+    }
+
+    #[test]
+    fn test_add_then_remove_round_trips() {
+        let manager = HostsManager::with_path(String::new());
+        let mut entries = HashMap::new();
+        entries.insert(
+            "test.example.com".to_string(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))],
+        );
+
+        for original in [
+            "",
+            "\n",
+            "127.0.0.1\tlocalhost",
+            "127.0.0.1\tlocalhost\n",
+            "127.0.0.1\tlocalhost\n\n\n",
+            "127.0.0.1\tlocalhost  \n\t\n",
+            "# comment\n\n127.0.0.1\tlocalhost\n\n# trailing note\n",
+            "127.0.0.1\tlocalhost\r\n\r\n",
+            "127.0.0.1\tlocalhost\r\n::1\tlocalhost\n",
+        ] {
+            let added = manager.update_content(original, &entries);
+            assert!(added.contains("10.0.0.1\ttest.example.com"), "{:?}", original);
+            assert_eq!(manager.remove_managed_section(&added), original, "{:?}", original);
+            // Rewriting the section doesn't drift either
+            let readded = manager.update_content(&added, &entries);
+            assert_eq!(readded, added, "{:?}", original);
+        }
+
+        // Exactly one blank line before the marker, trailing blank lines after the section
+        let added = manager.update_content("127.0.0.1\tlocalhost\n\n\n", &entries);
+        assert!(added.starts_with("127.0.0.1\tlocalhost\n\n# BEGIN pmacs-vpn\n"));
+        assert!(added.ends_with("# END pmacs-vpn\n\n\n"));
+    }
+
+    #[test]
+    fn test_detect_line_ending() {
+        assert_eq!(detect_line_ending("a\nb\n"), "\n");
+        assert_eq!(detect_line_ending("a\r\nb\r\n"), "\r\n");
+        assert_eq!(detect_line_ending("a\r\nb\nc\r\n"), "\r\n");
+    }
+
+    #[test]
+    fn test_labeled_sections_coexist() {
+        let lab = HostsManager::with_path(String::new()).with_label(Some("lab".to_string()));
+        let default = HostsManager::with_path(String::new());
+
+        let mut lab_entries = HashMap::new();
+        lab_entries.insert(
+            "lab.example.com".to_string(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))],
+        );
+        let mut default_entries = HashMap::new();
+        default_entries.insert(
+            "default.example.com".to_string(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))],
+        );
+
+        let content = default.update_content("127.0.0.1\tlocalhost\n", &default_entries);
+        let content = lab.update_content(&content, &lab_entries);
+
+        assert!(content.contains("# BEGIN pmacs-vpn [lab]"));
+        assert!(content.contains("# END pmacs-vpn [lab]"));
+        assert!(content.contains("# BEGIN pmacs-vpn\n"));
+        assert!(content.contains("lab.example.com"));
+        assert!(content.contains("default.example.com"));
+
+        // Removing the lab section leaves the default one intact
+        let cleaned = lab.remove_managed_section(&content);
+        assert!(!cleaned.contains("lab.example.com"));
+        assert!(!cleaned.contains("[lab]"));
+        assert!(cleaned.contains("# BEGIN pmacs-vpn\n"));
+        assert!(cleaned.contains("10.0.0.2\tdefault.example.com"));
+    }
+
     #[test]
     fn test_multiple_entries() {
         let manager = HostsManager::with_path(String::new());
@@ -266,11 +566,11 @@ mod tests {
         let mut entries = HashMap::new();
         entries.insert(
             "host1.example.com".to_string(),
-            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))],
         );
         entries.insert(
             "host2.example.com".to_string(),
-            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))],
         );
 
         let result = manager.update_content(original, &entries);
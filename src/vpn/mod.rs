@@ -1,6 +1,12 @@
 //! VPN connection and routing management
 
 pub mod hosts;
+pub mod resolver;
 pub mod routing;
+pub mod session;
 
 pub use routing::VpnRouter;
+pub use session::{
+    cleanup, cleanup_orphaned, cleanup_orphaned_with, cleanup_with, orphaned_interfaces, roll_back_connect,
+    CleanupReport, ConnectedVpn, DisconnectReport,
+};
@@ -0,0 +1,404 @@
+//! System resolver setup for split DNS (`manage_resolver`, Linux)
+//!
+//! The hosts file only covers the configured names. With `manage_resolver`,
+//! the VPN DNS servers and search domains are handed to the system resolver
+//! too, so short names and other internal names resolve system-wide. The
+//! first of these that's available is used:
+//!
+//! - systemd-resolved: DNS and domains scoped to the tunnel link
+//!   (`resolvectl`), so only the VPN's domains go to the VPN DNS
+//! - resolvconf: an interface record (`resolvconf -a`)
+//! - otherwise a managed block at the top of `/etc/resolv.conf`; the
+//!   original content is kept in the connection state and written back on
+//!   disconnect
+
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ResolverError {
+    #[error("Failed to update resolv.conf: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0} failed: {1}")]
+    Command(&'static str, String),
+    #[error("No DNS servers to configure")]
+    NoServers,
+}
+
+const RESOLV_MARKER_START: &str = "# BEGIN pmacs-vpn";
+const RESOLV_MARKER_END: &str = "# END pmacs-vpn";
+/// Prefix for original `search`/`domain` lines commented out by the block
+const RESOLV_DISABLED: &str = "# pmacs-vpn: ";
+
+/// Where `/etc/resolv.conf` lives
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// How the resolver was configured
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolverBackend {
+    /// systemd-resolved, per link
+    Resolved,
+    /// resolvconf, per interface record
+    Resolvconf,
+    /// Managed block in resolv.conf
+    File,
+}
+
+/// What `ResolverManager::apply` changed, recorded in the state so cleanup
+/// can undo it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ResolverSetup {
+    pub backend: ResolverBackend,
+    /// Tunnel interface the settings belong to
+    pub interface: String,
+    /// resolv.conf that was edited (`File` only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Its content before the edit (`File` only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+}
+
+/// Points the system resolver at the VPN DNS for one tunnel interface
+pub struct ResolverManager {
+    interface: String,
+    /// resolv.conf for the `File` backend
+    path: PathBuf,
+    /// Backend to use instead of detecting one
+    backend: Option<ResolverBackend>,
+}
+
+impl ResolverManager {
+    pub fn new(interface: String) -> Self {
+        Self {
+            interface,
+            path: PathBuf::from(RESOLV_CONF),
+            backend: None,
+        }
+    }
+
+    /// Edit `path` as resolv.conf, skipping detection (for tests and
+    /// containers)
+    pub fn with_file(mut self, path: PathBuf) -> Self {
+        self.path = path;
+        self.backend = Some(ResolverBackend::File);
+        self
+    }
+
+    /// Send `domains` (and, with the file or resolvconf, everything else)
+    /// to `servers`
+    pub fn apply(&self, servers: &[IpAddr], domains: &[String]) -> Result<ResolverSetup, ResolverError> {
+        if servers.is_empty() {
+            return Err(ResolverError::NoServers);
+        }
+        let backend = self.backend.unwrap_or_else(detect_backend);
+        let mut setup = ResolverSetup {
+            backend,
+            interface: self.interface.clone(),
+            path: None,
+            original: None,
+        };
+        match backend {
+            ResolverBackend::Resolved => {
+                run("resolvectl", &resolvectl_dns_args(&self.interface, servers), None)?;
+                if !domains.is_empty() {
+                    run("resolvectl", &resolvectl_domain_args(&self.interface, domains), None)?;
+                    // Only the VPN's domains go to its DNS
+                    run("resolvectl", &["default-route", &self.interface, "false"], None)?;
+                }
+            }
+            ResolverBackend::Resolvconf => {
+                let record = resolvconf_record(&self.interface);
+                let input = resolvconf_input(servers, domains);
+                run("resolvconf", &["-a", &record], Some(&input))?;
+            }
+            ResolverBackend::File => {
+                let current = read(&self.path)?;
+                // A block left by a crashed session isn't part of the original
+                let original = remove_managed_block(&current);
+                write(&self.path, &managed_content(&original, servers, domains))?;
+                setup.path = Some(self.path.clone());
+                setup.original = Some(original);
+            }
+        }
+        Ok(setup)
+    }
+}
+
+/// Undo `ResolverManager::apply`
+///
+/// resolv.conf is only written back while it still has our block: if
+/// something else (DHCP, NetworkManager) rewrote it since, its version stays.
+pub fn restore(setup: &ResolverSetup) -> Result<(), ResolverError> {
+    match setup.backend {
+        ResolverBackend::Resolved => {
+            // The link may be gone with the TUN device, taking its settings along
+            if let Err(e) = run("resolvectl", &["revert", &setup.interface], None) {
+                tracing::debug!("{}", e);
+            }
+            Ok(())
+        }
+        ResolverBackend::Resolvconf => {
+            run("resolvconf", &["-d", &resolvconf_record(&setup.interface)], None)
+        }
+        ResolverBackend::File => {
+            let path = setup.path.as_deref().unwrap_or(Path::new(RESOLV_CONF));
+            let current = read(path)?;
+            if !has_managed_block(&current) {
+                return Ok(());
+            }
+            let restored = setup.original.clone().unwrap_or_else(|| remove_managed_block(&current));
+            write(path, &restored)?;
+            Ok(())
+        }
+    }
+}
+
+/// The backend to use on this system (see the module docs)
+pub fn detect_backend() -> ResolverBackend {
+    if Path::new("/run/systemd/resolve").is_dir() && command_exists("resolvectl") {
+        ResolverBackend::Resolved
+    } else if command_exists("resolvconf") {
+        ResolverBackend::Resolvconf
+    } else {
+        ResolverBackend::File
+    }
+}
+
+fn command_exists(name: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| dir.join(name).is_file()))
+}
+
+/// Run `program`, feeding it `input` on stdin if given
+fn run<S: AsRef<OsStr>>(program: &'static str, args: &[S], input: Option<&str>) -> Result<(), ResolverError> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| ResolverError::Command(program, e.to_string()))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|e| ResolverError::Command(program, e.to_string()))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| ResolverError::Command(program, e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(ResolverError::Command(program, stderr));
+    }
+    Ok(())
+}
+
+/// resolv.conf content; a missing file reads as empty
+fn read(path: &Path) -> Result<String, ResolverError> {
+    match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        result => Ok(result?),
+    }
+}
+
+/// Replace resolv.conf's content without ever leaving it half-written
+///
+/// Writes a temp file beside it with the same permissions and renames it
+/// over. A symlinked resolv.conf is followed, so the link itself stays.
+fn write(path: &Path, contents: &str) -> Result<(), ResolverError> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let temp_path = target.with_extension("pmacs-vpn.tmp");
+    fs::write(&temp_path, contents)?;
+    if let Ok(metadata) = fs::metadata(&target) {
+        fs::set_permissions(&temp_path, metadata.permissions())?;
+    }
+    if let Err(e) = fs::rename(&temp_path, &target) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// `resolvectl dns <link> <servers...>` arguments
+fn resolvectl_dns_args(interface: &str, servers: &[IpAddr]) -> Vec<String> {
+    let mut args = vec!["dns".to_string(), interface.to_string()];
+    args.extend(servers.iter().map(IpAddr::to_string));
+    args
+}
+
+/// `resolvectl domain <link> <domains...>` arguments; each domain is both
+/// searched and routed to the link's DNS
+fn resolvectl_domain_args(interface: &str, domains: &[String]) -> Vec<String> {
+    let mut args = vec!["domain".to_string(), interface.to_string()];
+    args.extend(domains.iter().cloned());
+    args
+}
+
+/// Name of our resolvconf record for `interface`
+fn resolvconf_record(interface: &str) -> String {
+    format!("{}.pmacs-vpn", interface)
+}
+
+/// resolv.conf lines for `servers` and `domains`
+fn resolver_lines(servers: &[IpAddr], domains: &[String]) -> Vec<String> {
+    let mut lines: Vec<String> = servers.iter().map(|ip| format!("nameserver {}", ip)).collect();
+    if !domains.is_empty() {
+        lines.push(format!("search {}", domains.join(" ")));
+    }
+    lines
+}
+
+/// What resolvconf gets on stdin for our record
+fn resolvconf_input(servers: &[IpAddr], domains: &[String]) -> String {
+    resolver_lines(servers, domains)
+        .into_iter()
+        .map(|line| line + "\n")
+        .collect()
+}
+
+fn has_managed_block(content: &str) -> bool {
+    content.lines().any(|line| line.trim() == RESOLV_MARKER_START)
+}
+
+/// `content` without our block, with the lines it disabled put back
+fn remove_managed_block(content: &str) -> String {
+    let mut result = String::new();
+    let mut inside = false;
+    for line in content.lines() {
+        match line.trim() {
+            RESOLV_MARKER_START => inside = true,
+            RESOLV_MARKER_END => inside = false,
+            _ if !inside => {
+                result.push_str(line.strip_prefix(RESOLV_DISABLED).unwrap_or(line));
+                result.push('\n');
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// `original` with our block on top
+///
+/// Our nameservers come first so they're asked first. Only the last
+/// `search` or `domain` line counts, so the original ones are folded into
+/// ours (after the VPN's domains) and commented out.
+fn managed_content(original: &str, servers: &[IpAddr], domains: &[String]) -> String {
+    let mut search: Vec<String> = domains.to_vec();
+    let mut rest = String::new();
+    for line in original.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("search") | Some("domain") => {
+                for domain in words {
+                    if !search.iter().any(|d| d == domain) {
+                        search.push(domain.to_string());
+                    }
+                }
+                rest.push_str(RESOLV_DISABLED);
+                rest.push_str(line);
+                rest.push('\n');
+            }
+            _ => {
+                rest.push_str(line);
+                rest.push('\n');
+            }
+        }
+    }
+
+    let mut content = format!("{}\n", RESOLV_MARKER_START);
+    for line in resolver_lines(servers, &search) {
+        content.push_str(&line);
+        content.push('\n');
+    }
+    content.push_str(RESOLV_MARKER_END);
+    content.push('\n');
+    content.push_str(&rest);
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn servers() -> Vec<IpAddr> {
+        vec!["172.16.10.1".parse().unwrap(), "172.16.10.2".parse().unwrap()]
+    }
+
+    #[test]
+    fn test_resolvectl_and_resolvconf_args() {
+        let domains = vec!["pmacs.upenn.edu".to_string()];
+        assert_eq!(resolvectl_dns_args("tun0", &servers()), ["dns", "tun0", "172.16.10.1", "172.16.10.2"]);
+        assert_eq!(resolvectl_domain_args("tun0", &domains), ["domain", "tun0", "pmacs.upenn.edu"]);
+        assert_eq!(resolvconf_record("tun0"), "tun0.pmacs-vpn");
+        assert_eq!(
+            resolvconf_input(&servers(), &domains),
+            "nameserver 172.16.10.1\nnameserver 172.16.10.2\nsearch pmacs.upenn.edu\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolv_conf_written_through_symlink() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.path().join("resolv.conf.real");
+        let link = dir.path().join("resolv.conf");
+        fs::write(&target, "nameserver 192.168.1.1\n").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let manager = ResolverManager::new("tun0".to_string()).with_file(link.clone());
+        let setup = manager.apply(&servers(), &[]).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert!(fs::read_to_string(&target).unwrap().starts_with("# BEGIN pmacs-vpn\n"));
+
+        restore(&setup).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "nameserver 192.168.1.1\n");
+        // No temp file left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_resolv_conf_block_and_restore() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("resolv.conf");
+        let original = "# from DHCP\nnameserver 192.168.1.1\nsearch home.lan\noptions edns0\n";
+        fs::write(&path, original).unwrap();
+
+        let manager = ResolverManager::new("tun0".to_string()).with_file(path.clone());
+        let setup = manager.apply(&servers(), &["pmacs.upenn.edu".to_string()]).unwrap();
+        assert_eq!(setup.backend, ResolverBackend::File);
+        assert_eq!(setup.original.as_deref(), Some(original));
+
+        let edited = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            edited,
+            "# BEGIN pmacs-vpn\nnameserver 172.16.10.1\nnameserver 172.16.10.2\n\
+             search pmacs.upenn.edu home.lan\n# END pmacs-vpn\n\
+             # from DHCP\nnameserver 192.168.1.1\n# pmacs-vpn: search home.lan\noptions edns0\n"
+        );
+
+        // Applying again (a reconnect) keeps the real original
+        let again = manager.apply(&servers(), &[]).unwrap();
+        assert_eq!(again.original.as_deref(), Some(original));
+
+        restore(&setup).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+
+        // Rewritten by something else since: left alone
+        fs::write(&path, "nameserver 10.0.0.53\n").unwrap();
+        restore(&setup).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "nameserver 10.0.0.53\n");
+
+        assert!(matches!(manager.apply(&[], &[]), Err(ResolverError::NoServers)));
+    }
+}
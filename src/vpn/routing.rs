@@ -2,9 +2,16 @@
 //!
 //! Provides DNS resolution (system or VPN-specific) and route management.
 
-use crate::platform::{get_routing_manager, get_routing_manager_for_interface, PlatformError};
+use crate::platform::{
+    get_routing_manager, get_routing_manager_for_interface, get_routing_manager_in_table,
+    get_routing_manager_via_gateway, DefaultRoute, PlatformError,
+};
+use ipnet::IpNet;
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
-use std::time::Duration;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tracing::{debug, info, warn};
 
@@ -23,11 +30,236 @@ pub enum RoutingError {
     InvalidIpAddress(String),
     #[error("DNS query failed: {0}")]
     DnsQueryFailed(String),
+    #[error("Invalid route prefix /{prefix} for {ip} (expected IPv4 and /8 to /32)")]
+    InvalidPrefix { ip: IpAddr, prefix: u8 },
+    #[error(
+        "{host} resolved to public address {ip}, not routing it (VPN DNS may not be working; --allow-public-routes routes it anyway)"
+    )]
+    PublicAddress { host: String, ip: IpAddr },
+    #[error(
+        "{host} resolved to {ip}, outside the networks the gateway tunnels; not routing it (--skip-outside-tunnel)"
+    )]
+    OutsideTunnel { host: String, ip: IpAddr },
+    #[error("{0} is an IPv6 address; set ipv6 = true under [preferences] to route IPv6")]
+    Ipv6Disabled(IpAddr),
+}
+
+/// Narrowest and widest prefixes accepted for `host/prefix` network routes
+pub const MIN_ROUTE_PREFIX: u8 = 8;
+pub const MAX_ROUTE_PREFIX: u8 = 32;
+
+/// Routes sending all IPv4 traffic through the tunnel in full-tunnel mode
+///
+/// Together they cover everything, and being more specific than the
+/// default route they win over it without it being removed.
+pub const FULL_TUNNEL_NETWORKS: [&str; 2] = ["0.0.0.0/1", "128.0.0.0/1"];
+
+/// What `VpnRouter::remove_owned_route` found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteRemoval {
+    /// The route was ours and is gone now
+    Removed,
+    /// There was no route left to remove
+    AlreadyGone,
+    /// The route now points at another interface, and was left alone
+    Repointed(String),
+}
+
+/// Whether `ip` is in private, loopback, link-local or CGNAT address space
+///
+/// Internal hosts live in these ranges. A routed host resolving anywhere
+/// else usually means VPN DNS failed and system DNS answered instead.
+pub fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            let cgnat = a == 100 && (64..128).contains(&b); // 100.64.0.0/10
+            v4.is_private() || v4.is_loopback() || v4.is_link_local() || cgnat
+        }
+        IpAddr::V6(v6) => {
+            let segment = v6.segments()[0];
+            let unique_local = segment & 0xfe00 == 0xfc00; // fc00::/7
+            let link_local = segment & 0xffc0 == 0xfe80; // fe80::/10
+            v6.is_loopback() || unique_local || link_local
+        }
+    }
+}
+
+/// The address to route out of every address a name resolved to
+///
+/// With `prefer_private`, the first private address (see
+/// `is_private_address`) wins over any public one listed before it: a
+/// split-horizon name can come back with both the internal and the external
+/// address. Otherwise, and when nothing is private, the first address.
+pub fn pick_address(addrs: &[IpAddr], prefer_private: bool) -> Option<IpAddr> {
+    let first = *addrs.first()?;
+    if prefer_private && !is_private_address(first) {
+        return Some(addrs.iter().copied().find(|ip| is_private_address(*ip)).unwrap_or(first));
+    }
+    Some(first)
+}
+
+/// Whether `probe` (host, or host:port with 443 the default) takes a TCP
+/// connection within `timeout`
+///
+/// Run before connecting, an answer from an internal host means the machine
+/// is already on the PMACS network (`skip_if_onnet`).
+pub async fn is_on_network(probe: &str, timeout: Duration) -> bool {
+    let address = match probe_address(probe) {
+        Ok(address) => address,
+        Err(e) => {
+            warn!("onnet_probe: {}", e);
+            return false;
+        }
+    };
+    match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(&address)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            debug!("{} not reachable: {}", address, e);
+            false
+        }
+        Err(_) => {
+            debug!("{} didn't answer within {}ms", address, timeout.as_millis());
+            false
+        }
+    }
+}
+
+/// `probe` as host:port, adding port 443 if it has none
+///
+/// Parsed like a gateway (see `split_gateway_port`), IPv6 brackets and all.
+fn probe_address(probe: &str) -> Result<String, crate::config::ConfigError> {
+    let (host, port) = crate::config::split_gateway_port(probe)?;
+    Ok(format!("{}:{}", crate::config::url_host(host), port.unwrap_or(443)))
+}
+
+/// Network (CIDR) containing `ip` at the given prefix length
+///
+/// `172.16.38.40` with prefix 24 gives `"172.16.38.0/24"`.
+pub fn network_cidr(ip: IpAddr, prefix: u8) -> Result<String, RoutingError> {
+    match ip {
+        IpAddr::V4(v4) if (MIN_ROUTE_PREFIX..=MAX_ROUTE_PREFIX).contains(&prefix) => {
+            let mask = u32::MAX << (32 - prefix as u32);
+            let network = Ipv4Addr::from(u32::from(v4) & mask);
+            Ok(format!("{}/{}", network, prefix))
+        }
+        _ => Err(RoutingError::InvalidPrefix { ip, prefix }),
+    }
+}
+
+/// Whether `ip` falls inside a CIDR network like "172.16.38.0/24"
+pub fn network_contains(network: &str, ip: IpAddr) -> bool {
+    network
+        .split_once('/')
+        .and_then(|(_, prefix)| prefix.parse::<u8>().ok())
+        .and_then(|prefix| network_cidr(ip, prefix).ok())
+        .is_some_and(|cidr| cidr == network)
+}
+
+/// Prefix length of a dotted IPv4 netmask (`"255.255.255.0"` gives 24)
+///
+/// None if it isn't an address or its ones aren't contiguous.
+pub fn netmask_prefix(netmask: &str) -> Option<u8> {
+    let mask = u32::from(netmask.trim().parse::<Ipv4Addr>().ok()?);
+    let prefix = mask.leading_ones();
+    (mask.checked_shl(prefix).unwrap_or(0) == 0).then_some(prefix as u8)
+}
+
+/// How long a cached lookup stays valid (see `VpnRouter::with_resolve_cache`)
+pub const DEFAULT_RESOLVE_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Most lookups the resolution cache holds at once
+const RESOLVE_CACHE_CAPACITY: usize = 256;
+
+/// Name and the DNS servers it was asked of (empty for system DNS)
+type LookupKey = (String, Vec<IpAddr>);
+
+/// Successful lookups, with when they were made
+struct ResolveCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<LookupKey, (IpAddr, Instant)>>,
+}
+
+impl ResolveCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, name: &str, dns_servers: &[IpAddr]) -> Option<IpAddr> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let (ip, at) = entries.get(&(name.to_string(), dns_servers.to_vec()))?;
+        (at.elapsed() < self.ttl).then_some(*ip)
+    }
+
+    fn insert(&self, name: &str, dns_servers: &[IpAddr], ip: IpAddr) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= RESOLVE_CACHE_CAPACITY {
+            // Expired entries go first; if none have, drop the oldest
+            entries.retain(|_, (_, at)| at.elapsed() < self.ttl);
+            if entries.len() >= RESOLVE_CACHE_CAPACITY
+                && let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, (_, at))| *at)
+                    .map(|(key, _)| key.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert((name.to_string(), dns_servers.to_vec()), (ip, Instant::now()));
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+/// How long one lookup took and which server answered
+///
+/// Collected only when enabled with `with_dns_timings`; cache hits aren't
+/// recorded.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct DnsTiming {
+    pub host: String,
+    /// None if the lookup failed
+    pub ip: Option<IpAddr>,
+    /// The VPN DNS server that answered, None for system DNS or a failure
+    pub server: Option<IpAddr>,
+    #[serde(rename = "elapsed_ms", serialize_with = "serialize_millis")]
+    pub elapsed: Duration,
+}
+
+fn serialize_millis<S: serde::Serializer>(elapsed: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(elapsed.as_millis() as u64)
 }
 
 pub struct VpnRouter {
     gateway: String,
     interface_name: Option<String>,
+    /// Tunnel source IP to bind DNS query sockets to (see `with_dns_binding`)
+    dns_source_ip: Option<IpAddr>,
+    /// Domains to append when a name doesn't resolve (see `with_search_domains`)
+    search_domains: Vec<String>,
+    /// Dedicated routing table (Linux; see `with_route_table`)
+    route_table: Option<u32>,
+    /// Route hosts that resolve to public addresses (see `with_public_routes`)
+    allow_public: bool,
+    /// Networks the gateway tunnels (see `with_tunnel_networks`)
+    tunnel_networks: Vec<IpNet>,
+    /// Refuse hosts outside `tunnel_networks` instead of warning
+    skip_outside_tunnel: bool,
+    /// Recent lookups, if enabled (see `with_resolve_cache`)
+    resolve_cache: Option<ResolveCache>,
+    /// Route IPv6 addresses (see `with_ipv6`)
+    ipv6: bool,
+    /// Lookup timings, if enabled (see `with_dns_timings`)
+    dns_timings: Option<Mutex<Vec<DnsTiming>>>,
+    /// Records routes instead of changing the OS (see `with_routing_manager`)
+    #[cfg(any(test, feature = "testing"))]
+    mock: Option<crate::platform::mock::MockRoutingManager>,
+    /// Pick a private address among several (see `with_private_preference`)
+    prefer_private: bool,
     /// Interface index for binding sockets (Windows)
     #[cfg(windows)]
     interface_index: Option<u32>,
@@ -39,6 +271,18 @@ impl VpnRouter {
         Ok(Self {
             gateway,
             interface_name: None,
+            dns_source_ip: None,
+            search_domains: vec![],
+            route_table: None,
+            allow_public: false,
+            tunnel_networks: vec![],
+            skip_outside_tunnel: false,
+            ipv6: false,
+            prefer_private: true,
+            resolve_cache: None,
+            dns_timings: None,
+            #[cfg(any(test, feature = "testing"))]
+            mock: None,
             #[cfg(windows)]
             interface_index: None,
         })
@@ -57,11 +301,191 @@ impl VpnRouter {
         Ok(Self {
             gateway,
             interface_name: Some(interface_name),
+            dns_source_ip: None,
+            search_domains: vec![],
+            route_table: None,
+            allow_public: false,
+            tunnel_networks: vec![],
+            skip_outside_tunnel: false,
+            ipv6: false,
+            prefer_private: true,
+            resolve_cache: None,
+            dns_timings: None,
+            #[cfg(any(test, feature = "testing"))]
+            mock: None,
             #[cfg(windows)]
             interface_index,
         })
     }
 
+    /// Bind VPN DNS query sockets to the tunnel
+    ///
+    /// Queries sent by `resolve_with_dns` will use `source_ip` (normally
+    /// `TunnelConfig.internal_ip`) as their source address and, when the router
+    /// was built `with_interface`, are pinned to the TUN device via
+    /// `SO_BINDTODEVICE` (Linux) or `IP_BOUND_IF` (macOS). This keeps DNS
+    /// traffic on the tunnel even before the route to the DNS server exists.
+    pub fn with_dns_binding(mut self, source_ip: IpAddr) -> Self {
+        debug!("Binding VPN DNS queries to source IP {}", source_ip);
+        self.dns_source_ip = Some(source_ip);
+        self
+    }
+
+    /// Retry failed lookups with these domains appended (split-DNS search list)
+    ///
+    /// Used by `resolve_with_search`; domains are trimmed and lowercased,
+    /// and repeats after the first are dropped.
+    pub fn with_search_domains(mut self, domains: &[String]) -> Self {
+        self.search_domains.clear();
+        for domain in domains.iter().map(|d| d.trim().trim_matches('.').to_ascii_lowercase()) {
+            if !domain.is_empty() && !self.search_domains.contains(&domain) {
+                self.search_domains.push(domain);
+            }
+        }
+        self
+    }
+
+    /// Keep routes in this routing table instead of the main one
+    ///
+    /// Linux only: each destination also gets an `ip rule` pointing at the
+    /// table. Other platforms ignore it and use their normal routing table.
+    pub fn with_route_table(mut self, table: Option<u32>) -> Self {
+        #[cfg(not(target_os = "linux"))]
+        if let Some(table) = table {
+            warn!("route_table {} is only supported on Linux; using the main table", table);
+        }
+        self.route_table = table;
+        self
+    }
+
+    /// Accept hosts that resolve to public addresses in `resolve_with_search`
+    ///
+    /// Off by default: such a route would pull public traffic into the tunnel.
+    pub fn with_public_routes(mut self, allow: bool) -> Self {
+        self.allow_public = allow;
+        self
+    }
+
+    /// Networks the gateway tunnels, to check resolved addresses against in
+    /// `resolve_with_search`
+    ///
+    /// Return traffic for an address outside all of them may not come back
+    /// through the tunnel, so such a host gets a warning, or is refused with
+    /// `skip_outside`. No networks (the gateway pushed none) means no check.
+    pub fn with_tunnel_networks(mut self, networks: Vec<IpNet>, skip_outside: bool) -> Self {
+        self.tunnel_networks = networks;
+        self.skip_outside_tunnel = skip_outside;
+        self
+    }
+
+    /// Whether `ip` is outside every network the gateway tunnels (false
+    /// when none are known)
+    pub fn outside_tunnel(&self, ip: IpAddr) -> bool {
+        !self.tunnel_networks.is_empty() && !self.tunnel_networks.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Route IPv6 addresses through the tunnel
+    ///
+    /// Off by default, since the tunnel only has an IPv6 address with the
+    /// `ipv6` preference; IPv6 routes are refused with `Ipv6Disabled`.
+    pub fn with_ipv6(mut self, enabled: bool) -> Self {
+        self.ipv6 = enabled;
+        self
+    }
+
+    /// Among several addresses for a name, prefer a private one (default: yes)
+    ///
+    /// See `pick_address`. Off, the first address returned is used, as the
+    /// resolver ordered them.
+    pub fn with_private_preference(mut self, prefer: bool) -> Self {
+        self.prefer_private = prefer;
+        self
+    }
+
+    /// Pick the address to route for `hostname` (see `pick_address`)
+    fn pick(&self, hostname: &str, addrs: &[IpAddr]) -> Option<IpAddr> {
+        let ip = pick_address(addrs, self.prefer_private)?;
+        if ip != addrs[0] {
+            info!(
+                "{} resolved to {:?}; using private address {} instead of {}",
+                hostname, addrs, ip, addrs[0]
+            );
+        }
+        Some(ip)
+    }
+
+    /// Send every route request to `manager` instead of the OS
+    ///
+    /// For tests (and the `testing` feature): nothing touches the real
+    /// routing table, and a clone of `manager` shows what was requested.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_routing_manager(mut self, manager: crate::platform::mock::MockRoutingManager) -> Self {
+        self.mock = Some(manager);
+        self
+    }
+
+    /// Remember successful lookups for `ttl`
+    ///
+    /// Repeated resolutions of the same name (against the same DNS servers)
+    /// within a session then skip the query and get the same answer. The
+    /// cache holds at most a few hundred names; failures are never cached.
+    pub fn with_resolve_cache(mut self, ttl: Duration) -> Self {
+        self.resolve_cache = Some(ResolveCache::new(ttl));
+        self
+    }
+
+    /// Keep the time and answering server of each lookup (see `dns_timings`)
+    ///
+    /// Every lookup is logged at debug level either way; this only decides
+    /// whether they're kept, e.g. for the debug bundle.
+    pub fn with_dns_timings(mut self, enabled: bool) -> Self {
+        self.dns_timings = enabled.then(|| Mutex::new(vec![]));
+        self
+    }
+
+    /// Lookups recorded so far, in the order they finished
+    pub fn dns_timings(&self) -> Vec<DnsTiming> {
+        self.dns_timings
+            .as_ref()
+            .map(|t| t.lock().unwrap_or_else(|e| e.into_inner()).clone())
+            .unwrap_or_default()
+    }
+
+    fn record_timing(&self, host: &str, ip: Option<IpAddr>, server: Option<IpAddr>, started: Instant) {
+        let elapsed = started.elapsed();
+        match server {
+            Some(server) => debug!("Lookup of {} took {} ms (via {})", host, elapsed.as_millis(), server),
+            None => debug!("Lookup of {} took {} ms", host, elapsed.as_millis()),
+        }
+        if let Some(timings) = &self.dns_timings {
+            timings.lock().unwrap_or_else(|e| e.into_inner()).push(DnsTiming {
+                host: host.to_string(),
+                ip,
+                server,
+                elapsed,
+            });
+        }
+    }
+
+    /// Forget cached lookups so the next resolution queries DNS again
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.resolve_cache {
+            cache.clear();
+        }
+    }
+
+    fn cached(&self, hostname: &str, dns_servers: &[IpAddr]) -> Option<IpAddr> {
+        let ip = self.resolve_cache.as_ref()?.get(hostname, dns_servers)?;
+        debug!("Using cached address {} for {}", ip, hostname);
+        Some(ip)
+    }
+
+    fn cache(&self, hostname: &str, dns_servers: &[IpAddr], ip: IpAddr) {
+        if let Some(cache) = &self.resolve_cache {
+            cache.insert(hostname, dns_servers, ip);
+        }
+    }
+
     /// Get the gateway IP
     pub fn gateway(&self) -> &str {
         &self.gateway
@@ -69,7 +493,13 @@ impl VpnRouter {
 
     /// Get the routing manager (interface-aware if configured)
     fn get_manager(&self) -> Result<Box<dyn crate::platform::RoutingManager>, RoutingError> {
-        if let Some(ref iface) = self.interface_name {
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(mock) = &self.mock {
+            return Ok(Box::new(mock.clone()));
+        }
+        if let Some(table) = self.route_table {
+            Ok(get_routing_manager_in_table(self.interface_name.as_deref(), table)?)
+        } else if let Some(ref iface) = self.interface_name {
             Ok(get_routing_manager_for_interface(iface)?)
         } else {
             Ok(get_routing_manager()?)
@@ -78,6 +508,16 @@ impl VpnRouter {
 
     /// Resolve hostname using system DNS (std::net)
     pub fn resolve_host(&self, hostname: &str) -> Result<IpAddr, RoutingError> {
+        if let Some(ip) = self.cached(hostname, &[]) {
+            return Ok(ip);
+        }
+        let started = Instant::now();
+        let result = self.resolve_system(hostname);
+        self.record_timing(hostname, result.as_ref().ok().copied(), None, started);
+        result
+    }
+
+    fn resolve_system(&self, hostname: &str) -> Result<IpAddr, RoutingError> {
         debug!("Resolving {} via system DNS", hostname);
         let addr_str = format!("{}:0", hostname);
         let addrs = addr_str
@@ -87,13 +527,13 @@ impl VpnRouter {
                 source: Box::new(e),
             })?;
 
-        let ip = addrs
-            .into_iter()
-            .next()
-            .map(|a| a.ip())
+        let addrs: Vec<IpAddr> = addrs.map(|a| a.ip()).collect();
+        let ip = self
+            .pick(hostname, &addrs)
             .ok_or_else(|| RoutingError::NoAddressFound(hostname.to_string()))?;
 
-        info!("System DNS resolved {} -> {}", hostname, ip);
+        debug!("System DNS resolved {} -> {}", hostname, ip);
+        self.cache(hostname, &[], ip);
         Ok(ip)
     }
 
@@ -112,29 +552,46 @@ impl VpnRouter {
             warn!("No DNS servers provided, falling back to system DNS");
             return self.resolve_host(hostname);
         }
+        if let Some(ip) = self.cached(hostname, dns_servers) {
+            return Ok(ip);
+        }
 
         #[cfg(windows)]
         let if_index = self.interface_index;
         #[cfg(not(windows))]
         let if_index: Option<u32> = None;
 
+        let binding = DnsSocketBinding {
+            source_ip: self.dns_source_ip,
+            interface_name: self.dns_source_ip.and(self.interface_name.as_deref()),
+            interface_index: if_index,
+        };
+
         debug!(
-            "Resolving {} via VPN DNS servers: {:?} (interface: {:?})",
-            hostname, dns_servers, if_index
+            "Resolving {} via VPN DNS servers: {:?} (interface: {:?}, source: {:?})",
+            hostname, dns_servers, if_index, binding.source_ip
         );
 
         // Build DNS query packet
         let query = build_dns_query(hostname);
+        let started = Instant::now();
 
         for dns_server in dns_servers {
             debug!("Trying DNS server: {}", dns_server);
 
             let server_addr = SocketAddr::new(*dns_server, 53);
 
-            match query_dns_server(&query, server_addr, if_index) {
-                Ok(ip) => {
-                    info!("VPN DNS resolved {} -> {} (via {})", hostname, ip, dns_server);
-                    return Ok(IpAddr::V4(ip));
+            match query_dns_server(&query, server_addr, &binding) {
+                Ok(addrs) => {
+                    let addrs: Vec<IpAddr> = addrs.into_iter().map(IpAddr::V4).collect();
+                    let Some(ip) = self.pick(hostname, &addrs) else {
+                        warn!("DNS server {} returned no A record for {}", dns_server, hostname);
+                        continue;
+                    };
+                    debug!("VPN DNS resolved {} -> {} (via {})", hostname, ip, dns_server);
+                    self.record_timing(hostname, Some(ip), Some(*dns_server), started);
+                    self.cache(hostname, dns_servers, ip);
+                    return Ok(ip);
                 }
                 Err(e) => {
                     warn!("DNS query to {} failed: {}", dns_server, e);
@@ -143,12 +600,120 @@ impl VpnRouter {
             }
         }
 
+        self.record_timing(hostname, None, None, started);
         Err(RoutingError::DnsQueryFailed(format!(
             "All DNS servers failed for {}",
             hostname
         )))
     }
 
+    /// Resolve a hostname, trying each search domain if the name itself fails
+    ///
+    /// Uses `dns_servers` when given, system DNS otherwise. Returns the name
+    /// that resolved (e.g. "prometheus.pmacs.upenn.edu" for "prometheus")
+    /// along with its address. A name resolving to a public address is an
+    /// error unless allowed with `with_public_routes`; a literal IP is taken
+    /// as given.
+    pub fn resolve_with_search(
+        &self,
+        hostname: &str,
+        dns_servers: &[IpAddr],
+    ) -> Result<(String, IpAddr), RoutingError> {
+        let (name, ip) = self.resolve_search_candidates(hostname, dns_servers)?;
+        let (name, ip) = self.check_public(hostname, name, ip)?;
+        self.check_tunneled(hostname, name, ip)
+    }
+
+    /// Warn about, or with `skip_outside_tunnel` refuse, an address outside
+    /// the tunneled networks (see `with_tunnel_networks`)
+    ///
+    /// A literal IP in the config is taken as given, as in `check_public`.
+    fn check_tunneled(
+        &self,
+        configured: &str,
+        name: String,
+        ip: IpAddr,
+    ) -> Result<(String, IpAddr), RoutingError> {
+        if !self.outside_tunnel(ip) || configured.parse::<IpAddr>().is_ok() {
+            return Ok((name, ip));
+        }
+        if self.skip_outside_tunnel {
+            return Err(RoutingError::OutsideTunnel { host: name, ip });
+        }
+        warn!(
+            "{} resolved to {}, outside the networks the gateway tunnels; replies may not come back through the VPN",
+            name, ip
+        );
+        Ok((name, ip))
+    }
+
+    /// Refuse a public address for a configured name (see `resolve_with_search`)
+    fn check_public(
+        &self,
+        configured: &str,
+        name: String,
+        ip: IpAddr,
+    ) -> Result<(String, IpAddr), RoutingError> {
+        if !self.allow_public && !is_private_address(ip) && configured.parse::<IpAddr>().is_err() {
+            warn!("{} resolved to public address {}", name, ip);
+            return Err(RoutingError::PublicAddress { host: name, ip });
+        }
+        Ok((name, ip))
+    }
+
+    fn resolve_search_candidates(
+        &self,
+        hostname: &str,
+        dns_servers: &[IpAddr],
+    ) -> Result<(String, IpAddr), RoutingError> {
+        let resolve = |name: &str| {
+            if dns_servers.is_empty() {
+                self.resolve_host(name)
+            } else {
+                self.resolve_with_dns(name, dns_servers)
+            }
+        };
+
+        let first_err = match resolve(hostname) {
+            Ok(ip) => return Ok((hostname.to_string(), ip)),
+            Err(e) => e,
+        };
+
+        for domain in &self.search_domains {
+            if hostname.ends_with(&format!(".{}", domain)) {
+                continue;
+            }
+            let candidate = format!("{}.{}", hostname, domain);
+            debug!("{} did not resolve, trying {}", hostname, candidate);
+            if let Ok(ip) = resolve(&candidate) {
+                debug!("Resolved {} as {} -> {}", hostname, candidate, ip);
+                return Ok((candidate, ip));
+            }
+        }
+
+        Err(first_err)
+    }
+
+    /// Names to write to the hosts file for a resolved host
+    ///
+    /// Covers the configured name, the name that resolved, and the short form
+    /// of either under a search domain, so both "prometheus" and
+    /// "prometheus.pmacs.upenn.edu" work.
+    pub fn hosts_file_names(&self, configured: &str, resolved: &str) -> Vec<String> {
+        let mut names = vec![configured.to_string()];
+        if resolved != configured {
+            names.push(resolved.to_string());
+        }
+        for domain in &self.search_domains {
+            if let Some(short) = resolved.strip_suffix(&format!(".{}", domain))
+                && !names.iter().any(|n| n == short)
+            {
+                names.push(short.to_string());
+            }
+        }
+        names
+    }
+
     /// Add a route for a hostname (resolves via system DNS)
     pub fn add_host_route(&self, hostname: &str) -> Result<IpAddr, RoutingError> {
         let ip = self.resolve_host(hostname)?;
@@ -157,12 +722,17 @@ impl VpnRouter {
     }
 
     /// Add a route for a hostname using VPN DNS servers
+    ///
+    /// `preferred_dns` (a per-host override from config) is tried before
+    /// `dns_servers`; see `dns_servers_for`.
     pub fn add_host_route_with_dns(
         &self,
         hostname: &str,
+        preferred_dns: Option<IpAddr>,
         dns_servers: &[IpAddr],
     ) -> Result<IpAddr, RoutingError> {
-        let ip = self.resolve_with_dns(hostname, dns_servers)?;
+        let servers = dns_servers_for(preferred_dns, dns_servers);
+        let ip = self.resolve_with_dns(hostname, &servers)?;
         self.add_ip_route_internal(&ip)?;
         Ok(ip)
     }
@@ -180,20 +750,101 @@ impl VpnRouter {
 
     /// Internal route addition
     fn add_ip_route_internal(&self, ip: &IpAddr) -> Result<(), RoutingError> {
-        info!("Adding route: {} via gateway {}", ip, self.gateway);
+        if ip.is_ipv6() && !self.ipv6 {
+            return Err(RoutingError::Ipv6Disabled(*ip));
+        }
+        debug!("Adding route: {} via gateway {}", ip, self.gateway);
         let manager = self.get_manager()?;
         manager.add_route(&ip.to_string(), &self.gateway)?;
-        info!("Route added successfully: {} -> {}", ip, self.gateway);
+        debug!("Route added successfully: {} -> {}", ip, self.gateway);
         Ok(())
     }
 
-    /// Remove a route for a hostname
-    pub fn remove_host_route(&self, hostname: &str) -> Result<(), RoutingError> {
-        let ip = self.resolve_host(hostname)?;
-        self.remove_ip_route(&ip.to_string())
+    /// Add a route for a whole network (CIDR from `network_cidr`)
+    pub fn add_network_route(&self, network: &str) -> Result<(), RoutingError> {
+        validate_destination(network)?;
+        debug!("Adding network route: {} via gateway {}", network, self.gateway);
+        let manager = self.get_manager()?;
+        manager.add_route(network, &self.gateway)?;
+        Ok(())
     }
 
-    /// Remove a route by IP address
+    /// Send all IPv4 traffic through the tunnel (full-tunnel mode)
+    ///
+    /// First pins `gateway_addr` (the VPN gateway's public address) to the
+    /// `original` default route, so the tunnel's own packets keep leaving
+    /// over the physical network, then adds `FULL_TUNNEL_NETWORKS` through
+    /// the tunnel. The default route itself is left alone; removing these
+    /// routes restores it. On failure nothing added here is left behind.
+    pub fn add_full_tunnel(&self, gateway_addr: IpAddr, original: &DefaultRoute) -> Result<(), RoutingError> {
+        let pin = gateway_addr.to_string();
+        info!("Pinning gateway {} via {}", pin, original.gateway);
+        let pin_manager = self.get_gateway_manager()?;
+        pin_manager.add_route(&pin, &original.gateway.to_string())?;
+
+        let manager = self.get_manager()?;
+        for (i, network) in FULL_TUNNEL_NETWORKS.iter().enumerate() {
+            info!("Adding route: {} via gateway {}", network, self.gateway);
+            if let Err(e) = manager.add_route(network, &self.gateway) {
+                for added in &FULL_TUNNEL_NETWORKS[..i] {
+                    let _ = manager.delete_route(added);
+                }
+                let _ = pin_manager.delete_route(&pin);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Put back `original` as the default route if there is none any more
+    ///
+    /// Full-tunnel mode never removes the default route, but the system may
+    /// have while the tunnel was up (e.g. the network changed).
+    pub fn restore_default_route(&self, original: &DefaultRoute) -> Result<(), RoutingError> {
+        if crate::platform::default_route().is_ok() {
+            return Ok(());
+        }
+        info!("Restoring default route via {}", original.gateway);
+        self.get_gateway_manager()?
+            .add_route("0.0.0.0/0", &original.gateway.to_string())?;
+        Ok(())
+    }
+
+    /// Routing manager for routes over the physical network (see `add_full_tunnel`)
+    fn get_gateway_manager(&self) -> Result<Box<dyn crate::platform::RoutingManager>, RoutingError> {
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(mock) = &self.mock {
+            return Ok(Box::new(mock.clone()));
+        }
+        Ok(get_routing_manager_via_gateway(self.route_table)?)
+    }
+
+    /// Add a blackhole route for an IP or network so its traffic is dropped
+    /// (kill switch)
+    ///
+    /// Always uses the system routing table directly, since the tunnel
+    /// interface is usually gone by the time this is needed.
+    pub fn add_blackhole_route(&self, destination: &str) -> Result<(), RoutingError> {
+        validate_destination(destination)?;
+        info!("Adding blackhole route: {}", destination);
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(mock) = &self.mock {
+            crate::platform::RoutingManager::add_blackhole_route(mock, destination)?;
+            return Ok(());
+        }
+        let manager = match self.route_table {
+            Some(table) => get_routing_manager_in_table(None, table)?,
+            None => get_routing_manager()?,
+        };
+        manager.add_blackhole_route(destination)?;
+        Ok(())
+    }
+
+    /// Remove a route by IP address (or CIDR network)
+    ///
+    /// There's deliberately no by-hostname variant: re-resolving at cleanup
+    /// can fail or give a different IP and orphan the route, so callers pass
+    /// the address recorded in `VpnState` when the route was added.
     pub fn remove_ip_route(&self, ip_str: &str) -> Result<(), RoutingError> {
         info!("Removing route: {}", ip_str);
         let manager = self.get_manager()?;
@@ -201,6 +852,68 @@ impl VpnRouter {
         info!("Route removed: {}", ip_str);
         Ok(())
     }
+
+    /// Remove the route to `destination` if it still goes through `interface`
+    ///
+    /// A route that disappeared with the TUN device, or that something else
+    /// has since pointed elsewhere, isn't ours to delete. Without a recorded
+    /// interface, or when the platform can't tell, the route is removed
+    /// unchecked like `remove_ip_route`.
+    pub fn remove_owned_route(
+        &self,
+        destination: &str,
+        interface: Option<&str>,
+    ) -> Result<RouteRemoval, RoutingError> {
+        if let Some(expected) = interface {
+            match self.get_manager()?.route_interface(destination) {
+                Ok(None) => return Ok(RouteRemoval::AlreadyGone),
+                Ok(Some(current)) if current != expected => {
+                    return Ok(RouteRemoval::Repointed(current));
+                }
+                Ok(Some(_)) => {}
+                Err(e) => debug!("Can't check route for {} ({}), removing it anyway", destination, e),
+            }
+        }
+        self.remove_ip_route(destination)?;
+        Ok(RouteRemoval::Removed)
+    }
+}
+
+/// DNS servers to query for a host, in order
+///
+/// A host's own DNS override comes first, then the tunnel-provided servers
+/// (without repeating the override).
+pub fn dns_servers_for(preferred: Option<IpAddr>, tunnel_dns: &[IpAddr]) -> Vec<IpAddr> {
+    preferred
+        .into_iter()
+        .chain(tunnel_dns.iter().copied().filter(|ip| Some(*ip) != preferred))
+        .collect()
+}
+
+/// Check a route destination is an IP address or `ip/prefix` network
+fn validate_destination(destination: &str) -> Result<(), RoutingError> {
+    let invalid = || RoutingError::InvalidIpAddress(destination.to_string());
+    match destination.split_once('/') {
+        Some((addr, prefix)) => {
+            let ip: IpAddr = addr.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            network_cidr(ip, prefix).map(|_| ())
+        }
+        None => destination.parse::<IpAddr>().map(|_| ()).map_err(|_| invalid()),
+    }
+}
+
+/// How long to wait for the answer to a DNS query
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Random DNS transaction ID
+fn random_query_id() -> u16 {
+    let mut id = [0u8; 2];
+    if SystemRandom::new().fill(&mut id).is_err() {
+        // The OS RNG doesn't fail in practice; any ID still gets matched
+        warn!("System RNG unavailable; using a fixed DNS query ID");
+    }
+    u16::from_be_bytes(id)
 }
 
 /// Build a minimal DNS A record query packet
@@ -208,13 +921,8 @@ fn build_dns_query(hostname: &str) -> Vec<u8> {
     let mut packet = Vec::with_capacity(512);
 
     // Header (12 bytes)
-    // Transaction ID (random-ish)
-    let id: u16 = (std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_millis()
-        & 0xFFFF) as u16;
-    packet.extend_from_slice(&id.to_be_bytes());
+    // Transaction ID (random, so a stray or spoofed answer can't match it)
+    packet.extend_from_slice(&random_query_id().to_be_bytes());
 
     // Flags: standard query, recursion desired
     packet.extend_from_slice(&[0x01, 0x00]);
@@ -242,39 +950,90 @@ fn build_dns_query(hostname: &str) -> Vec<u8> {
     packet
 }
 
+/// How a DNS query socket should be bound to the tunnel
+struct DnsSocketBinding<'a> {
+    /// Local source address (tunnel IP); ignored if the server's family differs
+    source_ip: Option<IpAddr>,
+    /// TUN device name for SO_BINDTODEVICE / IP_BOUND_IF (Unix)
+    #[cfg_attr(not(unix), allow(dead_code))]
+    interface_name: Option<&'a str>,
+    /// Interface index for IP_UNICAST_IF (Windows)
+    #[cfg_attr(not(windows), allow(dead_code))]
+    interface_index: Option<u32>,
+}
+
 /// Send DNS query to server and parse response
 ///
-/// On Windows, if `interface_index` is provided, binds the socket to that
+/// On Windows, if an interface index is provided, binds the socket to that
 /// interface using IP_UNICAST_IF to ensure traffic goes through the TUN device.
+/// On Linux and macOS, if a source IP was requested, the socket is bound to it
+/// and pinned to the TUN device with SO_BINDTODEVICE / IP_BOUND_IF.
 fn query_dns_server(
     query: &[u8],
     server: SocketAddr,
-    #[cfg_attr(not(windows), allow(unused_variables))]
-    interface_index: Option<u32>,
-) -> Result<Ipv4Addr, String> {
-    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("bind failed: {}", e))?;
+    binding: &DnsSocketBinding,
+) -> Result<Vec<Ipv4Addr>, String> {
+    let local_addr = match binding.source_ip {
+        Some(ip) if ip.is_ipv4() == server.is_ipv4() => SocketAddr::new(ip, 0),
+        _ => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(local_addr).map_err(|e| format!("bind failed: {}", e))?;
 
     // On Windows, bind socket to specific interface using IP_UNICAST_IF
     #[cfg(windows)]
-    if let Some(if_index) = interface_index {
+    if let Some(if_index) = binding.interface_index {
         bind_socket_to_interface(&socket, if_index)?;
     }
 
-    socket
-        .set_read_timeout(Some(Duration::from_secs(5)))
-        .map_err(|e| format!("set timeout failed: {}", e))?;
+    // On Linux/macOS, pin the socket to the TUN device (best effort)
+    #[cfg(unix)]
+    if let Some(iface) = binding.interface_name
+        && let Err(e) = bind_socket_to_device(&socket, iface)
+    {
+        warn!("Could not bind DNS socket to {}: {}", iface, e);
+    }
 
     // Send query
     socket
         .send_to(query, server)
         .map_err(|e| format!("send failed: {}", e))?;
 
-    // Receive response
+    let id = u16::from_be_bytes([query[0], query[1]]);
+    receive_dns_answer(&socket, id, DNS_QUERY_TIMEOUT)
+}
+
+/// Wait up to `timeout` for the response to query `id` and parse it
+///
+/// Datagrams with another transaction ID (a late answer to an earlier
+/// query, or anything else arriving on the socket) are discarded.
+fn receive_dns_answer(socket: &UdpSocket, id: u16, timeout: Duration) -> Result<Vec<Ipv4Addr>, String> {
+    let deadline = Instant::now() + timeout;
     let mut response = [0u8; 512];
-    let (len, _) = socket
-        .recv_from(&mut response)
-        .map_err(|e| format!("recv failed: {}", e))?;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err("recv failed: timed out".to_string());
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|e| format!("set timeout failed: {}", e))?;
+        let (len, from) = socket
+            .recv_from(&mut response)
+            .map_err(|e| format!("recv failed: {}", e))?;
+
+        if len >= 2 && u16::from_be_bytes([response[0], response[1]]) == id {
+            return parse_dns_response(&response[..len]);
+        }
+        debug!("Ignoring DNS response from {} with the wrong transaction ID", from);
+    }
+}
 
+/// Every IPv4 address in the answer section of a DNS response
+///
+/// Other records (e.g. the CNAME chain in front of the A records) are
+/// skipped. An answer without any A record is an error.
+fn parse_dns_response(response: &[u8]) -> Result<Vec<Ipv4Addr>, String> {
+    let len = response.len();
     if len < 12 {
         return Err("response too short".to_string());
     }
@@ -324,59 +1083,69 @@ fn query_dns_server(
     }
     pos += 4;
 
-    // Parse first answer
-    // Skip answer name (might be pointer)
-    while pos < len {
-        let byte = response[pos];
-        if byte == 0 {
-            pos += 1;
-            break;
-        } else if byte & 0xC0 == 0xC0 {
-            if pos + 1 >= len {
-                return Err("truncated pointer in answer".to_string());
-            }
-            pos += 2;
-            break;
-        } else {
-            let label_len = byte as usize;
-            if pos + 1 + label_len > len {
-                return Err("truncated label in answer".to_string());
+    let mut addrs = Vec::new();
+    let mut last_type = None;
+    for _ in 0..ancount {
+        // Skip answer name (might be pointer)
+        while pos < len {
+            let byte = response[pos];
+            if byte == 0 {
+                pos += 1;
+                break;
+            } else if byte & 0xC0 == 0xC0 {
+                if pos + 1 >= len {
+                    return Err("truncated pointer in answer".to_string());
+                }
+                pos += 2;
+                break;
+            } else {
+                let label_len = byte as usize;
+                if pos + 1 + label_len > len {
+                    return Err("truncated label in answer".to_string());
+                }
+                pos += 1 + label_len;
             }
-            pos += 1 + label_len;
         }
-    }
 
-    // Need at least 10 bytes for TYPE(2) + CLASS(2) + TTL(4) + RDLENGTH(2)
-    if pos + 10 > len {
-        return Err("answer section truncated".to_string());
-    }
+        // Need at least 10 bytes for TYPE(2) + CLASS(2) + TTL(4) + RDLENGTH(2)
+        if pos + 10 > len {
+            return Err("answer section truncated".to_string());
+        }
 
-    // Read TYPE (2 bytes)
-    let atype = u16::from_be_bytes([response[pos], response[pos + 1]]);
-    pos += 2;
+        // Read TYPE (2 bytes)
+        let atype = u16::from_be_bytes([response[pos], response[pos + 1]]);
+        pos += 2;
 
-    // Skip CLASS (2 bytes) and TTL (4 bytes)
-    pos += 6;
+        // Skip CLASS (2 bytes) and TTL (4 bytes)
+        pos += 6;
 
-    // Read RDLENGTH
-    let rdlength = u16::from_be_bytes([response[pos], response[pos + 1]]) as usize;
-    pos += 2;
+        // Read RDLENGTH
+        let rdlength = u16::from_be_bytes([response[pos], response[pos + 1]]) as usize;
+        pos += 2;
 
-    // If TYPE is A (1) and RDLENGTH is 4, parse IPv4 address
-    if atype == 1 && rdlength == 4 {
-        if pos + 4 > len {
-            return Err("A record data truncated".to_string());
+        if pos + rdlength > len {
+            return Err("answer data truncated".to_string());
         }
-        let ip = Ipv4Addr::new(
-            response[pos],
-            response[pos + 1],
-            response[pos + 2],
-            response[pos + 3],
-        );
-        return Ok(ip);
+        // If TYPE is A (1) and RDLENGTH is 4, it's an IPv4 address
+        if atype == 1 && rdlength == 4 {
+            addrs.push(Ipv4Addr::new(
+                response[pos],
+                response[pos + 1],
+                response[pos + 2],
+                response[pos + 3],
+            ));
+        }
+        last_type = Some((atype, rdlength));
+        pos += rdlength;
     }
 
-    Err(format!("unexpected answer type: {} length: {}", atype, rdlength))
+    match last_type {
+        Some((atype, rdlength)) if addrs.is_empty() => Err(format!(
+            "unexpected answer type: {} length: {}",
+            atype, rdlength
+        )),
+        _ => Ok(addrs),
+    }
 }
 
 /// Bind a socket to a specific network interface on Windows using IP_UNICAST_IF
@@ -414,6 +1183,54 @@ fn bind_socket_to_interface(socket: &UdpSocket, interface_index: u32) -> Result<
     Ok(())
 }
 
+/// Bind a socket to a network device on Linux using SO_BINDTODEVICE
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_socket_to_device(socket: &UdpSocket, interface_name: &str) -> Result<(), String> {
+    use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
+    use std::ffi::OsString;
+
+    setsockopt(socket, BindToDevice, &OsString::from(interface_name))
+        .map_err(|e| format!("setsockopt SO_BINDTODEVICE failed: {}", e))?;
+
+    debug!("Bound socket to device {} via SO_BINDTODEVICE", interface_name);
+    Ok(())
+}
+
+/// Bind a socket to a network interface on macOS using IP_BOUND_IF
+#[cfg(target_os = "macos")]
+fn bind_socket_to_device(socket: &UdpSocket, interface_name: &str) -> Result<(), String> {
+    use nix::libc;
+    use std::os::unix::io::AsRawFd;
+
+    let if_index = nix::net::if_::if_nametoindex(interface_name)
+        .map_err(|e| format!("if_nametoindex({}) failed: {}", interface_name, e))?
+        as libc::c_int;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_BOUND_IF,
+            &if_index as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        let error = std::io::Error::last_os_error();
+        return Err(format!("setsockopt IP_BOUND_IF failed: {}", error));
+    }
+
+    debug!("Bound socket to interface {} (index {}) via IP_BOUND_IF", interface_name, if_index);
+    Ok(())
+}
+
+/// Device binding is not available on other Unix platforms
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android", target_os = "macos"))))]
+fn bind_socket_to_device(_socket: &UdpSocket, _interface_name: &str) -> Result<(), String> {
+    Err("device binding not supported on this platform".to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -447,6 +1264,65 @@ mod tests {
         assert_eq!(router.gateway(), "10.0.0.1");
     }
 
+    #[test]
+    fn test_resolve_cache() {
+        let router = VpnRouter::new("10.0.0.1".to_string())
+            .unwrap()
+            .with_resolve_cache(DEFAULT_RESOLVE_CACHE_TTL);
+        let vpn_dns: Vec<IpAddr> = vec!["10.0.0.2".parse().unwrap()];
+        let ip: IpAddr = "172.16.38.40".parse().unwrap();
+
+        router.cache("prometheus.pmacs.upenn.edu", &vpn_dns, ip);
+        assert_eq!(router.cached("prometheus.pmacs.upenn.edu", &vpn_dns), Some(ip));
+        // Answers from other servers are kept apart
+        assert_eq!(router.cached("prometheus.pmacs.upenn.edu", &[]), None);
+        // Served from the cache without sending a query
+        assert_eq!(router.resolve_with_dns("prometheus.pmacs.upenn.edu", &vpn_dns).unwrap(), ip);
+
+        router.clear_cache();
+        assert_eq!(router.cached("prometheus.pmacs.upenn.edu", &vpn_dns), None);
+
+        // Expired and overflow entries are dropped
+        let cache = ResolveCache::new(Duration::ZERO);
+        cache.insert("a.example.com", &[], ip);
+        assert_eq!(cache.get("a.example.com", &[]), None);
+        let cache = ResolveCache::new(DEFAULT_RESOLVE_CACHE_TTL);
+        for i in 0..RESOLVE_CACHE_CAPACITY + 10 {
+            cache.insert(&format!("host{}.example.com", i), &[], ip);
+        }
+        assert_eq!(cache.entries.lock().unwrap().len(), RESOLVE_CACHE_CAPACITY);
+
+        // Off unless enabled
+        let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
+        router.cache("a.example.com", &[], ip);
+        assert_eq!(router.cached("a.example.com", &[]), None);
+    }
+
+    #[test]
+    fn test_dns_timings() {
+        let router = VpnRouter::new("10.0.0.1".to_string())
+            .unwrap()
+            .with_resolve_cache(DEFAULT_RESOLVE_CACHE_TTL)
+            .with_dns_timings(true);
+        let ip = router.resolve_host("127.0.0.1").unwrap();
+        // A cache hit isn't a lookup
+        router.resolve_host("127.0.0.1").unwrap();
+
+        let timings = router.dns_timings();
+        assert_eq!(timings.len(), 1);
+        assert_eq!(timings[0].host, "127.0.0.1");
+        assert_eq!(timings[0].ip, Some(ip));
+        assert_eq!(timings[0].server, None);
+
+        let json = serde_json::to_value(&timings[0]).unwrap();
+        assert_eq!(json["elapsed_ms"], timings[0].elapsed.as_millis() as u64);
+
+        // Off unless enabled
+        let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
+        router.resolve_host("127.0.0.1").unwrap();
+        assert!(router.dns_timings().is_empty());
+    }
+
     #[test]
     fn test_resolve_known_host() {
         // This test requires network access
@@ -516,6 +1392,35 @@ mod tests {
         assert_eq!(query[20], 3); // length of "com"
     }
 
+    #[test]
+    fn test_dns_answer_matched_by_transaction_id() {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let query = build_dns_query("a.example.com");
+        let id = u16::from_be_bytes([query[0], query[1]]);
+
+        // Echo the question back with one A record
+        let answer = |id: u16, ip: [u8; 4]| {
+            let mut response = query.clone();
+            response[..2].copy_from_slice(&id.to_be_bytes());
+            response[2..8].copy_from_slice(&[0x81, 0x80, 0, 1, 0, 1]);
+            response.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            response.extend_from_slice(&ip);
+            response
+        };
+        let to = client.local_addr().unwrap();
+        server.send_to(&answer(id.wrapping_add(1), [130, 91, 1, 1]), to).unwrap();
+        server.send_to(&answer(id, [172, 16, 38, 40]), to).unwrap();
+
+        assert_eq!(
+            receive_dns_answer(&client, id, Duration::from_secs(2)).unwrap(),
+            [Ipv4Addr::new(172, 16, 38, 40)]
+        );
+        // Nothing else matches: time out rather than take a stray answer
+        server.send_to(&answer(id.wrapping_add(1), [130, 91, 1, 1]), to).unwrap();
+        assert!(receive_dns_answer(&client, id, Duration::from_millis(200)).is_err());
+    }
+
     #[test]
     fn test_resolve_with_dns_empty_servers_fallback() {
         let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
@@ -547,6 +1452,274 @@ mod tests {
         // If it fails, that's OK - network might not be available
     }
 
+    #[test]
+    fn test_with_dns_binding() {
+        let source: IpAddr = "10.0.1.100".parse().unwrap();
+
+        let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
+        assert!(router.dns_source_ip.is_none());
+
+        let router = router.with_dns_binding(source);
+        assert_eq!(router.dns_source_ip, Some(source));
+    }
+
+    #[test]
+    fn test_with_search_domains_normalizes() {
+        let router = VpnRouter::new("10.0.0.1".to_string())
+            .unwrap()
+            .with_search_domains(&[
+                " .PMACS.upenn.edu. ".to_string(),
+                "".to_string(),
+                "uphs.upenn.edu".to_string(),
+                "pmacs.upenn.edu".to_string(),
+            ]);
+        assert_eq!(router.search_domains, vec!["pmacs.upenn.edu", "uphs.upenn.edu"]);
+    }
+
+    #[test]
+    fn test_hosts_file_names() {
+        let router = VpnRouter::new("10.0.0.1".to_string())
+            .unwrap()
+            .with_search_domains(&["pmacs.upenn.edu".to_string()]);
+
+        // Short name that resolved via the search domain
+        assert_eq!(
+            router.hosts_file_names("prometheus", "prometheus.pmacs.upenn.edu"),
+            vec!["prometheus", "prometheus.pmacs.upenn.edu"]
+        );
+        // FQDN under a search domain also gets its short form
+        assert_eq!(
+            router.hosts_file_names("prometheus.pmacs.upenn.edu", "prometheus.pmacs.upenn.edu"),
+            vec!["prometheus.pmacs.upenn.edu", "prometheus"]
+        );
+        // Unrelated domain is left alone
+        assert_eq!(
+            router.hosts_file_names("example.com", "example.com"),
+            vec!["example.com"]
+        );
+    }
+
+    #[test]
+    fn test_hosts_file_names_without_search_domains() {
+        let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
+        assert_eq!(
+            router.hosts_file_names("prometheus.pmacs.upenn.edu", "prometheus.pmacs.upenn.edu"),
+            vec!["prometheus.pmacs.upenn.edu"]
+        );
+    }
+
+    #[test]
+    #[ignore] // Requires network access; run with: cargo test -- --ignored
+    fn test_resolve_with_search_appends_domain() {
+        let router = VpnRouter::new("10.0.0.1".to_string())
+            .unwrap()
+            .with_search_domains(&["com".to_string()])
+            .with_public_routes(true);
+        let (name, _) = router.resolve_with_search("google", &[]).unwrap();
+        assert_eq!(name, "google.com");
+    }
+
+    #[test]
+    fn test_is_private_address() {
+        for ip in ["10.1.2.3", "172.16.38.40", "192.168.1.1", "127.0.0.1", "169.254.1.1", "100.64.0.1", "100.127.255.255", "fd00::1", "fe80::1", "::1"] {
+            assert!(is_private_address(ip.parse().unwrap()), "{} should be private", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "100.128.0.1", "130.91.1.1", "2001:4860::8888"] {
+            assert!(!is_private_address(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_onnet_probe() {
+        assert_eq!(probe_address("prometheus.pmacs.upenn.edu").unwrap(), "prometheus.pmacs.upenn.edu:443");
+        assert_eq!(probe_address("prometheus.pmacs.upenn.edu:22").unwrap(), "prometheus.pmacs.upenn.edu:22");
+        assert_eq!(probe_address("10.0.0.5").unwrap(), "10.0.0.5:443");
+        assert_eq!(probe_address("fd00::5").unwrap(), "[fd00::5]:443");
+        assert_eq!(probe_address("[fd00::5]:22").unwrap(), "[fd00::5]:22");
+        assert!(probe_address("prometheus.pmacs.upenn.edu:ssh").is_err());
+        assert!(!is_on_network("prometheus.pmacs.upenn.edu:ssh", Duration::from_secs(2)).await);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let probe = listener.local_addr().unwrap().to_string();
+        assert!(is_on_network(&probe, Duration::from_secs(2)).await);
+        drop(listener);
+        assert!(!is_on_network(&probe, Duration::from_secs(2)).await);
+    }
+
+    #[test]
+    fn test_pick_address_prefers_private() {
+        let ips = |list: &[&str]| list.iter().map(|ip| ip.parse().unwrap()).collect::<Vec<IpAddr>>();
+        let split_horizon = ips(&["130.91.1.1", "172.16.38.40", "10.0.0.5"]);
+        assert_eq!(pick_address(&split_horizon, true), Some(split_horizon[1]));
+        assert_eq!(pick_address(&split_horizon, false), Some(split_horizon[0]));
+
+        let public = ips(&["130.91.1.1", "8.8.8.8"]);
+        assert_eq!(pick_address(&public, true), Some(public[0]));
+        let private_first = ips(&["10.0.0.5", "172.16.38.40"]);
+        assert_eq!(pick_address(&private_first, true), Some(private_first[0]));
+        assert_eq!(pick_address(&[], true), None);
+    }
+
+    #[test]
+    fn test_parse_dns_response_all_a_records() {
+        // Query for a.example.com answered with a CNAME and two A records
+        let mut response = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0];
+        response.extend_from_slice(b"\x01a\x07example\x03com\x00\x00\x01\x00\x01");
+        response.extend_from_slice(&[0xC0, 0x0C, 0, 5, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(b"\x01b\xC0\x0E");
+        for ip in [[130, 91, 1, 1], [172, 16, 38, 40]] {
+            response.extend_from_slice(&[0xC0, 0x2B, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            response.extend_from_slice(&ip);
+        }
+        assert_eq!(
+            parse_dns_response(&response).unwrap(),
+            [Ipv4Addr::new(130, 91, 1, 1), Ipv4Addr::new(172, 16, 38, 40)]
+        );
+
+        // Only the CNAME: no address to route
+        response[7] = 1;
+        response.truncate(12 + 19 + 16);
+        assert!(parse_dns_response(&response).is_err());
+    }
+
+    #[test]
+    fn test_public_address_rejected_unless_allowed() {
+        let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
+        let public: IpAddr = "93.184.216.34".parse().unwrap();
+        let private: IpAddr = "172.16.38.40".parse().unwrap();
+
+        assert!(router.check_public("prometheus", "prometheus.pmacs.upenn.edu".into(), private).is_ok());
+        let err = router
+            .check_public("prometheus", "prometheus.pmacs.upenn.edu".into(), public)
+            .unwrap_err();
+        assert!(matches!(err, RoutingError::PublicAddress { .. }));
+        assert!(err.to_string().contains("--allow-public-routes"));
+
+        // A literal address is taken as given
+        let (_, ip) = router.resolve_with_search("8.8.8.8", &[]).unwrap();
+        assert_eq!(ip, "8.8.8.8".parse::<IpAddr>().unwrap());
+
+        let router = router.with_public_routes(true);
+        assert!(router.check_public("example.com", "example.com".into(), public).is_ok());
+    }
+
+    #[test]
+    fn test_address_outside_tunnel() {
+        let networks = vec!["172.16.0.0/16".parse().unwrap(), "10.30.0.0/24".parse().unwrap()];
+        let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
+        assert!(!router.outside_tunnel("192.168.1.5".parse().unwrap()));
+
+        let outside: IpAddr = "192.168.1.5".parse().unwrap();
+        let router = router.with_tunnel_networks(networks.clone(), false);
+        assert!(!router.outside_tunnel("172.16.38.40".parse().unwrap()));
+        assert!(router.outside_tunnel(outside));
+        // Routed anyway, with a warning
+        let (_, ip) = router.check_tunneled("db.example.com", "db.example.com".into(), outside).unwrap();
+        assert_eq!(ip, outside);
+
+        let router = router.with_tunnel_networks(networks, true);
+        assert!(router.resolve_with_search("10.30.0.9", &[]).is_ok());
+        let err = router
+            .check_tunneled("db.example.com", "db.example.com".into(), outside)
+            .unwrap_err();
+        assert!(matches!(err, RoutingError::OutsideTunnel { .. }));
+        // A literal IP is routed as given
+        assert!(router.resolve_with_search("192.168.1.5", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_onlink_only_config_checks_nothing() {
+        let config = crate::gp::auth::TunnelConfig {
+            mtu: 1400,
+            internal_ip: "10.0.1.100".parse().unwrap(),
+            internal_ip6: None,
+            dns_servers: vec![],
+            timeout_seconds: None,
+            netmask: Some("255.255.255.0".to_string()),
+            split_include: vec![],
+            include_routes: vec![],
+            exclude_routes: vec![],
+            dns_suffixes: vec![],
+            gateway_host: None,
+            esp: None,
+        };
+        let router = VpnRouter::new("10.0.0.1".to_string())
+            .unwrap()
+            .with_tunnel_networks(config.vpn_networks(), true);
+        // Internal hosts outside the on-link pool are neither warned about nor refused
+        let internal: IpAddr = "172.16.38.40".parse().unwrap();
+        assert!(!router.outside_tunnel(internal));
+        assert!(router.check_tunneled("db.example.com", "db.example.com".into(), internal).is_ok());
+    }
+
+    #[test]
+    fn test_ipv6_route_needs_ipv6_enabled() {
+        let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
+        let err = router.add_ip_route("fd00::1").unwrap_err();
+        assert!(matches!(err, RoutingError::Ipv6Disabled(_)));
+        assert!(err.to_string().contains("ipv6 = true"));
+    }
+
+    #[test]
+    fn test_dns_servers_for() {
+        let tunnel: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+
+        assert_eq!(dns_servers_for(None, &tunnel), tunnel);
+        assert_eq!(
+            dns_servers_for(Some("10.9.9.9".parse().unwrap()), &tunnel),
+            vec![
+                "10.9.9.9".parse::<IpAddr>().unwrap(),
+                "10.0.0.1".parse().unwrap(),
+                "10.0.0.2".parse().unwrap(),
+            ]
+        );
+        // Override that is also a tunnel server moves to the front
+        assert_eq!(
+            dns_servers_for(Some("10.0.0.2".parse().unwrap()), &tunnel),
+            vec!["10.0.0.2".parse::<IpAddr>().unwrap(), "10.0.0.1".parse().unwrap()]
+        );
+        assert!(dns_servers_for(None, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_network_cidr() {
+        let ip: IpAddr = "172.16.38.40".parse().unwrap();
+        assert_eq!(network_cidr(ip, 24).unwrap(), "172.16.38.0/24");
+        assert_eq!(network_cidr(ip, 16).unwrap(), "172.16.0.0/16");
+        assert_eq!(network_cidr(ip, 8).unwrap(), "172.0.0.0/8");
+        assert_eq!(network_cidr(ip, 32).unwrap(), "172.16.38.40/32");
+        assert_eq!(network_cidr(ip, 27).unwrap(), "172.16.38.32/27");
+    }
+
+    #[test]
+    fn test_netmask_prefix() {
+        assert_eq!(netmask_prefix("255.255.255.0"), Some(24));
+        assert_eq!(netmask_prefix("255.255.255.255"), Some(32));
+        assert_eq!(netmask_prefix(" 255.255.240.0 "), Some(20));
+        assert_eq!(netmask_prefix("0.0.0.0"), Some(0));
+        assert_eq!(netmask_prefix("255.0.255.0"), None);
+        assert_eq!(netmask_prefix("24"), None);
+    }
+
+    #[test]
+    fn test_network_cidr_rejects_bad_prefix() {
+        let ip: IpAddr = "172.16.38.40".parse().unwrap();
+        assert!(matches!(network_cidr(ip, 7), Err(RoutingError::InvalidPrefix { .. })));
+        assert!(matches!(network_cidr(ip, 33), Err(RoutingError::InvalidPrefix { .. })));
+
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(network_cidr(v6, 24).is_err());
+    }
+
+    #[test]
+    fn test_validate_destination() {
+        assert!(validate_destination("172.16.38.40").is_ok());
+        assert!(validate_destination("172.16.38.0/24").is_ok());
+        assert!(validate_destination("172.16.38.0/4").is_err());
+        assert!(validate_destination("not-an-ip/24").is_err());
+        assert!(validate_destination("172.16.38.0/abc").is_err());
+    }
+
     #[test]
     fn test_add_ip_route_validation() {
         let router = VpnRouter::new("10.0.0.1".to_string()).unwrap();
@@ -0,0 +1,494 @@
+//! Tearing down a connection from inside the process
+//!
+//! The CLI's `disconnect` and the tray both stop a connection the same way:
+//! stop the tunnel (or the background daemon running it), then `cleanup`:
+//! remove the routes recorded in the state file, remove our hosts file
+//! section and resolver settings (and on Windows the tunnel's IPv6 address),
+//! remove the tunnel device if a crash left it behind, and delete the state.
+//! `ConnectedVpn::disconnect` does all of that without shelling out, and
+//! reports what it did. `cleanup` alone is for leftovers of a connection
+//! whose tunnel is already gone.
+
+use crate::state::{RoutesFile, VpnState};
+use crate::vpn::hosts::HostsManager;
+use crate::vpn::resolver;
+use crate::vpn::routing::{RouteRemoval, RoutingError, VpnRouter, FULL_TUNNEL_NETWORKS};
+use std::net::IpAddr;
+use tokio::task::AbortHandle;
+
+/// Label for full-tunnel routes in cleanup messages
+const FULL_TUNNEL_LABEL: &str = "(full tunnel)";
+
+/// A connection that can be torn down: its saved state, plus the tunnel
+/// task when the tunnel runs in this process
+pub struct ConnectedVpn {
+    state: VpnState,
+    tunnel: Option<AbortHandle>,
+    flush_dns: bool,
+    router: Option<VpnRouter>,
+}
+
+/// What `ConnectedVpn::disconnect` cleaned up, and what it couldn't
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DisconnectReport {
+    /// The in-process tunnel task was cancelled
+    pub tunnel_cancelled: bool,
+    /// The background daemon was stopped (and cleaned up after itself)
+    pub daemon_stopped: bool,
+    /// Routes removed, as IPs or CIDR networks
+    pub routes_removed: Vec<String>,
+    /// Our hosts file section was removed
+    pub hosts_restored: bool,
+    /// Tunnel device left by a crashed process, removed
+    pub interface_removed: Option<String>,
+    /// The system DNS cache was flushed
+    pub dns_flushed: bool,
+    /// The state file is gone
+    pub state_deleted: bool,
+    /// Steps that failed, one message each
+    pub failures: Vec<String>,
+}
+
+impl DisconnectReport {
+    /// Whether everything was cleaned up
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// What `cleanup` removed, and what it couldn't
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CleanupReport {
+    /// Routes removed, as IPs or CIDR networks
+    pub routes_removed: Vec<String>,
+    /// Our hosts file section was removed
+    pub hosts_restored: bool,
+    /// Tunnel device left by a crashed process, removed
+    pub interface_removed: Option<String>,
+    /// The system DNS cache was flushed
+    pub dns_flushed: bool,
+    /// The state file is gone (including when there was nothing to clean up)
+    pub state_deleted: bool,
+    /// Steps that failed, one message each
+    pub failures: Vec<String>,
+}
+
+impl CleanupReport {
+    /// Whether everything was cleaned up
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl ConnectedVpn {
+    /// A connection described by its saved state (tunnel in another process)
+    pub fn from_state(state: VpnState) -> Self {
+        Self {
+            state,
+            tunnel: None,
+            flush_dns: true,
+            router: None,
+        }
+    }
+
+    /// The saved connection for `profile`, if there is one
+    pub fn load(profile: &str) -> Result<Option<Self>, crate::state::StateError> {
+        Ok(VpnState::load_profile(profile)?.map(Self::from_state))
+    }
+
+    /// Cancel `tunnel` (the task running the tunnel here) on disconnect
+    pub fn with_tunnel_task(mut self, tunnel: AbortHandle) -> Self {
+        self.tunnel = Some(tunnel);
+        self
+    }
+
+    /// Whether to flush the system DNS cache once cleaned up (default: yes)
+    ///
+    /// The `flush_dns_on_disconnect` preference.
+    pub fn with_dns_flush(mut self, flush: bool) -> Self {
+        self.flush_dns = flush;
+        self
+    }
+
+    /// Remove routes through `router` rather than one built from the state
+    ///
+    /// See `cleanup_with`.
+    pub fn with_router(mut self, router: VpnRouter) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    /// Saved state of this connection
+    pub fn state(&self) -> &VpnState {
+        &self.state
+    }
+
+    /// Stop the tunnel and undo everything the connection set up
+    ///
+    /// Each step runs even if an earlier one failed; failures are collected
+    /// in the report rather than returned. Safe to call twice: once the
+    /// state is deleted `load` finds nothing, and disconnecting a stale copy
+    /// cleans up nothing (see `cleanup`).
+    pub async fn disconnect(self) -> DisconnectReport {
+        let mut report = DisconnectReport::default();
+        let state = self.state;
+
+        if let Some(tunnel) = self.tunnel {
+            report.tunnel_cancelled = !tunnel.is_finished();
+            tunnel.abort();
+        }
+
+        // A daemon running the tunnel cleans up after itself when asked to stop
+        if let Some(pid) = state.pid
+            && pid != std::process::id()
+            && state.is_daemon_running()
+        {
+            let daemon = state.clone();
+            match tokio::task::spawn_blocking(move || daemon.kill_daemon()).await {
+                Ok(Ok(())) => report.daemon_stopped = true,
+                Ok(Err(e)) => report.failures.push(format!("stopping daemon {}: {}", pid, e)),
+                Err(e) => report.failures.push(format!("stopping daemon {}: {}", pid, e)),
+            }
+            if let Ok(None) = VpnState::load_profile(state.profile_name()) {
+                report.state_deleted = true;
+                return report;
+            }
+        }
+
+        let cleaned = match self.router {
+            Some(router) => cleanup_with(&state, self.flush_dns, router),
+            None => cleanup(&state, self.flush_dns),
+        };
+        report.routes_removed = cleaned.routes_removed;
+        report.hosts_restored = cleaned.hosts_restored;
+        report.interface_removed = cleaned.interface_removed;
+        report.dns_flushed = cleaned.dns_flushed;
+        report.state_deleted = cleaned.state_deleted;
+        report.failures.extend(cleaned.failures);
+        report
+    }
+}
+
+/// Undo what a connection set up, from its saved state
+///
+/// Removes the routes by their stored IPs (nothing is resolved, the VPN may
+/// be down), our hosts file section, and the state and routes files, then
+/// flushes the DNS cache if `flush_dns` is set. Doesn't touch the tunnel or
+/// daemon; see `ConnectedVpn::disconnect` for that. Each step runs even if
+/// an earlier one failed.
+///
+/// Idempotent: once the profile's state file is gone there is nothing left
+/// to clean up, and this returns a clean report without doing anything.
+pub fn cleanup(state: &VpnState, flush_dns: bool) -> CleanupReport {
+    cleanup_through(state, flush_dns, state_router(state))
+}
+
+/// Like `cleanup`, removing routes through `router` rather than one built
+/// from the state (e.g. a router with a mock routing manager)
+pub fn cleanup_with(state: &VpnState, flush_dns: bool, router: VpnRouter) -> CleanupReport {
+    cleanup_through(state, flush_dns, Ok(router))
+}
+
+/// `cleanup` with the router to remove routes through
+fn cleanup_through(
+    state: &VpnState,
+    flush_dns: bool,
+    router: Result<VpnRouter, RoutingError>,
+) -> CleanupReport {
+    let mut report = CleanupReport::default();
+    if let Ok(None) = VpnState::load_profile(state.profile_name()) {
+        report.state_deleted = true;
+        return report;
+    }
+    undo_connection(state, flush_dns, router)
+}
+
+/// Undo what a connection set up when its state file is already gone
+///
+/// Like `cleanup`, but for a state rebuilt from the routes file or the
+/// config rather than loaded, so there is no state file to check for. Routes
+/// recorded without an interface are removed unchecked.
+pub fn cleanup_orphaned(state: &VpnState, flush_dns: bool) -> CleanupReport {
+    undo_connection(state, flush_dns, state_router(state))
+}
+
+/// Like `cleanup_orphaned`, removing routes through `router`
+pub fn cleanup_orphaned_with(state: &VpnState, flush_dns: bool, router: VpnRouter) -> CleanupReport {
+    undo_connection(state, flush_dns, Ok(router))
+}
+
+/// Router for the routes a state records
+fn state_router(state: &VpnState) -> Result<VpnRouter, RoutingError> {
+    Ok(VpnRouter::new(state.gateway.to_string())?.with_route_table(state.route_table))
+}
+
+/// Undo routes a connect added before it gave up (nothing saved yet)
+///
+/// Removes the routes to DNS servers (`dns_routes`, which the state doesn't
+/// record), every route `state` records and, on Windows, the tunnel's IPv6
+/// address. Returns the destinations removed; failures are logged.
+pub fn roll_back_connect(router: &VpnRouter, state: &VpnState, dns_routes: &[IpAddr]) -> Vec<String> {
+    let destinations = dns_routes
+        .iter()
+        .map(|ip| ip.to_string())
+        .chain(route_destinations(state).into_iter().map(|(_, destination, _)| destination));
+    let mut removed = Vec::new();
+    for destination in destinations {
+        match router.remove_ip_route(&destination) {
+            Ok(()) => removed.push(destination),
+            Err(e) => tracing::warn!("Rollback: failed to remove route {}: {}", destination, e),
+        }
+    }
+    #[cfg(target_os = "windows")]
+    if let Some(address) = state.ipv6_address
+        && let Err(e) = crate::platform::remove_interface_ipv6(address)
+    {
+        tracing::warn!("Rollback: failed to remove IPv6 address {}: {}", address, e);
+    }
+    removed
+}
+
+/// `cleanup_orphaned` with the router to remove routes through
+fn undo_connection(
+    state: &VpnState,
+    flush_dns: bool,
+    router: Result<VpnRouter, RoutingError>,
+) -> CleanupReport {
+    let mut report = CleanupReport::default();
+    if state.manage_hosts {
+        let hosts = HostsManager::for_path(state.hosts_file_path.as_deref())
+            .with_label(state.profile.clone());
+        match hosts.remove_entries() {
+            Ok(()) => report.hosts_restored = true,
+            Err(e) => report.failures.push(format!("hosts file: {}", e)),
+        }
+    }
+    if let Some(setup) = &state.resolver
+        && let Err(e) = resolver::restore(setup)
+    {
+        report.failures.push(format!("resolver: {}", e));
+    }
+
+    match router {
+        Ok(router) => {
+            for (hostname, destination, interface) in route_destinations(state) {
+                // Blackholed routes belong to no interface: remove them unchecked
+                let interface = interface.filter(|_| !state.kill_switch_active);
+                match router.remove_owned_route(&destination, interface) {
+                    Ok(RouteRemoval::Removed) => report.routes_removed.push(destination),
+                    Ok(RouteRemoval::AlreadyGone) => {
+                        tracing::info!("Route for {} ({}) is already gone", hostname, destination);
+                    }
+                    Ok(RouteRemoval::Repointed(current)) => tracing::info!(
+                        "Route for {} ({}) now goes through {}, leaving it alone",
+                        hostname,
+                        destination,
+                        current
+                    ),
+                    Err(e) => report
+                        .failures
+                        .push(format!("route for {} ({}): {}", hostname, destination, e)),
+                }
+            }
+            if let Some(full) = &state.full_tunnel
+                && let Err(e) = router.restore_default_route(&full.original_default)
+            {
+                report.failures.push(format!("default route: {}", e));
+            }
+        }
+        Err(e) => report.failures.push(format!("routes: {}", e)),
+    }
+
+    // A device outlives its process when persistent (or a wintun adapter)
+    if !state.is_daemon_running() {
+        let mut states = VpnState::load_all().unwrap_or_default();
+        states.push(state.clone());
+        if orphaned_interfaces(&states).contains(&state.tunnel_device) {
+            match crate::platform::remove_tunnel_interface(&state.tunnel_device) {
+                Ok(()) => {
+                    tracing::info!("Removed leftover tunnel device {}", state.tunnel_device);
+                    report.interface_removed = Some(state.tunnel_device.clone());
+                }
+                Err(e) => report.failures.push(format!("tunnel device: {}", e)),
+            }
+        } else if crate::platform::tunnel_interfaces().contains(&state.tunnel_device) {
+            tracing::info!(
+                "{} doesn't have our address {}; another program's device, leaving it alone",
+                state.tunnel_device,
+                state.gateway
+            );
+        }
+    }
+
+    // The adapter usually takes its address with it; this catches the rest
+    #[cfg(target_os = "windows")]
+    if let Some(address) = state.ipv6_address
+        && let Err(e) = crate::platform::remove_interface_ipv6(address)
+    {
+        report.failures.push(format!("IPv6 address {}: {}", address, e));
+    }
+
+    if let Err(e) = RoutesFile::delete(state.profile_name()) {
+        report.failures.push(format!("routes file: {}", e));
+    }
+    match VpnState::delete_profile(state.profile_name()) {
+        Ok(()) => report.state_deleted = true,
+        Err(e) => report.failures.push(format!("state file: {}", e)),
+    }
+
+    // Best effort: a resolver without a cache to flush isn't a failed cleanup
+    if flush_dns {
+        match crate::platform::flush_dns_cache() {
+            Ok(()) => report.dns_flushed = true,
+            Err(e) => tracing::warn!("{}", e),
+        }
+    }
+    report
+}
+
+/// Tunnel devices still present that belong to a connection in `states`
+/// whose process is gone, unless a live connection has reused the name
+///
+/// Names like `tun0` are shared with other VPN software, so a device only
+/// counts as ours while it still has the tunnel address the state records.
+pub fn orphaned_interfaces(states: &[VpnState]) -> Vec<String> {
+    let present: Vec<(String, Vec<IpAddr>)> = crate::platform::tunnel_interfaces()
+        .into_iter()
+        .map(|name| {
+            let addresses = crate::platform::interface_addresses(&name);
+            (name, addresses)
+        })
+        .collect();
+    orphaned_among(&present, states, VpnState::is_daemon_running)
+}
+
+/// `present` lists each tunnel device with its addresses
+fn orphaned_among(
+    present: &[(String, Vec<IpAddr>)],
+    states: &[VpnState],
+    live: impl Fn(&VpnState) -> bool,
+) -> Vec<String> {
+    let in_use: Vec<&str> = states
+        .iter()
+        .filter(|s| live(s))
+        .map(|s| s.tunnel_device.as_str())
+        .collect();
+    let ours = |state: &VpnState| {
+        present.iter().any(|(name, addresses)| {
+            *name == state.tunnel_device && addresses.contains(&state.gateway)
+        })
+    };
+    let mut orphaned: Vec<String> = states
+        .iter()
+        .filter(|s| !live(s) && ours(s) && !in_use.contains(&s.tunnel_device.as_str()))
+        .map(|s| s.tunnel_device.clone())
+        .collect();
+    orphaned.sort();
+    orphaned.dedup();
+    orphaned
+}
+
+/// Every route the state records, as (hostname, IP or CIDR network,
+/// interface it went through)
+///
+/// Full-tunnel routes come last, the gateway pin after the routes that
+/// needed it. The pin goes over the physical network, so it has no tunnel
+/// interface to check against.
+fn route_destinations(state: &VpnState) -> Vec<(&str, String, Option<&str>)> {
+    let full_tunnel = state.full_tunnel.iter().flat_map(|full| {
+        FULL_TUNNEL_NETWORKS
+            .iter()
+            .map(|network| (FULL_TUNNEL_LABEL, network.to_string(), state.route_interface()))
+            .chain([(FULL_TUNNEL_LABEL, full.gateway_pin.to_string(), None)])
+    });
+    state
+        .routes
+        .iter()
+        .map(|r| (r.hostname.as_str(), r.ip.to_string(), r.interface.as_deref()))
+        .chain(state.network_routes.iter().map(|r| {
+            (r.hostname.as_str(), r.network.clone(), r.interface.as_deref())
+        }))
+        .chain(full_tunnel)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_destinations() {
+        let mut state = VpnState::new("tun0".to_string(), "10.0.1.100".parse().unwrap());
+        state.add_route("a.example.com".to_string(), "10.0.0.1".parse().unwrap());
+        state.add_network_route("b.example.com".to_string(), "172.16.38.0/24".to_string());
+        assert_eq!(
+            route_destinations(&state),
+            [
+                ("a.example.com", "10.0.0.1".to_string(), Some("tun0")),
+                ("b.example.com", "172.16.38.0/24".to_string(), Some("tun0")),
+            ]
+        );
+
+        state.set_full_tunnel(crate::state::FullTunnelRoutes {
+            original_default: crate::platform::DefaultRoute {
+                gateway: "192.168.1.1".parse().unwrap(),
+                interface: Some("eth0".to_string()),
+            },
+            gateway_pin: "203.0.113.10".parse().unwrap(),
+        });
+        let destinations: Vec<(String, Option<&str>)> =
+            route_destinations(&state).into_iter().map(|(_, d, i)| (d, i)).collect();
+        assert_eq!(
+            destinations[2..],
+            [
+                ("0.0.0.0/1".to_string(), Some("tun0")),
+                ("128.0.0.0/1".to_string(), Some("tun0")),
+                ("203.0.113.10".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_orphaned_interfaces() {
+        let dead = VpnState::new("tun1".to_string(), "10.0.1.100".parse().unwrap());
+        let gone = VpnState::new("tun2".to_string(), "10.0.1.101".parse().unwrap());
+        let mut live = VpnState::new("tun0".to_string(), "10.0.1.102".parse().unwrap());
+        live.set_pid(1);
+        let present = vec![
+            ("tun0".to_string(), vec!["10.0.1.102".parse().unwrap()]),
+            ("tun1".to_string(), vec!["10.0.1.100".parse().unwrap()]),
+            ("tun3".to_string(), vec![]),
+        ];
+        let is_live = |s: &VpnState| s.pid.is_some();
+
+        // tun2 is already gone, tun3 isn't ours, tun0 is in use
+        let states = vec![dead.clone(), gone, live.clone()];
+        assert_eq!(orphaned_among(&present, &states, is_live), ["tun1"]);
+
+        // A live connection that got the same name keeps it
+        let mut reused = dead.clone();
+        reused.set_pid(2);
+        assert!(orphaned_among(&present, &[dead, reused], is_live).is_empty());
+    }
+
+    #[test]
+    fn test_foreign_device_with_our_old_name_is_not_orphaned() {
+        // A crashed connection recorded tun0; now OpenVPN has a tun0 of its own
+        let stale = VpnState::new("tun0".to_string(), "10.0.1.100".parse().unwrap());
+        let is_live = |s: &VpnState| s.pid.is_some();
+        let foreign = vec![("tun0".to_string(), vec!["10.8.0.2".parse().unwrap()])];
+        assert!(orphaned_among(&foreign, std::slice::from_ref(&stale), is_live).is_empty());
+
+        // Still carrying our address, it's the one we left behind
+        let ours = vec![("tun0".to_string(), vec!["10.0.1.100".parse().unwrap()])];
+        assert_eq!(orphaned_among(&ours, &[stale], is_live), ["tun0"]);
+    }
+
+    #[test]
+    fn test_report_is_clean() {
+        let mut report = DisconnectReport::default();
+        assert!(report.is_clean());
+        report.failures.push("hosts file: denied".to_string());
+        assert!(!report.is_clean());
+    }
+}
@@ -0,0 +1,239 @@
+//! Route orchestration against the in-memory routing manager
+//!
+//! Needs the `testing` feature: `cargo test --features testing`.
+#![cfg(feature = "testing")]
+
+use pmacs_vpn::platform::mock::{MockRoutingManager, RouteCall};
+use pmacs_vpn::platform::DefaultRoute;
+use pmacs_vpn::vpn::routing::{
+    network_cidr, RouteRemoval, RoutingError, VpnRouter, FULL_TUNNEL_NETWORKS,
+};
+use pmacs_vpn::{ConnectedVpn, FullTunnelRoutes, VpnState};
+use std::sync::OnceLock;
+
+fn router(mock: &MockRoutingManager) -> VpnRouter {
+    VpnRouter::with_interface("10.0.1.100".to_string(), "tun0".to_string())
+        .unwrap()
+        .with_routing_manager(mock.clone())
+}
+
+/// A connection's state, saved under a throwaway HOME (never the real one)
+fn saved_state(profile: &str) -> VpnState {
+    static HOME: OnceLock<tempfile::TempDir> = OnceLock::new();
+    HOME.get_or_init(|| {
+        let home = tempfile::TempDir::new().unwrap();
+        // SAFETY: set once, before any test in this binary reads state files
+        unsafe { std::env::set_var("HOME", home.path()) };
+        home
+    });
+    let mut state = VpnState::new("pmacs-itest0".to_string(), "10.0.1.100".parse().unwrap());
+    state.set_profile(Some(format!("itest-{}", profile)));
+    // Routes go through tun0 (see `router`); the device itself is made up
+    state.set_route_interface("tun0".to_string());
+    state.set_manage_hosts(false);
+    state.save().unwrap();
+    state
+}
+
+fn add(destination: &str) -> RouteCall {
+    RouteCall::Add {
+        destination: destination.to_string(),
+        gateway: "10.0.1.100".to_string(),
+    }
+}
+
+#[test]
+fn routes_hosts_and_networks_then_cleans_up_from_state() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock);
+    let mut state = saved_state("cleanup");
+
+    // Hosts as connect routes them: resolve (literal IPs here), route, record
+    for host in ["172.16.38.40", "172.16.38.41"] {
+        let (name, ip) = router.resolve_with_search(host, &[]).unwrap();
+        router.add_ip_route(&ip.to_string()).unwrap();
+        state.add_route(name, ip);
+    }
+    let network = network_cidr("172.16.40.7".parse().unwrap(), 24).unwrap();
+    router.add_network_route(&network).unwrap();
+    state.add_network_route("cluster".to_string(), network.clone());
+    state.save().unwrap();
+
+    assert_eq!(
+        mock.calls(),
+        [add("172.16.38.40"), add("172.16.38.41"), add("172.16.40.0/24")]
+    );
+
+    // Cleanup removes exactly what the state recorded, then the state itself
+    let report = pmacs_vpn::cleanup_with(&state, false, router);
+    assert!(report.is_clean(), "{:?}", report.failures);
+    assert_eq!(report.routes_removed, ["172.16.38.40", "172.16.38.41", "172.16.40.0/24"]);
+    assert!(report.state_deleted);
+    assert!(mock.routes().is_empty());
+    assert!(VpnState::load_profile("itest-cleanup").unwrap().is_none());
+
+    // Nothing left to do the second time
+    let calls = mock.calls().len();
+    let report = pmacs_vpn::cleanup_with(&state, false, self::router(&mock));
+    assert!(report.is_clean() && report.routes_removed.is_empty());
+    assert_eq!(mock.calls().len(), calls);
+}
+
+#[test]
+fn orphaned_cleanup_leaves_repointed_routes() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock);
+    let mut state = saved_state("orphaned");
+    for ip in ["172.16.38.40", "172.16.38.41"] {
+        router.add_ip_route(ip).unwrap();
+        state.add_route(ip.to_string(), ip.parse().unwrap());
+    }
+    // Another VPN took one of the routes over since
+    mock.repoint("172.16.38.41", "wg0");
+
+    let report = pmacs_vpn::cleanup_orphaned_with(&state, false, router);
+    assert!(report.is_clean(), "{:?}", report.failures);
+    assert_eq!(report.routes_removed, ["172.16.38.40"]);
+    assert_eq!(mock.routes(), ["172.16.38.41"]);
+}
+
+#[test]
+fn atomic_rollback_removes_everything_the_connect_added() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock);
+    let mut state = VpnState::new("pmacs-itest0".to_string(), "10.0.1.100".parse().unwrap());
+    let original = DefaultRoute {
+        gateway: "192.168.1.1".parse().unwrap(),
+        interface: Some("eth0".to_string()),
+    };
+
+    // DNS server routes aren't in the state; the rest is
+    let dns_routes: Vec<std::net::IpAddr> = vec!["10.0.1.53".parse().unwrap()];
+    router.add_ip_route("10.0.1.53").unwrap();
+    router.add_full_tunnel("203.0.113.10".parse().unwrap(), &original).unwrap();
+    state.set_full_tunnel(FullTunnelRoutes {
+        original_default: original,
+        gateway_pin: "203.0.113.10".parse().unwrap(),
+    });
+    router.add_ip_route("172.16.38.40").unwrap();
+    state.add_route("a.example.com".to_string(), "172.16.38.40".parse().unwrap());
+
+    let removed = pmacs_vpn::roll_back_connect(&router, &state, &dns_routes);
+    assert_eq!(
+        removed,
+        ["10.0.1.53", "172.16.38.40", "0.0.0.0/1", "128.0.0.0/1", "203.0.113.10"]
+    );
+    assert!(mock.routes().is_empty());
+}
+
+#[test]
+fn failed_and_refused_routes_change_nothing() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock);
+    mock.fail_on("172.16.38.40");
+
+    let err = router.add_ip_route("172.16.38.40").unwrap_err();
+    assert!(matches!(err, RoutingError::PlatformError(_)));
+
+    // IPv6 is refused before the routing manager is asked
+    let err = router.add_ip_route("fd00::1").unwrap_err();
+    assert!(matches!(err, RoutingError::Ipv6Disabled(_)));
+
+    assert!(mock.calls().is_empty());
+}
+
+#[test]
+fn kill_switch_blackholes_through_the_same_manager() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock).with_route_table(Some(100));
+
+    router.add_ip_route("172.16.38.40").unwrap();
+    router.remove_ip_route("172.16.38.40").unwrap();
+    router.add_blackhole_route("172.16.38.40").unwrap();
+
+    assert_eq!(mock.routes(), ["172.16.38.40"]);
+    assert_eq!(
+        mock.calls().last(),
+        Some(&RouteCall::Blackhole {
+            destination: "172.16.38.40".to_string()
+        })
+    );
+}
+
+#[test]
+fn full_tunnel_pins_the_gateway_before_taking_the_default() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock);
+    let original = DefaultRoute {
+        gateway: "192.168.1.1".parse().unwrap(),
+        interface: Some("eth0".to_string()),
+    };
+
+    router.add_full_tunnel("203.0.113.10".parse().unwrap(), &original).unwrap();
+    assert_eq!(
+        mock.calls(),
+        [
+            RouteCall::Add {
+                destination: "203.0.113.10".to_string(),
+                gateway: "192.168.1.1".to_string(),
+            },
+            add("0.0.0.0/1"),
+            add("128.0.0.0/1"),
+        ]
+    );
+}
+
+#[test]
+fn failed_full_tunnel_leaves_nothing_behind() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock);
+    let original = DefaultRoute {
+        gateway: "192.168.1.1".parse().unwrap(),
+        interface: None,
+    };
+    mock.fail_on(FULL_TUNNEL_NETWORKS[1]);
+
+    assert!(router.add_full_tunnel("203.0.113.10".parse().unwrap(), &original).is_err());
+    assert!(mock.routes().is_empty());
+}
+
+#[test]
+fn cleanup_leaves_routes_that_are_no_longer_ours() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock);
+    for ip in ["172.16.38.40", "172.16.38.41"] {
+        router.add_ip_route(ip).unwrap();
+    }
+    mock.repoint("172.16.38.41", "wg0");
+
+    let remove = |destination| router.remove_owned_route(destination, Some("tun0")).unwrap();
+    assert_eq!(remove("172.16.38.40"), RouteRemoval::Removed);
+    assert_eq!(remove("172.16.38.40"), RouteRemoval::AlreadyGone);
+    assert_eq!(remove("172.16.38.41"), RouteRemoval::Repointed("wg0".to_string()));
+    assert_eq!(mock.routes(), ["172.16.38.41"]);
+}
+
+#[tokio::test]
+async fn disconnect_stops_the_tunnel_task_and_removes_its_routes() {
+    let mock = MockRoutingManager::new();
+    let router = router(&mock);
+    let mut state = saved_state("disconnect");
+    router.add_ip_route("172.16.38.40").unwrap();
+    state.add_route("a.example.com".to_string(), "172.16.38.40".parse().unwrap());
+    state.save().unwrap();
+    let tunnel = tokio::spawn(std::future::pending::<()>());
+
+    let report = ConnectedVpn::from_state(state)
+        .with_tunnel_task(tunnel.abort_handle())
+        .with_dns_flush(false)
+        .with_router(router)
+        .disconnect()
+        .await;
+
+    assert!(report.tunnel_cancelled);
+    assert!(tunnel.await.unwrap_err().is_cancelled());
+    assert!(report.is_clean(), "{:?}", report.failures);
+    assert_eq!(report.routes_removed, ["172.16.38.40"]);
+    assert!(report.state_deleted);
+    assert!(mock.routes().is_empty());
+}